@@ -21,7 +21,6 @@ type PoolVariant = cfmms::dex::DexVariant;
 
 pub struct QilinBlockCollector<M> {
     provider: Arc<M>,
-    block_hash: Block<H256>,
     block: RwLock<Block<Transaction>>,
     fork_factory: Arc<ForkFactory>,
     all_pools: Arc<RwLockMap>,
@@ -164,8 +163,10 @@ where
             if let Some(hash) = block.hash {
                 rt.block_on(self.run_processore_n_update(&hash)).unwrap();
 
+                // the block just processed, with its tx hashes
+                let block_hash: Block<H256> = self.block.read().clone().into();
                 return Some(BlockPayload {
-                    block_hash: self.block_hash.clone(),
+                    block_hash,
                     all_pools: self.all_pools.clone(),
                 });
             } else {
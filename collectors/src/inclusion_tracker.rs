@@ -0,0 +1,365 @@
+use crate::types::{BundleOutcome, BundleResult, SentBundle};
+use ethers::{
+    providers::Middleware,
+    types::{Block, TransactionReceipt, H256, U256, U64},
+};
+use log::{error, info};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+
+/// Landing statistics of a strategy or a builder
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LandingStats {
+    pub sent: u64,
+    pub included: u64,
+    pub reverted: u64,
+    pub outbid: u64,
+    /// Simulated profit of the included bundles, before gas
+    pub profit: U256,
+    /// Gas fees paid by the included and reverted bundles, in wei
+    pub gas_spent: U256,
+}
+
+impl LandingStats {
+    /// Share of the resolved bundles that were included
+    pub fn landing_rate(&self) -> f64 {
+        let resolved = self.included + self.reverted + self.outbid;
+        if resolved == 0 {
+            return 0.0;
+        }
+        self.included as f64 / resolved as f64
+    }
+
+    /// Profit left after gas, negative if reverts cost more than the landed bundles made
+    pub fn net_profit(&self) -> (U256, bool) {
+        if self.profit >= self.gas_spent {
+            (self.profit - self.gas_spent, true)
+        } else {
+            (self.gas_spent - self.profit, false)
+        }
+    }
+
+    fn record(&mut self, bundle: &SentBundle, outcome: &BundleOutcome) {
+        match outcome {
+            BundleOutcome::Included { gas_spent, .. } => {
+                self.included += 1;
+                self.profit += bundle.expected_profit;
+                self.gas_spent += *gas_spent;
+            }
+            BundleOutcome::Reverted { gas_spent, .. } => {
+                self.reverted += 1;
+                self.gas_spent += *gas_spent;
+            }
+            BundleOutcome::Outbid { .. } => self.outbid += 1,
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct TrackerState {
+    pending: Vec<SentBundle>,
+    by_strategy: HashMap<String, LandingStats>,
+    by_builder: HashMap<String, LandingStats>,
+}
+
+/// Watches new blocks for the txs of the bundles we sent
+///
+/// A bundle is included if all of its txs landed and succeeded, reverted if only some of them
+/// landed or one of them failed, and outbid if its target block was built without it. Results
+/// are attributed to the bundle's strategy and to every builder that accepted it.
+#[derive(Debug, Default)]
+pub struct InclusionTracker {
+    state: Mutex<TrackerState>,
+}
+
+impl InclusionTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Start tracking a bundle
+    pub fn track(&self, bundle: SentBundle) {
+        let mut state = self.state.lock();
+        state
+            .by_strategy
+            .entry(bundle.strategy.clone())
+            .or_default()
+            .sent += 1;
+        for builder in &bundle.builders {
+            state.by_builder.entry(builder.clone()).or_default().sent += 1;
+        }
+        state.pending.push(bundle);
+    }
+
+    /// Number of bundles waiting for their target block
+    pub fn pending(&self) -> usize {
+        self.state.lock().pending.len()
+    }
+
+    pub fn strategy_stats(&self, strategy: &str) -> LandingStats {
+        self.state
+            .lock()
+            .by_strategy
+            .get(strategy)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    pub fn builder_stats(&self, builder: &str) -> LandingStats {
+        self.state
+            .lock()
+            .by_builder
+            .get(builder)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// Stats of every strategy that sent a bundle
+    pub fn all_strategy_stats(&self) -> HashMap<String, LandingStats> {
+        self.state.lock().by_strategy.clone()
+    }
+
+    /// Stats of every builder that accepted a bundle
+    pub fn all_builder_stats(&self) -> HashMap<String, LandingStats> {
+        self.state.lock().by_builder.clone()
+    }
+
+    /// Tracked tx hashes among `tx_hashes`, the receipts the tracker needs for a block
+    pub fn tracked_hashes(&self, tx_hashes: &[H256]) -> Vec<H256> {
+        let state = self.state.lock();
+        tx_hashes
+            .iter()
+            .filter(|hash| {
+                state
+                    .pending
+                    .iter()
+                    .any(|bundle| bundle.tx_hashes.contains(hash))
+            })
+            .copied()
+            .collect()
+    }
+
+    /// Resolve the bundles landed in, or targeting, a new block
+    ///
+    /// Arguments:
+    /// * `block_number`: number of the new block
+    /// * `tx_hashes`: hashes of the block's txs
+    /// * `receipts`: receipts of the tracked txs included in the block
+    ///
+    /// Returns the bundles resolved by the block
+    pub fn on_block(
+        &self,
+        block_number: U64,
+        tx_hashes: &[H256],
+        receipts: &HashMap<H256, TransactionReceipt>,
+    ) -> Vec<BundleResult> {
+        let mut state = self.state.lock();
+        let pending = std::mem::take(&mut state.pending);
+        let mut results = vec![];
+
+        for bundle in pending {
+            let landed: Vec<&H256> = bundle
+                .tx_hashes
+                .iter()
+                .filter(|hash| tx_hashes.contains(hash))
+                .collect();
+
+            let outcome = if landed.is_empty() {
+                if block_number < bundle.target_block {
+                    state.pending.push(bundle);
+                    continue;
+                }
+                BundleOutcome::Outbid {
+                    target_block: bundle.target_block,
+                }
+            } else {
+                let gas_spent = landed
+                    .iter()
+                    .filter_map(|hash| receipts.get(hash))
+                    .fold(U256::zero(), |total, receipt| total + gas_fees(receipt));
+                let failed = bundle.tx_hashes.iter().find(|hash| {
+                    !tx_hashes.contains(hash)
+                        || receipts.get(hash).and_then(|receipt| receipt.status)
+                            != Some(U64::from(1))
+                });
+
+                match failed {
+                    Some(tx_hash) => BundleOutcome::Reverted {
+                        block_number,
+                        tx_hash: *tx_hash,
+                        gas_spent,
+                    },
+                    None => BundleOutcome::Included {
+                        block_number,
+                        gas_spent,
+                    },
+                }
+            };
+
+            let TrackerState {
+                by_strategy,
+                by_builder,
+                ..
+            } = &mut *state;
+            by_strategy
+                .entry(bundle.strategy.clone())
+                .or_default()
+                .record(&bundle, &outcome);
+            for builder in &bundle.builders {
+                by_builder
+                    .entry(builder.clone())
+                    .or_default()
+                    .record(&bundle, &outcome);
+            }
+
+            info!(
+                "Bundle of {} for block {}: {:?}",
+                bundle.strategy, bundle.target_block, outcome
+            );
+            results.push(BundleResult { bundle, outcome });
+        }
+
+        results
+    }
+
+    /// Fetch the receipts of the tracked txs in `block` and resolve the bundles
+    ///
+    /// Meant to be called by a strategy as it handles each block of the block collector's
+    /// stream, rather than subscribing to blocks on its own.
+    pub async fn process_block<M: Middleware>(
+        &self,
+        provider: &M,
+        block: &Block<H256>,
+    ) -> Vec<BundleResult> {
+        let block_number = match block.number {
+            Some(number) => number,
+            None => return vec![],
+        };
+
+        let mut receipts = HashMap::new();
+        for hash in self.tracked_hashes(&block.transactions) {
+            match provider.get_transaction_receipt(hash).await {
+                Ok(Some(receipt)) => {
+                    receipts.insert(hash, receipt);
+                }
+                Ok(None) => {}
+                Err(e) => error!("Error getting receipt of {:?}: {}", hash, e),
+            }
+        }
+
+        self.on_block(block_number, &block.transactions, &receipts)
+    }
+}
+
+/// Fees paid by a tx, in wei
+fn gas_fees(receipt: &TransactionReceipt) -> U256 {
+    receipt.gas_used.unwrap_or_default() * receipt.effective_gas_price.unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bundle(strategy: &str, builders: &[&str], hashes: &[u64], target_block: u64) -> SentBundle {
+        SentBundle {
+            strategy: strategy.to_string(),
+            builders: builders.iter().map(|builder| builder.to_string()).collect(),
+            tx_hashes: hashes
+                .iter()
+                .map(|hash| H256::from_low_u64_be(*hash))
+                .collect(),
+            target_block: target_block.into(),
+            expected_profit: U256::from(1000),
+        }
+    }
+
+    fn receipt(hash: u64, status: u64) -> (H256, TransactionReceipt) {
+        let receipt = TransactionReceipt {
+            transaction_hash: H256::from_low_u64_be(hash),
+            status: Some(status.into()),
+            gas_used: Some(100.into()),
+            effective_gas_price: Some(2.into()),
+            ..Default::default()
+        };
+        (receipt.transaction_hash, receipt)
+    }
+
+    #[test]
+    fn test_bundle_outcomes_and_stats() {
+        let tracker = InclusionTracker::new();
+        tracker.track(bundle("sandwich", &["a", "b"], &[1, 2], 10));
+        tracker.track(bundle("sandwich", &["a"], &[3], 10));
+        tracker.track(bundle("arb", &["b"], &[4, 5], 10));
+        tracker.track(bundle("arb", &["b"], &[6], 11));
+
+        let block_txs: Vec<H256> = [7, 1, 2, 4, 5].map(H256::from_low_u64_be).to_vec();
+        let receipts: HashMap<H256, TransactionReceipt> =
+            [receipt(1, 1), receipt(2, 1), receipt(4, 1), receipt(5, 0)]
+                .into_iter()
+                .collect();
+
+        let results = tracker.on_block(10.into(), &block_txs, &receipts);
+        assert_eq!(results.len(), 3);
+        assert_eq!(
+            results[0].outcome,
+            BundleOutcome::Included {
+                block_number: 10.into(),
+                gas_spent: 400.into()
+            }
+        );
+        assert_eq!(
+            results[1].outcome,
+            BundleOutcome::Outbid {
+                target_block: 10.into()
+            }
+        );
+        assert_eq!(
+            results[2].outcome,
+            BundleOutcome::Reverted {
+                block_number: 10.into(),
+                tx_hash: H256::from_low_u64_be(5),
+                gas_spent: 400.into()
+            }
+        );
+        // the bundle for block 11 is still pending
+        assert_eq!(tracker.pending(), 1);
+
+        let sandwich = tracker.strategy_stats("sandwich");
+        assert_eq!(
+            (sandwich.sent, sandwich.included, sandwich.outbid),
+            (2, 1, 1)
+        );
+        assert_eq!(sandwich.landing_rate(), 0.5);
+        assert_eq!(sandwich.net_profit(), (U256::from(600), true));
+
+        let b = tracker.builder_stats("b");
+        assert_eq!((b.sent, b.included, b.reverted), (3, 1, 1));
+        assert_eq!(b.gas_spent, U256::from(800));
+    }
+
+    #[tokio::test]
+    async fn test_process_block() {
+        let tracker = InclusionTracker::new();
+        tracker.track(bundle("sandwich", &["a"], &[1], 10));
+
+        // only the receipt of the tracked tx is fetched
+        let (provider, mock) = ethers::providers::Provider::mocked();
+        mock.push(receipt(1, 1).1).unwrap();
+        let block = Block {
+            number: Some(10.into()),
+            transactions: [7, 1].map(H256::from_low_u64_be).to_vec(),
+            ..Default::default()
+        };
+
+        let results = tracker.process_block(&provider, &block).await;
+        assert_eq!(results.len(), 1);
+        assert_eq!(
+            results[0].outcome,
+            BundleOutcome::Included {
+                block_number: 10.into(),
+                gas_spent: 200.into()
+            }
+        );
+        assert_eq!(tracker.pending(), 0);
+    }
+}
@@ -1,5 +1,8 @@
 pub mod block_collector;
+pub mod inclusion_tracker;
 pub mod mempool_collector;
+pub mod mev_share_collector;
 pub mod slot_finder;
 pub mod state_diff;
+pub mod tx_tracker;
 pub mod types;
@@ -1,5 +1,8 @@
-use super::state_diff::{get_from_txs, StateDiffError};
-use crate::types::NewTx;
+use super::state_diff::{extract_pool_swaps, get_from_txs, StateDiffError};
+use crate::{
+    tx_tracker::{PendingTxTracker, TrackOutcome},
+    types::{NewTx, PoolSwap, RwLockMap},
+};
 use anyhow::Result;
 use artemis::types::{Collector, CollectorStream};
 use async_trait::async_trait;
@@ -10,6 +13,7 @@ use ethers::{
 };
 use log::error;
 use parking_lot::RwLock;
+use qilin_cfmms::pool::Pool;
 use std::collections::BTreeMap;
 use std::sync::Arc;
 use thiserror::Error;
@@ -19,11 +23,33 @@ use tokio_stream::StreamExt;
 pub struct QilinMempoolCollector<M> {
     provider: Arc<M>,
     block: RwLock<Block<H256>>,
+    tracker: Option<Arc<PendingTxTracker>>,
+    all_pools: Option<Arc<RwLockMap>>,
 }
 
 impl NewTx {
-    pub fn new(tx: Transaction, state_diff: BTreeMap<H160, AccountDiff>) -> Self {
-        Self { tx, state_diff }
+    pub fn new(
+        tx: Transaction,
+        state_diff: BTreeMap<H160, AccountDiff>,
+        effective_tip: U256,
+    ) -> Self {
+        Self {
+            tx,
+            state_diff,
+            effective_tip,
+            swaps: None,
+        }
+    }
+
+    /// Attach the swaps decoded from the tx's state diff
+    pub fn with_swaps(mut self, swaps: Vec<PoolSwap>) -> Self {
+        self.swaps = Some(swaps);
+        self
+    }
+
+    /// Pools the tx swaps against, empty if the swaps weren't decoded
+    pub fn touched_pools(&self) -> Vec<Pool> {
+        self.swaps.iter().flatten().map(|swap| swap.pool).collect()
     }
 }
 
@@ -71,6 +97,36 @@ where
         Self {
             provider,
             block: RwLock::new(block),
+            tracker: None,
+            all_pools: None,
+        }
+    }
+
+    /// Decode the swaps each tx performs against the given pools and attach them to [NewTx]
+    pub fn with_pools(mut self, all_pools: Arc<RwLockMap>) -> Self {
+        self.all_pools = Some(all_pools);
+        self
+    }
+
+    /// Feed every pending tx through the given tracker, dropping duplicates and underpriced
+    /// replacements before they are traced
+    pub fn with_tracker(mut self, tracker: Arc<PendingTxTracker>) -> Self {
+        self.tracker = Some(tracker);
+        self
+    }
+
+    /// Returns `false` if the tx was already seen or doesn't outbid the tx it replaces
+    fn track(&self, tx: &Transaction) -> bool {
+        let tracker = if let Some(tracker) = &self.tracker {
+            tracker
+        } else {
+            return true;
+        };
+
+        let block_number = self.block.read().number.unwrap_or_default();
+        match tracker.on_pending(tx, block_number) {
+            TrackOutcome::New | TrackOutcome::Replacement(_) => true,
+            TrackOutcome::Duplicate | TrackOutcome::Underpriced => false,
         }
     }
 
@@ -79,10 +135,12 @@ where
         *block_writer = new_block;
     }
 
+    /// Drop txs that can't pay the next block's base fee, returning the tx along with the
+    /// effective tip it pays at the next block
     async fn drop_max_fee_per_gas_tx<'a>(
         &self,
         tx: &'a mut Transaction,
-    ) -> Result<&'a mut Transaction, MempoolCollectorError<M>> {
+    ) -> Result<(&'a mut Transaction, U256), MempoolCollectorError<M>> {
         let block_num = if let Ok(block_num) = self.provider.get_block_number().await {
             block_num
         } else {
@@ -103,9 +161,12 @@ where
             return Err(MempoolCollectorError::BlockBaseFeeError);
         };
 
-        if tx.max_fee_per_gas.unwrap_or(U256::zero()) < next_block_base_fee {
+        // legacy and access list txs only carry a gas price, eip-1559 txs a max fee and tip
+        let effective_tip = if let Some(tip) = fees::effective_tip(tx, next_block_base_fee) {
+            tip
+        } else {
             return Err(MempoolCollectorError::MaxFeeCalcError);
-        }
+        };
 
         if let Ok(from) = tx.recover_from() {
             tx.from = from;
@@ -113,7 +174,7 @@ where
             return Err(MempoolCollectorError::EcdsaRecoveryError);
         };
 
-        Ok(tx)
+        Ok((tx, effective_tip))
     }
 
     pub async fn get_account_diffs(
@@ -155,16 +216,25 @@ where
                 return None;
             };
 
-            let tx = if let Ok(tx) = rt.block_on(self.drop_max_fee_per_gas_tx(&mut incoming_tx)) {
-                tx
-            } else {
+            let (tx, effective_tip) =
+                if let Ok(res) = rt.block_on(self.drop_max_fee_per_gas_tx(&mut incoming_tx)) {
+                    res
+                } else {
+                    return None;
+                };
+
+            if !self.track(tx) {
                 return None;
-            };
+            }
 
             let state_diff = rt.block_on(self.get_account_diffs(tx));
 
             if let Some(state_diff) = state_diff.ok() {
-                let res = NewTx::new(tx.clone(), state_diff);
+                let mut res = NewTx::new(tx.clone(), state_diff, effective_tip);
+                if let Some(all_pools) = &self.all_pools {
+                    let swaps = extract_pool_swaps(&res.state_diff, &all_pools.read());
+                    res = res.with_swaps(swaps);
+                }
                 return Some(res);
             } else {
                 return None;
@@ -0,0 +1,233 @@
+use crate::types::MevShareEvent;
+use anyhow::Result;
+use artemis::types::{Collector, CollectorStream};
+use async_trait::async_trait;
+use futures::{future, stream, Stream, StreamExt};
+use log::{error, info, warn};
+use reqwest::header::{ACCEPT, CACHE_CONTROL};
+use std::time::Duration;
+
+/// Flashbots MEV-Share event stream on mainnet
+pub const MEV_SHARE_MAINNET_URL: &str = "https://mev-share.flashbots.net";
+/// Flashbots MEV-Share event stream on goerli
+pub const MEV_SHARE_GOERLI_URL: &str = "https://mev-share-goerli.flashbots.net";
+/// Delay before the first reconnection attempt once the event stream ends
+const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+/// Longest delay between two failed reconnection attempts
+const DEFAULT_MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// Collects the hints of private orderflow broadcasted by an MEV-Share style SSE endpoint
+///
+/// The endpoint drops idle or long lived connections, so the stream reconnects whenever it
+/// ends, doubling the delay between failed attempts up to `max_reconnect_delay`.
+pub struct MevShareCollector {
+    url: String,
+    client: reqwest::Client,
+    reconnect_delay: Duration,
+    max_reconnect_delay: Duration,
+}
+
+impl MevShareCollector {
+    pub fn new(url: impl Into<String>) -> Self {
+        Self {
+            url: url.into(),
+            client: reqwest::Client::new(),
+            reconnect_delay: DEFAULT_RECONNECT_DELAY,
+            max_reconnect_delay: DEFAULT_MAX_RECONNECT_DELAY,
+        }
+    }
+
+    /// Set the delay before the first reconnection attempt and the longest backoff
+    pub fn with_reconnect_delay(mut self, delay: Duration, max_delay: Duration) -> Self {
+        self.reconnect_delay = delay;
+        self.max_reconnect_delay = max_delay.max(delay);
+        self
+    }
+
+    async fn connect(&self) -> Result<reqwest::Response> {
+        Ok(self
+            .client
+            .get(&self.url)
+            .header(ACCEPT, "text/event-stream")
+            .header(CACHE_CONTROL, "no-cache")
+            .send()
+            .await?
+            .error_for_status()?)
+    }
+
+    /// Connect again once the stream ended, backing off until the endpoint answers
+    async fn reconnect(&self) -> reqwest::Response {
+        let mut delay = self.reconnect_delay;
+        let mut attempt = 1;
+        loop {
+            warn!(
+                "MEV-Share event stream ended, reconnecting in {:?} (attempt {})",
+                delay, attempt
+            );
+            tokio::time::sleep(delay).await;
+            match self.connect().await {
+                Ok(response) => {
+                    info!("Reconnected to the MEV-Share event stream {}", self.url);
+                    return response;
+                }
+                Err(e) => error!("Failed to reconnect to the MEV-Share event stream: {}", e),
+            }
+            delay = (delay * 2).min(self.max_reconnect_delay);
+            attempt += 1;
+        }
+    }
+}
+
+#[async_trait]
+impl Collector<MevShareEvent> for MevShareCollector {
+    /// Stream the events of the endpoint, reconnecting whenever the connection ends
+    ///
+    /// Fails only if the first connection does.
+    async fn get_event_stream(&self) -> Result<CollectorStream<'_, MevShareEvent>> {
+        let response = self.connect().await?;
+
+        let connections = stream::unfold(Some(response), move |response| async move {
+            let response = match response {
+                Some(response) => response,
+                None => self.reconnect().await,
+            };
+            Some((decode_events(response), None))
+        });
+
+        Ok(Box::pin(connections.flatten()))
+    }
+}
+
+/// The events sent over one connection, until it ends or fails
+fn decode_events(response: reqwest::Response) -> impl Stream<Item = MevShareEvent> {
+    response
+        .bytes_stream()
+        .scan(SseDecoder::default(), |decoder, chunk| {
+            let payloads = match chunk {
+                Ok(bytes) => decoder.push(&bytes),
+                Err(e) => {
+                    error!("MEV-Share event stream error: {}", e);
+                    return future::ready(None);
+                }
+            };
+            future::ready(Some(stream::iter(payloads)))
+        })
+        .flatten()
+        .filter_map(|payload| async move {
+            match serde_json::from_str::<MevShareEvent>(&payload) {
+                Ok(event) => Some(event),
+                Err(e) => {
+                    warn!("Failed to decode MEV-Share event {}: {}", payload, e);
+                    None
+                }
+            }
+        })
+}
+
+/// Incrementally splits a server-sent events byte stream into the `data` payload of each event
+#[derive(Debug, Default)]
+pub struct SseDecoder {
+    buffer: Vec<u8>,
+}
+
+impl SseDecoder {
+    /// Feed a chunk of the stream, returning the payloads of all events completed by it
+    ///
+    /// Comments (used as heartbeats) and events without data are skipped
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<String> {
+        self.buffer
+            .extend(chunk.iter().copied().filter(|byte| *byte != b'\r'));
+
+        let mut payloads = vec![];
+        while let Some(end) = self.buffer.windows(2).position(|w| w == b"\n\n") {
+            let raw_event: Vec<u8> = self.buffer.drain(..end + 2).collect();
+            let raw_event = String::from_utf8_lossy(&raw_event);
+
+            let data: Vec<&str> = raw_event
+                .lines()
+                .filter_map(|line| line.strip_prefix("data:"))
+                .map(|data| data.strip_prefix(' ').unwrap_or(data))
+                .collect();
+
+            if !data.is_empty() {
+                payloads.push(data.join("\n"));
+            }
+        }
+        payloads
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::H256;
+    use test_utils::MockServer;
+
+    const FIRST_EVENT: &str = r#"{"hash":"0x1111111111111111111111111111111111111111111111111111111111111111","logs":[{"address":"0x88e6a0c2ddd26feeb64f039a2c41296fcb3f5640","topics":["0xc42079f94a6350d7e6235f29174924f928cc2ac818eb64fed8004e115fbcca67"],"data":"0x"}],"txs":null}"#;
+    const SECOND_EVENT: &str = r#"{"hash":"0x2222222222222222222222222222222222222222222222222222222222222222","logs":null,"txs":[{"to":"0x7a250d5630b4cf539739df2c5dacb4c659f2488d","functionSelector":"0x7ff36ab5","callData":"0x7ff36ab5"}]}"#;
+
+    #[test]
+    fn test_sse_decoder_split_chunks() {
+        let mut decoder = SseDecoder::default();
+
+        assert!(decoder.push(b": ping\n\ndata: {\"a\"").is_empty());
+        assert_eq!(decoder.push(b":1}\r\n\r\ndata: 2\n"), vec!["{\"a\":1}"]);
+        assert_eq!(decoder.push(b"\n"), vec!["2"]);
+    }
+
+    #[tokio::test]
+    async fn test_mev_share_collector() {
+        let (url, _) = MockServer::sse([FIRST_EVENT, "not json", SECOND_EVENT])
+            .spawn()
+            .await;
+        let collector = MevShareCollector::new(url);
+
+        let events: Vec<MevShareEvent> = collector
+            .get_event_stream()
+            .await
+            .unwrap()
+            .take(2)
+            .collect()
+            .await;
+
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].hash, H256::repeat_byte(0x11));
+        assert_eq!(events[0].logs().len(), 1);
+        assert!(events[0].txs().is_empty());
+
+        let tx = &events[1].txs()[0];
+        assert_eq!(
+            tx.function_selector.as_ref().map(|s| s.to_vec()),
+            Some(vec![0x7f, 0xf3, 0x6a, 0xb5])
+        );
+        assert!(events[1].logs().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_mev_share_collector_reconnects() {
+        let (url, received) = MockServer::sse([FIRST_EVENT])
+            .with_connections(3)
+            .spawn()
+            .await;
+        let collector = MevShareCollector::new(url)
+            .with_reconnect_delay(Duration::from_millis(10), Duration::from_millis(100));
+
+        // every connection closes after one event, the stream keeps going
+        let events: Vec<MevShareEvent> = collector
+            .get_event_stream()
+            .await
+            .unwrap()
+            .take(3)
+            .collect()
+            .await;
+
+        assert_eq!(events.len(), 3);
+        assert!(events
+            .iter()
+            .all(|event| event.hash == H256::repeat_byte(0x11)));
+        assert_eq!(
+            received.lock().unwrap().as_ref().unwrap().header("accept"),
+            Some("text/event-stream".to_string())
+        );
+    }
+}
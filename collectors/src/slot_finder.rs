@@ -1,119 +1,553 @@
-use ethers::abi::Abi;
-use ethers::prelude::*;
-use ethers::types::U256;
+use dashmap::DashMap;
 use ethers::{
-    contract::Contract,
-    providers::{Middleware, Provider},
-    types::H160,
+    abi::{self, Token},
+    types::{Address, Bytes, H256, U256},
+    utils::keccak256,
 };
-use log;
-use std::fs::File;
-use std::io::Read;
-use std::sync::Arc;
-
-/// Given a ERC20 token address and a pool address, find storage slot in the `balanceOf` mapping
-pub async fn slot_finder(
-    provider: Arc<Provider<Ws>>,
-    token_address: H160,
-    pool_address: H160,
-) -> Option<U256> {
-    let mut file = File::open("..abi/erc20.json").unwrap();
-    let mut contents = String::new();
-    file.read_to_string(&mut contents).unwrap();
-    let abi: Abi = serde_json::from_str(&contents).unwrap();
-
-    let token = Contract::new(token_address, abi.clone(), provider.clone());
-
-    //     let token_decimals = token.method::<_, u128>("decimals", ()).unwrap().call().await.unwrap();
-    //     let token_symnol = token.method::<_, String>("symbol", ()).unwrap().call().await.unwrap();
-    //     println!("token_decimals: {:?}", token_decimals);
-    //     println!("token_symnol: {:?}", token_symnol);
-
-    let balance = match token
-        .method::<_, U256>("balanceOf", pool_address.clone())
-        .unwrap()
-        .call()
-        .await
+use log::warn;
+use revm::{
+    db::DatabaseRef,
+    primitives::{AccountInfo, Bytecode, ExecutionResult, Output, TransactTo, B160, B256},
+    EVM,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt::Debug,
+    fs,
+    io::Write,
+    path::PathBuf,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+use thiserror::Error;
+
+type RU256 = revm::primitives::U256;
+
+/// `balanceOf(address)`
+const BALANCE_OF_SELECTOR: [u8; 4] = [0x70, 0xa0, 0x82, 0x31];
+/// `allowance(address,address)`
+const ALLOWANCE_SELECTOR: [u8; 4] = [0xdd, 0x62, 0xed, 0x3e];
+/// Default number of storage slots probed per layout
+pub const DEFAULT_MAX_SLOT: u64 = 100;
+/// Default number of blocks before a token whose slot wasn't found is probed again
+pub const DEFAULT_RETRY_AFTER: u64 = 7200;
+/// Number of layout changes written to the cache file at once
+const FLUSH_BATCH: usize = 16;
+
+#[derive(Error, Debug)]
+pub enum SlotFinderError {
+    #[error("Could not find the storage slot of {0} on token {1:?}")]
+    SlotNotFound(&'static str, Address),
+    #[error("EVM error while probing token {0:?}: {1}")]
+    EvmError(Address, String),
+    #[error("Failed to access the storage layout cache")]
+    CacheError(#[from] std::io::Error),
+    #[error("Failed to (de)serialize the storage layout cache")]
+    SerializationError(#[from] serde_json::Error),
+}
+
+/// How a compiler lays out the storage of a `mapping` entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MappingLayout {
+    /// `keccak256(key . slot)`
+    Solidity,
+    /// `keccak256(slot . key)`
+    Vyper,
+}
+
+/// Location of a mapping in a contract's storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MappingSlot {
+    pub slot: U256,
+    pub layout: MappingLayout,
+}
+
+impl MappingSlot {
+    pub fn new(slot: U256, layout: MappingLayout) -> Self {
+        Self { slot, layout }
+    }
+
+    /// Storage key of `mapping[key]`
+    pub fn key(&self, key: H256) -> H256 {
+        let mut slot = H256::zero();
+        self.slot.to_big_endian(slot.as_bytes_mut());
+        hash_key(self.layout, slot, key)
+    }
+
+    /// Storage key of `mapping[outer][inner]`
+    pub fn nested_key(&self, outer: H256, inner: H256) -> H256 {
+        hash_key(self.layout, self.key(outer), inner)
+    }
+
+    /// Storage key of `balanceOf[holder]`, `allowance[holder]` etc.
+    pub fn address_key(&self, address: Address) -> H256 {
+        self.key(H256::from(address))
+    }
+}
+
+fn hash_key(layout: MappingLayout, slot: H256, key: H256) -> H256 {
+    let (first, second) = match layout {
+        MappingLayout::Solidity => (key, slot),
+        MappingLayout::Vyper => (slot, key),
+    };
+    H256::from(keccak256(abi::encode(&[
+        Token::FixedBytes(first.as_bytes().to_vec()),
+        Token::FixedBytes(second.as_bytes().to_vec()),
+    ])))
+}
+
+/// The storage slots detected for an ERC20 token
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TokenLayout {
+    pub balance_of: Option<MappingSlot>,
+    pub allowance: Option<MappingSlot>,
+    /// Block at which no `balanceOf` slot was found
+    pub balance_of_missing_at: Option<u64>,
+    /// Block at which no `allowance` slot was found
+    pub allowance_missing_at: Option<u64>,
+}
+
+/// Finds the `balanceOf` and `allowance` storage slots of ERC20 tokens
+///
+/// Candidate slots are probed by overriding a single storage value and calling the token in
+/// revm, so probing only touches the given fork database and never costs an RPC call on its own.
+/// Results are cached per token and can be persisted to disk. Tokens where no slot was found are
+/// only probed again `retry_after` blocks later.
+///
+/// Layouts are written in batches; the owner must call [flush](Self::flush) at shutdown to
+/// persist the last ones.
+#[derive(Debug)]
+pub struct StorageLayoutFinder {
+    layouts: DashMap<Address, TokenLayout>,
+    cache_path: Option<PathBuf>,
+    max_slot: u64,
+    retry_after: u64,
+    /// Layout changes not written to the cache file yet
+    unflushed: AtomicUsize,
+}
+
+impl Default for StorageLayoutFinder {
+    fn default() -> Self {
+        Self {
+            layouts: DashMap::new(),
+            cache_path: None,
+            max_slot: DEFAULT_MAX_SLOT,
+            retry_after: DEFAULT_RETRY_AFTER,
+            unflushed: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl StorageLayoutFinder {
+    /// Create a new finder, loading previously detected layouts from `cache_path` if it exists
+    pub fn new(cache_path: Option<PathBuf>) -> Self {
+        let layouts = cache_path
+            .as_ref()
+            .and_then(|path| Self::load(path).ok())
+            .unwrap_or_default();
+
+        Self {
+            layouts,
+            cache_path,
+            ..Default::default()
+        }
+    }
+
+    /// Set the number of slots probed per layout
+    pub fn with_max_slot(mut self, max_slot: u64) -> Self {
+        self.max_slot = max_slot;
+        self
+    }
+
+    /// Set the number of blocks before a token whose slot wasn't found is probed again
+    pub fn with_retry_after(mut self, blocks: u64) -> Self {
+        self.retry_after = blocks;
+        self
+    }
+
+    fn load(path: &PathBuf) -> Result<DashMap<Address, TokenLayout>, SlotFinderError> {
+        let file = fs::File::open(path)?;
+        let layouts: BTreeMap<Address, TokenLayout> =
+            serde_json::from_reader(std::io::BufReader::new(file))?;
+        Ok(layouts.into_iter().collect())
+    }
+
+    /// Write all detected layouts to the cache file, if one is configured
+    ///
+    /// Layouts are written every few detections, call this to persist them right away and at
+    /// shutdown. The pending changes are kept if writing fails.
+    pub fn flush(&self) -> Result<(), SlotFinderError> {
+        let pending = self.unflushed.load(Ordering::Relaxed);
+        if let Some(path) = &self.cache_path {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let layouts: BTreeMap<Address, TokenLayout> = self
+                .layouts
+                .iter()
+                .map(|entry| (*entry.key(), *entry.value()))
+                .collect();
+            let mut writer = std::io::BufWriter::new(fs::File::create(path)?);
+            serde_json::to_writer(&mut writer, &layouts)?;
+            writer.flush()?;
+        }
+        // changes recorded while writing are left for the next flush
+        self.unflushed.fetch_sub(pending, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Number of layout changes not written to the cache file yet
+    pub fn unflushed(&self) -> usize {
+        self.unflushed.load(Ordering::Relaxed)
+    }
+
+    /// Returns the layout detected so far for the token, without probing
+    pub fn cached(&self, token: Address) -> Option<TokenLayout> {
+        self.layouts.get(&token).map(|layout| *layout)
+    }
+
+    /// Find the slot of the `balanceOf` mapping of the token
+    ///
+    /// Arguments:
+    /// * `db`: database the token is probed against
+    /// * `token`: the ERC20 token
+    /// * `block`: the block `db` is at, a token without a slot isn't probed again for
+    /// `retry_after` blocks
+    pub fn balance_of_slot<DB>(
+        &self,
+        db: &DB,
+        token: Address,
+        block: u64,
+    ) -> Result<MappingSlot, SlotFinderError>
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
     {
-        Ok(b) => b,
-        Err(e) => {
-            log::error!("Error: {}", e);
-            return None;
+        let layout = self.cached(token).unwrap_or_default();
+        if let Some(slot) = layout.balance_of {
+            return Ok(slot);
+        }
+        if self.missing_since(layout.balance_of_missing_at, block) {
+            return Err(SlotFinderError::SlotNotFound("balanceOf", token));
         }
-    };
 
-    let mut slot;
-    // TODO: use threads
-    // TODO: use while loop
-    for i in 0..=100 {
-        slot = U256::from(i);
-        let tx_hash = TxHash::from(ethers::utils::keccak256(abi::encode(&[
-            abi::Token::Address(pool_address),
-            abi::Token::Uint(slot.clone()),
-        ])));
+        let holder = probe_address(1);
+        let mut calldata = BALANCE_OF_SELECTOR.to_vec();
+        calldata.extend(abi::encode(&[Token::Address(holder)]));
 
-        let storage_value: TxHash = provider
-            .clone()
-            .get_storage_at(token_address.clone(), tx_hash, None)
-            .await
-            .unwrap();
-        let storage_value_u256 = U256::from_big_endian(&storage_value.as_bytes());
+        let slot =
+            self.find_mapping_slot(db, token, calldata.into(), |slot| slot.address_key(holder))?;
 
-        if storage_value_u256 == balance.clone() {
-            return Some(U256::from(i));
+        {
+            let mut layout = self.layouts.entry(token).or_default();
+            layout.balance_of = slot;
+            layout.balance_of_missing_at = slot.is_none().then_some(block);
         }
+        self.record_change();
+        slot.ok_or(SlotFinderError::SlotNotFound("balanceOf", token))
     }
 
-    // TODO: add vyper support
+    /// Find the slot of the `allowance` mapping of the token, see [Self::balance_of_slot]
+    pub fn allowance_slot<DB>(
+        &self,
+        db: &DB,
+        token: Address,
+        block: u64,
+    ) -> Result<MappingSlot, SlotFinderError>
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+    {
+        let layout = self.cached(token).unwrap_or_default();
+        if let Some(slot) = layout.allowance {
+            return Ok(slot);
+        }
+        if self.missing_since(layout.allowance_missing_at, block) {
+            return Err(SlotFinderError::SlotNotFound("allowance", token));
+        }
+
+        let (owner, spender) = (probe_address(1), probe_address(2));
+        let mut calldata = ALLOWANCE_SELECTOR.to_vec();
+        calldata.extend(abi::encode(&[
+            Token::Address(owner),
+            Token::Address(spender),
+        ]));
+
+        let slot = self.find_mapping_slot(db, token, calldata.into(), |slot| {
+            slot.nested_key(H256::from(owner), H256::from(spender))
+        })?;
+
+        {
+            let mut layout = self.layouts.entry(token).or_default();
+            layout.allowance = slot;
+            layout.allowance_missing_at = slot.is_none().then_some(block);
+        }
+        self.record_change();
+        slot.ok_or(SlotFinderError::SlotNotFound("allowance", token))
+    }
+
+    /// Find the mapping slot read by a view call on `contract`
+    ///
+    /// For every candidate slot and layout, the storage key returned by `storage_key` is
+    /// overridden with a marker value, the call is considered to read the mapping if it returns
+    /// the marker
+    pub fn find_mapping_slot<DB, F>(
+        &self,
+        db: &DB,
+        contract: Address,
+        calldata: Bytes,
+        storage_key: F,
+    ) -> Result<Option<MappingSlot>, SlotFinderError>
+    where
+        DB: DatabaseRef,
+        DB::Error: Debug,
+        F: Fn(&MappingSlot) -> H256,
+    {
+        let marker = U256::from_big_endian(&keccak256(b"qilin.slot_finder.marker"));
 
-    None
+        for i in 0..self.max_slot {
+            for layout in [MappingLayout::Solidity, MappingLayout::Vyper] {
+                let candidate = MappingSlot::new(U256::from(i), layout);
+                let probe_db = ProbeDb {
+                    db,
+                    address: B160(contract.0),
+                    index: RU256::from_be_bytes(storage_key(&candidate).to_fixed_bytes()),
+                    value: RU256::from_be_bytes(h256_from_u256(marker).to_fixed_bytes()),
+                };
+
+                if probe_call(probe_db, contract, calldata.clone())? == Some(marker) {
+                    return Ok(Some(candidate));
+                }
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Whether the slot wasn't found less than `retry_after` blocks before `block`
+    fn missing_since(&self, missing_at: Option<u64>, block: u64) -> bool {
+        missing_at.map_or(false, |at| block.saturating_sub(at) < self.retry_after)
+    }
+
+    /// Count a layout change, flushing once a batch of them is pending
+    fn record_change(&self) {
+        if self.unflushed.fetch_add(1, Ordering::Relaxed) + 1 >= FLUSH_BATCH {
+            self.flush_logged();
+        }
+    }
+
+    fn flush_logged(&self) {
+        if let Err(e) = self.flush() {
+            warn!("Failed to flush storage layout cache: {}", e);
+        }
+    }
+}
+
+fn probe_address(n: u64) -> Address {
+    Address::from_low_u64_be(0x51_07_f1_4d_00 + n)
+}
+
+fn h256_from_u256(value: U256) -> H256 {
+    let mut h = H256::zero();
+    value.to_big_endian(h.as_bytes_mut());
+    h
+}
+
+/// Run a call in revm, returning the first word of the output if it succeeded
+fn probe_call<DB>(
+    db: ProbeDb<'_, DB>,
+    contract: Address,
+    calldata: Bytes,
+) -> Result<Option<U256>, SlotFinderError>
+where
+    DB: DatabaseRef,
+    DB::Error: Debug,
+{
+    let mut evm = EVM::new();
+    evm.database(db);
+    evm.env.tx.caller = B160(probe_address(0).0);
+    evm.env.tx.transact_to = TransactTo::Call(B160(contract.0));
+    evm.env.tx.data = calldata.0;
+    evm.env.tx.gas_limit = 1_000_000;
+
+    let result = evm
+        .transact_ref()
+        .map_err(|e| SlotFinderError::EvmError(contract, format!("{:?}", e)))?
+        .result;
+
+    match result {
+        ExecutionResult::Success {
+            output: Output::Call(output),
+            ..
+        } if output.len() >= 32 => Ok(Some(U256::from_big_endian(&output[..32]))),
+        _ => Ok(None),
+    }
+}
+
+/// Overrides a single storage value of the wrapped database
+struct ProbeDb<'a, DB> {
+    db: &'a DB,
+    address: B160,
+    index: RU256,
+    value: RU256,
+}
+
+impl<'a, DB: DatabaseRef> DatabaseRef for ProbeDb<'a, DB> {
+    type Error = DB::Error;
+
+    fn basic(&self, address: B160) -> Result<Option<AccountInfo>, Self::Error> {
+        self.db.basic(address)
+    }
+
+    fn code_by_hash(&self, code_hash: B256) -> Result<Bytecode, Self::Error> {
+        self.db.code_by_hash(code_hash)
+    }
+
+    fn storage(&self, address: B160, index: RU256) -> Result<RU256, Self::Error> {
+        if address == self.address && index == self.index {
+            Ok(self.value)
+        } else {
+            self.db.storage(address, index)
+        }
+    }
+
+    fn block_hash(&self, number: RU256) -> Result<B256, Self::Error> {
+        self.db.block_hash(number)
+    }
 }
 
-// #[cfg(test)]
-// mod test {
-
-//     use super::*;
-//     use ethers::prelude::*;
-//     use ethers::types::U256;
-//     use ethers::{
-//         providers::{Middleware, Provider, Ws},
-//         types::H160,
-//     };
-//     use std::sync::Arc;
-
-//     use crate::block_collector::process_block_update;
-//     use dotenv::dotenv;
-//     use ethers::providers::{Middleware, Provider, Ws};
-//     use ethers::types::{BlockId, BlockNumber};
-//     use futures_util::StreamExt;
-//     use revm::db::{CacheDB, EmptyDB};
-//     use rusty::prelude::fork_factory::ForkFactory;
-//     use std::env;
-//     use std::error::Error;
-//     use std::sync::Arc;
-
-//     #[tokio::test]
-//     async fn test_balance_of_slot_finder() {
-
-//         dotenv().ok();
-//         let _blast_key = env::var("BLAST_API_KEY").unwrap();
-//         let mainnet_blast_url = format!("wss://eth-mainnet.blastapi.io/{}", _blast_key);
-
-//         let ws_provider = Provider::<Ws>::connect(mainnet_blast_url).await.unwrap();
-
-//         let val = slot_finder(
-//             // WETH
-//             "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
-//                 .parse::<H160>()
-//                 .unwrap(),
-//             // Uniswap V2: WETH/USDT
-//             "0x06da0fd433C1A5d7a4faa01111c044910A184553"
-//                 .parse::<H160>()
-//                 .unwrap(),
-//         )
-//         .await;
-
-// 	// `balanceOf` slot on WETH is 3
-//         assert_eq!(val, Some(U256::from(3)));
-//     }
-// }
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use revm::db::{CacheDB, EmptyDB};
+
+    // returns sload(keccak256(abi.encode(calldata[4..36], 5)))
+    const SOLIDITY_TOKEN: &str = "600435600052600560205260406000205460005260206000f3";
+    // returns sload(keccak256(abi.encode(7, calldata[4..36])))
+    const VYPER_TOKEN: &str = "600760005260043560205260406000205460005260206000f3";
+
+    fn temp_path(test: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "qilin_slot_finder_{}_{}.json",
+            test,
+            std::process::id()
+        ));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    fn deploy(db: &mut CacheDB<EmptyDB>, address: Address, code: &str) {
+        let code = code.parse::<Bytes>().unwrap();
+        db.insert_account_info(
+            B160(address.0),
+            AccountInfo::new(RU256::ZERO, 0, Bytecode::new_raw(code.0)),
+        );
+    }
+
+    #[test]
+    fn test_balance_of_slot_finder() {
+        let mut db = CacheDB::new(EmptyDB::default());
+        let solidity_token = Address::from_low_u64_be(0x1000);
+        let vyper_token = Address::from_low_u64_be(0x2000);
+        deploy(&mut db, solidity_token, SOLIDITY_TOKEN);
+        deploy(&mut db, vyper_token, VYPER_TOKEN);
+
+        let cache_path = temp_path("balance_of");
+        let finder = StorageLayoutFinder::new(Some(cache_path.clone())).with_max_slot(10);
+
+        assert_eq!(
+            finder.balance_of_slot(&db, solidity_token, 1).unwrap(),
+            MappingSlot::new(U256::from(5), MappingLayout::Solidity)
+        );
+        assert_eq!(
+            finder.balance_of_slot(&db, vyper_token, 1).unwrap(),
+            MappingSlot::new(U256::from(7), MappingLayout::Vyper)
+        );
+        assert!(finder
+            .balance_of_slot(&db, Address::from_low_u64_be(0x3000), 1)
+            .is_err());
+        // written in a batch or when flushed, never on drop
+        assert!(!cache_path.exists());
+        assert_eq!(finder.unflushed(), 3);
+        drop(finder);
+        assert!(!cache_path.exists());
+
+        let finder = StorageLayoutFinder::new(Some(cache_path.clone())).with_max_slot(10);
+        finder.balance_of_slot(&db, vyper_token, 1).unwrap();
+        finder.flush().unwrap();
+        assert_eq!(finder.unflushed(), 0);
+
+        // detected layouts are reloaded from disk
+        let reloaded = StorageLayoutFinder::new(Some(cache_path.clone()));
+        assert_eq!(
+            reloaded.cached(vyper_token).unwrap().balance_of,
+            Some(MappingSlot::new(U256::from(7), MappingLayout::Vyper))
+        );
+        let _ = fs::remove_file(&cache_path);
+    }
+
+    #[test]
+    fn test_missing_slot_cached_with_block() {
+        let mut db = CacheDB::new(EmptyDB::default());
+        let token = Address::from_low_u64_be(0x1000);
+        let cache_path = temp_path("missing");
+        let finder = StorageLayoutFinder::new(Some(cache_path.clone()))
+            .with_max_slot(10)
+            .with_retry_after(5);
+
+        assert!(finder.balance_of_slot(&db, token, 100).is_err());
+        assert_eq!(
+            finder.cached(token).unwrap().balance_of_missing_at,
+            Some(100)
+        );
+
+        // not probed again until `retry_after` blocks passed
+        deploy(&mut db, token, SOLIDITY_TOKEN);
+        assert!(finder.balance_of_slot(&db, token, 104).is_err());
+        assert_eq!(
+            finder.balance_of_slot(&db, token, 105).unwrap(),
+            MappingSlot::new(U256::from(5), MappingLayout::Solidity)
+        );
+
+        finder.flush().unwrap();
+        let reloaded = StorageLayoutFinder::new(Some(cache_path.clone()));
+        assert_eq!(reloaded.cached(token), finder.cached(token));
+        let _ = fs::remove_file(&cache_path);
+    }
+
+    #[test]
+    fn test_failed_flush_keeps_changes() {
+        let mut db = CacheDB::new(EmptyDB::default());
+        let token = Address::from_low_u64_be(0x1000);
+        deploy(&mut db, token, SOLIDITY_TOKEN);
+
+        // the cache file can't be created under a file
+        let blocker = temp_path("flush_blocker");
+        fs::write(&blocker, b"").unwrap();
+        let finder = StorageLayoutFinder::new(Some(blocker.join("layouts.json"))).with_max_slot(10);
+        finder.balance_of_slot(&db, token, 1).unwrap();
+
+        assert!(finder.flush().is_err());
+        assert_eq!(finder.unflushed(), 1);
+        let _ = fs::remove_file(&blocker);
+    }
+
+    #[test]
+    fn test_mapping_keys() {
+        // `balanceOf` slot on WETH is 3
+        let weth_balance_of = MappingSlot::new(U256::from(3), MappingLayout::Solidity);
+        let holder = Address::from_low_u64_be(1);
+        let expected = H256::from(keccak256(abi::encode(&[
+            Token::Address(holder),
+            Token::Uint(U256::from(3)),
+        ])));
+        assert_eq!(weth_balance_of.address_key(holder), expected);
+
+        let vyper = MappingSlot::new(U256::from(3), MappingLayout::Vyper);
+        let expected = H256::from(keccak256(abi::encode(&[
+            Token::Uint(U256::from(3)),
+            Token::Address(holder),
+        ])));
+        assert_eq!(vyper.address_key(holder), expected);
+    }
+}
@@ -1,19 +1,19 @@
 use anyhow::Result;
 use dashmap::DashMap;
 use ethers::types::H160;
-use hashbrown::HashMap;
-use qilin_cfmms::pool::Pool;
+use qilin_cfmms::pool::{Pool, PoolType, PoolVariant};
 use std::{
     collections::{hash_map::DefaultHasher, HashSet},
     hash::{Hash, Hasher},
 };
 use thiserror::Error;
 
-use super::slot_finder;
+use super::slot_finder::StorageLayoutFinder;
+use crate::types::{PoolSwap, SwapRoute};
 use ethers::prelude::*;
 use futures::stream::FuturesUnordered;
 use revm::{
-    db::{CacheDB, EmptyDB},
+    db::{CacheDB, DatabaseRef, EmptyDB},
     primitives::{AccountInfo, Bytecode},
 };
 use serde::{Serialize, Serializer};
@@ -21,9 +21,7 @@ use std::{
     collections::{btree_map::Entry, BTreeMap},
     sync::Arc,
 };
-use tokio::sync::RwLock;
 
-pub type ArbPools = Vec<HashMap<Pool, Vec<Pool>>>;
 type RustyPool = rusty::cfmm::Pool;
 struct SerializedBTreeMap<K, V>(BTreeMap<K, V>);
 
@@ -122,137 +120,609 @@ where
     Some(merged_state_diffs)
 }
 
-pub async fn extract_arb_pools(
-    provider: Arc<Provider<Ws>>,
+/// A price gap left by a tx between a pool it traded against and another pool of the same pair
+#[derive(Debug, Clone, Copy)]
+pub struct ArbOpportunity {
+    /// Pool whose price was moved by the tx
+    pub touched_pool: Pool,
+    /// Pool of the same pair the price is compared against
+    pub other_pool: Pool,
+    /// Is `token_0` cheaper on `touched_pool` after the tx? If so the arb buys `token_0` on
+    /// `touched_pool` and sells it on `other_pool`, otherwise the other way around
+    pub token_0_cheaper_on_touched: bool,
+    /// Rough amount of `token_1` to sell into the cheaper pool
+    pub amount_in: U256,
+    /// Rough profit in `token_1`, net of both pools' fees
+    pub expected_profit: U256,
+}
+
+impl ArbOpportunity {
+    /// Returns the pools to buy `token_0` from and to sell it to
+    pub fn route(&self) -> (Pool, Pool) {
+        if self.token_0_cheaper_on_touched {
+            (self.touched_pool, self.other_pool)
+        } else {
+            (self.other_pool, self.touched_pool)
+        }
+    }
+}
+
+/// Key of the pools trading `token_0` against `token_1` in `hash_pools`
+pub fn pair_key(token_0: Address, token_1: Address) -> H160 {
+    let mut hasher = DefaultHasher::new();
+    token_0.hash(&mut hasher);
+    token_1.hash(&mut hasher);
+    H160::from_low_u64_be(hasher.finish())
+}
+
+/// Find the arbitrage opportunities a tx leaves between the pools it traded against and the
+/// other pools of the same pairs
+///
+/// The post-tx price of every pool whose price slot changed is compared against the other
+/// pools of its pair, using their post-tx state if the tx touched them too. Pools are
+/// approximated as constant product curves over their active liquidity, so the size is a
+/// starting point for a simulation rather than the optimum.
+///
+/// Arguments:
+/// * `state_diffs`: BTreeMap of Address and AccountDiff
+/// * `all_pools`: HashMap of Address and Pool
+/// * `hash_pools`: pools of each pair, keyed by [pair_key]
+/// * `is_tradable`: called with both tokens of every touched pool, pools trading a token it
+///   rejects (honeypots, taxed tokens) are skipped
+///
+/// Returns:
+/// Vec<ArbOpportunity>: every profitable pair of pools, in both directions
+pub fn extract_arb_pools(
     state_diffs: &BTreeMap<Address, AccountDiff>,
-    all_pools: &Arc<RwLock<DashMap<Address, Pool>>>,
-    hash_pools: &Arc<DashMap<H160, Vec<Pool>>>,
-) -> Option<ArbPools> {
-    let read_lock = all_pools.read().await;
-    let touched_pools: Vec<Pool> = state_diffs
-        .keys()
-        .filter_map(|e| read_lock.get(e).map(|p| (*p.value())))
-        .collect();
-    drop(read_lock);
+    all_pools: &DashMap<Address, Pool>,
+    hash_pools: &DashMap<H160, Vec<Pool>>,
+    is_tradable: impl FnMut(Address, &Pool) -> bool,
+) -> Vec<ArbOpportunity> {
+    // only pools whose price moved
+    let touched_pools = state_diffs.iter().filter_map(|(address, account_diff)| {
+        let pool = *all_pools.get(address)?.value();
+        let price_slot = match pool.pool_variant {
+            PoolVariant::UniswapV2 => V2_RESERVES_SLOT,
+            PoolVariant::UniswapV3 => V3_SLOT0_SLOT,
+        };
+        slot_change(account_diff, price_slot).map(|_| pool)
+    });
 
-    let mut arb_pools: ArbPools = vec![];
+    arb_opportunities(touched_pools, state_diffs, hash_pools, is_tradable)
+}
+
+/// Same as [extract_arb_pools] for a tx whose swaps were already decoded, e.g. by the mempool
+/// collector into [NewTx](crate::types::NewTx)
+///
+/// Arguments:
+/// * `swaps`: swaps the tx performs, their pools are the touched pools
+/// * `state_diffs`: BTreeMap of Address and AccountDiff, read for the post-tx pool states
+/// * `hash_pools`: pools of each pair, keyed by [pair_key]
+/// * `is_tradable`: called with both tokens of every touched pool
+///
+/// Returns:
+/// Vec<ArbOpportunity>: every profitable pair of pools, in both directions
+pub fn extract_arb_pools_from_swaps(
+    swaps: &[PoolSwap],
+    state_diffs: &BTreeMap<Address, AccountDiff>,
+    hash_pools: &DashMap<H160, Vec<Pool>>,
+    is_tradable: impl FnMut(Address, &Pool) -> bool,
+) -> Vec<ArbOpportunity> {
+    arb_opportunities(
+        swaps.iter().map(|swap| swap.pool),
+        state_diffs,
+        hash_pools,
+        is_tradable,
+    )
+}
 
-    let mut exclusion_map: HashSet<Pool> = HashSet::new();
+fn arb_opportunities(
+    touched_pools: impl Iterator<Item = Pool>,
+    state_diffs: &BTreeMap<Address, AccountDiff>,
+    hash_pools: &DashMap<H160, Vec<Pool>>,
+    mut is_tradable: impl FnMut(Address, &Pool) -> bool,
+) -> Vec<ArbOpportunity> {
+    let mut opportunities = vec![];
+    let mut seen: HashSet<(Address, Address)> = HashSet::new();
 
-    for pool in touched_pools {
-        if exclusion_map.contains(&pool) {
+    for touched_pool in touched_pools {
+        let touched_curve = if let Some(curve) = Curve::from_pool(&touched_pool, state_diffs) {
+            curve
+        } else {
             continue;
         };
 
-        let token0 = pool.token_0;
-        let token1 = pool.token_1;
-
-        let token0_state_diff = &state_diffs.get(&token0)?.storage;
+        if !is_tradable(touched_pool.token_0, &touched_pool)
+            || !is_tradable(touched_pool.token_1, &touched_pool)
+        {
+            continue;
+        }
 
-        // read the balanceOf mapping from the ERC20 contract
-        let slot = if let Some(slot) =
-            slot_finder::slot_finder(provider.clone(), token0.clone(), pool.address).await
+        let pools = if let Some(pools) =
+            hash_pools.get(&pair_key(touched_pool.token_0, touched_pool.token_1))
         {
-            slot
+            pools.clone()
         } else {
-            // if not found, skip
-            // currently bot don't support Vyper contract balanceOf slot finding
-            break;
+            continue;
         };
 
-        // key in the balanceOf mapping with pool's address
-        let storage_key = TxHash::from(ethers::utils::keccak256(abi::encode(&[
-            abi::Token::Address(pool.address),
-            abi::Token::Uint(slot),
-        ])));
-
-        // if storage_diff is true, then pool has more token0 than before
-        let storage_diff = match token0_state_diff.get(&storage_key)? {
-            Diff::Changed(c) => {
-                let from = U256::from(c.from.to_fixed_bytes());
-                let to = U256::from(c.to.to_fixed_bytes());
-                to > from
+        for other_pool in pools.iter().filter(|p| p.address != touched_pool.address) {
+            let pair = if touched_pool.address < other_pool.address {
+                (touched_pool.address, other_pool.address)
+            } else {
+                (other_pool.address, touched_pool.address)
+            };
+            // both pools were touched, the pair was already compared
+            if !seen.insert(pair) {
+                continue;
+            }
+
+            let other_curve = if let Some(curve) = Curve::from_pool(other_pool, state_diffs) {
+                curve
+            } else {
+                continue;
+            };
+
+            let token_0_cheaper_on_touched = touched_curve.sqrt_price < other_curve.sqrt_price;
+            let (cheap, expensive) = if token_0_cheaper_on_touched {
+                (touched_curve, other_curve)
+            } else {
+                (other_curve, touched_curve)
+            };
+
+            if let Some((amount_in, expected_profit)) = cheap.arb_against(&expensive) {
+                opportunities.push(ArbOpportunity {
+                    touched_pool,
+                    other_pool: *other_pool,
+                    token_0_cheaper_on_touched,
+                    amount_in,
+                    expected_profit,
+                });
             }
-            _ => break,
-        };
-        // hash token0 & token1 addresses to key in all the relevant pools from
-        // hash_pools
-        let mut hasher = DefaultHasher::new();
-        token0.hash(&mut hasher);
-        token1.hash(&mut hasher);
-        let hash = hasher.finish();
-
-        let mut pool_map: HashMap<Pool, Vec<Pool>> = HashMap::new();
-        let pools = hash_pools.get(&H160::from_low_u64_be(hash))?;
-
-        if storage_diff {
-            let mut vec_pool: Vec<Pool> = vec![];
-            for pool in pools.iter().filter(|p| p.address != pool.address) {
-                exclusion_map.insert(pool.clone());
-                vec_pool.push(pool.clone());
+        }
+    }
+
+    opportunities
+}
+
+/// Constant product approximation of a pool over its active liquidity, in raw token units
+#[derive(Debug, Clone, Copy)]
+struct Curve {
+    /// sqrt of the price of `token_0` in `token_1`
+    sqrt_price: f64,
+    liquidity: f64,
+    /// Fraction of the input taken as fee
+    fee: f64,
+}
+
+impl Curve {
+    /// Read the curve of a pool, from its post-tx storage if the tx changed it
+    fn from_pool(pool: &Pool, state_diffs: &BTreeMap<Address, AccountDiff>) -> Option<Self> {
+        let account_diff = state_diffs.get(&pool.address);
+        let post_tx = |slot: u64| account_diff.and_then(|diff| slot_change(diff, slot));
+
+        let (sqrt_price, liquidity) = match (pool.pool_variant, pool.pool_type) {
+            (PoolVariant::UniswapV2, PoolType::UniswapV2(cached)) => {
+                let (reserve_0, reserve_1) = match post_tx(V2_RESERVES_SLOT) {
+                    Some((_, to)) => (
+                        low_bits(to, 112).as_u128(),
+                        low_bits(to >> 112, 112).as_u128(),
+                    ),
+                    None => (cached.reserve_0, cached.reserve_1),
+                };
+                let (reserve_0, reserve_1) = (reserve_0 as f64, reserve_1 as f64);
+                (
+                    (reserve_1 / reserve_0).sqrt(),
+                    (reserve_0 * reserve_1).sqrt(),
+                )
             }
+            (PoolVariant::UniswapV3, PoolType::UniswapV3(cached)) => {
+                let sqrt_price_x96 = match post_tx(V3_SLOT0_SLOT) {
+                    Some((_, to)) => low_bits(to, 160),
+                    None => cached.sqrt_price,
+                };
+                let liquidity = match post_tx(V3_LIQUIDITY_SLOT) {
+                    Some((_, to)) => low_bits(to, 128).as_u128(),
+                    None => cached.liquidity,
+                };
+                (
+                    u256_to_f64(sqrt_price_x96) / 2f64.powi(96),
+                    liquidity as f64,
+                )
+            }
+            _ => return None,
+        };
 
-            pool_map.insert(pool, vec_pool);
+        let fee = pool.swap_fee.min(U256::from(1_000_000)).as_u64() as f64 / 1e6;
 
-            // if to > from, then pool has more token0 and less token1 than before*
-            // to arb, buy token0 and sell token1 to other pools
-            // *not always the case
-            arb_pools.push(pool_map);
+        if sqrt_price.is_finite() && sqrt_price > 0.0 && liquidity > 0.0 {
+            Some(Self {
+                sqrt_price,
+                liquidity,
+                fee,
+            })
         } else {
-            // need to add logic to handle when
-            // to < from
-            continue;
+            None
         }
     }
-    Some(arb_pools)
+
+    /// Size an arb buying `token_0` on this curve and selling it on the more expensive one
+    ///
+    /// Returns the `token_1` input and the profit in `token_1`, `None` if the gap doesn't cover
+    /// the fees
+    fn arb_against(&self, expensive: &Curve) -> Option<(U256, U256)> {
+        // the price both pools converge to, where the token_0 bought on this curve equals the
+        // token_0 sold on the other: (L_c + L_e) / s = L_c / s_c + L_e / s_e
+        let sqrt_price = (self.liquidity + expensive.liquidity)
+            / (self.liquidity / self.sqrt_price + expensive.liquidity / expensive.sqrt_price);
+
+        let amount_0 = self.liquidity * (1.0 / self.sqrt_price - 1.0 / sqrt_price);
+        let amount_in = self.liquidity * (sqrt_price - self.sqrt_price) / (1.0 - self.fee);
+
+        // sell the token_0 net of fees into the expensive curve
+        let amount_0_net = amount_0 * (1.0 - expensive.fee);
+        let sqrt_price_after =
+            1.0 / (1.0 / expensive.sqrt_price + amount_0_net / expensive.liquidity);
+        let amount_out = expensive.liquidity * (expensive.sqrt_price - sqrt_price_after);
+
+        let profit = amount_out - amount_in;
+        if !profit.is_finite() || profit <= 0.0 || amount_in <= 0.0 {
+            return None;
+        }
+
+        Some((f64_to_u256(amount_in), f64_to_u256(profit)))
+    }
 }
 
-// credit to rusty-sando
-// https://github.com/mouseless-eth/rusty-sando/blob/master/bot/src/utils/state_diff.rs
-pub fn extract_sandwich_pools(
+fn u256_to_f64(value: U256) -> f64 {
+    value
+        .0
+        .iter()
+        .rev()
+        .fold(0.0, |acc, limb| acc * 2f64.powi(64) + *limb as f64)
+}
+
+/// Saturates at `u128::MAX`, far above any realistic trade size
+fn f64_to_u256(value: f64) -> U256 {
+    U256::from(value as u128)
+}
+
+/// Storage slot of `reserve0`, `reserve1` and `blockTimestampLast` in a UniswapV2 pair
+const V2_RESERVES_SLOT: u64 = 8;
+/// Storage slot of `slot0` (`sqrtPriceX96`, `tick`, ...) in a UniswapV3 pool
+const V3_SLOT0_SLOT: u64 = 0;
+/// Storage slot of `liquidity` in a UniswapV3 pool
+const V3_LIQUIDITY_SLOT: u64 = 4;
+
+/// Decode the swaps performed against known pools from a tx's state diffs
+///
+/// The direction and amounts are read from the pools' own storage:
+/// * UniswapV2: the change in the packed reserves slot
+/// * UniswapV3: the change in `sqrtPriceX96`, amounts are estimated assuming the swap stays
+///   within the active tick range
+///
+/// Arguments:
+/// * `state_diffs`: BTreeMap of Address and AccountDiff
+/// * `all_pools`: HashMap of Address and Pool
+///
+/// Returns:
+/// Vec<PoolSwap>: a swap for every touched pool whose state change could be decoded
+pub fn extract_pool_swaps(
     state_diffs: &BTreeMap<Address, AccountDiff>,
     all_pools: &DashMap<Address, Pool>,
-) -> Option<Vec<TradablePool>> {
-    // capture all addresses that have a state change and are also a pool
-    let touched_pools: Vec<Pool> = state_diffs
-        .keys()
-        .filter_map(|e| all_pools.get(e).map(|p| (*p.value())))
+) -> Vec<PoolSwap> {
+    state_diffs
+        .iter()
+        .filter_map(|(address, account_diff)| {
+            let pool = *all_pools.get(address)?.value();
+            match pool.pool_variant {
+                PoolVariant::UniswapV2 => decode_v2_swap(pool, account_diff),
+                PoolVariant::UniswapV3 => decode_v3_swap(pool, account_diff),
+            }
+        })
+        .collect()
+}
+
+/// Returns the `(from, to)` values of a storage slot, `None` if the slot didn't change
+fn slot_change(account_diff: &AccountDiff, slot: u64) -> Option<(U256, U256)> {
+    match account_diff.storage.get(&H256::from_low_u64_be(slot))? {
+        Diff::Changed(c) => Some((
+            U256::from(c.from.to_fixed_bytes()),
+            U256::from(c.to.to_fixed_bytes()),
+        )),
+        Diff::Born(to) => Some((U256::zero(), U256::from(to.to_fixed_bytes()))),
+        _ => None,
+    }
+}
+
+fn low_bits(value: U256, bits: usize) -> U256 {
+    value & ((U256::one() << bits) - 1)
+}
+
+fn decode_v2_swap(pool: Pool, account_diff: &AccountDiff) -> Option<PoolSwap> {
+    let (from, to) = slot_change(account_diff, V2_RESERVES_SLOT)?;
+
+    let (reserve0_before, reserve1_before) = (low_bits(from, 112), low_bits(from >> 112, 112));
+    let (reserve0_after, reserve1_after) = (low_bits(to, 112), low_bits(to >> 112, 112));
+
+    // the reserve of the token sold into the pool goes up
+    let zero_for_one = if reserve0_after > reserve0_before && reserve1_after < reserve1_before {
+        true
+    } else if reserve1_after > reserve1_before && reserve0_after < reserve0_before {
+        false
+    } else {
+        // mint, burn or sync
+        return None;
+    };
+
+    let (amount_in, amount_out) = if zero_for_one {
+        (
+            reserve0_after - reserve0_before,
+            reserve1_before - reserve1_after,
+        )
+    } else {
+        (
+            reserve1_after - reserve1_before,
+            reserve0_before - reserve0_after,
+        )
+    };
+
+    Some(PoolSwap {
+        pool,
+        zero_for_one,
+        amount_in,
+        amount_out,
+    })
+}
+
+fn decode_v3_swap(pool: Pool, account_diff: &AccountDiff) -> Option<PoolSwap> {
+    let (from, to) = slot_change(account_diff, V3_SLOT0_SLOT)?;
+    let sqrt_price_before = low_bits(from, 160);
+    let sqrt_price_after = low_bits(to, 160);
+
+    if sqrt_price_before.is_zero() || sqrt_price_after.is_zero() {
+        return None;
+    }
+
+    // selling token0 pushes the price of token0 down
+    let zero_for_one = match sqrt_price_after.cmp(&sqrt_price_before) {
+        std::cmp::Ordering::Less => true,
+        std::cmp::Ordering::Greater => false,
+        std::cmp::Ordering::Equal => return None,
+    };
+
+    // use the liquidity before the swap, falling back on the cached pool state
+    let liquidity = match slot_change(account_diff, V3_LIQUIDITY_SLOT) {
+        Some((liquidity, _)) => low_bits(liquidity, 128),
+        None => match pool.pool_type {
+            PoolType::UniswapV3(v3_pool) => U256::from(v3_pool.liquidity),
+            _ => return None,
+        },
+    };
+
+    let sqrt_price_delta = if zero_for_one {
+        sqrt_price_before - sqrt_price_after
+    } else {
+        sqrt_price_after - sqrt_price_before
+    };
+
+    // amount0 = L * Q96 * |delta| / (sqrtP_before * sqrtP_after)
+    let amount_0 = U256::try_from(
+        (liquidity << 96).full_mul(sqrt_price_delta) / sqrt_price_before.full_mul(sqrt_price_after),
+    )
+    .ok()?;
+    // amount1 = L * |delta| / Q96
+    let amount_1 =
+        U256::try_from(liquidity.full_mul(sqrt_price_delta) / U512::from(U256::one() << 96))
+            .ok()?;
+
+    let (amount_in, amount_out) = if zero_for_one {
+        (amount_0, amount_1)
+    } else {
+        (amount_1, amount_0)
+    };
+
+    // the price only moves by the input net of fees, `swap_fee` is in hundredths of a bip
+    let fee_denominator = U256::from(1_000_000);
+    let amount_in = amount_in * fee_denominator / fee_denominator.checked_sub(pool.swap_fee)?;
+
+    Some(PoolSwap {
+        pool,
+        zero_for_one,
+        amount_in,
+        amount_out,
+    })
+}
+
+/// Decode the swap routes of a tx and keep those that trade one of the `base_tokens`
+///
+/// Arguments:
+/// * `layouts`: finder used to locate the `balanceOf` mapping of each token
+/// * `db`: database the token contracts are probed against
+/// * `block`: the block `db` is at
+/// * `state_diffs`: BTreeMap of Address and AccountDiff
+/// * `all_pools`: HashMap of Address and Pool
+/// * `base_tokens`: tokens the bot holds inventory in (WETH, USDC, ...)
+///
+/// Returns:
+/// Some(Vec<SwapRoute>): full routes where a base token is sold or bought in at least one hop
+/// None: If no known pool was touched
+pub fn extract_sandwich_pools<DB>(
+    layouts: &StorageLayoutFinder,
+    db: &DB,
+    block: u64,
+    state_diffs: &BTreeMap<Address, AccountDiff>,
+    all_pools: &DashMap<Address, Pool>,
+    base_tokens: &[Address],
+) -> Option<Vec<SwapRoute>>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    let routes = extract_swap_routes(layouts, db, block, state_diffs, all_pools);
+    if routes.is_empty() {
+        return None;
+    }
+
+    Some(
+        routes
+            .into_iter()
+            .filter(|route| route.base_token_hops(base_tokens).next().is_some())
+            .collect(),
+    )
+}
+
+/// Decode the swaps of a tx from the ERC20 balances of the pools it touched, and chain them
+/// into routes
+///
+/// A token whose pool balance went up was sold into the pool, a token whose pool balance went
+/// down was bought from it. Pools where either token's balance couldn't be read fall back to
+/// decoding the pool's own storage, and are left out if that fails too.
+///
+/// Arguments:
+/// * `layouts`: finder used to locate the `balanceOf` mapping of each token
+/// * `db`: database the token contracts are probed against
+/// * `block`: the block `db` is at
+/// * `state_diffs`: BTreeMap of Address and AccountDiff
+/// * `all_pools`: HashMap of Address and Pool
+///
+/// Returns:
+/// Vec<SwapRoute>: every route found, hops ordered from the first token sold to the last bought
+pub fn extract_swap_routes<DB>(
+    layouts: &StorageLayoutFinder,
+    db: &DB,
+    block: u64,
+    state_diffs: &BTreeMap<Address, AccountDiff>,
+    all_pools: &DashMap<Address, Pool>,
+) -> Vec<SwapRoute>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    let swaps = state_diffs
+        .iter()
+        .filter_map(|(address, account_diff)| {
+            let pool = *all_pools.get(address)?.value();
+            decode_balance_swap(layouts, db, block, state_diffs, pool).or_else(|| {
+                match pool.pool_variant {
+                    PoolVariant::UniswapV2 => decode_v2_swap(pool, account_diff),
+                    PoolVariant::UniswapV3 => decode_v3_swap(pool, account_diff),
+                }
+            })
+        })
         .collect();
 
-    // find direction of swap based on state diff (does weth have state changes?)
-    let weth_state_diff = &state_diffs
-        .get(
-            &"0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
-                .parse::<H160>()
-                .unwrap(),
-        )?
-        .storage;
-
-    let mut tradable_pools: Vec<TradablePool> = vec![];
-
-    // find storage mapping index for each pool
-    for pool in touched_pools {
-        // find mapping storage location
-        // reading balanceOf mapping given the address of the pool's address
-        let storage_key = TxHash::from(ethers::utils::keccak256(abi::encode(&[
-            abi::Token::Address(pool.address),
-            abi::Token::Uint(U256::from(3)),
-        ])));
-
-        let is_weth_input = match weth_state_diff.get(&storage_key)? {
-            Diff::Changed(c) => {
-                let from = U256::from(c.from.to_fixed_bytes());
-                let to = U256::from(c.to.to_fixed_bytes());
-                to > from
-            }
-            // TODO: handle reverse direction
-            _ => continue,
-        };
-        let rp = pool.to_rp();
-        tradable_pools.push(TradablePool::new(rp, is_weth_input));
+    chain_routes(swaps)
+}
+
+/// Returns the `(from, to)` balance of `holder` in `token` if the tx changed it
+fn balance_change<DB>(
+    layouts: &StorageLayoutFinder,
+    db: &DB,
+    block: u64,
+    state_diffs: &BTreeMap<Address, AccountDiff>,
+    token: Address,
+    holder: Address,
+) -> Option<(U256, U256)>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    // only look for the mapping of tokens that had a storage change
+    let token_state_diff = &state_diffs.get(&token)?.storage;
+    let slot = layouts.balance_of_slot(db, token, block).ok()?;
+
+    match token_state_diff.get(&slot.address_key(holder))? {
+        Diff::Changed(c) => Some((
+            U256::from(c.from.to_fixed_bytes()),
+            U256::from(c.to.to_fixed_bytes()),
+        )),
+        Diff::Born(to) => Some((U256::zero(), U256::from(to.to_fixed_bytes()))),
+        Diff::Died(from) => Some((U256::from(from.to_fixed_bytes()), U256::zero())),
+        Diff::Same => None,
     }
+}
 
-    Some(tradable_pools)
+/// Decode the swap of `pool` from the change of both of its token balances
+///
+/// Returns `None` if either balance didn't change or its slot couldn't be found, the caller then
+/// decodes the pool's storage with `decode_v2_swap` or `decode_v3_swap` instead.
+fn decode_balance_swap<DB>(
+    layouts: &StorageLayoutFinder,
+    db: &DB,
+    block: u64,
+    state_diffs: &BTreeMap<Address, AccountDiff>,
+    pool: Pool,
+) -> Option<PoolSwap>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    // both amounts are needed, a side that can't be read isn't assumed to be 0
+    let balance_0 = balance_change(layouts, db, block, state_diffs, pool.token_0, pool.address)?;
+    let balance_1 = balance_change(layouts, db, block, state_diffs, pool.token_1, pool.address)?;
+
+    // `true` if the pool received the token
+    let received = |(from, to): (U256, U256)| to > from;
+
+    let zero_for_one = match (received(balance_0), received(balance_1)) {
+        (true, false) => true,
+        (false, true) => false,
+        // mint or burn
+        _ => return None,
+    };
+
+    let delta = |(from, to): (U256, U256)| if to > from { to - from } else { from - to };
+
+    let (amount_in, amount_out) = if zero_for_one {
+        (delta(balance_0), delta(balance_1))
+    } else {
+        (delta(balance_1), delta(balance_0))
+    };
+
+    Some(PoolSwap {
+        pool,
+        zero_for_one,
+        amount_in,
+        amount_out,
+    })
+}
+
+/// Order swaps into routes, linking a swap to the next one selling the token it bought
+fn chain_routes(swaps: Vec<PoolSwap>) -> Vec<SwapRoute> {
+    let mut visited = vec![false; swaps.len()];
+    let mut routes = vec![];
+
+    // a route starts with a swap whose input isn't bought by any other swap, cyclic routes
+    // (e.g. WETH -> X -> WETH) have no such swap and start wherever is left
+    let mut starts: Vec<usize> = (0..swaps.len())
+        .filter(|i| {
+            !swaps
+                .iter()
+                .enumerate()
+                .any(|(j, other)| j != *i && other.token_out() == swaps[*i].token_in())
+        })
+        .collect();
+    starts.extend(0..swaps.len());
+
+    for start in starts {
+        if visited[start] {
+            continue;
+        }
+
+        let mut hops = vec![];
+        let mut current = Some(start);
+        while let Some(i) = current {
+            visited[i] = true;
+            hops.push(swaps[i]);
+            current = (0..swaps.len())
+                .find(|j| !visited[*j] && swaps[*j].token_in() == swaps[i].token_out());
+        }
+
+        routes.push(SwapRoute { hops });
+    }
+
+    routes
 }
 
 // credit to rusty-sando
@@ -327,3 +797,344 @@ pub async fn to_cache_db(
 
     Ok(cache_db)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::slot_finder::{MappingLayout, MappingSlot};
+    use revm::primitives::B160;
+
+    // returns sload(keccak256(abi.encode(calldata[4..36], 5)))
+    const TOKEN_CODE: &str = "600435600052600560205260406000205460005260206000f3";
+
+    fn balance_diff(holder: Address, from: u64, to: u64) -> (H256, Diff<H256>) {
+        let key = MappingSlot::new(U256::from(5), MappingLayout::Solidity).address_key(holder);
+        let word = |v: u64| H256::from_low_u64_be(v);
+        (
+            key,
+            Diff::Changed(ChangedType {
+                from: word(from),
+                to: word(to),
+            }),
+        )
+    }
+
+    fn account_diff(storage: Vec<(H256, Diff<H256>)>) -> AccountDiff {
+        AccountDiff {
+            balance: Diff::Same,
+            nonce: Diff::Same,
+            code: Diff::Same,
+            storage: storage.into_iter().collect(),
+        }
+    }
+
+    #[test]
+    fn test_extract_multi_hop_route() {
+        let (usdc, token_b, token_c) = (
+            Address::from_low_u64_be(0x10),
+            Address::from_low_u64_be(0x20),
+            Address::from_low_u64_be(0x30),
+        );
+        // the second hop sorts first in the state diffs
+        let pool_1 = Pool::new_empty_pool(
+            Address::from_low_u64_be(0x200),
+            usdc,
+            token_b,
+            U256::from(3000),
+            PoolVariant::UniswapV2,
+        );
+        let pool_2 = Pool::new_empty_pool(
+            Address::from_low_u64_be(0x100),
+            token_b,
+            token_c,
+            U256::from(3000),
+            PoolVariant::UniswapV2,
+        );
+
+        let mut db = CacheDB::new(EmptyDB::default());
+        let code = TOKEN_CODE.parse::<Bytes>().unwrap();
+        for token in [usdc, token_b, token_c] {
+            db.insert_account_info(
+                B160(token.0),
+                AccountInfo::new(
+                    revm::primitives::U256::ZERO,
+                    0,
+                    Bytecode::new_raw(code.0.clone()),
+                ),
+            );
+        }
+        let layouts = StorageLayoutFinder::default().with_max_slot(10);
+
+        // usdc is sold into pool_1 and token_c bought from pool_2, token_b's balances are unknown
+        // so the amounts come from the reserves
+        let reserves = |from: (u128, u128), to: (u128, u128)| {
+            (
+                H256::from_low_u64_be(V2_RESERVES_SLOT),
+                Diff::Changed(ChangedType {
+                    from: packed_reserves(from.0, from.1),
+                    to: packed_reserves(to.0, to.1),
+                }),
+            )
+        };
+        let mut state_diffs: BTreeMap<Address, AccountDiff> = [
+            (
+                pool_1.address,
+                account_diff(vec![reserves((100, 200), (150, 160))]),
+            ),
+            (
+                pool_2.address,
+                account_diff(vec![reserves((300, 90), (340, 40))]),
+            ),
+            (
+                usdc,
+                account_diff(vec![balance_diff(pool_1.address, 100, 150)]),
+            ),
+            (
+                token_c,
+                account_diff(vec![balance_diff(pool_2.address, 90, 40)]),
+            ),
+        ]
+        .into_iter()
+        .collect();
+
+        let all_pools = DashMap::new();
+        all_pools.insert(pool_1.address, pool_1);
+        all_pools.insert(pool_2.address, pool_2);
+
+        let routes =
+            extract_sandwich_pools(&layouts, &db, 1, &state_diffs, &all_pools, &[usdc]).unwrap();
+
+        assert_eq!(routes.len(), 1);
+        let route = &routes[0];
+        assert_eq!(
+            route.pools().map(|p| p.address).collect::<Vec<_>>(),
+            vec![pool_1.address, pool_2.address]
+        );
+        assert_eq!(route.token_in(), Some(usdc));
+        assert_eq!(route.token_out(), Some(token_c));
+        assert_eq!(route.hops[0].amount_in, U256::from(50));
+        assert_eq!(route.hops[0].amount_out, U256::from(40));
+        assert_eq!(route.hops[1].amount_in, U256::from(40));
+        assert_eq!(route.hops[1].amount_out, U256::from(50));
+
+        // the same route is dropped when it doesn't trade a base token
+        let routes = extract_sandwich_pools(
+            &layouts,
+            &db,
+            1,
+            &state_diffs,
+            &all_pools,
+            &[Address::from_low_u64_be(0x40)],
+        )
+        .unwrap();
+        assert!(routes.is_empty());
+
+        // a single known balance isn't enough without the reserves
+        for pool in [pool_1.address, pool_2.address] {
+            state_diffs.insert(pool, account_diff(vec![]));
+        }
+        assert!(extract_swap_routes(&layouts, &db, 1, &state_diffs, &all_pools).is_empty());
+    }
+
+    fn v2_pool(address: u64, token_0: Address, token_1: Address, reserves: (u128, u128)) -> Pool {
+        let mut pool = Pool::new_empty_pool(
+            Address::from_low_u64_be(address),
+            token_0,
+            token_1,
+            U256::from(3000),
+            PoolVariant::UniswapV2,
+        );
+        if let PoolType::UniswapV2(ref mut v2_pool) = pool.pool_type {
+            v2_pool.reserve_0 = reserves.0;
+            v2_pool.reserve_1 = reserves.1;
+        }
+        pool
+    }
+
+    fn packed_reserves(reserve_0: u128, reserve_1: u128) -> H256 {
+        let mut word = H256::zero();
+        (U256::from(reserve_0) | (U256::from(reserve_1) << 112)).to_big_endian(word.as_bytes_mut());
+        word
+    }
+
+    #[test]
+    fn test_extract_arb_pools_both_directions() {
+        let (token_0, token_1) = (
+            Address::from_low_u64_be(0x10),
+            Address::from_low_u64_be(0x20),
+        );
+        let e21 = 10u128.pow(21);
+        let touched = v2_pool(0x100, token_0, token_1, (e21, 2 * e21));
+        let other = v2_pool(0x200, token_0, token_1, (e21, 2 * e21));
+
+        let all_pools = DashMap::new();
+        all_pools.insert(touched.address, touched);
+        all_pools.insert(other.address, other);
+        let hash_pools = DashMap::new();
+        hash_pools.insert(pair_key(token_0, token_1), vec![touched, other]);
+
+        let reserves_diff = |to: (u128, u128)| {
+            let storage = vec![(
+                H256::from_low_u64_be(V2_RESERVES_SLOT),
+                Diff::Changed(ChangedType {
+                    from: packed_reserves(e21, 2 * e21),
+                    to: packed_reserves(to.0, to.1),
+                }),
+            )];
+            [(touched.address, account_diff(storage))]
+                .into_iter()
+                .collect::<BTreeMap<_, _>>()
+        };
+
+        // the tx sold token_0 into the touched pool, token_0 is now cheaper there
+        let arbs = extract_arb_pools(
+            &reserves_diff((e21 * 11 / 10, e21 * 182 / 100)),
+            &all_pools,
+            &hash_pools,
+            |_, _| true,
+        );
+        assert_eq!(arbs.len(), 1);
+        assert!(arbs[0].token_0_cheaper_on_touched);
+        assert_eq!(arbs[0].route(), (touched, other));
+        assert!(arbs[0].amount_in > U256::zero());
+        assert!(arbs[0].expected_profit > U256::zero());
+
+        // the swaps decoded by the mempool collector lead to the same opportunity
+        let state_diffs = reserves_diff((e21 * 11 / 10, e21 * 182 / 100));
+        let swaps = extract_pool_swaps(&state_diffs, &all_pools);
+        let from_swaps =
+            extract_arb_pools_from_swaps(&swaps, &state_diffs, &hash_pools, |_, _| true);
+        assert_eq!(from_swaps.len(), 1);
+        assert_eq!(from_swaps[0].route(), (touched, other));
+        assert_eq!(from_swaps[0].amount_in, arbs[0].amount_in);
+
+        // the tx bought token_0 from the touched pool
+        let arbs = extract_arb_pools(
+            &reserves_diff((e21 * 9 / 10, e21 * 223 / 100)),
+            &all_pools,
+            &hash_pools,
+            |_, _| true,
+        );
+        assert_eq!(arbs.len(), 1);
+        assert_eq!(arbs[0].route(), (other, touched));
+
+        // a gap smaller than the fees is not an opportunity
+        let arbs = extract_arb_pools(
+            &reserves_diff((e21 * 1001 / 1000, e21 * 1999 / 1000)),
+            &all_pools,
+            &hash_pools,
+            |_, _| true,
+        );
+        assert!(arbs.is_empty());
+
+        // pools trading a rejected token are skipped
+        let arbs = extract_arb_pools(
+            &reserves_diff((e21 * 11 / 10, e21 * 182 / 100)),
+            &all_pools,
+            &hash_pools,
+            |token, _| token != token_0,
+        );
+        assert!(arbs.is_empty());
+    }
+
+    fn word(value: U256) -> H256 {
+        let mut word = H256::zero();
+        value.to_big_endian(word.as_bytes_mut());
+        word
+    }
+
+    fn slot_diff(slot: u64, from: H256, to: H256) -> (H256, Diff<H256>) {
+        (
+            H256::from_low_u64_be(slot),
+            Diff::Changed(ChangedType { from, to }),
+        )
+    }
+
+    #[test]
+    fn test_decode_v2_swap() {
+        let pool = v2_pool(
+            0x100,
+            Address::from_low_u64_be(0x10),
+            Address::from_low_u64_be(0x20),
+            (100, 200),
+        );
+        let reserves = |from: (u128, u128), to: (u128, u128)| {
+            account_diff(vec![slot_diff(
+                V2_RESERVES_SLOT,
+                packed_reserves(from.0, from.1),
+                packed_reserves(to.0, to.1),
+            )])
+        };
+
+        let swap = decode_v2_swap(pool, &reserves((100, 200), (150, 160))).unwrap();
+        assert!(swap.zero_for_one);
+        assert_eq!(
+            (swap.amount_in, swap.amount_out),
+            (U256::from(50), U256::from(40))
+        );
+
+        let swap = decode_v2_swap(pool, &reserves((100, 200), (90, 230))).unwrap();
+        assert!(!swap.zero_for_one);
+        assert_eq!(
+            (swap.amount_in, swap.amount_out),
+            (U256::from(30), U256::from(10))
+        );
+
+        // mints, burns and syncs move both reserves the same way
+        assert!(decode_v2_swap(pool, &reserves((100, 200), (110, 220))).is_none());
+        assert!(decode_v2_swap(pool, &reserves((100, 200), (90, 180))).is_none());
+        assert!(decode_v2_swap(pool, &account_diff(vec![])).is_none());
+    }
+
+    #[test]
+    fn test_decode_v3_swap() {
+        let pool = Pool::new_empty_pool(
+            Address::from_low_u64_be(0x100),
+            Address::from_low_u64_be(0x10),
+            Address::from_low_u64_be(0x20),
+            U256::from(3000),
+            PoolVariant::UniswapV3,
+        );
+        let q96 = U256::one() << 96;
+        let liquidity = U256::exp10(18);
+        // the tick packed above `sqrtPriceX96` is ignored
+        let slot0 = |sqrt_price: U256| word(sqrt_price | (U256::from(0x123456) << 160));
+        let swap_diff = |to: U256| {
+            account_diff(vec![
+                slot_diff(V3_SLOT0_SLOT, slot0(q96), slot0(to)),
+                // a crossed tick changes the liquidity, the amounts use the one before
+                slot_diff(V3_LIQUIDITY_SLOT, word(liquidity), word(liquidity * 2)),
+            ])
+        };
+
+        // token0 sold, its price drops by 1%
+        let swap = decode_v3_swap(pool, &swap_diff(q96 * 99 / 100)).unwrap();
+        assert!(swap.zero_for_one);
+        // the input is grossed up by the 0.3% fee
+        assert_eq!(swap.amount_in, U256::from(10131404313951956u64));
+        assert_eq!(swap.amount_out, U256::from(10000000000000000u64));
+
+        // token1 sold, the price of token0 rises by 1%
+        let swap = decode_v3_swap(pool, &swap_diff(q96 * 101 / 100)).unwrap();
+        assert!(!swap.zero_for_one);
+        assert_eq!(swap.amount_in, U256::from(10030090270812436u64));
+        assert_eq!(swap.amount_out, U256::from(9900990099009900u64));
+
+        // an unchanged price isn't a swap
+        assert!(decode_v3_swap(pool, &swap_diff(q96)).is_none());
+        // without the liquidity in the diff the cached pool state is used, empty here
+        let swap = decode_v3_swap(
+            pool,
+            &account_diff(vec![slot_diff(
+                V3_SLOT0_SLOT,
+                slot0(q96),
+                slot0(q96 * 99 / 100),
+            )]),
+        )
+        .unwrap();
+        assert_eq!(
+            (swap.amount_in, swap.amount_out),
+            (U256::zero(), U256::zero())
+        );
+    }
+}
@@ -0,0 +1,469 @@
+use crate::types::{EvictionReason, TxEvent};
+use anyhow::Result;
+use artemis::types::{Collector, CollectorStream};
+use async_trait::async_trait;
+use ethers::{
+    providers::{Middleware, PubsubClient},
+    types::{Address, Block, Transaction, H256, U256, U64},
+};
+use fees::TxFees;
+use futures::{stream, StreamExt};
+use log::{error, warn};
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use tokio::sync::broadcast::{self, error::RecvError, Receiver, Sender};
+
+/// Default number of pending txs kept in the cache
+pub const DEFAULT_TRACKER_CAPACITY: usize = 10_000;
+/// Default number of blocks a pending tx is kept before being evicted
+pub const DEFAULT_TRACKER_MAX_AGE: u64 = 25;
+/// Percentage both the fee cap and the tip of a replacement must rise by, as enforced by geth
+pub const REPLACEMENT_FEE_BUMP: u64 = 10;
+
+/// Result of feeding a pending tx into the [PendingTxTracker]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TrackOutcome {
+    /// First time the tx is seen
+    New,
+    /// The tx hash is already being tracked
+    Duplicate,
+    /// The tx replaces a tracked tx with the same sender and nonce
+    Replacement(H256),
+    /// The tx uses the sender and nonce of a tracked tx without bumping both its fee cap and
+    /// its tip by [REPLACEMENT_FEE_BUMP] percent
+    Underpriced,
+}
+
+/// A pending tx held by the [PendingTxTracker]
+#[derive(Debug, Clone)]
+pub struct TrackedTx {
+    pub tx: Transaction,
+    /// Block number at the time the tx was first seen
+    pub first_seen: U64,
+}
+
+#[derive(Debug, Default)]
+struct TrackerState {
+    by_hash: HashMap<H256, TrackedTx>,
+    by_sender_nonce: HashMap<(Address, U256), H256>,
+    /// Insertion order of the tracked hashes, may contain hashes already removed
+    order: VecDeque<H256>,
+}
+
+impl TrackerState {
+    fn remove(&mut self, hash: &H256) -> Option<TrackedTx> {
+        let tracked = self.by_hash.remove(hash)?;
+        let key = (tracked.tx.from, tracked.tx.nonce);
+        if self.by_sender_nonce.get(&key) == Some(hash) {
+            self.by_sender_nonce.remove(&key);
+        }
+        Some(tracked)
+    }
+
+    /// Pop the oldest hash that is still tracked
+    fn pop_oldest(&mut self) -> Option<TrackedTx> {
+        while let Some(hash) = self.order.pop_front() {
+            if let Some(tracked) = self.remove(&hash) {
+                return Some(tracked);
+            }
+        }
+        None
+    }
+}
+
+/// Bounded cache of pending txs keyed by hash and by (sender, nonce)
+///
+/// Replacements and evictions caused by new pending txs are broadcasted to subscribers, while
+/// inclusions and evictions caused by new blocks are returned from [PendingTxTracker::on_block]
+#[derive(Debug)]
+pub struct PendingTxTracker {
+    state: Mutex<TrackerState>,
+    capacity: usize,
+    max_age: u64,
+    events: Sender<TxEvent>,
+}
+
+impl Default for PendingTxTracker {
+    fn default() -> Self {
+        Self::new(DEFAULT_TRACKER_CAPACITY, DEFAULT_TRACKER_MAX_AGE)
+    }
+}
+
+impl PendingTxTracker {
+    /// Create a new tracker holding at most `capacity` txs, each for at most `max_age` blocks
+    pub fn new(capacity: usize, max_age: u64) -> Self {
+        let (events, _) = broadcast::channel(1024);
+        Self {
+            state: Mutex::new(TrackerState::default()),
+            capacity,
+            max_age,
+            events,
+        }
+    }
+
+    /// Subscribe to the replacement and eviction events emitted when pending txs are added
+    pub fn subscribe(&self) -> Receiver<TxEvent> {
+        self.events.subscribe()
+    }
+
+    /// Number of pending txs currently tracked
+    pub fn len(&self) -> usize {
+        self.state.lock().by_hash.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the tracked tx for the given hash
+    pub fn get(&self, hash: &H256) -> Option<TrackedTx> {
+        self.state.lock().by_hash.get(hash).cloned()
+    }
+
+    /// Returns the hash of the tracked tx for the given sender and nonce
+    pub fn get_by_sender_nonce(&self, sender: Address, nonce: U256) -> Option<H256> {
+        self.state
+            .lock()
+            .by_sender_nonce
+            .get(&(sender, nonce))
+            .copied()
+    }
+
+    /// Add a pending tx to the cache
+    ///
+    /// The tx's `from` field must already be recovered
+    pub fn on_pending(&self, tx: &Transaction, block_number: U64) -> TrackOutcome {
+        let mut events = vec![];
+        let outcome = {
+            let mut state = self.state.lock();
+
+            if state.by_hash.contains_key(&tx.hash) {
+                return TrackOutcome::Duplicate;
+            }
+
+            let key = (tx.from, tx.nonce);
+            let outcome = match state.by_sender_nonce.get(&key).copied() {
+                Some(old_hash) => {
+                    let old_fees = state
+                        .by_hash
+                        .get(&old_hash)
+                        .and_then(|old| TxFees::from_tx(&old.tx));
+
+                    if !outbids(old_fees, TxFees::from_tx(tx)) {
+                        return TrackOutcome::Underpriced;
+                    }
+
+                    state.remove(&old_hash);
+                    events.push(TxEvent::Replaced {
+                        replaced: old_hash,
+                        replacement: tx.clone(),
+                    });
+                    TrackOutcome::Replacement(old_hash)
+                }
+                None => TrackOutcome::New,
+            };
+
+            state.by_hash.insert(
+                tx.hash,
+                TrackedTx {
+                    tx: tx.clone(),
+                    first_seen: block_number,
+                },
+            );
+            state.by_sender_nonce.insert(key, tx.hash);
+            state.order.push_back(tx.hash);
+
+            while state.by_hash.len() > self.capacity {
+                if let Some(evicted) = state.pop_oldest() {
+                    events.push(TxEvent::Evicted {
+                        hash: evicted.tx.hash,
+                        reason: EvictionReason::Capacity,
+                    });
+                } else {
+                    break;
+                }
+            }
+
+            // compact the insertion order once stale hashes pile up
+            if state.order.len() > self.capacity.saturating_mul(2) {
+                let TrackerState { by_hash, order, .. } = &mut *state;
+                order.retain(|hash| by_hash.contains_key(hash));
+            }
+
+            outcome
+        };
+
+        for event in events {
+            // no subscribers is not an error, the events are simply dropped
+            let _ = self.events.send(event);
+        }
+
+        outcome
+    }
+
+    /// Update the cache with a newly mined block
+    ///
+    /// Returns the inclusion events for tracked txs found in the block, and the eviction events
+    /// for txs whose nonce was consumed by another tx or that are older than the max age
+    pub fn on_block(&self, block: &Block<Transaction>) -> Vec<TxEvent> {
+        let block_number = block.number.unwrap_or_default();
+        let mut events = vec![];
+        let mut state = self.state.lock();
+
+        for tx in block.transactions.iter() {
+            if state.remove(&tx.hash).is_some() {
+                events.push(TxEvent::Included {
+                    hash: tx.hash,
+                    block_number,
+                });
+                continue;
+            }
+
+            // a tx we never saw landed with the nonce of a tracked tx
+            if let Some(hash) = state.by_sender_nonce.get(&(tx.from, tx.nonce)).copied() {
+                state.remove(&hash);
+                events.push(TxEvent::Evicted {
+                    hash,
+                    reason: EvictionReason::NonceUsed,
+                });
+            }
+        }
+
+        let max_age = self.max_age;
+        let expired: Vec<H256> = state
+            .by_hash
+            .iter()
+            .filter(|(_, tracked)| tracked.first_seen + max_age < block_number)
+            .map(|(hash, _)| *hash)
+            .collect();
+        for hash in expired {
+            state.remove(&hash);
+            events.push(TxEvent::Evicted {
+                hash,
+                reason: EvictionReason::Expired,
+            });
+        }
+
+        events
+    }
+}
+
+/// Does a replacement pay enough to evict the tx it replaces from the node's mempool?
+fn outbids(old_fees: Option<TxFees>, new_fees: Option<TxFees>) -> bool {
+    let new_fees = if let Some(fees) = new_fees {
+        fees
+    } else {
+        return false;
+    };
+    let old_fees = if let Some(fees) = old_fees {
+        fees
+    } else {
+        return true;
+    };
+
+    let bumped =
+        |old: U256, new: U256| new > old && new >= old * (100 + REPLACEMENT_FEE_BUMP) / 100;
+    bumped(old_fees.max_fee_per_gas(), new_fees.max_fee_per_gas())
+        && bumped(
+            old_fees.max_priority_fee_per_gas(),
+            new_fees.max_priority_fee_per_gas(),
+        )
+}
+
+/// Emits the lifecycle events of the txs tracked by a [PendingTxTracker]
+///
+/// Inclusions are detected from the new block stream, replacements from the pending txs fed to
+/// the tracker by the [QilinMempoolCollector](crate::mempool_collector::QilinMempoolCollector)
+pub struct QilinTxTrackerCollector<M> {
+    provider: Arc<M>,
+    tracker: Arc<PendingTxTracker>,
+}
+
+impl<M> QilinTxTrackerCollector<M> {
+    pub fn new(provider: Arc<M>, tracker: Arc<PendingTxTracker>) -> Self {
+        Self { provider, tracker }
+    }
+}
+
+#[async_trait]
+impl<M> Collector<TxEvent> for QilinTxTrackerCollector<M>
+where
+    M: Middleware + 'static,
+    M::Provider: PubsubClient,
+    M::Error: 'static,
+{
+    async fn get_event_stream(&self) -> Result<CollectorStream<'_, TxEvent>> {
+        let pending_events = stream::unfold(self.tracker.subscribe(), |mut rx| async move {
+            loop {
+                match rx.recv().await {
+                    Ok(event) => return Some((event, rx)),
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!("Tx tracker lagged behind, skipped {} events", skipped);
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        });
+
+        let block_stream = self.provider.subscribe_blocks().await?;
+        let block_events = block_stream
+            .then(move |block| async move {
+                let hash = if let Some(hash) = block.hash {
+                    hash
+                } else {
+                    return vec![];
+                };
+
+                match self.provider.get_block_with_txs(hash).await {
+                    Ok(Some(full_block)) => self.tracker.on_block(&full_block),
+                    Ok(None) => vec![],
+                    Err(e) => {
+                        error!("Error getting block {:?}: {}", hash, e);
+                        vec![]
+                    }
+                }
+            })
+            .flat_map(stream::iter);
+
+        Ok(Box::pin(stream::select(block_events, pending_events)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pending_tx(hash: u64, from: u64, nonce: u64, gas_price: u64) -> Transaction {
+        Transaction {
+            hash: H256::from_low_u64_be(hash),
+            from: Address::from_low_u64_be(from),
+            nonce: U256::from(nonce),
+            gas_price: Some(U256::from(gas_price)),
+            ..Default::default()
+        }
+    }
+
+    fn pending_eip1559_tx(hash: u64, max_fee: u64, tip: u64) -> Transaction {
+        Transaction {
+            hash: H256::from_low_u64_be(hash),
+            from: Address::from_low_u64_be(1),
+            transaction_type: Some(U64::from(2)),
+            max_fee_per_gas: Some(U256::from(max_fee)),
+            max_priority_fee_per_gas: Some(U256::from(tip)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_dedup_and_replacement() {
+        let tracker = PendingTxTracker::new(10, 5);
+        let mut rx = tracker.subscribe();
+        let tx = pending_tx(1, 1, 0, 10);
+
+        assert_eq!(tracker.on_pending(&tx, U64::one()), TrackOutcome::New);
+        assert_eq!(tracker.on_pending(&tx, U64::one()), TrackOutcome::Duplicate);
+
+        // same sender and nonce without a fee bump
+        let underpriced = pending_tx(2, 1, 0, 10);
+        assert_eq!(
+            tracker.on_pending(&underpriced, U64::one()),
+            TrackOutcome::Underpriced
+        );
+
+        let replacement = pending_tx(3, 1, 0, 20);
+        assert_eq!(
+            tracker.on_pending(&replacement, U64::one()),
+            TrackOutcome::Replacement(tx.hash)
+        );
+        assert_eq!(tracker.len(), 1);
+        assert_eq!(
+            tracker.get_by_sender_nonce(tx.from, tx.nonce),
+            Some(replacement.hash)
+        );
+
+        match rx.try_recv().unwrap() {
+            TxEvent::Replaced {
+                replaced,
+                replacement: new_tx,
+            } => {
+                assert_eq!(replaced, tx.hash);
+                assert_eq!(new_tx.hash, replacement.hash);
+            }
+            event => panic!("unexpected event {:?}", event),
+        }
+    }
+
+    #[test]
+    fn test_replacement_fee_bump() {
+        let tracker = PendingTxTracker::new(10, 5);
+        let tx = pending_eip1559_tx(1, 100, 10);
+        assert_eq!(tracker.on_pending(&tx, U64::one()), TrackOutcome::New);
+
+        // both the fee cap and the tip must rise by 10%
+        for (hash, max_fee, tip) in [(2, 109, 20), (3, 200, 10), (4, 100, 10)] {
+            assert_eq!(
+                tracker.on_pending(&pending_eip1559_tx(hash, max_fee, tip), U64::one()),
+                TrackOutcome::Underpriced
+            );
+        }
+        assert_eq!(
+            tracker.on_pending(&pending_eip1559_tx(5, 110, 11), U64::one()),
+            TrackOutcome::Replacement(tx.hash)
+        );
+    }
+
+    #[test]
+    fn test_capacity_eviction() {
+        let tracker = PendingTxTracker::new(2, 5);
+        let mut rx = tracker.subscribe();
+
+        for i in 0..3 {
+            tracker.on_pending(&pending_tx(i, i, 0, 10), U64::one());
+        }
+
+        assert_eq!(tracker.len(), 2);
+        assert!(tracker.get(&H256::from_low_u64_be(0)).is_none());
+        assert!(matches!(
+            rx.try_recv().unwrap(),
+            TxEvent::Evicted {
+                reason: EvictionReason::Capacity,
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn test_block_inclusion_and_expiry() {
+        let tracker = PendingTxTracker::new(10, 2);
+        let included = pending_tx(1, 1, 0, 10);
+        let nonce_used = pending_tx(2, 2, 0, 10);
+        let stale = pending_tx(3, 3, 0, 10);
+
+        tracker.on_pending(&included, U64::from(10));
+        tracker.on_pending(&nonce_used, U64::from(10));
+        tracker.on_pending(&stale, U64::from(10));
+
+        let block = Block {
+            number: Some(U64::from(13)),
+            transactions: vec![included.clone(), pending_tx(4, 2, 0, 50)],
+            ..Default::default()
+        };
+        let events = tracker.on_block(&block);
+
+        assert_eq!(events.len(), 3);
+        assert!(matches!(
+            events[0],
+            TxEvent::Included { hash, .. } if hash == included.hash
+        ));
+        assert!(matches!(
+            events[1],
+            TxEvent::Evicted { hash, reason: EvictionReason::NonceUsed } if hash == nonce_used.hash
+        ));
+        assert!(matches!(
+            events[2],
+            TxEvent::Evicted { hash, reason: EvictionReason::Expired } if hash == stale.hash
+        ));
+        assert!(tracker.is_empty());
+    }
+}
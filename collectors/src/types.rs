@@ -1,9 +1,10 @@
 /// Artemis Collectors types implementations
-use ethers::types::{AccountDiff, Block, Transaction, H160, H256};
+use ethers::types::{AccountDiff, Block, Bytes, Transaction, H160, H256, U256, U64};
 use qilin_cfmms::pool::Pool;
 
 use dashmap::DashMap;
 use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
 use std::sync::Arc;
 
@@ -17,11 +18,83 @@ pub struct BlockPayload {
     pub all_pools: Arc<RwLockMap>,
 }
 
+/// A swap a tx performs against a known pool, decoded from the pool's state diff
+#[derive(Debug, Clone, Copy)]
+pub struct PoolSwap {
+    pub pool: Pool,
+    /// Is `token_0` sold into the pool?
+    pub zero_for_one: bool,
+    pub amount_in: U256,
+    pub amount_out: U256,
+}
+
+impl PoolSwap {
+    /// Token sold into the pool
+    pub fn token_in(&self) -> H160 {
+        if self.zero_for_one {
+            self.pool.token_0
+        } else {
+            self.pool.token_1
+        }
+    }
+
+    /// Token bought from the pool
+    pub fn token_out(&self) -> H160 {
+        if self.zero_for_one {
+            self.pool.token_1
+        } else {
+            self.pool.token_0
+        }
+    }
+
+    /// Does the swap buy or sell one of the `tokens`?
+    pub fn touches_any(&self, tokens: &[H160]) -> bool {
+        tokens.contains(&self.token_in()) || tokens.contains(&self.token_out())
+    }
+}
+
+/// The swaps performed by a tx, ordered so that each hop sells the token bought by the previous one
+#[derive(Debug, Clone, Default)]
+pub struct SwapRoute {
+    pub hops: Vec<PoolSwap>,
+}
+
+impl SwapRoute {
+    /// Token sold into the first pool of the route
+    pub fn token_in(&self) -> Option<H160> {
+        self.hops.first().map(|hop| hop.token_in())
+    }
+
+    /// Token bought from the last pool of the route
+    pub fn token_out(&self) -> Option<H160> {
+        self.hops.last().map(|hop| hop.token_out())
+    }
+
+    /// Pools traded against, in route order
+    pub fn pools(&self) -> impl Iterator<Item = &Pool> {
+        self.hops.iter().map(|hop| &hop.pool)
+    }
+
+    /// Hops that buy or sell one of the `base_tokens`, on either side of the pool
+    pub fn base_token_hops<'a>(
+        &'a self,
+        base_tokens: &'a [H160],
+    ) -> impl Iterator<Item = &'a PoolSwap> + 'a {
+        self.hops
+            .iter()
+            .filter(move |hop| hop.touches_any(base_tokens))
+    }
+}
+
 /// A new block event, containing the [Transaction] type and the `state_diff` BTreeMap.
 #[derive(Debug, Clone)]
 pub struct NewTx {
     pub tx: Transaction,
     pub state_diff: BTreeMap<H160, AccountDiff>,
+    /// Tip per gas the tx pays to the builder if included in the next block
+    pub effective_tip: U256,
+    /// Swaps against the pools in `all_pools`, `None` if the collector doesn't decode swaps
+    pub swaps: Option<Vec<PoolSwap>>,
 }
 
 impl Default for NewTx {
@@ -29,6 +102,127 @@ impl Default for NewTx {
         Self {
             tx: Transaction::default(),
             state_diff: BTreeMap::new(),
+            effective_tip: U256::zero(),
+            swaps: None,
         }
     }
 }
+
+/// Lifecycle event of a pending tx tracked by the
+/// [PendingTxTracker](crate::tx_tracker::PendingTxTracker)
+#[derive(Debug, Clone)]
+pub enum TxEvent {
+    /// A pending tx was replaced by a tx with the same sender and nonce and a higher fee
+    Replaced {
+        replaced: H256,
+        replacement: Transaction,
+    },
+    /// A pending tx was included in a block
+    Included { hash: H256, block_number: U64 },
+    /// A pending tx was removed from the cache without being seen in a block
+    Evicted { hash: H256, reason: EvictionReason },
+}
+
+/// Why a pending tx was evicted from the tracker
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionReason {
+    /// The cache is full and the tx was the oldest one tracked
+    Capacity,
+    /// The tx has been pending for longer than the tracker's max age
+    Expired,
+    /// Another tx with the same sender and nonce was included
+    NonceUsed,
+}
+
+/// A bundle handed to builders, tracked until its target block
+#[derive(Debug, Clone)]
+pub struct SentBundle {
+    /// Strategy the bundle was built by
+    pub strategy: String,
+    /// Builders that accepted the bundle
+    pub builders: Vec<String>,
+    /// Hashes of our txs in the bundle
+    pub tx_hashes: Vec<H256>,
+    pub target_block: U64,
+    /// Profit of the bundle in simulation, before gas
+    pub expected_profit: U256,
+}
+
+/// What became of a [SentBundle]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BundleOutcome {
+    /// All of the bundle's txs landed and succeeded
+    Included {
+        block_number: U64,
+        /// Gas fees paid by the bundle's txs, in wei
+        gas_spent: U256,
+    },
+    /// Some of the bundle's txs landed but `tx_hash` reverted or is missing
+    Reverted {
+        block_number: U64,
+        tx_hash: H256,
+        gas_spent: U256,
+    },
+    /// The target block was built without the bundle
+    Outbid { target_block: U64 },
+}
+
+/// A [SentBundle] resolved by the
+/// [InclusionTracker](crate::inclusion_tracker::InclusionTracker)
+#[derive(Debug, Clone)]
+pub struct BundleResult {
+    pub bundle: SentBundle,
+    pub outcome: BundleOutcome,
+}
+
+/// A hint about a private tx or bundle broadcasted by an MEV-Share event stream
+///
+/// See https://docs.flashbots.net/flashbots-mev-share/searchers/event-stream
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MevShareEvent {
+    /// Hash of the tx or bundle
+    pub hash: H256,
+    /// Logs emitted by the tx, if shared
+    #[serde(default)]
+    pub logs: Option<Vec<MevShareLog>>,
+    /// Txs in the bundle, with the fields the user chose to share
+    #[serde(default)]
+    pub txs: Option<Vec<MevShareTx>>,
+    #[serde(default)]
+    pub mev_gas_price: Option<U256>,
+    #[serde(default)]
+    pub gas_used: Option<U256>,
+}
+
+impl MevShareEvent {
+    /// Shared logs, empty if none were shared
+    pub fn logs(&self) -> &[MevShareLog] {
+        self.logs.as_deref().unwrap_or_default()
+    }
+
+    /// Shared txs, empty if none were shared
+    pub fn txs(&self) -> &[MevShareTx] {
+        self.txs.as_deref().unwrap_or_default()
+    }
+}
+
+/// A log shared in a [MevShareEvent]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MevShareLog {
+    pub address: H160,
+    pub topics: Vec<H256>,
+    pub data: Bytes,
+}
+
+/// A tx shared in a [MevShareEvent]
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct MevShareTx {
+    #[serde(default)]
+    pub to: Option<H160>,
+    #[serde(default)]
+    pub function_selector: Option<Bytes>,
+    #[serde(default)]
+    pub call_data: Option<Bytes>,
+}
@@ -0,0 +1,403 @@
+use ethers::{
+    signers::{LocalWallet, Signer},
+    types::{Bytes, H256, U64},
+    utils::keccak256,
+};
+use futures::future::join_all;
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use thiserror::Error;
+
+/// Header carrying the searcher's signature of the request body
+pub const FLASHBOTS_SIGNATURE_HEADER: &str = "X-Flashbots-Signature";
+
+#[derive(Error, Debug)]
+pub enum BroadcastError {
+    #[error("Request to builder failed")]
+    Http(#[from] reqwest::Error),
+    #[error("Builder returned error {code}: {message}")]
+    Rpc { code: i64, message: String },
+    #[error("Builder answered with status {0}")]
+    Status(reqwest::StatusCode),
+    #[error("Failed to sign the bundle: {0}")]
+    Signing(String),
+    #[error("Invalid builder response: {0}")]
+    InvalidResponse(String),
+}
+
+/// JSON-RPC method a builder accepts bundles through
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BundleRpc {
+    /// `eth_sendBundle`, accepted by most builders
+    EthSendBundle,
+    /// `mev_sendBundle`, the MEV-Share bundle format
+    MevSendBundle,
+}
+
+/// A block builder bundles are sent to
+#[derive(Debug, Clone)]
+pub struct BuilderConfig {
+    pub name: String,
+    pub url: String,
+    pub rpc: BundleRpc,
+    /// Key signing the requests to this builder, the broadcaster's default signer if `None`
+    pub signer: Option<LocalWallet>,
+}
+
+impl BuilderConfig {
+    pub fn new(name: impl Into<String>, url: impl Into<String>, rpc: BundleRpc) -> Self {
+        Self {
+            name: name.into(),
+            url: url.into(),
+            rpc,
+            signer: None,
+        }
+    }
+
+    pub fn with_signer(mut self, signer: LocalWallet) -> Self {
+        self.signer = Some(signer);
+        self
+    }
+}
+
+/// A bundle of signed txs targeting a block
+#[derive(Debug, Clone, Default)]
+pub struct SignedBundle {
+    /// RLP encoded signed txs, in bundle order
+    pub txs: Vec<Bytes>,
+    pub block_number: U64,
+    pub min_timestamp: Option<u64>,
+    pub max_timestamp: Option<u64>,
+    /// Txs the bundle is still valid without if they revert
+    pub reverting_tx_hashes: Vec<H256>,
+}
+
+impl SignedBundle {
+    pub fn new(txs: Vec<Bytes>, block_number: U64) -> Self {
+        Self {
+            txs,
+            block_number,
+            ..Default::default()
+        }
+    }
+
+    /// Hashes of the bundle's txs
+    pub fn tx_hashes(&self) -> Vec<H256> {
+        self.txs
+            .iter()
+            .map(|tx| H256::from(keccak256(tx)))
+            .collect()
+    }
+
+    /// JSON-RPC params of the bundle in the format of `rpc`
+    pub fn params(&self, rpc: BundleRpc) -> Value {
+        match rpc {
+            BundleRpc::EthSendBundle => {
+                let mut bundle = json!({
+                    "txs": self.txs,
+                    "blockNumber": self.block_number,
+                });
+                if let Some(min_timestamp) = self.min_timestamp {
+                    bundle["minTimestamp"] = json!(min_timestamp);
+                }
+                if let Some(max_timestamp) = self.max_timestamp {
+                    bundle["maxTimestamp"] = json!(max_timestamp);
+                }
+                if !self.reverting_tx_hashes.is_empty() {
+                    bundle["revertingTxHashes"] = json!(self.reverting_tx_hashes);
+                }
+                json!([bundle])
+            }
+            BundleRpc::MevSendBundle => {
+                let body: Vec<Value> = self
+                    .txs
+                    .iter()
+                    .zip(self.tx_hashes())
+                    .map(|(tx, hash)| {
+                        json!({
+                            "tx": tx,
+                            "canRevert": self.reverting_tx_hashes.contains(&hash),
+                        })
+                    })
+                    .collect();
+                json!([{
+                    "version": "v0.1",
+                    "inclusion": { "block": self.block_number },
+                    "body": body,
+                }])
+            }
+        }
+    }
+}
+
+/// A builder's answer to a bundle
+#[derive(Debug)]
+pub struct BuilderAck {
+    pub builder: String,
+    /// Hash the builder identifies the bundle by, if it returned one
+    pub result: Result<Option<H256>, BroadcastError>,
+}
+
+impl BuilderAck {
+    pub fn is_ok(&self) -> bool {
+        self.result.is_ok()
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonRpcResponse {
+    #[serde(default)]
+    result: Option<Value>,
+    #[serde(default)]
+    error: Option<JsonRpcError>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JsonRpcError {
+    code: i64,
+    message: String,
+}
+
+/// Sends the same bundle to every configured builder
+#[derive(Debug, Clone)]
+pub struct BundleBroadcaster {
+    builders: Vec<BuilderConfig>,
+    /// Key signing the requests to builders without their own signer
+    signer: LocalWallet,
+    client: reqwest::Client,
+}
+
+impl BundleBroadcaster {
+    pub fn new(builders: Vec<BuilderConfig>, signer: LocalWallet) -> Self {
+        Self {
+            builders,
+            signer,
+            client: reqwest::Client::new(),
+        }
+    }
+
+    pub fn builders(&self) -> &[BuilderConfig] {
+        &self.builders
+    }
+
+    /// Send `bundle` to all builders concurrently
+    ///
+    /// Returns one ack per builder, in the order the builders are configured
+    pub async fn broadcast(&self, bundle: &SignedBundle) -> Vec<BuilderAck> {
+        let acks = join_all(self.builders.iter().map(|builder| async move {
+            BuilderAck {
+                builder: builder.name.clone(),
+                result: self.send(builder, bundle).await,
+            }
+        }))
+        .await;
+
+        let accepted = acks.iter().filter(|ack| ack.is_ok()).count();
+        info!(
+            "Bundle for block {} accepted by {}/{} builders",
+            bundle.block_number,
+            accepted,
+            acks.len()
+        );
+        for ack in acks.iter() {
+            if let Err(e) = &ack.result {
+                warn!("Builder {} rejected bundle: {}", ack.builder, e);
+            }
+        }
+
+        acks
+    }
+
+    /// Send `bundle` to a single builder
+    pub async fn send(
+        &self,
+        builder: &BuilderConfig,
+        bundle: &SignedBundle,
+    ) -> Result<Option<H256>, BroadcastError> {
+        let method = match builder.rpc {
+            BundleRpc::EthSendBundle => "eth_sendBundle",
+            BundleRpc::MevSendBundle => "mev_sendBundle",
+        };
+        let body = json!({
+            "jsonrpc": "2.0",
+            "id": 1,
+            "method": method,
+            "params": bundle.params(builder.rpc),
+        })
+        .to_string();
+
+        let signer = builder.signer.as_ref().unwrap_or(&self.signer);
+        let signature = sign_body(signer, &body).await?;
+
+        let response = self
+            .client
+            .post(&builder.url)
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .header(FLASHBOTS_SIGNATURE_HEADER, signature)
+            .body(body)
+            .send()
+            .await?;
+        let status = response.status();
+        let body = response.text().await?;
+
+        // builders reject bundles with a JSON-RPC error, often under a 4xx status
+        let response: JsonRpcResponse = match serde_json::from_str(&body) {
+            Ok(response) => response,
+            Err(_) if !status.is_success() => return Err(BroadcastError::Status(status)),
+            Err(e) => return Err(BroadcastError::InvalidResponse(e.to_string())),
+        };
+        if let Some(error) = response.error {
+            return Err(BroadcastError::Rpc {
+                code: error.code,
+                message: error.message,
+            });
+        }
+        if !status.is_success() {
+            return Err(BroadcastError::Status(status));
+        }
+
+        match response.result {
+            Some(Value::Object(result)) => match result.get("bundleHash") {
+                Some(hash) => serde_json::from_value(hash.clone())
+                    .map(Some)
+                    .map_err(|e| BroadcastError::InvalidResponse(e.to_string())),
+                None => Ok(None),
+            },
+            // some builders only acknowledge with `null` or a bare string
+            Some(_) | None => Ok(None),
+        }
+    }
+}
+
+/// Sign a request body the way Flashbots-compatible relays expect:
+/// `<address>:<signature of the hex keccak256 of the body>`
+pub async fn sign_body(signer: &LocalWallet, body: &str) -> Result<String, BroadcastError> {
+    let digest = format!("{:?}", H256::from(keccak256(body.as_bytes())));
+    let signature = signer
+        .sign_message(digest)
+        .await
+        .map_err(|e| BroadcastError::Signing(e.to_string()))?;
+    Ok(format!("{:?}:0x{}", signer.address(), signature))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{Address, Signature};
+    use std::str::FromStr;
+    use test_utils::{MockServer, ReceivedRequest};
+
+    fn wallet(key: &str) -> LocalWallet {
+        LocalWallet::from_str(key).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_broadcast_to_builders() {
+        let default_signer =
+            wallet("0x0000000000000000000000000000000000000000000000000000000000000001");
+        let builder_signer =
+            wallet("0x0000000000000000000000000000000000000000000000000000000000000002");
+
+        let (eth_url, eth_received) = MockServer::json(
+            r#"{"jsonrpc":"2.0","id":1,"result":{"bundleHash":"0x1111111111111111111111111111111111111111111111111111111111111111"}}"#,
+        )
+        .spawn()
+        .await;
+        let (mev_url, mev_received) = MockServer::json(r#"{"jsonrpc":"2.0","id":1,"result":null}"#)
+            .spawn()
+            .await;
+        let (err_url, _) = MockServer::json(
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32000,"message":"bundle too late"}}"#,
+        )
+        .spawn()
+        .await;
+        let (rejected_url, _) = MockServer::json(
+            r#"{"jsonrpc":"2.0","id":1,"error":{"code":-32602,"message":"invalid bundle"}}"#,
+        )
+        .with_status("400 Bad Request")
+        .spawn()
+        .await;
+        let (failed_url, _) = MockServer::json("bad gateway")
+            .with_status("502 Bad Gateway")
+            .spawn()
+            .await;
+
+        let broadcaster = BundleBroadcaster::new(
+            vec![
+                BuilderConfig::new("eth", eth_url, BundleRpc::EthSendBundle),
+                BuilderConfig::new("mev", mev_url, BundleRpc::MevSendBundle)
+                    .with_signer(builder_signer.clone()),
+                BuilderConfig::new("late", err_url, BundleRpc::EthSendBundle),
+                BuilderConfig::new("rejected", rejected_url, BundleRpc::EthSendBundle),
+                BuilderConfig::new("failed", failed_url, BundleRpc::EthSendBundle),
+                BuilderConfig::new("down", "http://127.0.0.1:1", BundleRpc::EthSendBundle),
+            ],
+            default_signer.clone(),
+        );
+
+        let mut bundle = SignedBundle::new(
+            vec![Bytes::from(vec![0x02, 0xaa]), Bytes::from(vec![0x02, 0xbb])],
+            U64::from(17_000_000),
+        );
+        bundle.reverting_tx_hashes = vec![bundle.tx_hashes()[1]];
+
+        let acks = broadcaster.broadcast(&bundle).await;
+        assert_eq!(
+            acks.iter()
+                .map(|ack| ack.builder.as_str())
+                .collect::<Vec<_>>(),
+            vec!["eth", "mev", "late", "rejected", "failed", "down"]
+        );
+        assert_eq!(
+            acks[0].result.as_ref().unwrap(),
+            &Some(H256::repeat_byte(0x11))
+        );
+        assert_eq!(acks[1].result.as_ref().unwrap(), &None);
+        assert!(matches!(
+            acks[2].result,
+            Err(BroadcastError::Rpc { code: -32000, .. })
+        ));
+        // the error body of a 4xx is still reported
+        assert!(matches!(
+            &acks[3].result,
+            Err(BroadcastError::Rpc { code: -32602, message }) if message == "invalid bundle"
+        ));
+        assert!(matches!(
+            acks[4].result,
+            Err(BroadcastError::Status(reqwest::StatusCode::BAD_GATEWAY))
+        ));
+        assert!(matches!(acks[5].result, Err(BroadcastError::Http(_))));
+
+        // eth_sendBundle, signed by the default signer
+        let eth = eth_received.lock().unwrap().clone().unwrap();
+        let body: Value = serde_json::from_str(&eth.body).unwrap();
+        assert_eq!(body["method"], "eth_sendBundle");
+        assert_eq!(body["params"][0]["txs"], json!(["0x02aa", "0x02bb"]));
+        assert_eq!(body["params"][0]["blockNumber"], "0x1036640");
+        assert_eq!(
+            body["params"][0]["revertingTxHashes"],
+            json!([bundle.tx_hashes()[1]])
+        );
+        assert_signed_by(&eth, default_signer.address());
+
+        // mev_sendBundle, signed by the builder's own signer
+        let mev = mev_received.lock().unwrap().clone().unwrap();
+        let body: Value = serde_json::from_str(&mev.body).unwrap();
+        assert_eq!(body["method"], "mev_sendBundle");
+        assert_eq!(body["params"][0]["inclusion"]["block"], "0x1036640");
+        assert_eq!(body["params"][0]["body"][0]["canRevert"], false);
+        assert_eq!(body["params"][0]["body"][1]["canRevert"], true);
+        assert_signed_by(&mev, builder_signer.address());
+    }
+
+    fn assert_signed_by(request: &ReceivedRequest, signer: Address) {
+        let header = request.header(FLASHBOTS_SIGNATURE_HEADER).unwrap();
+        let (address, signature) = header.split_once(':').unwrap();
+        assert_eq!(Address::from_str(address).unwrap(), signer);
+
+        let signature = Signature::from_str(signature.trim_start_matches("0x")).unwrap();
+        let digest = format!("{:?}", H256::from(keccak256(request.body.as_bytes())));
+        signature.verify(digest, signer).unwrap();
+    }
+}
@@ -1 +1,2 @@
-
+pub mod bundle_broadcaster;
+pub mod nonce_manager;
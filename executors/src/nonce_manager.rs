@@ -0,0 +1,287 @@
+use ethers::{
+    providers::Middleware,
+    types::{Address, BlockId, BlockNumber, U256, U64},
+};
+use log::warn;
+use parking_lot::Mutex;
+use std::collections::BTreeMap;
+
+/// Nonces handed out to a bundle, valid until the bundle's target block
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NonceReservation {
+    pub id: u64,
+    pub first_nonce: U256,
+    pub count: u64,
+    /// Block the bundle is sent for, the reservation is resolved once it's mined
+    pub target_block: U64,
+}
+
+impl NonceReservation {
+    /// Nonce of the `index`th tx of the bundle
+    pub fn nonce(&self, index: u64) -> U256 {
+        self.first_nonce + index
+    }
+
+    /// Nonces of the bundle's txs, in order
+    pub fn nonces(&self) -> impl Iterator<Item = U256> + '_ {
+        (0..self.count).map(|index| self.nonce(index))
+    }
+
+    /// Nonce following the last tx of the bundle
+    pub fn end(&self) -> U256 {
+        self.first_nonce + self.count
+    }
+}
+
+/// What happened to the reservations resolved by a new block
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ReservationUpdate {
+    /// Reservations whose txs all landed
+    pub included: Vec<u64>,
+    /// Reservations that can no longer land: their bundle missed its target block, or they
+    /// were stacked on top of such a bundle. Their txs must be rebuilt with new nonces.
+    pub dropped: Vec<u64>,
+}
+
+#[derive(Debug, Default)]
+struct NonceState {
+    /// Tx count of the account at the latest block
+    chain_nonce: U256,
+    /// Latest block the chain nonce was synced at
+    synced_block: U64,
+    /// Outstanding reservations, by first nonce
+    reservations: BTreeMap<U256, NonceReservation>,
+    next_id: u64,
+}
+
+impl NonceState {
+    /// Drop the reservation starting at `first_nonce` and every reservation stacked on top of it
+    fn drop_from(&mut self, first_nonce: U256, dropped: &mut Vec<u64>) {
+        let stacked: Vec<U256> = self
+            .reservations
+            .range(first_nonce..)
+            .map(|(nonce, _)| *nonce)
+            .collect();
+        for nonce in stacked {
+            if let Some(reservation) = self.reservations.remove(&nonce) {
+                dropped.push(reservation.id);
+            }
+        }
+    }
+}
+
+/// Hands out the nonces of a searcher account to the bundles of every strategy
+///
+/// Each bundle reserves consecutive nonces after the chain nonce and the nonces of the bundles
+/// still pending, so that concurrent bundles never sign two txs with the same nonce. A bundle
+/// stacked on a pending one only lands if the pending one does. On each new block the chain
+/// nonce is resynced: reservations below it landed, reservations whose target block passed
+/// without landing are dropped along with everything stacked on them, freeing their nonces.
+#[derive(Debug)]
+pub struct NonceManager {
+    address: Address,
+    state: Mutex<NonceState>,
+}
+
+impl NonceManager {
+    pub fn new(address: Address, chain_nonce: U256) -> Self {
+        Self {
+            address,
+            state: Mutex::new(NonceState {
+                chain_nonce,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Create a manager starting at the account's tx count at the latest block
+    pub async fn from_chain<M: Middleware>(
+        provider: &M,
+        address: Address,
+    ) -> Result<Self, M::Error> {
+        let chain_nonce = provider
+            .get_transaction_count(address, Some(BlockId::Number(BlockNumber::Latest)))
+            .await?;
+        Ok(Self::new(address, chain_nonce))
+    }
+
+    /// Account the nonces are managed for
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Tx count of the account at the latest synced block
+    pub fn chain_nonce(&self) -> U256 {
+        self.state.lock().chain_nonce
+    }
+
+    /// Nonce the next reservation would start at
+    pub fn next_nonce(&self) -> U256 {
+        let state = self.state.lock();
+        next_free(&state)
+    }
+
+    /// Reserve `count` consecutive nonces for a bundle targeting `target_block`
+    pub fn reserve(&self, count: u64, target_block: U64) -> NonceReservation {
+        let mut state = self.state.lock();
+        let reservation = NonceReservation {
+            id: state.next_id,
+            first_nonce: next_free(&state),
+            count,
+            target_block,
+        };
+        state.next_id += 1;
+        if count > 0 {
+            state
+                .reservations
+                .insert(reservation.first_nonce, reservation);
+        }
+        reservation
+    }
+
+    /// Give back the nonces of a bundle that won't be sent
+    ///
+    /// Returns the ids of the reservations stacked on top of it, which are dropped too
+    pub fn release(&self, reservation: &NonceReservation) -> Vec<u64> {
+        let mut state = self.state.lock();
+        if state
+            .reservations
+            .get(&reservation.first_nonce)
+            .map(|r| r.id)
+            != Some(reservation.id)
+        {
+            return vec![];
+        }
+
+        let mut dropped = vec![];
+        state.drop_from(reservation.first_nonce, &mut dropped);
+        dropped.retain(|id| *id != reservation.id);
+        dropped
+    }
+
+    /// Record that a bundle landed, before the block is synced
+    pub fn mark_included(&self, reservation: &NonceReservation) {
+        let mut state = self.state.lock();
+        state.chain_nonce = state.chain_nonce.max(reservation.end());
+        let landed = reservation.end();
+        state
+            .reservations
+            .retain(|first_nonce, _| *first_nonce >= landed);
+    }
+
+    /// Resolve the reservations against the account's tx count after `block_number`
+    ///
+    /// Reservations whose nonces are all below `chain_nonce` landed. Reservations targeting
+    /// `block_number` or earlier that didn't land are dropped, along with the reservations
+    /// stacked on them.
+    pub fn on_block(&self, block_number: U64, chain_nonce: U256) -> ReservationUpdate {
+        let mut state = self.state.lock();
+        let mut update = ReservationUpdate::default();
+
+        if block_number < state.synced_block {
+            return update;
+        }
+        state.synced_block = block_number;
+        if chain_nonce < state.chain_nonce {
+            warn!(
+                "Nonce of {:?} went back from {} to {} at block {}",
+                self.address, state.chain_nonce, chain_nonce, block_number
+            );
+        }
+        state.chain_nonce = chain_nonce;
+
+        // landed, or consumed by a tx sent outside the manager
+        let landed: Vec<U256> = state
+            .reservations
+            .range(..chain_nonce)
+            .map(|(nonce, _)| *nonce)
+            .collect();
+        for nonce in landed {
+            if let Some(reservation) = state.reservations.remove(&nonce) {
+                if reservation.end() <= chain_nonce {
+                    update.included.push(reservation.id);
+                } else {
+                    // partially consumed, its remaining txs can't land anymore
+                    update.dropped.push(reservation.id);
+                }
+            }
+        }
+
+        // the first expired reservation invalidates everything stacked on it
+        if let Some(expired) = state
+            .reservations
+            .values()
+            .find(|reservation| reservation.target_block <= block_number)
+            .map(|reservation| reservation.first_nonce)
+        {
+            state.drop_from(expired, &mut update.dropped);
+        }
+
+        update
+    }
+
+    /// Fetch the account's tx count at `block_number` and resolve the reservations
+    pub async fn sync<M: Middleware>(
+        &self,
+        provider: &M,
+        block_number: U64,
+    ) -> Result<ReservationUpdate, M::Error> {
+        let chain_nonce = provider
+            .get_transaction_count(
+                self.address,
+                Some(BlockId::Number(BlockNumber::Number(block_number))),
+            )
+            .await?;
+        Ok(self.on_block(block_number, chain_nonce))
+    }
+}
+
+fn next_free(state: &NonceState) -> U256 {
+    state
+        .reservations
+        .values()
+        .next_back()
+        .map(|reservation| reservation.end())
+        .unwrap_or_default()
+        .max(state.chain_nonce)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_nonce_reservations() {
+        let manager = NonceManager::new(Address::zero(), U256::from(10));
+
+        let a = manager.reserve(2, 100.into());
+        let b = manager.reserve(1, 101.into());
+        let c = manager.reserve(2, 102.into());
+        assert_eq!(a.nonces().collect::<Vec<_>>(), vec![10.into(), 11.into()]);
+        assert_eq!(b.first_nonce, 12.into());
+        assert_eq!(c.first_nonce, 13.into());
+
+        // `a` landed at its target block
+        let update = manager.on_block(100.into(), 12.into());
+        assert_eq!(update.included, vec![a.id]);
+        assert!(update.dropped.is_empty());
+
+        // `b` missed its target block, `c` was stacked on it
+        let update = manager.on_block(101.into(), 12.into());
+        assert!(update.included.is_empty());
+        assert_eq!(update.dropped, vec![b.id, c.id]);
+        assert_eq!(manager.next_nonce(), 12.into());
+
+        // released nonces are handed out again
+        let d = manager.reserve(1, 103.into());
+        let e = manager.reserve(1, 103.into());
+        assert_eq!(d.first_nonce, 12.into());
+        assert_eq!(manager.release(&d), vec![e.id]);
+        assert_eq!(manager.next_nonce(), 12.into());
+
+        let f = manager.reserve(3, 104.into());
+        manager.mark_included(&f);
+        assert_eq!(manager.chain_nonce(), 15.into());
+        assert_eq!(manager.next_nonce(), 15.into());
+    }
+}
@@ -0,0 +1,177 @@
+use ethers::types::{transaction::eip2718::TypedTransaction, Transaction, U256};
+
+/// The fee fields a transaction carries, depending on its [EIP-2718](https://eips.ethereum.org/EIPS/eip-2718) type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TxFees {
+    /// Legacy (type 0) and access list (type 1) transactions, which only set `gasPrice`
+    GasPrice(U256),
+    /// EIP-1559 (type 2) transactions
+    Eip1559 {
+        max_fee_per_gas: U256,
+        max_priority_fee_per_gas: U256,
+    },
+}
+
+impl TxFees {
+    /// Read the fee fields from a transaction
+    ///
+    /// Returns `None` if the fields required by the transaction type are missing
+    pub fn from_tx(tx: &Transaction) -> Option<Self> {
+        match tx.transaction_type.map(|t| t.as_u64()) {
+            None | Some(0) | Some(1) => tx.gas_price.map(TxFees::GasPrice),
+            Some(2) => Some(TxFees::Eip1559 {
+                max_fee_per_gas: tx.max_fee_per_gas?,
+                max_priority_fee_per_gas: tx.max_priority_fee_per_gas?,
+            }),
+            // unknown type, use whatever fee fields are populated
+            Some(_) => match (tx.max_fee_per_gas, tx.max_priority_fee_per_gas) {
+                (Some(max_fee_per_gas), Some(max_priority_fee_per_gas)) => Some(TxFees::Eip1559 {
+                    max_fee_per_gas,
+                    max_priority_fee_per_gas,
+                }),
+                _ => tx.gas_price.map(TxFees::GasPrice),
+            },
+        }
+    }
+
+    /// Read the fee fields from a transaction being signed or decoded from its raw bytes
+    ///
+    /// Returns `None` if the fields required by the transaction type are missing
+    pub fn from_typed_tx(tx: &TypedTransaction) -> Option<Self> {
+        match tx {
+            TypedTransaction::Eip1559(inner) => Some(TxFees::Eip1559 {
+                max_fee_per_gas: inner.max_fee_per_gas?,
+                max_priority_fee_per_gas: inner.max_priority_fee_per_gas?,
+            }),
+            _ => tx.gas_price().map(TxFees::GasPrice),
+        }
+    }
+
+    /// The most the sender is willing to pay per unit of gas
+    pub fn max_fee_per_gas(&self) -> U256 {
+        match self {
+            TxFees::GasPrice(gas_price) => *gas_price,
+            TxFees::Eip1559 {
+                max_fee_per_gas, ..
+            } => *max_fee_per_gas,
+        }
+    }
+
+    /// The most the sender is willing to tip per unit of gas, the whole gas price for legacy
+    /// transactions
+    pub fn max_priority_fee_per_gas(&self) -> U256 {
+        match self {
+            TxFees::GasPrice(gas_price) => *gas_price,
+            TxFees::Eip1559 {
+                max_priority_fee_per_gas,
+                ..
+            } => *max_priority_fee_per_gas,
+        }
+    }
+
+    /// The tip per gas paid to the block builder given a base fee
+    ///
+    /// Returns `None` if the transaction can not be included at this base fee
+    pub fn effective_tip(&self, base_fee: U256) -> Option<U256> {
+        match self {
+            TxFees::GasPrice(gas_price) => gas_price.checked_sub(base_fee),
+            TxFees::Eip1559 {
+                max_fee_per_gas,
+                max_priority_fee_per_gas,
+            } => max_fee_per_gas
+                .checked_sub(base_fee)
+                .map(|headroom| headroom.min(*max_priority_fee_per_gas)),
+        }
+    }
+}
+
+/// Returns `true` if the transaction pays at least `base_fee` per gas
+pub fn is_fee_eligible(tx: &Transaction, base_fee: U256) -> bool {
+    effective_tip(tx, base_fee).is_some()
+}
+
+/// Calculate the effective tip per gas of a transaction if it were included in a block with
+/// the given `base_fee`
+///
+/// Returns `None` if the transaction is underpriced or its fee fields are malformed
+pub fn effective_tip(tx: &Transaction, base_fee: U256) -> Option<U256> {
+    TxFees::from_tx(tx)?.effective_tip(base_fee)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::types::{Eip1559TransactionRequest, TransactionRequest, U64};
+
+    fn legacy_tx(gas_price: u64) -> Transaction {
+        Transaction {
+            gas_price: Some(U256::from(gas_price)),
+            ..Default::default()
+        }
+    }
+
+    fn eip1559_tx(max_fee: u64, max_priority_fee: u64) -> Transaction {
+        Transaction {
+            transaction_type: Some(U64::from(2)),
+            max_fee_per_gas: Some(U256::from(max_fee)),
+            max_priority_fee_per_gas: Some(U256::from(max_priority_fee)),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_legacy_and_access_list_effective_tip() {
+        let tx = legacy_tx(30);
+        assert_eq!(effective_tip(&tx, U256::from(20)), Some(U256::from(10)));
+        assert!(!is_fee_eligible(&tx, U256::from(31)));
+
+        let mut access_list_tx = legacy_tx(25);
+        access_list_tx.transaction_type = Some(U64::from(1));
+        assert_eq!(
+            effective_tip(&access_list_tx, U256::from(20)),
+            Some(U256::from(5))
+        );
+    }
+
+    #[test]
+    fn test_eip1559_effective_tip() {
+        // tip is capped by the priority fee
+        let tx = eip1559_tx(100, 2);
+        assert_eq!(effective_tip(&tx, U256::from(20)), Some(U256::from(2)));
+        // tip is capped by the headroom above the base fee
+        assert_eq!(effective_tip(&tx, U256::from(99)), Some(U256::from(1)));
+        assert_eq!(effective_tip(&tx, U256::from(101)), None);
+    }
+
+    #[test]
+    fn test_typed_tx_fees() {
+        let tx: TypedTransaction = Eip1559TransactionRequest::new()
+            .max_fee_per_gas(100)
+            .max_priority_fee_per_gas(2)
+            .into();
+        assert_eq!(
+            TxFees::from_typed_tx(&tx)
+                .unwrap()
+                .effective_tip(U256::from(99)),
+            Some(U256::from(1))
+        );
+
+        let tx: TypedTransaction = TransactionRequest::new().gas_price(30).into();
+        assert_eq!(
+            TxFees::from_typed_tx(&tx),
+            Some(TxFees::GasPrice(U256::from(30)))
+        );
+        assert_eq!(
+            TxFees::from_typed_tx(&Eip1559TransactionRequest::new().into()),
+            None
+        );
+    }
+
+    #[test]
+    fn test_missing_fee_fields() {
+        let mut tx = eip1559_tx(100, 2);
+        tx.max_priority_fee_per_gas = None;
+        assert_eq!(TxFees::from_tx(&tx), None);
+        assert!(!is_fee_eligible(&Transaction::default(), U256::zero()));
+    }
+}
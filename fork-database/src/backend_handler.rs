@@ -3,21 +3,22 @@
 use super::{
     blockchain_db::BlockchainDb,
     errors::{DatabaseError, DatabaseResult},
+    prefetch::{account_info, fetch_concurrently, BatchClient, PrefetchList, PrefetchedState},
+    proof::{fetch_verified, get_verified_account, StateRootCache},
     utils::{b256_to_h256, h160_to_b160, ru256_to_u256, u256_to_ru256},
 };
 use ethers::{
     core::abi::ethereum_types::BigEndianHash,
     providers::Middleware,
     types::{Address, Block, BlockId, Bytes, Transaction, H256, U256},
-    utils::keccak256,
 };
 use futures::{
     channel::mpsc::Receiver,
     stream::Stream,
     task::{Context, Poll},
-    Future, FutureExt,
+    Future, FutureExt, TryFutureExt,
 };
-use revm::primitives::{bytes, AccountInfo, Bytecode, KECCAK_EMPTY, U256 as rU256};
+use revm::primitives::{AccountInfo, B160, KECCAK_EMPTY, U256 as rU256};
 use std::{
     collections::{hash_map::Entry, HashMap, VecDeque},
     pin::Pin,
@@ -43,12 +44,20 @@ type FullBlockFuture<Err> = Pin<
 type TransactionFuture<Err> = Pin<
     Box<dyn Future<Output = (TransactionSender, Result<Option<Transaction>, Err>, H256)> + Send>,
 >;
+type PrefetchFuture =
+    Pin<Box<dyn Future<Output = (PrefetchSender, DatabaseResult<PrefetchedState>)> + Send>>;
+type VerifiedAccountFuture =
+    Pin<Box<dyn Future<Output = (DatabaseResult<AccountInfo>, Address)> + Send>>;
+type VerifiedStorageFuture =
+    Pin<Box<dyn Future<Output = (DatabaseResult<U256>, Address, U256)> + Send>>;
 
 type AccountInfoSender = OneshotSender<DatabaseResult<AccountInfo>>;
 type StorageSender = OneshotSender<DatabaseResult<U256>>;
 type BlockHashSender = OneshotSender<DatabaseResult<H256>>;
 type FullBlockSender = OneshotSender<DatabaseResult<Block<Transaction>>>;
 type TransactionSender = OneshotSender<DatabaseResult<Transaction>>;
+type PrefetchSender = OneshotSender<DatabaseResult<usize>>;
+type PinnedBlockSender = OneshotSender<()>;
 
 /// Request variants that are executed by the provider
 enum ProviderRequest<Err> {
@@ -57,6 +66,9 @@ enum ProviderRequest<Err> {
     BlockHash(BlockHashFuture<Err>),
     FullBlock(FullBlockFuture<Err>),
     Transaction(TransactionFuture<Err>),
+    Prefetch(PrefetchFuture),
+    VerifiedAccount(VerifiedAccountFuture),
+    VerifiedStorage(VerifiedStorageFuture),
 }
 
 /// The Request type the Backend listens for
@@ -72,8 +84,16 @@ pub enum BackendRequest {
     FullBlock(BlockId, FullBlockSender),
     /// Fetch a transaction
     Transaction(H256, TransactionSender),
-    /// Sets the pinned block to fetch data from
-    SetPinnedBlock(BlockId),
+    /// Sets the pinned block to fetch data from, answered once the fetches in flight at the
+    /// previous block have been written to the cache
+    SetPinnedBlock(BlockId, PinnedBlockSender),
+    /// Load the accounts and slots of the list missing from the cache, answers how many were
+    Prefetch(PrefetchList, PrefetchSender),
+    /// Sets the client prefetches are sent as a JSON-RPC batch with
+    SetBatchClient(BatchClient),
+    /// Fetch accounts and storage with `eth_getProof` and check them against the pinned block's
+    /// state root
+    SetVerifyProofs(bool),
 }
 
 /// Handles an internal provider and listens for requests.
@@ -100,6 +120,14 @@ pub struct BackendHandler<M: Middleware> {
     /// The block to fetch data from.
     // This is an `Option` so that we can have less code churn in the functions below
     block_id: Option<BlockId>,
+    /// Block to pin once the account and storage fetches in flight are done, requests received
+    /// after it wait in `queued_requests` until then
+    pending_pin: Option<(BlockId, PinnedBlockSender)>,
+    /// Sends prefetches as a single batch, they're sent through `provider` otherwise
+    batch_client: Option<BatchClient>,
+    /// Only insert state proven against the state root of the pinned block
+    verify_proofs: bool,
+    state_root: StateRootCache,
 }
 
 impl<M> BackendHandler<M>
@@ -122,6 +150,10 @@ where
             queued_requests: Default::default(),
             incoming: rx,
             block_id,
+            pending_pin: None,
+            batch_client: None,
+            verify_proofs: false,
+            state_root: Default::default(),
         }
     }
 
@@ -176,12 +208,83 @@ where
                     self.request_account_storage(addr, idx, sender);
                 }
             }
-            BackendRequest::SetPinnedBlock(block_id) => {
-                self.block_id = Some(block_id);
+            BackendRequest::SetPinnedBlock(block_id, sender) => {
+                // values fetched at the previous block must land before the caller updates the
+                // cache to the new one, so they don't overwrite it
+                self.pending_pin = Some((block_id, sender));
+                self.try_pin_block();
+            }
+            BackendRequest::Prefetch(list, sender) => {
+                self.request_prefetch(list, sender);
+            }
+            BackendRequest::SetBatchClient(client) => {
+                self.batch_client = Some(client);
+            }
+            BackendRequest::SetVerifyProofs(verify) => {
+                // drop what was fetched unverified since the request was sent
+                if verify && !self.verify_proofs {
+                    self.db.db().clear();
+                }
+                self.verify_proofs = verify;
             }
         }
     }
 
+    /// Pins the pending block once no fetch writing to the cache is in flight
+    fn try_pin_block(&mut self) {
+        let fetching = self.pending_requests.iter().any(|req| {
+            matches!(
+                req,
+                ProviderRequest::Account(_)
+                    | ProviderRequest::Storage(_)
+                    | ProviderRequest::Prefetch(_)
+                    | ProviderRequest::VerifiedAccount(_)
+                    | ProviderRequest::VerifiedStorage(_)
+            )
+        });
+        if fetching {
+            return;
+        }
+        if let Some((block_id, sender)) = self.pending_pin.take() {
+            trace!(target: "backendhandler", "pinned block {:?}", block_id);
+            self.block_id = Some(block_id);
+            let _ = sender.send(());
+        }
+    }
+
+    /// process a request to load many accounts and slots at once
+    fn request_prefetch(&mut self, mut list: PrefetchList, sender: PrefetchSender) {
+        list.retain_missing(&self.db);
+        if list.is_empty() {
+            let _ = sender.send(Ok(0));
+            return;
+        }
+
+        trace!(target: "backendhandler", "preparing prefetch of {} entries", list.len());
+        let provider = self.provider.clone();
+        let batch_client = self.batch_client.clone();
+        let block_id = self.block_id;
+        let verify_proofs = self.verify_proofs;
+        let state_root = self.state_root.clone();
+        let fut = Box::pin(async move {
+            if verify_proofs {
+                let state = fetch_verified(&provider, &list, block_id, &state_root).await;
+                return (sender, state);
+            }
+            let state = match batch_client {
+                Some(client) => client.fetch(&list, block_id).await,
+                None => fetch_concurrently(provider, &list, block_id)
+                    .await
+                    .map_err(eyre::Error::new),
+            };
+            (
+                sender,
+                state.map_err(|err| DatabaseError::Prefetch(Arc::new(err))),
+            )
+        });
+        self.pending_requests.push(ProviderRequest::Prefetch(fut));
+    }
+
     /// process a request for account's storage
     fn request_account_storage(&mut self, address: Address, idx: U256, listener: StorageSender) {
         match self.storage_requests.entry((address, idx)) {
@@ -193,6 +296,26 @@ where
                 entry.insert(vec![listener]);
                 let provider = self.provider.clone();
                 let block_id = self.block_id;
+                if self.verify_proofs {
+                    let state_root = self.state_root.clone();
+                    let fut = Box::pin(async move {
+                        let fetch_err = |err| DatabaseError::GetStorage(address, idx, err);
+                        let account = get_verified_account(
+                            &provider,
+                            address,
+                            &[idx],
+                            block_id,
+                            &state_root,
+                            fetch_err,
+                        )
+                        .await;
+                        let value = account.map(|account| account.storage[0].1);
+                        (value, address, idx)
+                    });
+                    self.pending_requests
+                        .push(ProviderRequest::VerifiedStorage(fut));
+                    return;
+                }
                 let fut = Box::pin(async move {
                     // serialize & deserialize back to U256
                     let idx_req = H256::from_uint(&idx);
@@ -210,6 +333,21 @@ where
         trace!(target: "backendhandler", "preparing account request, address={:?}", address);
         let provider = self.provider.clone();
         let block_id = self.block_id;
+        if self.verify_proofs {
+            let state_root = self.state_root.clone();
+            let fut = Box::pin(async move {
+                let fetch_err = |err| DatabaseError::GetAccount(address, err);
+                let account =
+                    get_verified_account(&provider, address, &[], block_id, &state_root, fetch_err);
+                let code = provider
+                    .get_code(address, block_id)
+                    .map_err(|err| fetch_err(Arc::new(eyre::Error::new(err))));
+                let resp = futures::try_join!(account, code)
+                    .and_then(|(account, code)| account.with_code(code));
+                (resp, address)
+            });
+            return ProviderRequest::VerifiedAccount(fut);
+        }
         let fut = Box::pin(async move {
             let balance = provider.get_balance(address, block_id);
             let nonce = provider.get_transaction_count(address, block_id);
@@ -301,9 +439,12 @@ where
     fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
         let pin = self.get_mut();
         loop {
-            // Drain queued requests first.
-            while let Some(req) = pin.queued_requests.pop_front() {
-                pin.on_request(req)
+            // Drain queued requests first, unless they wait for a block to be pinned
+            while pin.pending_pin.is_none() {
+                match pin.queued_requests.pop_front() {
+                    Some(req) => pin.on_request(req),
+                    None => break,
+                }
             }
 
             // receive new requests to delegate to the underlying provider
@@ -343,21 +484,9 @@ where
                                 }
                             };
 
-                            // convert it to revm-style types
-                            let (code, code_hash) = if !code.0.is_empty() {
-                                (Some(code.0.clone()), keccak256(&code).into())
-                            } else {
-                                (Some(bytes::Bytes::default()), KECCAK_EMPTY)
-                            };
-
                             // update the cache
-                            let acc = AccountInfo {
-                                nonce: nonce.as_u64(),
-                                balance: balance.into(),
-                                code: code.map(|bytes| Bytecode::new_raw(bytes).to_checked()),
-                                code_hash,
-                            };
-                            pin.db.accounts().write().insert(addr.into(), acc.clone());
+                            let acc = account_info(balance, nonce, code);
+                            pin.db.db().do_insert_account(addr.into(), acc.clone());
 
                             // notify all listeners
                             if let Some(listeners) = pin.account_requests.remove(&addr) {
@@ -392,11 +521,8 @@ where
 
                             // update the cache
                             pin.db
-                                .storage()
-                                .write()
-                                .entry(addr.into())
-                                .or_default()
-                                .insert(idx.into(), value.into());
+                                .db()
+                                .do_insert_storage(addr.into(), idx.into(), value.into());
 
                             // notify all listeners
                             if let Some(listeners) = pin.storage_requests.remove(&(addr, idx)) {
@@ -428,9 +554,8 @@ where
 
                             // update the cache
                             pin.db
-                                .block_hashes()
-                                .write()
-                                .insert(rU256::from(number), value.into());
+                                .db()
+                                .do_insert_block_hash(rU256::from(number), value.into());
 
                             // notify all listeners
                             if let Some(listeners) = pin.block_requests.remove(&number) {
@@ -455,6 +580,65 @@ where
                             continue;
                         }
                     }
+                    ProviderRequest::Prefetch(fut) => {
+                        if let Poll::Ready((sender, state)) = fut.poll_unpin(cx) {
+                            let msg = state.map(|state| {
+                                let fetched = state.accounts.len() + state.storage.len();
+                                // entries cached since the request was made are kept
+                                let db = pin.db.db();
+                                for (addr, acc) in state.accounts {
+                                    let addr = addr.into();
+                                    if !db.accounts.read().contains_key(&addr) {
+                                        db.do_insert_account(addr, acc);
+                                    }
+                                }
+                                for (addr, idx, value) in state.storage {
+                                    let (addr, idx): (B160, rU256) = (addr.into(), idx.into());
+                                    let cached = db
+                                        .storage
+                                        .read()
+                                        .get(&addr)
+                                        .map_or(false, |slots| slots.contains_key(&idx));
+                                    if !cached {
+                                        db.do_insert_storage(addr, idx, value.into());
+                                    }
+                                }
+                                fetched
+                            });
+                            let _ = sender.send(msg);
+                            continue;
+                        }
+                    }
+                    ProviderRequest::VerifiedAccount(fut) => {
+                        if let Poll::Ready((resp, addr)) = fut.poll_unpin(cx) {
+                            if let Ok(acc) = &resp {
+                                pin.db.db().do_insert_account(addr.into(), acc.clone());
+                            }
+                            if let Some(listeners) = pin.account_requests.remove(&addr) {
+                                listeners.into_iter().for_each(|l| {
+                                    let _ = l.send(resp.clone());
+                                })
+                            }
+                            continue;
+                        }
+                    }
+                    ProviderRequest::VerifiedStorage(fut) => {
+                        if let Poll::Ready((resp, addr, idx)) = fut.poll_unpin(cx) {
+                            if let Ok(value) = resp {
+                                pin.db.db().do_insert_storage(
+                                    addr.into(),
+                                    idx.into(),
+                                    value.into(),
+                                );
+                            }
+                            if let Some(listeners) = pin.storage_requests.remove(&(addr, idx)) {
+                                listeners.into_iter().for_each(|l| {
+                                    let _ = l.send(resp.clone());
+                                })
+                            }
+                            continue;
+                        }
+                    }
                     ProviderRequest::Transaction(fut) => {
                         if let Poll::Ready((sender, tx, tx_hash)) = fut.poll_unpin(cx) {
                             let msg = match tx {
@@ -474,9 +658,13 @@ where
                 pin.pending_requests.push(request);
             }
 
-            // If no new requests have been queued, break to
+            if pin.pending_pin.is_some() {
+                pin.try_pin_block();
+            }
+
+            // If no new requests have been queued or they wait for in flight fetches, break to
             // be polled again later.
-            if pin.queued_requests.is_empty() {
+            if pin.queued_requests.is_empty() || pin.pending_pin.is_some() {
                 return Poll::Pending;
             }
         }
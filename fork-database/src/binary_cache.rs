@@ -0,0 +1,478 @@
+use super::{
+    blockchain_db::{BlockCacheDB, BlockchainDbMeta, DirtyKeys, MemDb},
+    snapshot::StateSnapshot,
+};
+use parking_lot::{Mutex, RwLock};
+use revm::primitives::{AccountInfo, B160, B256, U256};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{BufReader, BufWriter, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tracing::{trace, warn};
+
+/// First bytes of a binary cache file
+const MAGIC: &[u8; 4] = b"QBC1";
+/// The log is rewritten once it holds this many records per live entry
+const COMPACTION_RATIO: usize = 2;
+/// Logs smaller than this are never rewritten
+const MIN_COMPACTION_RECORDS: usize = 4096;
+
+/// An entry of the log, later records override earlier ones
+#[derive(Debug, Serialize, Deserialize)]
+enum CacheRecord {
+    /// JSON of the [BlockchainDbMeta], to go through its backwards compatible deserialization
+    Meta(String),
+    Account(B160, AccountInfo),
+    RemoveAccount(B160),
+    Storage(B160, U256, U256),
+    RemoveStorage(B160, U256),
+    BlockHash(U256, B256),
+    RemoveBlockHash(U256),
+    /// Wipes the account's storage, before the storage records that follow
+    ClearStorage(B160),
+}
+
+/// What the file holds as of the last flush
+#[derive(Debug, Default)]
+struct Flushed {
+    meta: String,
+    records: usize,
+    /// The file ends with a torn record and can't be appended to
+    torn: bool,
+}
+
+/// A [BlockCacheDB] that stores the cached content in an append-only log of binary records
+///
+/// Flushes only append the entries that changed since the previous flush, and the log is
+/// rewritten once overridden records outnumber the live entries. A record torn by an
+/// interrupted flush is dropped on load.
+#[derive(Debug)]
+pub struct BinaryBlockCacheDB {
+    /// Where this cache file is stored.
+    ///
+    /// If this is a [None] then caching is disabled
+    cache_path: Option<PathBuf>,
+    meta: Arc<RwLock<BlockchainDbMeta>>,
+    data: Arc<MemDb>,
+    flushed: Mutex<Flushed>,
+}
+
+impl BinaryBlockCacheDB {
+    /// Creates a new instance.
+    pub(crate) fn new(meta: Arc<RwLock<BlockchainDbMeta>>, cache_path: Option<PathBuf>) -> Self {
+        Self {
+            cache_path,
+            meta,
+            data: Arc::new(MemDb::with_dirty_tracking()),
+            flushed: Mutex::new(Default::default()),
+        }
+    }
+
+    /// Loads the contents of the log file and returns the read object
+    ///
+    /// # Errors
+    /// This will fail if
+    ///   - the `path` does not exist
+    ///   - the file isn't a binary cache or has no metadata
+    pub fn load(path: impl Into<PathBuf>) -> eyre::Result<Self> {
+        let path = path.into();
+        trace!(target : "cache", ?path, "reading binary cache");
+        let mut reader = BufReader::new(fs::File::open(&path)?);
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        eyre::ensure!(&magic == MAGIC, "{:?} is not a binary block cache", path);
+
+        let mut flushed = Flushed::default();
+        let mut state = StateSnapshot::default();
+        let mut meta = None;
+        loop {
+            let record = match read_record(&mut reader) {
+                Ok(Some(record)) => record,
+                Ok(None) => break,
+                Err(err) => {
+                    warn!(target : "cache", ?err, ?path, "Dropping torn record of binary cache");
+                    flushed.torn = true;
+                    break;
+                }
+            };
+            flushed.records += 1;
+
+            match record {
+                CacheRecord::Meta(json) => meta = Some(json),
+                CacheRecord::Account(address, info) => {
+                    state.accounts.insert(address, info);
+                }
+                CacheRecord::RemoveAccount(address) => {
+                    state.accounts.remove(&address);
+                }
+                CacheRecord::Storage(address, index, value) => {
+                    state
+                        .storage
+                        .entry(address)
+                        .or_default()
+                        .insert(index, value);
+                }
+                CacheRecord::RemoveStorage(address, index) => {
+                    if let Some(slots) = state.storage.get_mut(&address) {
+                        slots.remove(&index);
+                        if slots.is_empty() {
+                            state.storage.remove(&address);
+                        }
+                    }
+                }
+                CacheRecord::BlockHash(number, hash) => {
+                    state.block_hashes.insert(number, hash);
+                }
+                CacheRecord::RemoveBlockHash(number) => {
+                    state.block_hashes.remove(&number);
+                }
+                CacheRecord::ClearStorage(address) => {
+                    state.storage.remove(&address);
+                }
+            }
+        }
+
+        let meta_json = meta.ok_or_else(|| eyre::eyre!("{:?} has no metadata", path))?;
+        let meta: BlockchainDbMeta = serde_json::from_str(&meta_json)?;
+        flushed.meta = meta_json;
+
+        let data = MemDb {
+            accounts: RwLock::new(state.accounts),
+            storage: RwLock::new(state.storage),
+            block_hashes: RwLock::new(state.block_hashes),
+            dirty: Some(Default::default()),
+        };
+
+        Ok(Self {
+            cache_path: Some(path),
+            meta: Arc::new(RwLock::new(meta)),
+            data: Arc::new(data),
+            flushed: Mutex::new(flushed),
+        })
+    }
+
+    /// Returns the [MemDb] it holds access to
+    pub fn db(&self) -> &Arc<MemDb> {
+        &self.data
+    }
+
+    /// Metadata stored alongside the data
+    pub fn meta(&self) -> &Arc<RwLock<BlockchainDbMeta>> {
+        &self.meta
+    }
+
+    /// Returns `true` if this is a transient cache and nothing will be flushed
+    pub fn is_transient(&self) -> bool {
+        self.cache_path.is_none()
+    }
+
+    /// Appends the entries written since the last flush, or rewrites the log if it's mostly
+    /// made of overridden records
+    pub fn flush(&self) {
+        let path = match &self.cache_path {
+            Some(path) => path,
+            None => return,
+        };
+        let meta = match serde_json::to_string(&*self.meta.read()) {
+            Ok(meta) => meta,
+            Err(err) => {
+                warn!(target: "cache", "Failed to serialize binary cache metadata: {}", err);
+                return;
+            }
+        };
+
+        let mut flushed = self.flushed.lock();
+        let dirty = self.data.take_dirty();
+        if dirty.is_empty() && flushed.meta == meta && flushed.records > 0 && !flushed.torn {
+            return;
+        }
+        let changes = dirty_records(&self.data, &dirty);
+        let live = 1
+            + self.data.accounts.read().len()
+            + self.data.block_hashes.read().len()
+            + self
+                .data
+                .storage
+                .read()
+                .values()
+                .map(|slots| slots.len())
+                .sum::<usize>();
+        let rewrite = dirty.all
+            || flushed.torn
+            || flushed.records == 0
+            || !path.exists()
+            || flushed.records + changes.len() + 1
+                > (COMPACTION_RATIO * live).max(MIN_COMPACTION_RECORDS);
+
+        let result = if rewrite {
+            trace!(target: "cache", "rewriting binary cache path={:?}", path);
+            let records = all_records(&meta, &self.data);
+            write_log(path, &records).map(|_| records.len())
+        } else {
+            let mut records = changes;
+            if flushed.meta != meta {
+                records.push(CacheRecord::Meta(meta.clone()));
+            }
+            trace!(target: "cache", "appending {} records to binary cache path={:?}", records.len(), path);
+            append_log(path, &records).map(|_| flushed.records + records.len())
+        };
+
+        match result {
+            Ok(records) => {
+                *flushed = Flushed {
+                    meta,
+                    records,
+                    torn: false,
+                };
+            }
+            Err(err) => {
+                warn!(target: "cache", "Failed to write binary cache: {}", err);
+                // the written keys are no longer tracked, the next flush rewrites the log
+                flushed.torn = true;
+            }
+        }
+    }
+}
+
+impl BlockCacheDB for BinaryBlockCacheDB {
+    fn db(&self) -> &Arc<MemDb> {
+        BinaryBlockCacheDB::db(self)
+    }
+
+    fn meta(&self) -> &Arc<RwLock<BlockchainDbMeta>> {
+        BinaryBlockCacheDB::meta(self)
+    }
+
+    fn is_transient(&self) -> bool {
+        BinaryBlockCacheDB::is_transient(self)
+    }
+
+    fn flush(&self) {
+        BinaryBlockCacheDB::flush(self)
+    }
+}
+
+/// Records writing the current value of each dirty key, a key no longer in `db` is removed
+fn dirty_records(db: &MemDb, dirty: &DirtyKeys) -> Vec<CacheRecord> {
+    let mut records = vec![];
+
+    let accounts = db.accounts.read();
+    records.extend(
+        dirty
+            .accounts
+            .iter()
+            .map(|address| match accounts.get(address) {
+                Some(info) => CacheRecord::Account(*address, info.clone()),
+                None => CacheRecord::RemoveAccount(*address),
+            }),
+    );
+    drop(accounts);
+
+    let storage = db.storage.read();
+    records.extend(
+        dirty
+            .cleared_storage
+            .iter()
+            .map(|address| CacheRecord::ClearStorage(*address)),
+    );
+    records.extend(dirty.storage.iter().filter_map(|(address, index)| {
+        match storage.get(address).and_then(|slots| slots.get(index)) {
+            Some(value) => Some(CacheRecord::Storage(*address, *index, *value)),
+            // already wiped by the clear record
+            None if dirty.cleared_storage.contains(address) => None,
+            None => Some(CacheRecord::RemoveStorage(*address, *index)),
+        }
+    }));
+    drop(storage);
+
+    let block_hashes = db.block_hashes.read();
+    records.extend(
+        dirty
+            .block_hashes
+            .iter()
+            .map(|number| match block_hashes.get(number) {
+                Some(hash) => CacheRecord::BlockHash(*number, *hash),
+                None => CacheRecord::RemoveBlockHash(*number),
+            }),
+    );
+
+    records
+}
+
+/// Records of a compacted log holding the content of `db`
+fn all_records(meta: &str, db: &MemDb) -> Vec<CacheRecord> {
+    let mut records = vec![CacheRecord::Meta(meta.to_string())];
+    records.extend(
+        db.accounts
+            .read()
+            .iter()
+            .map(|(address, info)| CacheRecord::Account(*address, info.clone())),
+    );
+    records.extend(db.storage.read().iter().flat_map(|(address, slots)| {
+        slots
+            .iter()
+            .map(|(index, value)| CacheRecord::Storage(*address, *index, *value))
+    }));
+    records.extend(
+        db.block_hashes
+            .read()
+            .iter()
+            .map(|(number, hash)| CacheRecord::BlockHash(*number, *hash)),
+    );
+    records
+}
+
+/// Write a new log, replacing the file only once it's complete
+fn write_log(path: &Path, records: &[CacheRecord]) -> eyre::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("bin.tmp");
+    {
+        let mut writer = BufWriter::new(fs::File::create(&tmp)?);
+        writer.write_all(MAGIC)?;
+        for record in records {
+            write_record(&mut writer, record)?;
+        }
+        writer.flush()?;
+    }
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+fn append_log(path: &Path, records: &[CacheRecord]) -> eyre::Result<()> {
+    if records.is_empty() {
+        return Ok(());
+    }
+    let file = fs::OpenOptions::new().append(true).open(path)?;
+    let mut writer = BufWriter::new(file);
+    for record in records {
+        write_record(&mut writer, record)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Records are prefixed with their length as a little endian `u32`
+fn write_record(writer: &mut impl Write, record: &CacheRecord) -> eyre::Result<()> {
+    let bytes = bincode::serialize(record)?;
+    writer.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    writer.write_all(&bytes)?;
+    Ok(())
+}
+
+/// Reads the next record, `None` at the end of the log
+fn read_record(reader: &mut impl Read) -> eyre::Result<Option<CacheRecord>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let mut bytes = vec![0u8; u32::from_le_bytes(len) as usize];
+    reader.read_exact(&mut bytes)?;
+    Ok(Some(bincode::deserialize(&bytes)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain_db::BlockchainDb;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn test_incremental_flush_and_meta_check() {
+        let path = std::env::temp_dir().join(format!("qilin_cache_{}.bin", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let mut meta = BlockchainDbMeta {
+            cfg_env: Default::default(),
+            block_env: Default::default(),
+            hosts: BTreeSet::from(["localhost".to_string()]),
+        };
+        meta.block_env.number = U256::from(100u64);
+
+        let (a, b) = (B160::random(), B160::random());
+        let db = BlockchainDb::new(meta.clone(), Some(path.clone()));
+        db.db().do_insert_account(a, AccountInfo::default());
+        db.db().do_insert_account(b, AccountInfo::default());
+        db.db()
+            .do_insert_storage(a, U256::from(1u64), U256::from(2u64));
+        db.cache().flush();
+        let full_len = fs::metadata(&path).unwrap().len();
+
+        // only the changed slot and the removed account are appended
+        db.db()
+            .do_insert_storage(a, U256::from(1u64), U256::from(3u64));
+        db.db().do_remove_account(b);
+        db.cache().flush();
+        let appended = fs::metadata(&path).unwrap().len() - full_len;
+        assert!(appended > 0 && appended < full_len);
+
+        let reloaded = BlockchainDb::new(meta.clone(), Some(path.clone()));
+        assert!(reloaded.accounts().read().contains_key(&a));
+        assert!(!reloaded.accounts().read().contains_key(&b));
+        assert_eq!(
+            reloaded.storage().read()[&a][&U256::from(1u64)],
+            U256::from(3u64)
+        );
+
+        // a cache of another block is ignored
+        meta.block_env.number = U256::from(101u64);
+        let other = BlockchainDb::new(meta, Some(path.clone()));
+        assert!(other.accounts().read().is_empty());
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_replace_only_appends() {
+        let path =
+            std::env::temp_dir().join(format!("qilin_cache_replace_{}.bin", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let meta = BlockchainDbMeta {
+            cfg_env: Default::default(),
+            block_env: Default::default(),
+            hosts: BTreeSet::from(["localhost".to_string()]),
+        };
+        let a = B160::random();
+        let db = BlockchainDb::new(meta.clone(), Some(path.clone()));
+        db.db().do_insert_account(a, AccountInfo::default());
+        db.db()
+            .do_insert_storage(a, U256::from(1u64), U256::from(2u64));
+        db.cache().flush();
+
+        let snapshot = StateSnapshot {
+            accounts: db.accounts().read().clone(),
+            storage: db.storage().read().clone(),
+            block_hashes: db.block_hashes().read().clone(),
+        };
+
+        // a slot fetched during a simulation, dropped again when the snapshot is reverted
+        db.db()
+            .do_insert_storage(a, U256::from(5u64), U256::from(6u64));
+        db.cache().flush();
+        let before = fs::read(&path).unwrap();
+
+        db.db().replace(snapshot.clone());
+        db.cache().flush();
+        let after = fs::read(&path).unwrap();
+        assert!(after.len() > before.len());
+        assert_eq!(&after[..before.len()], &before[..]);
+
+        // reverting to an identical state writes nothing
+        db.db().replace(snapshot);
+        db.cache().flush();
+        assert_eq!(fs::read(&path).unwrap(), after);
+
+        let reloaded = BlockchainDb::new(meta, Some(path.clone()));
+        assert!(!reloaded.storage().read()[&a].contains_key(&U256::from(5u64)));
+
+        let _ = fs::remove_file(&path);
+    }
+}
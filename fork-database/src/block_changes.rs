@@ -0,0 +1,287 @@
+use crate::utils::{h160_to_b160, u256_to_ru256};
+use ethers::types::{AccountDiff, Diff, StateDiff, Withdrawal};
+use hashbrown::{HashMap as Map, HashSet as Set};
+use revm::{
+    db::{AccountState, CacheDB, DatabaseRef},
+    primitives::{Bytecode, B160, U256},
+};
+
+/// Changes a block made to an account
+///
+/// Fields left to `None` weren't changed by the block.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccountChange {
+    pub balance: Option<U256>,
+    pub nonce: Option<u64>,
+    pub code: Option<Bytecode>,
+    /// The account self destructed, wiping its storage before `storage` was written
+    pub storage_cleared: bool,
+    pub storage: Map<U256, U256>,
+}
+
+impl AccountChange {
+    /// Apply the changes of a later tx on top of these
+    pub fn merge(&mut self, later: AccountChange) {
+        if later.storage_cleared {
+            self.storage_cleared = true;
+            self.storage.clear();
+        }
+        self.balance = later.balance.or(self.balance);
+        self.nonce = later.nonce.or(self.nonce);
+        self.code = later.code.or(self.code.take());
+        self.storage.extend(later.storage);
+    }
+
+    fn destroyed() -> Self {
+        Self {
+            balance: Some(U256::ZERO),
+            nonce: Some(0),
+            code: Some(Bytecode::new()),
+            storage_cleared: true,
+            storage: Map::new(),
+        }
+    }
+}
+
+/// Account and storage changes of a block, applied to the cached state when a fork advances
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockChanges {
+    pub accounts: Map<B160, AccountChange>,
+    /// Balance credited to each account by the block's withdrawals, in wei
+    pub withdrawals: Map<B160, U256>,
+}
+
+impl BlockChanges {
+    /// Changes of a block from the `stateDiff` of each of its txs, in block order, e.g. from
+    /// `trace_replayBlockTransactions`
+    pub fn from_state_diffs<'a>(diffs: impl IntoIterator<Item = &'a StateDiff>) -> Self {
+        let mut changes = Self::default();
+        for diff in diffs {
+            for (address, account_diff) in &diff.0 {
+                changes.record(h160_to_b160(*address), account_change(account_diff));
+            }
+        }
+        changes
+    }
+
+    /// Changes committed to a [CacheDB] the block's txs were executed on
+    ///
+    /// Accounts only read by the txs are left out. A [CacheDB] marks both the accounts that self
+    /// destructed and the ones read but missing from its database as `NotExisting`, only those in
+    /// `touched`, the accounts the txs changed, are destroyed.
+    ///
+    /// Arguments:
+    /// * `db`: the database the block's txs were committed to
+    /// * `touched`: the accounts touched by the committed txs
+    pub fn from_cache_db<ExtDB: DatabaseRef>(db: &CacheDB<ExtDB>, touched: &Set<B160>) -> Self {
+        let mut changes = Self::default();
+        for (address, account) in &db.accounts {
+            let change = match account.account_state {
+                AccountState::None => continue,
+                AccountState::NotExisting if !touched.contains(address) => continue,
+                AccountState::NotExisting => AccountChange::destroyed(),
+                AccountState::Touched | AccountState::StorageCleared => AccountChange {
+                    balance: Some(account.info.balance),
+                    nonce: Some(account.info.nonce),
+                    code: account.info.code.clone(),
+                    storage_cleared: matches!(account.account_state, AccountState::StorageCleared),
+                    storage: account.storage.clone(),
+                },
+            };
+            changes.record(*address, change);
+        }
+        changes
+    }
+
+    /// Credit the block's withdrawals, processed after its txs
+    pub fn with_withdrawals(mut self, withdrawals: &[Withdrawal]) -> Self {
+        for withdrawal in withdrawals {
+            // withdrawal amounts are in gwei
+            let amount = u256_to_ru256(withdrawal.amount) * U256::from(1_000_000_000u64);
+            *self
+                .withdrawals
+                .entry(h160_to_b160(withdrawal.address))
+                .or_default() += amount;
+        }
+        self
+    }
+
+    fn record(&mut self, address: B160, change: AccountChange) {
+        match self.accounts.get_mut(&address) {
+            Some(existing) => existing.merge(change),
+            None => {
+                self.accounts.insert(address, change);
+            }
+        }
+    }
+}
+
+fn account_change(diff: &AccountDiff) -> AccountChange {
+    if matches!(diff.balance, Diff::Died(_)) {
+        return AccountChange::destroyed();
+    }
+
+    AccountChange {
+        balance: diff_to(&diff.balance).map(u256_to_ru256),
+        nonce: diff_to(&diff.nonce).map(|nonce| nonce.as_u64()),
+        code: diff_to(&diff.code).map(|code| Bytecode::new_raw(code.0).to_checked()),
+        storage_cleared: false,
+        storage: diff
+            .storage
+            .iter()
+            .filter_map(|(slot, value)| {
+                let value = match value {
+                    Diff::Same => return None,
+                    Diff::Died(_) => U256::ZERO,
+                    Diff::Born(to) | Diff::Changed(ethers::types::ChangedType { to, .. }) => {
+                        U256::from_be_bytes(to.0)
+                    }
+                };
+                Some((U256::from_be_bytes(slot.0), value))
+            })
+            .collect(),
+    }
+}
+
+/// Value after the block, `None` if unchanged
+fn diff_to<T: Clone + Default>(diff: &Diff<T>) -> Option<T> {
+    match diff {
+        Diff::Same => None,
+        Diff::Born(to) => Some(to.clone()),
+        Diff::Died(_) => Some(T::default()),
+        Diff::Changed(changed) => Some(changed.to.clone()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain_db::MemDb;
+    use ethers::types::{Address, ChangedType, H256, U256 as eU256};
+    use revm::{
+        db::{DbAccount, EmptyDB},
+        primitives::AccountInfo,
+    };
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn test_apply_block_changes() {
+        let (cached, uncached) = (Address::random(), Address::random());
+        let slot = |n: u64| H256::from_low_u64_be(n);
+        let changed = |from: u64, to: u64| {
+            Diff::Changed(ChangedType {
+                from: slot(from),
+                to: slot(to),
+            })
+        };
+
+        let tx_diff = |balance: u64, storage: BTreeMap<H256, Diff<H256>>| {
+            StateDiff(BTreeMap::from([
+                (
+                    cached,
+                    AccountDiff {
+                        balance: Diff::Changed(ChangedType {
+                            from: eU256::zero(),
+                            to: eU256::from(balance),
+                        }),
+                        nonce: Diff::Same,
+                        code: Diff::Same,
+                        storage,
+                    },
+                ),
+                (
+                    uncached,
+                    AccountDiff {
+                        balance: Diff::Born(eU256::from(1)),
+                        nonce: Diff::Born(eU256::zero()),
+                        code: Diff::Born(Default::default()),
+                        storage: BTreeMap::new(),
+                    },
+                ),
+            ]))
+        };
+        let diffs = [
+            tx_diff(10, BTreeMap::from([(slot(1), changed(1, 2))])),
+            tx_diff(20, BTreeMap::from([(slot(2), changed(5, 6))])),
+        ];
+        let changes = BlockChanges::from_state_diffs(&diffs).with_withdrawals(&[Withdrawal {
+            index: 0.into(),
+            validator_index: 0.into(),
+            address: cached,
+            amount: 1.into(),
+        }]);
+
+        let db = MemDb::default();
+        let key = |n: u64| U256::from(n);
+        db.do_insert_account(
+            h160_to_b160(cached),
+            AccountInfo {
+                nonce: 3,
+                ..Default::default()
+            },
+        );
+        db.storage.write().insert(
+            h160_to_b160(cached),
+            Map::from([(key(1), key(1)), (key(3), key(7))]),
+        );
+
+        db.apply_block_changes(&changes);
+
+        let account = db.accounts.read()[&h160_to_b160(cached)].clone();
+        assert_eq!(account.balance, U256::from(20 + 1_000_000_000u64));
+        assert_eq!(account.nonce, 3);
+        let storage = db.storage.read()[&h160_to_b160(cached)].clone();
+        // cached slots are updated, untouched ones kept, uncached ones left to fetch
+        assert_eq!(storage, Map::from([(key(1), key(2)), (key(3), key(7))]));
+        assert!(!db.accounts.read().contains_key(&h160_to_b160(uncached)));
+    }
+
+    #[test]
+    fn test_from_cache_db() {
+        let account = |info: AccountInfo, account_state: AccountState| DbAccount {
+            info,
+            account_state,
+            storage: Map::new(),
+        };
+        let (read, missing, destroyed, changed) = (
+            B160::from_low_u64_be(1),
+            B160::from_low_u64_be(2),
+            B160::from_low_u64_be(3),
+            B160::from_low_u64_be(4),
+        );
+
+        let mut db = CacheDB::new(EmptyDB::default());
+        db.accounts
+            .insert(read, account(Default::default(), AccountState::None));
+        db.accounts.insert(
+            missing,
+            account(Default::default(), AccountState::NotExisting),
+        );
+        db.accounts.insert(
+            destroyed,
+            account(Default::default(), AccountState::NotExisting),
+        );
+        let mut changed_account = account(
+            AccountInfo {
+                balance: U256::from(5),
+                nonce: 2,
+                ..Default::default()
+            },
+            AccountState::Touched,
+        );
+        changed_account.storage.insert(U256::from(1), U256::from(9));
+        db.accounts.insert(changed, changed_account);
+
+        let changes = BlockChanges::from_cache_db(&db, &Set::from([destroyed, changed]));
+
+        // read only, or missing from the database without being touched
+        assert!(!changes.accounts.contains_key(&read));
+        assert!(!changes.accounts.contains_key(&missing));
+        assert_eq!(changes.accounts[&destroyed], AccountChange::destroyed());
+        let change = &changes.accounts[&changed];
+        assert_eq!(change.balance, Some(U256::from(5)));
+        assert_eq!(change.nonce, Some(2));
+        assert!(!change.storage_cleared);
+        assert_eq!(change.storage, Map::from([(U256::from(1), U256::from(9))]));
+    }
+}
@@ -1,14 +1,22 @@
 // ported from foundry's executor with some modifications
 // https://github.com/foundry-rs/foundry/blob/master/evm/src/executor/fork/cache.rs
-use super::snapshot::StateSnapshot;
-use hashbrown::HashMap as Map;
-use parking_lot::RwLock;
+use super::{
+    binary_cache::BinaryBlockCacheDB, block_changes::BlockChanges, snapshot::StateSnapshot,
+};
+use hashbrown::{HashMap as Map, HashSet as Set};
+use parking_lot::{Mutex, RwLock};
 use revm::{
     primitives::{Account, AccountInfo, B160, B256, KECCAK_EMPTY, U256},
     DatabaseCommit,
 };
 use serde::{ser::SerializeMap, Deserialize, Deserializer, Serialize, Serializer};
-use std::{collections::BTreeSet, fs, io::BufWriter, path::PathBuf, sync::Arc};
+use std::{
+    collections::BTreeSet,
+    fmt, fs,
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 use tracing::{trace, warn};
 
 use url::Url;
@@ -23,14 +31,15 @@ pub struct BlockchainDb {
     /// metadata of the current config
     meta: Arc<RwLock<BlockchainDbMeta>>,
     /// the cache that can be flushed
-    cache: Arc<JsonBlockCacheDB>,
+    cache: Arc<dyn BlockCacheDB>,
 }
 
 impl BlockchainDb {
     /// Creates a new instance of the [BlockchainDb]
     ///
     /// if a `cache_path` is provided it attempts to load a previously stored [JsonBlockCacheData]
-    /// and will try to use the cached entries it holds.
+    /// and will try to use the cached entries it holds. Paths ending in `.bin` are loaded as a
+    /// [BinaryBlockCacheDB] instead, see [CacheFormat::from_path].
     ///
     /// This will return a new and empty [MemDb] if
     ///   - `cache_path` is `None`
@@ -38,7 +47,8 @@ impl BlockchainDb {
     ///   - the file contains malformed data, or if it couldn't be read
     ///   - the provided `meta` differs from [BlockchainDbMeta] that's stored on disk
     pub fn new(meta: BlockchainDbMeta, cache_path: Option<PathBuf>) -> Self {
-        Self::new_db(meta, cache_path, false)
+        let format = CacheFormat::from_path(cache_path.as_deref());
+        Self::new_db(meta, cache_path, false, format)
     }
 
     /// Creates a new instance of the [BlockchainDb] and skips check when comparing meta
@@ -53,16 +63,31 @@ impl BlockchainDb {
     ///   - the file contains malformed data, or if it couldn't be read
     ///   - the provided `meta` differs from [BlockchainDbMeta] that's stored on disk
     pub fn new_skip_check(meta: BlockchainDbMeta, cache_path: Option<PathBuf>) -> Self {
-        Self::new_db(meta, cache_path, true)
+        let format = CacheFormat::from_path(cache_path.as_deref());
+        Self::new_db(meta, cache_path, true, format)
     }
 
-    fn new_db(meta: BlockchainDbMeta, cache_path: Option<PathBuf>, skip_check: bool) -> Self {
-        trace!(target : "forge::cache", cache=?cache_path, "initialising blockchain db");
+    /// Same as [BlockchainDb::new] with an explicit cache file format
+    pub fn new_with_format(
+        meta: BlockchainDbMeta,
+        cache_path: Option<PathBuf>,
+        format: CacheFormat,
+    ) -> Self {
+        Self::new_db(meta, cache_path, false, format)
+    }
+
+    fn new_db(
+        meta: BlockchainDbMeta,
+        cache_path: Option<PathBuf>,
+        skip_check: bool,
+        format: CacheFormat,
+    ) -> Self {
+        trace!(target : "forge::cache", cache=?cache_path, ?format, "initialising blockchain db");
         // read cache and check if metadata matches
         let cache = cache_path
             .as_ref()
             .and_then(|p| {
-                JsonBlockCacheDB::load(p).ok().filter(|cache| {
+                format.load(p).ok().filter(|cache| {
                     if skip_check {
                         return true;
                     }
@@ -76,12 +101,12 @@ impl BlockchainDb {
                     }
                 })
             })
-            .unwrap_or_else(|| JsonBlockCacheDB::new(Arc::new(RwLock::new(meta)), cache_path));
+            .unwrap_or_else(|| format.create(Arc::new(RwLock::new(meta)), cache_path));
 
         Self {
             db: Arc::clone(cache.db()),
             meta: Arc::clone(cache.meta()),
-            cache: Arc::new(cache),
+            cache,
         }
     }
 
@@ -106,7 +131,7 @@ impl BlockchainDb {
     }
 
     /// Returns the inner cache
-    pub fn cache(&self) -> &Arc<JsonBlockCacheDB> {
+    pub fn cache(&self) -> &Arc<dyn BlockCacheDB> {
         &self.cache
     }
 
@@ -230,8 +255,33 @@ impl<'de> Deserialize<'de> for BlockchainDbMeta {
     }
 }
 
+/// Entries of a [MemDb] written since they were last taken
+#[derive(Debug, Clone, Default)]
+pub struct DirtyKeys {
+    /// Everything may have changed, e.g. after a clear
+    pub all: bool,
+    pub accounts: Set<B160>,
+    /// Accounts whose storage was wiped, before the writes of `storage`
+    pub cleared_storage: Set<B160>,
+    pub storage: Set<(B160, U256)>,
+    pub block_hashes: Set<U256>,
+}
+
+impl DirtyKeys {
+    pub fn is_empty(&self) -> bool {
+        !self.all
+            && self.accounts.is_empty()
+            && self.cleared_storage.is_empty()
+            && self.storage.is_empty()
+            && self.block_hashes.is_empty()
+    }
+}
+
 /// In Memory cache containing all fetched accounts and storage slots
 /// and their values from RPC
+///
+/// A db created with [MemDb::with_dirty_tracking] records the keys written through its methods,
+/// writes made directly through the locks aren't tracked.
 #[derive(Debug, Default)]
 pub struct MemDb {
     /// Account related data
@@ -240,27 +290,125 @@ pub struct MemDb {
     pub storage: RwLock<Map<B160, StorageInfo>>,
     /// All retrieved block hashes
     pub block_hashes: RwLock<Map<U256, B256>>,
-    // TODO: add a block number hashmap
+    /// Keys written since the last [MemDb::take_dirty], if tracked
+    pub(crate) dirty: Option<Mutex<DirtyKeys>>,
 }
 
 impl MemDb {
+    /// An empty db tracking the keys written to it
+    pub fn with_dirty_tracking() -> Self {
+        Self {
+            dirty: Some(Default::default()),
+            ..Default::default()
+        }
+    }
+
+    /// Returns the keys written since the previous call, they're no longer tracked as dirty
+    pub fn take_dirty(&self) -> DirtyKeys {
+        self.dirty
+            .as_ref()
+            .map(|dirty| std::mem::take(&mut *dirty.lock()))
+            .unwrap_or_default()
+    }
+
+    fn track(&self, f: impl FnOnce(&mut DirtyKeys)) {
+        if let Some(dirty) = &self.dirty {
+            f(&mut dirty.lock());
+        }
+    }
+
     /// Clears all data stored in this db
     pub fn clear(&self) {
         self.accounts.write().clear();
         self.storage.write().clear();
         self.block_hashes.write().clear();
+        self.track(|dirty| dirty.all = true);
+    }
+
+    /// Replaces all data stored in this db by the snapshot's
+    ///
+    /// Only the entries that differ from the snapshot are tracked as dirty, which are usually the
+    /// few fetched since the snapshot was taken.
+    pub fn replace(&self, snapshot: StateSnapshot) {
+        let mut storage = self.storage.write();
+        let mut accounts = self.accounts.write();
+        let mut block_hashes = self.block_hashes.write();
+
+        if let Some(dirty) = &self.dirty {
+            let mut dirty = dirty.lock();
+            dirty
+                .accounts
+                .extend(changed_keys(&accounts, &snapshot.accounts));
+            for address in changed_keys(&storage, &snapshot.storage) {
+                let empty = StorageInfo::default();
+                let current = storage.get(&address).unwrap_or(&empty);
+                let restored = snapshot.storage.get(&address).unwrap_or(&empty);
+                dirty
+                    .storage
+                    .extend(changed_keys(current, restored).map(|index| (address, index)));
+            }
+            dirty
+                .block_hashes
+                .extend(changed_keys(&block_hashes, &snapshot.block_hashes));
+        }
+
+        *accounts = snapshot.accounts;
+        *storage = snapshot.storage;
+        *block_hashes = snapshot.block_hashes;
     }
 
     // Inserts the account, replacing it if it exists already
     pub fn do_insert_account(&self, address: B160, account: AccountInfo) {
         self.accounts.write().insert(address, account);
+        self.track(|dirty| {
+            dirty.accounts.insert(address);
+        });
+    }
+
+    /// Removes the account info, keeping its storage
+    pub fn do_remove_account(&self, address: B160) {
+        self.accounts.write().remove(&address);
+        self.track(|dirty| {
+            dirty.accounts.insert(address);
+        });
+    }
+
+    /// Inserts the storage slot, replacing it if it exists already
+    pub fn do_insert_storage(&self, address: B160, index: U256, value: U256) {
+        self.storage
+            .write()
+            .entry(address)
+            .or_default()
+            .insert(index, value);
+        self.track(|dirty| {
+            dirty.storage.insert((address, index));
+        });
+    }
+
+    /// Inserts the block hash, replacing it if it exists already
+    pub fn do_insert_block_hash(&self, number: U256, hash: B256) {
+        self.block_hashes.write().insert(number, hash);
+        self.track(|dirty| {
+            dirty.block_hashes.insert(number);
+        });
     }
 
     /// The implementation of [DatabaseCommit::commit()]
     pub fn do_commit(&self, changes: Map<B160, Account>) {
         let mut storage = self.storage.write();
         let mut accounts = self.accounts.write();
+        let mut dirty = self.dirty.as_ref().map(|dirty| dirty.lock());
         for (add, mut acc) in changes {
+            if let Some(dirty) = dirty.as_mut() {
+                dirty.accounts.insert(add);
+                if acc.is_empty() || acc.is_destroyed || acc.storage_cleared {
+                    dirty.cleared_storage.insert(add);
+                }
+                dirty
+                    .storage
+                    .extend(acc.storage.keys().map(|index| (add, *index)));
+            }
+
             if acc.is_empty() || acc.is_destroyed {
                 accounts.remove(&add);
                 storage.remove(&add);
@@ -296,6 +444,83 @@ impl MemDb {
             }
         }
     }
+
+    /// Apply the changes of a mined block to the cached state
+    ///
+    /// Only the accounts and slots already cached are updated, the others are fetched at the new
+    /// block when first read. The storage of self destructed accounts is dropped from the cache.
+    pub fn apply_block_changes(&self, changes: &BlockChanges) {
+        let mut storage = self.storage.write();
+        let mut accounts = self.accounts.write();
+        let mut dirty = self.dirty.as_ref().map(|dirty| dirty.lock());
+
+        for (address, change) in &changes.accounts {
+            if let Some(dirty) = dirty.as_mut() {
+                if accounts.contains_key(address) {
+                    dirty.accounts.insert(*address);
+                }
+                if change.storage_cleared {
+                    dirty.cleared_storage.insert(*address);
+                } else if let Some(cached) = storage.get(address) {
+                    dirty.storage.extend(
+                        change
+                            .storage
+                            .keys()
+                            .filter(|index| cached.contains_key(*index))
+                            .map(|index| (*address, *index)),
+                    );
+                }
+            }
+
+            if let Some(account) = accounts.get_mut(address) {
+                if let Some(balance) = change.balance {
+                    account.balance = balance;
+                }
+                if let Some(nonce) = change.nonce {
+                    account.nonce = nonce;
+                }
+                if let Some(code) = &change.code {
+                    account.code_hash = if code.is_empty() {
+                        KECCAK_EMPTY
+                    } else {
+                        code.hash()
+                    };
+                    account.code = Some(code.clone());
+                }
+            }
+
+            if change.storage_cleared {
+                storage.remove(address);
+            } else if let Some(cached) = storage.get_mut(address) {
+                for (index, value) in &change.storage {
+                    if let Some(slot) = cached.get_mut(index) {
+                        *slot = *value;
+                    }
+                }
+            }
+        }
+
+        for (address, amount) in &changes.withdrawals {
+            if let Some(account) = accounts.get_mut(address) {
+                account.balance += *amount;
+                if let Some(dirty) = dirty.as_mut() {
+                    dirty.accounts.insert(*address);
+                }
+            }
+        }
+    }
+}
+
+/// Keys whose value differs between `old` and `new`, including those missing from either
+fn changed_keys<'a, K, V>(old: &'a Map<K, V>, new: &'a Map<K, V>) -> impl Iterator<Item = K> + 'a
+where
+    K: Copy + Eq + std::hash::Hash,
+    V: PartialEq,
+{
+    old.iter()
+        .filter(|(key, value)| new.get(*key) != Some(*value))
+        .map(|(key, _)| *key)
+        .chain(new.keys().filter(|key| !old.contains_key(*key)).copied())
 }
 
 impl Clone for MemDb {
@@ -304,6 +529,10 @@ impl Clone for MemDb {
             storage: RwLock::new(self.storage.read().clone()),
             accounts: RwLock::new(self.accounts.read().clone()),
             block_hashes: RwLock::new(self.block_hashes.read().clone()),
+            dirty: self
+                .dirty
+                .as_ref()
+                .map(|dirty| Mutex::new(dirty.lock().clone())),
         }
     }
 }
@@ -314,6 +543,61 @@ impl DatabaseCommit for MemDb {
     }
 }
 
+/// A cache of the remote state fetched by a [BlockchainDb], that can be flushed to disk
+pub trait BlockCacheDB: fmt::Debug + Send + Sync {
+    /// Returns the [MemDb] it holds access to
+    fn db(&self) -> &Arc<MemDb>;
+
+    /// Metadata stored alongside the data
+    fn meta(&self) -> &Arc<RwLock<BlockchainDbMeta>>;
+
+    /// Returns `true` if this is a transient cache and nothing will be flushed
+    fn is_transient(&self) -> bool;
+
+    /// Flushes the DB to disk if caching is enabled
+    fn flush(&self);
+}
+
+/// File format of a [BlockCacheDB]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CacheFormat {
+    /// The whole cache in a json file, see [JsonBlockCacheDB]
+    #[default]
+    Json,
+    /// An append-only log of binary records, see [BinaryBlockCacheDB]
+    Binary,
+}
+
+impl CacheFormat {
+    /// Format of a cache file, binary for `.bin` files and json otherwise
+    pub fn from_path(path: Option<&Path>) -> Self {
+        match path.and_then(|path| path.extension()) {
+            Some(ext) if ext == "bin" => CacheFormat::Binary,
+            _ => CacheFormat::Json,
+        }
+    }
+
+    /// Loads the cache stored at `path`
+    pub fn load(&self, path: &Path) -> eyre::Result<Arc<dyn BlockCacheDB>> {
+        Ok(match self {
+            CacheFormat::Json => Arc::new(JsonBlockCacheDB::load(path)?),
+            CacheFormat::Binary => Arc::new(BinaryBlockCacheDB::load(path)?),
+        })
+    }
+
+    /// Creates an empty cache stored at `cache_path`
+    pub fn create(
+        &self,
+        meta: Arc<RwLock<BlockchainDbMeta>>,
+        cache_path: Option<PathBuf>,
+    ) -> Arc<dyn BlockCacheDB> {
+        match self {
+            CacheFormat::Json => Arc::new(JsonBlockCacheDB::new(meta, cache_path)),
+            CacheFormat::Binary => Arc::new(BinaryBlockCacheDB::new(meta, cache_path)),
+        }
+    }
+}
+
 /// A [BlockCacheDB] that stores the cached content in a json file
 #[derive(Debug)]
 pub struct JsonBlockCacheDB {
@@ -395,6 +679,24 @@ impl JsonBlockCacheDB {
     }
 }
 
+impl BlockCacheDB for JsonBlockCacheDB {
+    fn db(&self) -> &Arc<MemDb> {
+        JsonBlockCacheDB::db(self)
+    }
+
+    fn meta(&self) -> &Arc<RwLock<BlockchainDbMeta>> {
+        JsonBlockCacheDB::meta(self)
+    }
+
+    fn is_transient(&self) -> bool {
+        JsonBlockCacheDB::is_transient(self)
+    }
+
+    fn flush(&self) {
+        JsonBlockCacheDB::flush(self)
+    }
+}
+
 /// The Data the [JsonBlockCacheDB] can read and flush
 ///
 /// This will be deserialized in a JSON object with the keys:
@@ -460,19 +762,20 @@ impl<'de> Deserialize<'de> for JsonBlockCacheData {
                 accounts: RwLock::new(accounts),
                 storage: RwLock::new(storage),
                 block_hashes: RwLock::new(block_hashes),
+                dirty: None,
             }),
         })
     }
 }
 
-/// A type that flushes a `JsonBlockCacheDB` on drop
+/// A type that flushes a [BlockCacheDB] on drop
 ///
 /// This type intentionally does not implement `Clone` since it's intended that there's only once
 /// instance that will flush the cache.
 #[derive(Debug)]
-pub struct FlushJsonBlockCacheDB(pub Arc<JsonBlockCacheDB>);
+pub struct FlushBlockCacheDB(pub Arc<dyn BlockCacheDB>);
 
-impl Drop for FlushJsonBlockCacheDB {
+impl Drop for FlushBlockCacheDB {
     fn drop(&mut self) {
         trace!(target: "fork::cache", "flushing cache");
         self.0.flush();
@@ -0,0 +1,542 @@
+use crate::{
+    forked_db::ForkedDatabase,
+    prefetch::PrefetchList,
+    utils::{b160_to_h160, b256_to_h256, h160_to_b160, ru256_to_u256, u256_to_ru256},
+};
+use ethers::{
+    types::{
+        transaction::eip2718::TypedTransaction, AccountDiff, Address, Bytes, ChangedType, Diff,
+        Log, NameOrAddress, StateDiff, H256, U256, U64,
+    },
+    utils::{keccak256, rlp::Rlp},
+};
+use ethers_flashbots::{SimulatedBundle, SimulatedTransaction};
+use fees::TxFees;
+use revm::{
+    db::DatabaseRef,
+    primitives::{
+        BlockEnv, ExecutionResult, Output, ResultAndState, State, TransactTo, TxEnv, B160,
+        U256 as rU256,
+    },
+    DatabaseCommit, EVM,
+};
+use std::collections::BTreeMap;
+use thiserror::Error;
+use tracing::{trace, warn};
+
+#[derive(Error, Debug)]
+pub enum SimulationError {
+    #[error("Failed to decode tx {index}: {reason}")]
+    Decode { index: usize, reason: String },
+    #[error("Tx {index} is invalid: {reason}")]
+    InvalidTx { index: usize, reason: String },
+    #[error("Bundle has no txs")]
+    EmptyBundle,
+}
+
+/// What a local bundle simulation adds over `eth_callBundle`, per tx in bundle order
+#[derive(Debug, Clone, Default)]
+pub struct TxTrace {
+    pub logs: Vec<Log>,
+    /// State changed by the tx, in the shape of `trace_callMany`'s `stateDiff`
+    pub state_diff: StateDiff,
+}
+
+/// Result of [simulate_bundle]
+#[derive(Debug, Clone)]
+pub struct LocalSimulatedBundle {
+    /// Same fields as the response of the relay's `eth_callBundle`
+    pub bundle: SimulatedBundle,
+    pub traces: Vec<TxTrace>,
+}
+
+/// Env of the block following the fork's block
+///
+/// Arguments:
+/// * `fork_db`: the fork, pinned at the current block
+/// * `base_fee`: base fee of the next block
+/// * `coinbase`: fee recipient of the next block
+pub fn next_block_env(fork_db: &ForkedDatabase, base_fee: U256, coinbase: Address) -> BlockEnv {
+    let mut block = fork_db.inner().meta().read().block_env.clone();
+    block.number += rU256::from(1);
+    block.timestamp += rU256::from(12);
+    block.basefee = u256_to_ru256(base_fee);
+    block.coinbase = h160_to_b160(coinbase);
+    block
+}
+
+/// Simulate a bundle on the fork, the way the relay's `eth_callBundle` does
+///
+/// The signed txs are applied in order on top of a snapshot of the fork, which is reverted
+/// once the bundle ran. Reverted or halted txs don't stop the simulation, but a tx that can't
+/// be included at all (bad nonce, insufficient funds, ...) fails the whole bundle.
+///
+/// Arguments:
+/// * `fork_db`: the fork to simulate on, left untouched
+/// * `signed_txs`: raw signed txs of the bundle, in order
+/// * `block`: env of the block the bundle targets, see [next_block_env]
+///
+/// Returns:
+/// Ok(LocalSimulatedBundle): gas used, coinbase diff, reverts, logs and state diffs of every tx
+/// Err(SimulationError): if a tx can't be decoded or included
+pub fn simulate_bundle(
+    fork_db: &mut ForkedDatabase,
+    signed_txs: &[Bytes],
+    block: &BlockEnv,
+) -> Result<LocalSimulatedBundle, SimulationError> {
+    if signed_txs.is_empty() {
+        return Err(SimulationError::EmptyBundle);
+    }
+
+    // load what the txs declare they touch in one go, instead of a fetch per cache miss
+    let mut prefetch = PrefetchList::new().with_account(b160_to_h160(block.coinbase));
+    for (tx, from) in signed_txs
+        .iter()
+        .filter_map(|raw| decode_signed_tx(raw).ok())
+    {
+        prefetch.extend(PrefetchList::from_transaction(&tx, from));
+    }
+    if let Err(err) = fork_db.prefetch(prefetch) {
+        warn!(target: "bundle_simulator", ?err, "Failed to prefetch the bundle's state");
+    }
+
+    let snapshot = fork_db.insert_snapshot();
+    let result = run_bundle(fork_db, signed_txs, block);
+    fork_db.revert_snapshot(snapshot);
+    result
+}
+
+fn run_bundle(
+    fork_db: &mut ForkedDatabase,
+    signed_txs: &[Bytes],
+    block: &BlockEnv,
+) -> Result<LocalSimulatedBundle, SimulationError> {
+    let cfg = fork_db.inner().meta().read().cfg_env.clone();
+    let base_fee = ru256_to_u256(block.basefee);
+    let coinbase = b160_to_h160(block.coinbase);
+
+    let mut transactions = Vec::with_capacity(signed_txs.len());
+    let mut traces = Vec::with_capacity(signed_txs.len());
+    let mut bundle_hash_preimage = Vec::with_capacity(signed_txs.len() * 32);
+    let mut log_index = 0u64;
+
+    for (index, raw) in signed_txs.iter().enumerate() {
+        let (tx, from) =
+            decode_signed_tx(raw).map_err(|reason| SimulationError::Decode { index, reason })?;
+        let hash = H256(keccak256(raw));
+        bundle_hash_preimage.extend_from_slice(hash.as_bytes());
+
+        let coinbase_before = account_balance(fork_db, block.coinbase);
+
+        let ResultAndState { result, state } = {
+            let mut evm = EVM::new();
+            evm.env.cfg = cfg.clone();
+            evm.env.block = block.clone();
+            evm.env.tx = tx_env(&tx, from);
+            evm.database(&mut *fork_db);
+            evm.transact().map_err(|e| SimulationError::InvalidTx {
+                index,
+                reason: format!("{:?}", e),
+            })?
+        };
+
+        let state_diff = state_diff(fork_db, &state);
+        fork_db.commit(state);
+
+        let coinbase_diff = account_balance(fork_db, block.coinbase)
+            .checked_sub(coinbase_before)
+            .unwrap_or_default();
+        let gas_used = U256::from(result.gas_used());
+        // priority fee per gas paid to the coinbase
+        let tip = TxFees::from_typed_tx(&tx)
+            .and_then(|fees| fees.effective_tip(base_fee))
+            .unwrap_or_default();
+        let gas_fees = gas_used * tip;
+
+        let (value, error, revert, logs) = match result {
+            ExecutionResult::Success { output, logs, .. } => {
+                let value = match output {
+                    Output::Call(data) => Bytes::from(data),
+                    Output::Create(data, _) => Bytes::from(data),
+                };
+                (Some(value), None, None, logs)
+            }
+            ExecutionResult::Revert { output, .. } => {
+                (None, None, Some(Bytes::from(output).to_string()), vec![])
+            }
+            ExecutionResult::Halt { reason, .. } => {
+                (None, Some(format!("{:?}", reason)), None, vec![])
+            }
+        };
+
+        let logs = logs
+            .into_iter()
+            .map(|log| {
+                let log = Log {
+                    address: b160_to_h160(log.address),
+                    topics: log.topics.into_iter().map(b256_to_h256).collect(),
+                    data: log.data.into(),
+                    block_number: Some(block_number(block)),
+                    transaction_hash: Some(hash),
+                    transaction_index: Some(U64::from(index)),
+                    log_index: Some(U256::from(log_index)),
+                    ..Default::default()
+                };
+                log_index += 1;
+                log
+            })
+            .collect();
+
+        transactions.push(SimulatedTransaction {
+            hash,
+            coinbase_diff,
+            // eth sent to the coinbase on top of the priority fees
+            coinbase_tip: coinbase_diff.checked_sub(gas_fees).unwrap_or_default(),
+            // effective price per gas the coinbase got, direct payments included
+            gas_price: if gas_used.is_zero() {
+                U256::zero()
+            } else {
+                coinbase_diff / gas_used
+            },
+            gas_used,
+            gas_fees,
+            from,
+            to: match tx.to() {
+                Some(NameOrAddress::Address(to)) => Some(*to),
+                _ => None,
+            },
+            value,
+            error,
+            revert,
+        });
+        traces.push(TxTrace { logs, state_diff });
+    }
+
+    let fold = |field: fn(&SimulatedTransaction) -> U256| {
+        transactions
+            .iter()
+            .fold(U256::zero(), |total, tx| total + field(tx))
+    };
+    let coinbase_diff = fold(|tx| tx.coinbase_diff);
+    let gas_used = fold(|tx| tx.gas_used);
+    let bundle = SimulatedBundle {
+        hash: H256(keccak256(bundle_hash_preimage)),
+        coinbase_diff,
+        coinbase_tip: fold(|tx| tx.coinbase_tip),
+        gas_price: if gas_used.is_zero() {
+            U256::zero()
+        } else {
+            coinbase_diff / gas_used
+        },
+        gas_used,
+        gas_fees: fold(|tx| tx.gas_fees),
+        simulation_block: block_number(block).saturating_sub(U64::one()),
+        transactions,
+    };
+
+    trace!(
+        target: "bundle_simulator",
+        hash = ?bundle.hash,
+        coinbase_diff = %bundle.coinbase_diff,
+        ?coinbase,
+        "Simulated bundle"
+    );
+
+    Ok(LocalSimulatedBundle { bundle, traces })
+}
+
+fn block_number(block: &BlockEnv) -> U64 {
+    U64::from(block.number.as_limbs()[0])
+}
+
+/// Decode a raw signed tx and recover its sender
+fn decode_signed_tx(raw: &Bytes) -> Result<(TypedTransaction, Address), String> {
+    let (tx, signature) =
+        TypedTransaction::decode_signed(&Rlp::new(raw)).map_err(|e| e.to_string())?;
+    let from = signature.recover(tx.sighash()).map_err(|e| e.to_string())?;
+    Ok((tx, from))
+}
+
+fn tx_env(tx: &TypedTransaction, from: Address) -> TxEnv {
+    let (gas_price, gas_priority_fee) = match tx {
+        TypedTransaction::Eip1559(inner) => (
+            inner.max_fee_per_gas.unwrap_or_default(),
+            inner.max_priority_fee_per_gas,
+        ),
+        _ => (tx.gas_price().unwrap_or_default(), None),
+    };
+
+    TxEnv {
+        caller: h160_to_b160(from),
+        gas_limit: tx.gas().copied().unwrap_or_default().as_u64(),
+        gas_price: u256_to_ru256(gas_price),
+        gas_priority_fee: gas_priority_fee.map(u256_to_ru256),
+        transact_to: match tx.to() {
+            Some(NameOrAddress::Address(to)) => TransactTo::Call(h160_to_b160(*to)),
+            _ => TransactTo::create(),
+        },
+        value: u256_to_ru256(tx.value().copied().unwrap_or_default()),
+        data: tx.data().cloned().unwrap_or_default().0,
+        chain_id: tx.chain_id().map(|id| id.as_u64()),
+        nonce: tx.nonce().map(|nonce| nonce.as_u64()),
+        access_list: tx
+            .access_list()
+            .map(|list| {
+                list.0
+                    .iter()
+                    .map(|item| {
+                        (
+                            h160_to_b160(item.address),
+                            item.storage_keys
+                                .iter()
+                                .map(|key| rU256::from_be_bytes(key.0))
+                                .collect(),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
+}
+
+fn account_balance(fork_db: &ForkedDatabase, address: B160) -> U256 {
+    DatabaseRef::basic(fork_db, address)
+        .ok()
+        .flatten()
+        .map(|info| ru256_to_u256(info.balance))
+        .unwrap_or_default()
+}
+
+/// Diff the state touched by a tx against the fork before the tx is committed
+fn state_diff(fork_db: &ForkedDatabase, state: &State) -> StateDiff {
+    let mut diff = BTreeMap::new();
+
+    for (address, account) in state {
+        let before = DatabaseRef::basic(fork_db, *address)
+            .ok()
+            .flatten()
+            .unwrap_or_default();
+        let after = &account.info;
+
+        let code_before = before.code.clone().unwrap_or_default().bytes().clone();
+        let code_after = match &after.code {
+            Some(code) => code.bytes().clone(),
+            None if after.code_hash == before.code_hash => code_before.clone(),
+            None => DatabaseRef::code_by_hash(fork_db, after.code_hash)
+                .map(|code| code.bytes().clone())
+                .unwrap_or_default(),
+        };
+
+        let storage: BTreeMap<H256, Diff<H256>> = account
+            .storage
+            .iter()
+            .filter(|(_, slot)| slot.original_value != slot.present_value)
+            .map(|(index, slot)| {
+                (
+                    H256(index.to_be_bytes()),
+                    changed(
+                        H256(slot.original_value.to_be_bytes()),
+                        H256(slot.present_value.to_be_bytes()),
+                    ),
+                )
+            })
+            .collect();
+
+        let account_diff = AccountDiff {
+            balance: changed(ru256_to_u256(before.balance), ru256_to_u256(after.balance)),
+            nonce: changed(U256::from(before.nonce), U256::from(after.nonce)),
+            code: changed(Bytes::from(code_before), Bytes::from(code_after)),
+            storage,
+        };
+
+        let unchanged = matches!(account_diff.balance, Diff::Same)
+            && matches!(account_diff.nonce, Diff::Same)
+            && matches!(account_diff.code, Diff::Same)
+            && account_diff.storage.is_empty();
+        if !unchanged {
+            diff.insert(b160_to_h160(*address), account_diff);
+        }
+    }
+
+    StateDiff(diff)
+}
+
+fn changed<T: PartialEq>(from: T, to: T) -> Diff<T> {
+    if from == to {
+        Diff::Same
+    } else {
+        Diff::Changed(ChangedType { from, to })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        blockchain_db::{BlockchainDb, BlockchainDbMeta},
+        forked_db::tests::offline_fork,
+        shared_backend::SharedBackend,
+    };
+    use ethers::{
+        providers::{Middleware, Provider, Ws},
+        signers::{LocalWallet, Signer},
+        types::Eip1559TransactionRequest,
+    };
+    use revm::primitives::{AccountInfo, Bytecode};
+    use std::{collections::BTreeSet, sync::Arc};
+
+    /// Sign an EIP-1559 tx paying 1 gwei of priority fee
+    fn sign_tx(wallet: &LocalWallet, to: Address, value: U256, nonce: u64, gas: u64) -> Bytes {
+        let gwei = U256::exp10(9);
+        let tx: TypedTransaction = Eip1559TransactionRequest::new()
+            .to(to)
+            .value(value)
+            .nonce(nonce)
+            .gas(gas)
+            .max_fee_per_gas(gwei * 12)
+            .max_priority_fee_per_gas(gwei)
+            .chain_id(1u64)
+            .into();
+        let signature = wallet.sign_transaction_sync(&tx).unwrap();
+        tx.rlp_signed(&signature)
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_simulate_reverting_tx() {
+        let wallet = LocalWallet::new(&mut ethers::core::rand::thread_rng()).with_chain_id(1u64);
+        let (coinbase, receiver, reverter) =
+            (Address::random(), Address::random(), Address::random());
+        let mut fork_db = offline_fork(&[
+            (wallet.address(), 10u64.pow(18)),
+            (coinbase, 0),
+            (receiver, 0),
+        ]);
+        // PUSH1 0 PUSH1 0 REVERT
+        let code = Bytecode::new_raw(vec![0x60, 0x00, 0x60, 0x00, 0xfd].into()).to_checked();
+        fork_db.inner().db().do_insert_account(
+            h160_to_b160(reverter),
+            AccountInfo {
+                code_hash: code.hash(),
+                code: Some(code),
+                ..Default::default()
+            },
+        );
+
+        let gwei = U256::exp10(9);
+        let block = next_block_env(&fork_db, gwei * 10, coinbase);
+        let txs = vec![
+            sign_tx(&wallet, reverter, U256::zero(), 0, 100_000),
+            sign_tx(&wallet, receiver, U256::from(1000), 1, 21_000),
+        ];
+
+        // a reverted tx is reported, and the txs after it still run
+        let bundle = simulate_bundle(&mut fork_db, &txs, &block).unwrap().bundle;
+        let reverted = &bundle.transactions[0];
+        assert_eq!(reverted.revert, Some(Bytes::new().to_string()));
+        assert_eq!(reverted.gas_used, U256::from(21_006));
+        assert_eq!(reverted.gas_fees, gwei * 21_006);
+        assert!(bundle.transactions[1].revert.is_none());
+        assert_eq!(bundle.gas_used, U256::from(42_006));
+        assert_eq!(bundle.coinbase_diff, gwei * 42_006);
+        assert_eq!(bundle.coinbase_tip, U256::zero());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_simulate_invalid_nonce() {
+        let wallet = LocalWallet::new(&mut ethers::core::rand::thread_rng()).with_chain_id(1u64);
+        let (coinbase, receiver) = (Address::random(), Address::random());
+        let mut fork_db = offline_fork(&[
+            (wallet.address(), 10u64.pow(18)),
+            (coinbase, 0),
+            (receiver, 0),
+        ]);
+
+        let block = next_block_env(&fork_db, U256::exp10(9) * 10, coinbase);
+        let txs = vec![
+            sign_tx(&wallet, receiver, U256::from(1000), 0, 21_000),
+            sign_tx(&wallet, receiver, U256::from(1000), 5, 21_000),
+        ];
+
+        // a tx that can't be included fails the whole bundle
+        let err = simulate_bundle(&mut fork_db, &txs, &block).unwrap_err();
+        assert!(matches!(err, SimulationError::InvalidTx { index: 1, .. }));
+        // and the txs before it are reverted
+        assert_eq!(
+            account_balance(&fork_db, h160_to_b160(receiver)),
+            U256::zero()
+        );
+        assert_eq!(
+            account_balance(&fork_db, h160_to_b160(wallet.address())),
+            U256::exp10(18)
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_simulate_bundle() {
+        let mainnet_ws_url = "wss://eth.llamarpc.com";
+        let provider = Provider::<Ws>::connect(mainnet_ws_url).await.unwrap();
+        let block_num = provider.get_block_number().await.unwrap();
+
+        let mut meta = BlockchainDbMeta {
+            cfg_env: Default::default(),
+            block_env: Default::default(),
+            hosts: BTreeSet::from([mainnet_ws_url.to_string()]),
+        };
+        meta.block_env.number = rU256::from(block_num.as_u64());
+        let db = BlockchainDb::new(meta, None);
+        let backend =
+            SharedBackend::spawn_backend(Arc::new(provider), db.clone(), Some(block_num.into()))
+                .await;
+        let mut fork_db = ForkedDatabase::new(backend, db);
+
+        let wallet = LocalWallet::new(&mut ethers::core::rand::thread_rng()).with_chain_id(1u64);
+        fork_db.database_mut().insert_account_info(
+            h160_to_b160(wallet.address()),
+            AccountInfo {
+                balance: rU256::from(10u128.pow(18)),
+                ..Default::default()
+            },
+        );
+
+        let gwei = U256::exp10(9);
+        let coinbase = Address::random();
+        let receiver = Address::random();
+        let block = next_block_env(&fork_db, gwei * 10, coinbase);
+
+        let txs = vec![
+            sign_tx(&wallet, receiver, U256::from(1000), 0, 21_000),
+            sign_tx(&wallet, coinbase, gwei, 1, 21_000),
+        ];
+
+        let result = simulate_bundle(&mut fork_db, &txs, &block).unwrap();
+        let bundle = result.bundle;
+        assert_eq!(bundle.gas_used, U256::from(42_000));
+        assert_eq!(bundle.gas_fees, gwei * 42_000);
+        // the second tx pays the coinbase directly
+        assert_eq!(bundle.coinbase_tip, gwei);
+        assert_eq!(bundle.coinbase_diff, gwei * 42_001);
+        assert_eq!(bundle.transactions[0].from, wallet.address());
+        assert_eq!(bundle.transactions[0].gas_price, gwei);
+        // direct payments count towards the effective gas price
+        assert_eq!(
+            bundle.transactions[1].gas_price,
+            (gwei * 21_000 + gwei) / 21_000
+        );
+        assert!(bundle.transactions.iter().all(|tx| tx.revert.is_none()));
+
+        let receiver_diff = &result.traces[0].state_diff.0[&receiver];
+        assert_eq!(
+            receiver_diff.balance,
+            Diff::Changed(ChangedType {
+                from: U256::zero(),
+                to: U256::from(1000)
+            })
+        );
+
+        // the fork is left untouched
+        assert_eq!(
+            account_balance(&fork_db, h160_to_b160(receiver)),
+            U256::zero()
+        );
+    }
+}
@@ -1,13 +1,15 @@
 // ported from foundry's executor
 // // https://github.com/foundry-rs/foundry/blob/master/evm/src/executor/backend/error.rs
+use super::proof::ProofError;
 use ethers::types::{Address, BlockId, H256, U256};
 use futures::channel::mpsc::{SendError, TrySendError};
 use std::{
     convert::Infallible,
+    path::PathBuf,
     sync::{mpsc::RecvError, Arc},
 };
 
-#[derive(Debug, thiserror::Error)]
+#[derive(Debug, Clone, thiserror::Error)]
 pub enum DatabaseError {
     #[error("Could should already be loaded: {0:?}")]
     MissingCode(H256),
@@ -27,6 +29,14 @@ pub enum DatabaseError {
     GetTransaction(H256, Arc<eyre::Error>),
     #[error("Transaction {0:?} not found")]
     TransactionNotFound(H256),
+    #[error("Proof of {0:?} (slot {1:?}) doesn't match the pinned block's state root: {2}")]
+    InvalidProof(Address, Option<U256>, ProofError),
+    #[error("Proofs can only be checked at a block number or hash, the backend is at {0:?}")]
+    UnpinnedProof(Option<BlockId>),
+    #[error("Failed to prefetch accounts and storage: {0:?}")]
+    Prefetch(Arc<eyre::Error>),
+    #[error("Failed to read or write snapshot {0:?}: {1}")]
+    Snapshot(PathBuf, String),
     #[error(transparent)]
     Recv(#[from] RecvError),
     #[error(transparent)]
@@ -35,6 +45,23 @@ pub enum DatabaseError {
 
 pub type DatabaseResult<T> = Result<T, DatabaseError>;
 
+/// Errors setting up a [ForkedDatabase](crate::forked_db::ForkedDatabase)
+#[derive(Debug, thiserror::Error)]
+pub enum ForkDbError {
+    #[error("Failed to get the chain id: {0:?}")]
+    GetChainId(Arc<eyre::Error>),
+    #[error("Failed to get block {0:?}: {1:?}")]
+    GetBlock(BlockId, Arc<eyre::Error>),
+    #[error("Block {0:?} does not exist")]
+    BlockNotFound(BlockId),
+    #[error("Block {0:?} is still pending")]
+    PendingBlock(BlockId),
+    #[error("Failed to configure the backend: {0:?}")]
+    Backend(Arc<eyre::Error>),
+    #[error("Verified state can't be loaded from the unverified cache file {0:?}")]
+    VerifiedCache(PathBuf),
+}
+
 impl DatabaseError {
     /// Create a new error with a message
     pub fn msg(msg: impl Into<String>) -> Self {
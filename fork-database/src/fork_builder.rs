@@ -0,0 +1,211 @@
+use super::{
+    blockchain_db::{BlockchainDb, BlockchainDbMeta, CacheFormat},
+    errors::ForkDbError,
+    forked_db::{block_env, ForkedDatabase},
+    prefetch::BatchClient,
+    shared_backend::SharedBackend,
+};
+use ethers::{
+    providers::Middleware,
+    types::{BlockId, BlockNumber},
+};
+use revm::primitives::{CfgEnv, U256 as rU256};
+use std::{collections::BTreeSet, path::PathBuf, sync::Arc};
+use url::Url;
+
+/// Memory limit of the EVM, same as foundry's default
+const DEFAULT_MEMORY_LIMIT: u64 = 1 << 25;
+
+/// Builds a [ForkedDatabase] on top of any [Middleware]
+///
+/// The block env is read from the forked block and the backend is pinned to it, so the state
+/// fetched lazily matches the env.
+#[derive(Debug, Clone)]
+pub struct ForkDbBuilder<M> {
+    provider: M,
+    chain_id: Option<u64>,
+    block: BlockId,
+    cache_path: Option<PathBuf>,
+    cache_format: Option<CacheFormat>,
+    hosts: BTreeSet<String>,
+    batch_url: Option<Url>,
+    verify_proofs: bool,
+}
+
+impl<M> ForkDbBuilder<M>
+where
+    M: Middleware + Unpin + 'static + Clone,
+{
+    /// Fork the latest block of `provider`, without a cache file
+    ///
+    /// NOTE: this should be called with `Arc<Provider>`
+    pub fn new(provider: M) -> Self {
+        Self {
+            provider,
+            chain_id: None,
+            block: BlockNumber::Latest.into(),
+            cache_path: None,
+            cache_format: None,
+            hosts: BTreeSet::new(),
+            batch_url: None,
+            verify_proofs: false,
+        }
+    }
+
+    /// Use this chain id instead of asking the provider
+    pub fn with_chain_id(mut self, chain_id: u64) -> Self {
+        self.chain_id = Some(chain_id);
+        self
+    }
+
+    /// Fork at this block instead of the latest one
+    pub fn with_block(mut self, block: impl Into<BlockId>) -> Self {
+        self.block = block.into();
+        self
+    }
+
+    /// Load and flush the fetched state from and to this file
+    ///
+    /// The file is only loaded if it was written for the same block, chain and hosts.
+    pub fn with_cache_path(mut self, cache_path: impl Into<PathBuf>) -> Self {
+        self.cache_path = Some(cache_path.into());
+        self
+    }
+
+    /// Format of the cache file, guessed from its extension by default
+    pub fn with_cache_format(mut self, format: CacheFormat) -> Self {
+        self.cache_format = Some(format);
+        self
+    }
+
+    /// Endpoint the provider connects to, recorded in the cache metadata
+    pub fn with_host(mut self, host: impl Into<String>) -> Self {
+        self.hosts.insert(host.into());
+        self
+    }
+
+    /// HTTP endpoint prefetches are sent to as a single JSON-RPC batch, see [BatchClient]
+    ///
+    /// [Middleware] can't batch calls, so without it a prefetch sends one call per account field
+    /// and slot, concurrently, through the provider. The provider's transport isn't reused, the
+    /// batch goes to this separate URL, which should serve the same chain.
+    pub fn with_batch_url(mut self, url: Url) -> Self {
+        self.batch_url = Some(url);
+        self
+    }
+
+    /// Only cache state proven against the forked block's state root, see
+    /// [SharedBackend::set_verify_proofs]
+    ///
+    /// Can't be combined with [Self::with_cache_path], a cache file's content isn't proven.
+    pub fn with_verified_state(mut self) -> Self {
+        self.verify_proofs = true;
+        self
+    }
+
+    /// Fetch the env of the forked block and spawn the backend fetching its state
+    ///
+    /// Returns:
+    /// Ok(ForkedDatabase): the fork, at the requested block
+    /// Err(ForkDbError): if the chain id or the block couldn't be fetched, or verified state was
+    /// requested along with a cache file
+    pub async fn build(self) -> Result<ForkedDatabase, ForkDbError> {
+        if let (true, Some(path)) = (self.verify_proofs, &self.cache_path) {
+            return Err(ForkDbError::VerifiedCache(path.clone()));
+        }
+
+        let chain_id = match self.chain_id {
+            Some(chain_id) => chain_id,
+            None => self
+                .provider
+                .get_chainid()
+                .await
+                .map_err(|err| ForkDbError::GetChainId(Arc::new(eyre::Error::new(err))))?
+                .as_u64(),
+        };
+
+        let block = self
+            .provider
+            .get_block(self.block)
+            .await
+            .map_err(|err| ForkDbError::GetBlock(self.block, Arc::new(eyre::Error::new(err))))?
+            .ok_or(ForkDbError::BlockNotFound(self.block))?;
+        let number = block.number.ok_or(ForkDbError::PendingBlock(self.block))?;
+
+        let mut cfg_env = CfgEnv::default();
+        cfg_env.chain_id = rU256::from(chain_id);
+        cfg_env.memory_limit = DEFAULT_MEMORY_LIMIT;
+        cfg_env.limit_contract_code_size = Some(usize::MAX);
+        cfg_env.disable_eip3607 = true;
+
+        let meta = BlockchainDbMeta {
+            cfg_env,
+            block_env: block_env(&block),
+            hosts: self.hosts,
+        };
+        let format = self
+            .cache_format
+            .unwrap_or_else(|| CacheFormat::from_path(self.cache_path.as_deref()));
+        let db = BlockchainDb::new_with_format(meta, self.cache_path, format);
+
+        let backend =
+            SharedBackend::spawn_backend(self.provider, db.clone(), Some(number.into())).await;
+        if let Some(url) = self.batch_url {
+            backend
+                .set_batch_client(BatchClient::new(url))
+                .map_err(|err| ForkDbError::Backend(Arc::new(err)))?;
+        }
+        if self.verify_proofs {
+            backend
+                .set_verify_proofs(true)
+                .map_err(|err| ForkDbError::Backend(Arc::new(err)))?;
+        }
+
+        Ok(ForkedDatabase::new(backend, db))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::{b160_to_h160, u256_to_ru256};
+    use ethers::providers::{Provider, Ws};
+    use revm::{db::DatabaseRef, primitives::B160};
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_build_fork_at_block() {
+        let provider = Provider::<Ws>::connect("wss://eth.llamarpc.com")
+            .await
+            .unwrap();
+        let provider = Arc::new(provider);
+        let number = provider.get_block_number().await.unwrap() - 5;
+
+        let fork_db = ForkDbBuilder::new(provider.clone())
+            .with_block(number)
+            .build()
+            .await
+            .unwrap();
+
+        let meta = fork_db.inner().meta().read().clone();
+        assert_eq!(meta.cfg_env.chain_id, rU256::from(1u64));
+        assert_eq!(meta.block_env.number, rU256::from(number.as_u64()));
+
+        // state is fetched at the forked block
+        let address: B160 = "0xd8da6bf26964af9d7eed9e03e53415d37aa96045"
+            .parse()
+            .unwrap();
+        let balance = provider
+            .get_balance(b160_to_h160(address), Some(number.into()))
+            .await
+            .unwrap();
+        let account = fork_db.database().basic(address).unwrap().unwrap();
+        assert_eq!(account.balance, u256_to_ru256(balance));
+
+        let missing = ForkDbBuilder::new(provider)
+            .with_chain_id(1)
+            .with_block(number + 1_000_000)
+            .build()
+            .await;
+        assert!(matches!(missing, Err(ForkDbError::BlockNotFound(_))));
+    }
+}
@@ -1,20 +1,41 @@
 // ported from foundry's executor with some modifications
 // https://github.com/foundry-rs/foundry/blob/master/evm/src/executor/fork/database.rs
 use super::{
-    blockchain_db::BlockchainDb, errors::DatabaseError, shared_backend::SharedBackend,
-    snapshot::StateSnapshot,
+    block_changes::BlockChanges,
+    blockchain_db::BlockchainDb,
+    errors::{DatabaseError, DatabaseResult},
+    prefetch::PrefetchList,
+    shared_backend::SharedBackend,
+    snapshot::{LocalState, SnapshotFile, StateSnapshot},
+    utils::{h160_to_b160, h256_to_b256, u256_to_ru256},
 };
-use ethers::{prelude::U256, types::BlockId};
-use hashbrown::HashMap as Map;
+use ethers::{
+    prelude::U256,
+    providers::{MockProvider, Provider},
+    types::{Block, BlockId, StateDiff, Transaction},
+};
+use hashbrown::{HashMap as Map, HashSet as Set};
 use log::{trace, warn};
 use parking_lot::Mutex;
 use revm::db::CacheDB;
 use revm::{
     db::DatabaseRef,
-    primitives::{Account, AccountInfo, Bytecode, B160, B256, U256 as rU256},
-    Database, DatabaseCommit,
+    primitives::{
+        Account, AccountInfo, BlockEnv, Bytecode, ResultAndState, TransactTo, TxEnv, B160, B256,
+        U256 as rU256,
+    },
+    Database, DatabaseCommit, EVM,
 };
-use std::sync::Arc;
+use std::{path::Path, sync::Arc};
+
+/// Where the state changes of the block a [ForkedDatabase] advances to come from
+#[derive(Debug, Clone)]
+pub enum BlockStateSource {
+    /// `stateDiff` of each tx of the block in order, e.g. from `trace_replayBlockTransactions`
+    StateDiffs(Vec<StateDiff>),
+    /// Re-execute the block's txs on the fork, for nodes without tracing
+    Reexecute,
+}
 
 /// a [revm::Database] that's forked off another client
 ///
@@ -71,8 +92,6 @@ impl ForkedDatabase {
             .set_pinned_block(block_number)
             .map_err(|err| err.to_string())?;
 
-        // TODO need to find a way to update generic provider via url
-
         // wipe the storage retrieved from remote
         self.inner().db().clear();
         // create a fresh `CacheDB`, effectively wiping modified state
@@ -81,6 +100,90 @@ impl ForkedDatabase {
         Ok(())
     }
 
+    /// Advance the fork to `block`, mined on top of the fork's block, keeping the cache warm
+    ///
+    /// Unlike [reset](Self::reset), the state fetched from the remote client is kept: the changes
+    /// of `block` are applied to the cached accounts and slots, and only what the block touched
+    /// is updated. Local modifications and snapshots, made against the previous block, are
+    /// discarded.
+    ///
+    /// Arguments:
+    /// * `block`: the new block, with its txs
+    /// * `source`: where the block's state changes come from
+    pub fn advance_to(
+        &mut self,
+        block: &Block<Transaction>,
+        source: BlockStateSource,
+    ) -> DatabaseResult<()> {
+        let number = block
+            .number
+            .ok_or_else(|| DatabaseError::msg("Can't advance to a pending block"))?;
+        let current = self.db.meta().read().block_env.number;
+        if rU256::from(number.as_u64()) != current + rU256::from(1) {
+            return Err(DatabaseError::msg(format!(
+                "Block {} isn't the child of the fork's block {}",
+                number, current
+            )));
+        }
+
+        let env = block_env(block);
+        let changes = match source {
+            BlockStateSource::StateDiffs(diffs) => BlockChanges::from_state_diffs(&diffs),
+            BlockStateSource::Reexecute => self.reexecute(block, &env)?,
+        };
+        let changes = match &block.withdrawals {
+            Some(withdrawals) => changes.with_withdrawals(withdrawals),
+            None => changes,
+        };
+
+        self.backend
+            .set_pinned_block(number)
+            .map_err(|err| DatabaseError::msg(err.to_string()))?;
+        self.db.db().apply_block_changes(&changes);
+        if let Some(hash) = block.hash {
+            self.db
+                .db()
+                .do_insert_block_hash(env.number, h256_to_b256(hash));
+        }
+        self.db.meta().write().block_env = env;
+
+        self.cache_db = CacheDB::new(self.backend.clone());
+        *self.snapshots.lock() = Default::default();
+        trace!(target: "backend::forkdb", "Advanced to block {} updating {} accounts", number, changes.accounts.len());
+        Ok(())
+    }
+
+    /// Execute the txs of `block` on top of the fork's block, without touching the fork
+    fn reexecute(
+        &self,
+        block: &Block<Transaction>,
+        env: &BlockEnv,
+    ) -> DatabaseResult<BlockChanges> {
+        let cfg = self.db.meta().read().cfg_env.clone();
+        let mut scratch = CacheDB::new(self.backend.clone());
+        let mut touched = Set::new();
+
+        for tx in &block.transactions {
+            let mut evm = EVM::new();
+            evm.env.cfg = cfg.clone();
+            evm.env.block = env.clone();
+            evm.env.tx = tx_env(tx);
+            evm.database(&mut scratch);
+            let ResultAndState { state, .. } = evm.transact().map_err(|err| {
+                DatabaseError::msg(format!("Failed to re-execute {:?}: {:?}", tx.hash, err))
+            })?;
+            touched.extend(
+                state
+                    .iter()
+                    .filter(|(_, account)| account.is_touched)
+                    .map(|(address, _)| *address),
+            );
+            scratch.commit(state);
+        }
+
+        Ok(BlockChanges::from_cache_db(&scratch, &touched))
+    }
+
     /// Flushes the cache to disk if configured
     pub fn flush_cache(&self) {
         self.db.cache().flush()
@@ -91,6 +194,11 @@ impl ForkedDatabase {
         &self.db
     }
 
+    /// Loads the remote state of `list` missing from the cache, see [SharedBackend::prefetch]
+    pub fn prefetch(&self, list: PrefetchList) -> DatabaseResult<usize> {
+        self.backend.prefetch(list)
+    }
+
     pub fn create_snapshot(&self) -> ForkDbSnapshot {
         let db = self.db.db();
         let snapshot = StateSnapshot {
@@ -124,22 +232,11 @@ impl ForkedDatabase {
                         block_hashes,
                     },
             } = snapshot;
-            let db = self.inner().db();
-            {
-                let mut accounts_lock = db.accounts.write();
-                accounts_lock.clear();
-                accounts_lock.extend(accounts);
-            }
-            {
-                let mut storage_lock = db.storage.write();
-                storage_lock.clear();
-                storage_lock.extend(storage);
-            }
-            {
-                let mut block_hashes_lock = db.block_hashes.write();
-                block_hashes_lock.clear();
-                block_hashes_lock.extend(block_hashes);
-            }
+            self.restore_remote(StateSnapshot {
+                accounts,
+                storage,
+                block_hashes,
+            });
 
             self.cache_db = local;
 
@@ -150,6 +247,83 @@ impl ForkedDatabase {
             false
         }
     }
+
+    /// Replace the remote state fetched so far
+    fn restore_remote(&self, snapshot: StateSnapshot) {
+        self.inner().db().replace(snapshot);
+    }
+
+    /// Write the fork's current state to `path`, see [SnapshotFile]
+    pub fn save_snapshot(&self, path: impl AsRef<Path>) -> DatabaseResult<()> {
+        self.snapshot_file(&self.create_snapshot()).save(path)
+    }
+
+    /// Write the stored snapshot `id` to `path`, along with the fork's block env
+    pub fn export_snapshot(&self, id: U256, path: impl AsRef<Path>) -> DatabaseResult<()> {
+        let snapshots = self.snapshots().lock();
+        let snapshot = snapshots
+            .get(id)
+            .ok_or_else(|| DatabaseError::msg(format!("No snapshot {}", id)))?;
+        self.snapshot_file(snapshot).save(path)
+    }
+
+    fn snapshot_file(&self, snapshot: &ForkDbSnapshot) -> SnapshotFile {
+        SnapshotFile {
+            meta: self.db.meta().read().clone(),
+            remote: snapshot.snapshot.clone(),
+            local: LocalState::from_cache_db(&snapshot.local),
+        }
+    }
+
+    /// Replace the fork's state and block env by the snapshot at `path`
+    ///
+    /// The backend is pinned to the snapshot's block, so state missing from the snapshot is
+    /// fetched at that block. Stored snapshots are discarded.
+    pub fn load_snapshot(&mut self, path: impl AsRef<Path>) -> DatabaseResult<()> {
+        let SnapshotFile {
+            meta,
+            remote,
+            local,
+        } = SnapshotFile::load(path)?;
+
+        self.backend
+            .set_pinned_block(meta.block_env.number.as_limbs()[0])
+            .map_err(|err| DatabaseError::msg(err.to_string()))?;
+        {
+            let mut current = self.db.meta().write();
+            current.cfg_env = meta.cfg_env;
+            current.block_env = meta.block_env;
+        }
+        self.restore_remote(remote);
+
+        let mut cache_db = CacheDB::new(self.backend.clone());
+        local.restore(&mut cache_db);
+        self.cache_db = cache_db;
+        *self.snapshots.lock() = Default::default();
+        Ok(())
+    }
+
+    /// Fork replaying the snapshot at `path`, without a remote client
+    ///
+    /// Reading state the snapshot doesn't hold fails instead of reaching the network, so
+    /// replays are deterministic.
+    pub fn from_snapshot_file(path: impl AsRef<Path>) -> DatabaseResult<Self> {
+        let SnapshotFile {
+            meta,
+            remote,
+            local,
+        } = SnapshotFile::load(path)?;
+
+        let pin_block = BlockId::from(meta.block_env.number.as_limbs()[0]);
+        let db = BlockchainDb::new(meta, None);
+        let provider = Provider::new(MockProvider::new());
+        let backend = SharedBackend::spawn_backend_thread(provider, db.clone(), Some(pin_block));
+
+        let mut fork = Self::new(backend, db);
+        fork.restore_remote(remote);
+        local.restore(fork.database_mut());
+        Ok(fork)
+    }
 }
 
 impl Database for ForkedDatabase {
@@ -201,6 +375,61 @@ impl DatabaseCommit for ForkedDatabase {
     }
 }
 
+/// Env of a mined block
+pub(crate) fn block_env<TX>(block: &Block<TX>) -> BlockEnv {
+    BlockEnv {
+        number: rU256::from(block.number.unwrap_or_default().as_u64()),
+        coinbase: h160_to_b160(block.author.unwrap_or_default()),
+        timestamp: u256_to_ru256(block.timestamp),
+        difficulty: u256_to_ru256(block.difficulty),
+        prevrandao: block.mix_hash.map(h256_to_b256),
+        basefee: u256_to_ru256(block.base_fee_per_gas.unwrap_or_default()),
+        gas_limit: u256_to_ru256(block.gas_limit),
+    }
+}
+
+/// Env of a mined tx
+fn tx_env(tx: &Transaction) -> TxEnv {
+    // 1559 txs report their effective gas price, they're executed with their fee caps
+    let (gas_price, gas_priority_fee) = match tx.max_fee_per_gas {
+        Some(max_fee) => (max_fee, tx.max_priority_fee_per_gas),
+        None => (tx.gas_price.unwrap_or_default(), None),
+    };
+
+    TxEnv {
+        caller: h160_to_b160(tx.from),
+        gas_limit: tx.gas.as_u64(),
+        gas_price: u256_to_ru256(gas_price),
+        gas_priority_fee: gas_priority_fee.map(u256_to_ru256),
+        transact_to: match tx.to {
+            Some(to) => TransactTo::Call(h160_to_b160(to)),
+            None => TransactTo::create(),
+        },
+        value: u256_to_ru256(tx.value),
+        data: tx.input.0.clone(),
+        chain_id: tx.chain_id.map(|id| id.as_u64()),
+        nonce: Some(tx.nonce.as_u64()),
+        access_list: tx
+            .access_list
+            .as_ref()
+            .map(|list| {
+                list.0
+                    .iter()
+                    .map(|item| {
+                        (
+                            h160_to_b160(item.address),
+                            item.storage_keys
+                                .iter()
+                                .map(|key| rU256::from_be_bytes(key.0))
+                                .collect(),
+                        )
+                    })
+                    .collect()
+            })
+            .unwrap_or_default(),
+    }
+}
+
 /// Represents a snapshot of the database
 ///
 /// This mimics `revm::CacheDB`
@@ -324,3 +553,114 @@ impl<T> Default for Snapshots<T> {
         }
     }
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use crate::blockchain_db::BlockchainDbMeta;
+    use ethers::types::{AccountDiff, Address, ChangedType, Diff, H256, U64};
+    use std::collections::{BTreeMap, BTreeSet};
+
+    /// Fork at block 1 of a backend without a remote client, holding `accounts`
+    pub(crate) fn offline_fork(accounts: &[(Address, u64)]) -> ForkedDatabase {
+        let mut meta = BlockchainDbMeta {
+            cfg_env: Default::default(),
+            block_env: Default::default(),
+            hosts: BTreeSet::new(),
+        };
+        meta.block_env.number = rU256::from(1u64);
+        let db = BlockchainDb::new(meta, None);
+        for (address, balance) in accounts {
+            db.db().do_insert_account(
+                h160_to_b160(*address),
+                AccountInfo {
+                    balance: rU256::from(*balance),
+                    ..Default::default()
+                },
+            );
+        }
+        let provider = Provider::new(MockProvider::new());
+        let backend =
+            SharedBackend::spawn_backend_thread(provider, db.clone(), Some(BlockId::from(1u64)));
+        ForkedDatabase::new(backend, db)
+    }
+
+    fn block(number: u64, transactions: Vec<Transaction>) -> Block<Transaction> {
+        Block {
+            number: Some(U64::from(number)),
+            hash: Some(H256::random()),
+            gas_limit: 30_000_000u64.into(),
+            transactions,
+            ..Default::default()
+        }
+    }
+
+    fn balance(fork: &ForkedDatabase, address: Address) -> rU256 {
+        fork.inner().db().accounts.read()[&h160_to_b160(address)].balance
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_advance_to_state_diffs() {
+        let account = Address::random();
+        let mut fork = offline_fork(&[(account, 10)]);
+        let snapshot = fork.insert_snapshot();
+
+        // only the fork's child can be applied
+        assert!(fork
+            .advance_to(&block(3, vec![]), BlockStateSource::Reexecute)
+            .is_err());
+
+        let diff = StateDiff(BTreeMap::from([(
+            account,
+            AccountDiff {
+                balance: Diff::Changed(ChangedType {
+                    from: U256::from(10),
+                    to: U256::from(25),
+                }),
+                nonce: Diff::Same,
+                code: Diff::Same,
+                storage: BTreeMap::new(),
+            },
+        )]));
+        let next = block(2, vec![]);
+        fork.advance_to(&next, BlockStateSource::StateDiffs(vec![diff]))
+            .unwrap();
+
+        assert_eq!(balance(&fork, account), rU256::from(25u64));
+        assert_eq!(
+            fork.inner().meta().read().block_env.number,
+            rU256::from(2u64)
+        );
+        assert_eq!(
+            fork.inner().block_hashes().read()[&rU256::from(2u64)],
+            h256_to_b256(next.hash.unwrap())
+        );
+        // snapshots were taken against the previous block
+        assert!(fork.snapshots().lock().get(snapshot).is_none());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_advance_to_reexecute() {
+        let (sender, receiver, coinbase) = (Address::random(), Address::random(), Address::zero());
+        let mut fork = offline_fork(&[(sender, 1_000), (receiver, 0), (coinbase, 1)]);
+
+        let transfer = Transaction {
+            from: sender,
+            to: Some(receiver),
+            value: U256::from(400),
+            gas: U256::from(21_000),
+            gas_price: Some(U256::zero()),
+            ..Default::default()
+        };
+        fork.advance_to(&block(2, vec![transfer]), BlockStateSource::Reexecute)
+            .unwrap();
+
+        assert_eq!(balance(&fork, sender), rU256::from(600u64));
+        assert_eq!(balance(&fork, receiver), rU256::from(400u64));
+        assert_eq!(balance(&fork, coinbase), rU256::from(1u64));
+        assert_eq!(
+            fork.inner().db().accounts.read()[&h160_to_b160(sender)].nonce,
+            1
+        );
+    }
+}
@@ -1,49 +1,29 @@
 pub mod backend_handler;
+pub mod binary_cache;
+pub mod block_changes;
 pub mod blockchain_db;
+pub mod bundle_simulator;
 pub mod errors;
+pub mod fork_builder;
 pub mod forked_db;
+pub mod prefetch;
+pub mod proof;
 pub mod shared_backend;
 pub mod snapshot;
 pub mod utils;
 
-use crate::blockchain_db::{BlockchainDb, BlockchainDbMeta};
-use crate::forked_db::ForkedDatabase;
-use crate::shared_backend::SharedBackend;
-use ethers::providers::{Middleware, Provider, Ws};
-use foundry_config::Config;
-use foundry_evm::executor::opts::EvmOpts;
-use std::{collections::BTreeSet, sync::Arc};
-
-/// Setup forked database
-pub async fn setup_fork_db(provider: Arc<Provider<Ws>>, http_url: String) -> ForkedDatabase {
-    let block_num = provider.get_block_number().await.unwrap();
-    let config = Config::figment();
-    let mut evm_opts = config.extract::<EvmOpts>().unwrap();
-    evm_opts.fork_block_number = Some(block_num.as_u64().clone());
-
-    let (env, _block) = evm_opts.fork_evm_env(http_url.clone()).await.unwrap();
-
-    let meta = BlockchainDbMeta {
-        cfg_env: env.cfg,
-        block_env: env.block,
-        hosts: BTreeSet::from([http_url.clone()]),
-    };
-
-    let db = BlockchainDb::new(meta, None);
-    let backend = SharedBackend::spawn_backend(provider.clone(), db.clone(), None).await;
-
-    let forked_db = ForkedDatabase::new(backend.clone(), db.clone());
-
-    forked_db
-}
+pub use fork_builder::ForkDbBuilder;
 
 #[cfg(test)]
 mod tests {
     use crate::blockchain_db::{BlockchainDb, BlockchainDbMeta, JsonBlockCacheDB};
     use crate::forked_db::ForkedDatabase;
     use crate::shared_backend::SharedBackend;
+    use crate::snapshot::{
+        LocalAccount, LocalAccountState, LocalState, SnapshotFile, StateSnapshot,
+    };
     use revm::db::{DatabaseCommit, DatabaseRef};
-    use revm::primitives::{Account, B160, U256 as rU256};
+    use revm::primitives::{Account, AccountInfo, B160, U256 as rU256};
 
     use ethers::providers::{Http, Middleware, Provider, Ws};
     use ethers::types::U64;
@@ -211,4 +191,126 @@ mod tests {
         // test reset
         assert_eq!(cleared_account.read().is_empty(), true);
     }
+
+    /// A path in the temp dir unique to the test and the test process
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("qilin_{}_{}.json", name, std::process::id()))
+    }
+
+    /// Snapshot file of block 17M holding a cached account and a locally modified one
+    fn write_snapshot_file(path: &PathBuf, cached: B160, modified: B160) {
+        let mut meta = BlockchainDbMeta {
+            cfg_env: Default::default(),
+            block_env: Default::default(),
+            hosts: BTreeSet::new(),
+        };
+        meta.block_env.number = rU256::from(17_000_000u64);
+        let mut remote = StateSnapshot::default();
+        remote.accounts.insert(
+            cached,
+            AccountInfo {
+                balance: rU256::from(5u64),
+                ..Default::default()
+            },
+        );
+        let mut local = LocalState::default();
+        local.accounts.insert(
+            modified,
+            LocalAccount {
+                info: AccountInfo {
+                    nonce: 7,
+                    ..Default::default()
+                },
+                state: LocalAccountState::Touched,
+                storage: Map::from([(rU256::from(1u64), rU256::from(2u64))]),
+            },
+        );
+
+        SnapshotFile {
+            meta,
+            remote,
+            local,
+        }
+        .save(path)
+        .unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_snapshot_file_replay() {
+        let (cached, modified) = (B160::random(), B160::random());
+        let path = temp_path("snapshot_replay");
+        write_snapshot_file(&path, cached, modified);
+
+        let fork = ForkedDatabase::from_snapshot_file(&path).unwrap();
+        assert_eq!(
+            fork.basic(cached).unwrap().unwrap().balance,
+            rU256::from(5u64)
+        );
+        assert_eq!(fork.basic(modified).unwrap().unwrap().nonce, 7);
+        assert_eq!(
+            fork.storage(modified, rU256::from(1u64)).unwrap(),
+            rU256::from(2u64)
+        );
+        // state missing from the snapshot isn't fetched
+        assert!(fork.basic(B160::random()).is_err());
+
+        // saving the replayed fork gives back the same state
+        let copy = temp_path("snapshot_replay_copy");
+        fork.save_snapshot(&copy).unwrap();
+        let saved = SnapshotFile::load(&copy).unwrap();
+        assert_eq!(saved.meta.block_env.number, rU256::from(17_000_000u64));
+        assert_eq!(saved.remote.accounts[&cached].balance, rU256::from(5u64));
+        assert_eq!(saved.local.accounts[&modified].info.nonce, 7);
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&copy);
+    }
+
+    // `load_snapshot` blocks until the backend is pinned, which needs a multi threaded runtime
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_export_load_snapshot_roundtrip() {
+        let (cached, modified, created) = (B160::random(), B160::random(), B160::random());
+        let path = temp_path("snapshot_roundtrip");
+        write_snapshot_file(&path, cached, modified);
+
+        let account = |balance: u64| AccountInfo {
+            balance: rU256::from(balance),
+            ..Default::default()
+        };
+        let mut fork = ForkedDatabase::from_snapshot_file(&path).unwrap();
+        fork.database_mut().insert_account_info(created, account(9));
+        let id = fork.insert_snapshot();
+        // changes made after the snapshot aren't exported
+        fork.database_mut()
+            .insert_account_info(created, account(10));
+        fork.database_mut()
+            .insert_account_info(B160::random(), account(11));
+
+        let exported = temp_path("snapshot_roundtrip_export");
+        fork.export_snapshot(id, &exported).unwrap();
+        assert!(fork.export_snapshot(id + 1, &exported).is_err());
+
+        let mut other = ForkedDatabase::from_snapshot_file(&path).unwrap();
+        other.load_snapshot(&exported).unwrap();
+        assert_eq!(
+            other.inner().meta().read().block_env.number,
+            rU256::from(17_000_000u64)
+        );
+        assert_eq!(
+            other.basic(cached).unwrap().unwrap().balance,
+            rU256::from(5u64)
+        );
+        assert_eq!(other.basic(modified).unwrap().unwrap().nonce, 7);
+        assert_eq!(
+            other.storage(modified, rU256::from(1u64)).unwrap(),
+            rU256::from(2u64)
+        );
+        assert_eq!(
+            other.basic(created).unwrap().unwrap().balance,
+            rU256::from(9u64)
+        );
+
+        let _ = std::fs::remove_file(&path);
+        let _ = std::fs::remove_file(&exported);
+    }
 }
@@ -0,0 +1,428 @@
+use super::{
+    blockchain_db::BlockchainDb,
+    utils::{h160_to_b160, h256_to_u256_be, u256_to_h256_be, u256_to_ru256},
+};
+use ethers::{
+    providers::Middleware,
+    types::{
+        transaction::{eip2718::TypedTransaction, eip2930::AccessList},
+        Address, BlockId, BlockNumber, Bytes, NameOrAddress, StateDiff, U256,
+    },
+    utils::keccak256,
+};
+use futures::future::try_join_all;
+use revm::primitives::{AccountInfo, Bytecode, KECCAK_EMPTY};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, BTreeSet};
+use url::Url;
+
+/// Accounts and storage slots to load into the cache before a simulation
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PrefetchList {
+    pub accounts: BTreeSet<Address>,
+    pub storage: BTreeMap<Address, BTreeSet<U256>>,
+}
+
+impl PrefetchList {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Accounts and slots of an EIP-2930 access list
+    pub fn from_access_list(access_list: &AccessList) -> Self {
+        access_list.0.iter().fold(Self::new(), |list, item| {
+            item.storage_keys
+                .iter()
+                .fold(list.with_account(item.address), |list, key| {
+                    list.with_slot(item.address, h256_to_u256_be(*key))
+                })
+        })
+    }
+
+    /// Accounts and slots touched by a tx, from its `stateDiff`
+    pub fn from_state_diff(diff: &StateDiff) -> Self {
+        diff.0.iter().fold(Self::new(), |list, (address, account)| {
+            account
+                .storage
+                .keys()
+                .fold(list.with_account(*address), |list, key| {
+                    list.with_slot(*address, h256_to_u256_be(*key))
+                })
+        })
+    }
+
+    /// Sender, recipient and access list of a tx
+    pub fn from_transaction(tx: &TypedTransaction, from: Address) -> Self {
+        let mut list = Self::new().with_account(from);
+        if let Some(NameOrAddress::Address(to)) = tx.to() {
+            list = list.with_account(*to);
+        }
+        if let Some(access_list) = tx.access_list() {
+            list.extend(Self::from_access_list(access_list));
+        }
+        list
+    }
+
+    pub fn with_account(mut self, address: Address) -> Self {
+        self.accounts.insert(address);
+        self
+    }
+
+    pub fn with_slot(mut self, address: Address, slot: U256) -> Self {
+        self.storage.entry(address).or_default().insert(slot);
+        self
+    }
+
+    pub fn extend(&mut self, other: PrefetchList) {
+        self.accounts.extend(other.accounts);
+        for (address, slots) in other.storage {
+            self.storage.entry(address).or_default().extend(slots);
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.accounts.is_empty() && self.storage.is_empty()
+    }
+
+    /// Number of accounts and slots in the list
+    pub fn len(&self) -> usize {
+        self.accounts.len()
+            + self
+                .storage
+                .values()
+                .map(|slots| slots.len())
+                .sum::<usize>()
+    }
+
+    /// Drop the accounts and slots already in the cache
+    pub(crate) fn retain_missing(&mut self, db: &BlockchainDb) {
+        let accounts = db.accounts().read();
+        self.accounts
+            .retain(|address| !accounts.contains_key(&h160_to_b160(*address)));
+
+        let storage = db.storage().read();
+        self.storage.retain(|address, slots| {
+            if let Some(cached) = storage.get(&h160_to_b160(*address)) {
+                slots.retain(|slot| !cached.contains_key(&u256_to_ru256(*slot)));
+            }
+            !slots.is_empty()
+        });
+    }
+}
+
+/// Accounts and slots loaded by a prefetch
+#[derive(Debug, Default)]
+pub(crate) struct PrefetchedState {
+    pub accounts: Vec<(Address, AccountInfo)>,
+    pub storage: Vec<(Address, U256, U256)>,
+}
+
+/// Sends the calls of a prefetch as a single JSON-RPC batch over HTTP
+///
+/// The [Middleware] API has no batching, without this client a prefetch is sent as one call per
+/// account field and slot, concurrently. The batch is posted to its own URL rather than through
+/// the provider's transport, so it needs an HTTP endpoint of the same node or chain.
+#[derive(Debug, Clone)]
+pub struct BatchClient {
+    client: reqwest::Client,
+    url: Url,
+}
+
+#[derive(Debug, Deserialize)]
+struct BatchResponse {
+    id: usize,
+    result: Option<Value>,
+    error: Option<Value>,
+}
+
+impl BatchClient {
+    pub fn new(url: Url) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+
+    /// Fetch the accounts and slots of `list` at `block` in one request
+    pub(crate) async fn fetch(
+        &self,
+        list: &PrefetchList,
+        block: Option<BlockId>,
+    ) -> eyre::Result<PrefetchedState> {
+        let block = serde_json::to_value(block.unwrap_or_else(|| BlockNumber::Latest.into()))?;
+
+        let mut calls = Vec::with_capacity(list.len() + list.accounts.len() * 2);
+        let mut call = |method: &str, params: Value| {
+            calls.push(json!({
+                "jsonrpc": "2.0",
+                "id": calls.len(),
+                "method": method,
+                "params": params,
+            }));
+        };
+        for address in &list.accounts {
+            call("eth_getBalance", json!([address, block]));
+            call("eth_getTransactionCount", json!([address, block]));
+            call("eth_getCode", json!([address, block]));
+        }
+        for (address, slots) in &list.storage {
+            for slot in slots {
+                call(
+                    "eth_getStorageAt",
+                    json!([address, u256_to_h256_be(*slot), block]),
+                );
+            }
+        }
+
+        let responses: Vec<BatchResponse> = self
+            .client
+            .post(self.url.clone())
+            .json(&calls)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+
+        let mut results: Vec<Option<Value>> = vec![None; calls.len()];
+        for response in responses {
+            if let Some(error) = response.error {
+                eyre::bail!("batch call {} failed: {}", response.id, error);
+            }
+            if let Some(slot) = results.get_mut(response.id) {
+                *slot = response.result;
+            }
+        }
+        let mut results = results.into_iter().enumerate();
+        let mut next = || -> eyre::Result<Value> {
+            match results.next() {
+                Some((_, Some(value))) => Ok(value),
+                Some((id, None)) => eyre::bail!("batch call {} has no result", id),
+                None => eyre::bail!("batch returned fewer results than calls"),
+            }
+        };
+
+        let mut state = PrefetchedState::default();
+        for address in &list.accounts {
+            let balance: U256 = decode(next()?)?;
+            let nonce: U256 = decode(next()?)?;
+            let code: Bytes = decode(next()?)?;
+            state
+                .accounts
+                .push((*address, account_info(balance, nonce, code)));
+        }
+        for (address, slots) in &list.storage {
+            for slot in slots {
+                let value = h256_to_u256_be(decode(next()?)?);
+                state.storage.push((*address, *slot, value));
+            }
+        }
+        Ok(state)
+    }
+}
+
+fn decode<T: DeserializeOwned>(value: Value) -> eyre::Result<T> {
+    Ok(serde_json::from_value(value)?)
+}
+
+/// Fetch the accounts and slots of `list` at `block` with concurrent calls to `provider`
+pub(crate) async fn fetch_concurrently<M: Middleware>(
+    provider: M,
+    list: &PrefetchList,
+    block: Option<BlockId>,
+) -> Result<PrefetchedState, M::Error> {
+    let accounts = try_join_all(list.accounts.iter().map(|address| {
+        let provider = &provider;
+        async move {
+            let (balance, nonce, code) = tokio::try_join!(
+                provider.get_balance(*address, block),
+                provider.get_transaction_count(*address, block),
+                provider.get_code(*address, block),
+            )?;
+            Ok::<_, M::Error>((*address, account_info(balance, nonce, code)))
+        }
+    }));
+    let storage = try_join_all(list.storage.iter().flat_map(|(address, slots)| {
+        let provider = &provider;
+        slots.iter().map(move |slot| async move {
+            let value = provider
+                .get_storage_at(*address, u256_to_h256_be(*slot), block)
+                .await?;
+            Ok::<_, M::Error>((*address, *slot, h256_to_u256_be(value)))
+        })
+    }));
+
+    let (accounts, storage) = futures::try_join!(accounts, storage)?;
+    Ok(PrefetchedState { accounts, storage })
+}
+
+/// Converts the fetched fields of an account to revm's type
+pub(crate) fn account_info(balance: U256, nonce: U256, code: Bytes) -> AccountInfo {
+    let code_hash = if code.0.is_empty() {
+        KECCAK_EMPTY
+    } else {
+        keccak256(&code).into()
+    };
+
+    AccountInfo {
+        nonce: nonce.as_u64(),
+        balance: balance.into(),
+        code: Some(Bytecode::new_raw(code.0).to_checked()),
+        code_hash,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::blockchain_db::BlockchainDbMeta;
+    use ethers::types::{
+        transaction::eip2930::AccessListItem, AccountDiff, ChangedType, Diff, H256,
+    };
+    use std::sync::{Arc, Mutex};
+    use test_utils::{MockServer, ReceivedRequest};
+
+    /// Serve one JSON-RPC batch on a local socket, answering with `response`
+    async fn spawn_node(response: Value) -> (Url, Arc<Mutex<Option<ReceivedRequest>>>) {
+        let (url, received) = MockServer::json(response).spawn().await;
+        (Url::parse(&url).unwrap(), received)
+    }
+
+    #[tokio::test]
+    async fn test_batch_client_fetch() {
+        let address = Address::from_low_u64_be(0xaa);
+        let list = PrefetchList::new()
+            .with_account(address)
+            .with_slot(address, U256::from(1))
+            .with_slot(address, U256::from(2));
+        let word = |n: u64| json!(H256::from_low_u64_be(n));
+
+        // answered out of order
+        let (url, received) = spawn_node(json!([
+            {"jsonrpc": "2.0", "id": 4, "result": word(22)},
+            {"jsonrpc": "2.0", "id": 2, "result": "0x6000"},
+            {"jsonrpc": "2.0", "id": 0, "result": "0x64"},
+            {"jsonrpc": "2.0", "id": 3, "result": word(11)},
+            {"jsonrpc": "2.0", "id": 1, "result": "0x7"},
+        ]))
+        .await;
+        let state = BatchClient::new(url)
+            .fetch(&list, Some(BlockId::from(17_000_000u64)))
+            .await
+            .unwrap();
+
+        let request = received.lock().unwrap().clone().unwrap();
+        let calls: Value = serde_json::from_str(&request.body).unwrap();
+        let calls = calls.as_array().unwrap();
+        assert_eq!(
+            calls
+                .iter()
+                .map(|call| (
+                    call["id"].as_u64().unwrap(),
+                    call["method"].as_str().unwrap()
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (0, "eth_getBalance"),
+                (1, "eth_getTransactionCount"),
+                (2, "eth_getCode"),
+                (3, "eth_getStorageAt"),
+                (4, "eth_getStorageAt"),
+            ]
+        );
+        assert_eq!(calls[0]["params"], json!([address, "0x1036640"]));
+        assert_eq!(calls[4]["params"][1], word(2));
+
+        let (fetched, account) = &state.accounts[0];
+        assert_eq!(*fetched, address);
+        assert_eq!(account.balance, revm::primitives::U256::from(100u64));
+        assert_eq!(account.nonce, 7);
+        assert_eq!(
+            account.code_hash,
+            revm::primitives::B256::from(keccak256([0x60u8, 0x00]))
+        );
+        assert_eq!(
+            state.storage,
+            vec![
+                (address, U256::from(1), U256::from(11)),
+                (address, U256::from(2), U256::from(22)),
+            ]
+        );
+
+        // a call left unanswered fails the prefetch
+        let (url, _) = spawn_node(json!([
+            {"jsonrpc": "2.0", "id": 0, "result": "0x64"},
+            {"jsonrpc": "2.0", "id": 1, "result": "0x7"},
+            {"jsonrpc": "2.0", "id": 2, "result": "0x"},
+            {"jsonrpc": "2.0", "id": 4, "result": word(22)},
+        ]))
+        .await;
+        let err = BatchClient::new(url).fetch(&list, None).await.unwrap_err();
+        assert!(err.to_string().contains("batch call 3 has no result"));
+
+        // so does a failed call
+        let (url, _) = spawn_node(json!([
+            {"jsonrpc": "2.0", "id": 0, "result": "0x64"},
+            {"jsonrpc": "2.0", "id": 1, "error": {"code": -32000, "message": "header not found"}},
+        ]))
+        .await;
+        let err = BatchClient::new(url).fetch(&list, None).await.unwrap_err();
+        assert!(err.to_string().contains("batch call 1 failed"));
+    }
+
+    #[test]
+    fn test_prefetch_list() {
+        let (a, b) = (Address::random(), Address::random());
+        let key = H256::from_low_u64_be;
+
+        let mut list = PrefetchList::from_access_list(&AccessList(vec![AccessListItem {
+            address: a,
+            storage_keys: vec![key(1), key(2)],
+        }]));
+        list.extend(PrefetchList::from_state_diff(&StateDiff(BTreeMap::from([
+            (
+                b,
+                AccountDiff {
+                    balance: Diff::Same,
+                    nonce: Diff::Same,
+                    code: Diff::Same,
+                    storage: BTreeMap::from([(
+                        key(3),
+                        Diff::Changed(ChangedType {
+                            from: key(0),
+                            to: key(1),
+                        }),
+                    )]),
+                },
+            ),
+        ]))));
+        assert_eq!(list.len(), 5);
+
+        let db = BlockchainDb::new(
+            BlockchainDbMeta {
+                cfg_env: Default::default(),
+                block_env: Default::default(),
+                hosts: Default::default(),
+            },
+            None,
+        );
+        db.db()
+            .do_insert_account(h160_to_b160(b), AccountInfo::default());
+        db.storage()
+            .write()
+            .entry(h160_to_b160(a))
+            .or_default()
+            .insert(revm::primitives::U256::from(1u64), Default::default());
+
+        // only what's not cached is left to fetch
+        list.retain_missing(&db);
+        assert_eq!(
+            list,
+            PrefetchList::new()
+                .with_account(a)
+                .with_slot(a, U256::from(2))
+                .with_slot(b, U256::from(3))
+        );
+    }
+}
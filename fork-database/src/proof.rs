@@ -0,0 +1,534 @@
+use super::{
+    errors::{DatabaseError, DatabaseResult},
+    prefetch::{account_info, PrefetchList, PrefetchedState},
+    utils::{b256_to_h256, u256_to_h256_be},
+};
+use ethers::{
+    providers::Middleware,
+    types::{Address, BlockId, BlockNumber, Bytes, EIP1186ProofResponse, StorageProof, H256, U256},
+    utils::{
+        keccak256,
+        rlp::{DecoderError, Rlp},
+    },
+};
+use futures::future::try_join_all;
+use parking_lot::Mutex;
+use revm::primitives::{AccountInfo, KECCAK_EMPTY};
+use std::{collections::BTreeSet, sync::Arc};
+use thiserror::Error;
+
+/// Root of an empty trie, `keccak256(rlp(""))`
+pub const EMPTY_ROOT: H256 = H256([
+    0x56, 0xe8, 0x1f, 0x17, 0x1b, 0xcc, 0x55, 0xa6, 0xff, 0x83, 0x45, 0xe6, 0x92, 0xc0, 0xf8, 0x6e,
+    0x5b, 0x48, 0xe0, 0x1b, 0x99, 0x6c, 0xad, 0xc0, 0x01, 0x62, 0x2f, 0xb5, 0xe3, 0x63, 0xb4, 0x21,
+]);
+
+/// Why an `eth_getProof` response doesn't prove the returned state
+#[derive(Error, Debug, Clone, PartialEq, Eq)]
+pub enum ProofError {
+    #[error("proof node {index} doesn't hash to {expected:?}")]
+    HashMismatch { index: usize, expected: H256 },
+    #[error("proof ends before reaching the value")]
+    Incomplete,
+    #[error("proof node {index} is malformed: {reason}")]
+    InvalidNode { index: usize, reason: String },
+    #[error("proven {field} {proven} doesn't match the returned {returned}")]
+    ValueMismatch {
+        field: &'static str,
+        proven: String,
+        returned: String,
+    },
+    #[error("no storage proof was returned")]
+    MissingStorageProof,
+}
+
+/// Check the account fields of an `eth_getProof` response against the state root of its block
+///
+/// Arguments:
+/// * `state_root`: state root of the block the proof was requested at
+/// * `address`: the requested account, the proof's own `address` is ignored
+/// * `proof`: response of `eth_getProof`
+///
+/// Returns:
+/// Ok(()): if nonce, balance, storage hash and code hash are the ones in the state trie
+/// Err(ProofError): if the proof is invalid or proves other values
+pub fn verify_account(
+    state_root: H256,
+    address: Address,
+    proof: &EIP1186ProofResponse,
+) -> Result<(), ProofError> {
+    let leaf = verify_proof(state_root, &keccak256(address), &proof.account_proof)?;
+    let (nonce, balance, storage_hash, code_hash) = match leaf {
+        Some(leaf) => {
+            let invalid = |err: DecoderError| ProofError::InvalidNode {
+                index: proof.account_proof.len() - 1,
+                reason: err.to_string(),
+            };
+            let account = Rlp::new(&leaf);
+            (
+                account.val_at::<U256>(0).map_err(invalid)?,
+                account.val_at::<U256>(1).map_err(invalid)?,
+                account.val_at::<H256>(2).map_err(invalid)?,
+                account.val_at::<H256>(3).map_err(invalid)?,
+            )
+        }
+        // the account doesn't exist
+        None => (
+            U256::zero(),
+            U256::zero(),
+            EMPTY_ROOT,
+            b256_to_h256(KECCAK_EMPTY),
+        ),
+    };
+
+    // nodes report the hashes of missing accounts as zero
+    let or = |hash: H256, empty: H256| if hash.is_zero() { empty } else { hash };
+    check("nonce", nonce, U256::from(proof.nonce.as_u64()))?;
+    check("balance", balance, proof.balance)?;
+    check(
+        "storage hash",
+        storage_hash,
+        or(proof.storage_hash, EMPTY_ROOT),
+    )?;
+    check(
+        "code hash",
+        code_hash,
+        or(proof.code_hash, b256_to_h256(KECCAK_EMPTY)),
+    )
+}
+
+/// Check a storage proof against the storage root of a verified account
+///
+/// Arguments:
+/// * `storage_root`: the account's `storageHash`, checked with [verify_account]
+/// * `slot`: the requested slot, the proof's own `key` is ignored
+/// * `proof`: the slot's entry of the `eth_getProof` response
+pub fn verify_storage(
+    storage_root: H256,
+    slot: U256,
+    proof: &StorageProof,
+) -> Result<(), ProofError> {
+    let leaf = verify_proof(
+        storage_root,
+        &keccak256(u256_to_h256_be(slot)),
+        &proof.proof,
+    )?;
+    let value = match leaf {
+        Some(leaf) => Rlp::new(&leaf)
+            .as_val::<U256>()
+            .map_err(|err| ProofError::InvalidNode {
+                index: proof.proof.len() - 1,
+                reason: err.to_string(),
+            })?,
+        // unset slots are zero
+        None => U256::zero(),
+    };
+    check("storage value", value, proof.value)
+}
+
+/// Storage root of a verified account, nodes report the one of missing accounts as zero
+pub fn storage_root(proof: &EIP1186ProofResponse) -> H256 {
+    if proof.storage_hash.is_zero() {
+        EMPTY_ROOT
+    } else {
+        proof.storage_hash
+    }
+}
+
+/// Check the code returned for an account against its verified code hash
+pub fn verify_code(code_hash: H256, code: &Bytes) -> Result<(), ProofError> {
+    if code.is_empty() && (code_hash.is_zero() || code_hash == b256_to_h256(KECCAK_EMPTY)) {
+        return Ok(());
+    }
+    check("code hash", code_hash, H256(keccak256(code)))
+}
+
+fn check<T: PartialEq + std::fmt::Debug>(
+    field: &'static str,
+    proven: T,
+    returned: T,
+) -> Result<(), ProofError> {
+    if proven == returned {
+        return Ok(());
+    }
+    Err(ProofError::ValueMismatch {
+        field,
+        proven: format!("{:?}", proven),
+        returned: format!("{:?}", returned),
+    })
+}
+
+/// State root of the block the backend is pinned at, fetched once per pinned block
+pub(crate) type StateRootCache = Arc<Mutex<Option<(BlockId, H256)>>>;
+
+/// Account fields and slots proven against the pinned block's state root
+#[derive(Debug, Clone)]
+pub(crate) struct VerifiedAccount {
+    pub address: Address,
+    pub balance: U256,
+    pub nonce: U256,
+    pub code_hash: H256,
+    pub storage: Vec<(U256, U256)>,
+}
+
+impl VerifiedAccount {
+    /// The account with its code, checked against the proven code hash
+    pub fn with_code(&self, code: Bytes) -> DatabaseResult<AccountInfo> {
+        verify_code(self.code_hash, &code)
+            .map_err(|reason| DatabaseError::InvalidProof(self.address, None, reason))?;
+        Ok(account_info(self.balance, self.nonce, code))
+    }
+}
+
+/// The pinned block, if it's one whose state can't change under the proofs
+fn pinned_block(block_id: Option<BlockId>) -> DatabaseResult<BlockId> {
+    match block_id {
+        Some(BlockId::Hash(hash)) => Ok(BlockId::Hash(hash)),
+        Some(BlockId::Number(BlockNumber::Number(number))) => Ok(number.into()),
+        // tags like `latest` move between the state root and the proof
+        block_id => Err(DatabaseError::UnpinnedProof(block_id)),
+    }
+}
+
+async fn pinned_state_root<M: Middleware + 'static>(
+    provider: &M,
+    block: BlockId,
+    cache: &StateRootCache,
+) -> DatabaseResult<H256> {
+    let cached = *cache.lock();
+    if let Some((pinned, root)) = cached {
+        if pinned == block {
+            return Ok(root);
+        }
+    }
+
+    let root = provider
+        .get_block(block)
+        .await
+        .map_err(|err| DatabaseError::GetFullBlock(block, Arc::new(eyre::Error::new(err))))?
+        .ok_or(DatabaseError::BlockNotFound(block))?
+        .state_root;
+    *cache.lock() = Some((block, root));
+    Ok(root)
+}
+
+/// Fetch an account and some of its slots with `eth_getProof`, checked against the state root
+/// of the pinned block
+///
+/// Arguments:
+/// * `slots`: slots to prove along with the account
+/// * `block_id`: the pinned block, proofs of a moving tag like `latest` can't be checked and
+///   are a [DatabaseError::UnpinnedProof]
+/// * `fetch_err`: error of a failed RPC call
+pub(crate) async fn get_verified_account<M: Middleware + 'static>(
+    provider: &M,
+    address: Address,
+    slots: &[U256],
+    block_id: Option<BlockId>,
+    state_root: &StateRootCache,
+    fetch_err: impl Fn(Arc<eyre::Error>) -> DatabaseError,
+) -> DatabaseResult<VerifiedAccount> {
+    let block = pinned_block(block_id)?;
+    let state_root = pinned_state_root(provider, block, state_root).await?;
+    let locations = slots.iter().map(|slot| u256_to_h256_be(*slot)).collect();
+    let proof = provider
+        .get_proof(address, locations, Some(block))
+        .await
+        .map_err(|err| fetch_err(Arc::new(eyre::Error::new(err))))?;
+
+    let invalid = |slot, reason| DatabaseError::InvalidProof(address, slot, reason);
+    verify_account(state_root, address, &proof).map_err(|reason| invalid(None, reason))?;
+
+    // slots are proven in the order they were requested
+    let mut storage = Vec::with_capacity(slots.len());
+    for (i, slot) in slots.iter().enumerate() {
+        let slot_proof = proof
+            .storage_proof
+            .get(i)
+            .ok_or_else(|| invalid(Some(*slot), ProofError::MissingStorageProof))?;
+        verify_storage(storage_root(&proof), *slot, slot_proof)
+            .map_err(|reason| invalid(Some(*slot), reason))?;
+        storage.push((*slot, slot_proof.value));
+    }
+
+    Ok(VerifiedAccount {
+        address,
+        balance: proof.balance,
+        nonce: U256::from(proof.nonce.as_u64()),
+        code_hash: proof.code_hash,
+        storage,
+    })
+}
+
+/// Prefetch the accounts and slots of `list` with one `eth_getProof` per account
+pub(crate) async fn fetch_verified<M: Middleware + 'static>(
+    provider: &M,
+    list: &PrefetchList,
+    block_id: Option<BlockId>,
+    state_root: &StateRootCache,
+) -> DatabaseResult<PrefetchedState> {
+    let fetch_err = |err: Arc<eyre::Error>| DatabaseError::Prefetch(err);
+    let addresses: BTreeSet<Address> = list
+        .accounts
+        .iter()
+        .chain(list.storage.keys())
+        .copied()
+        .collect();
+
+    let accounts = try_join_all(addresses.into_iter().map(|address| async move {
+        let slots: Vec<U256> = list
+            .storage
+            .get(&address)
+            .map(|slots| slots.iter().copied().collect())
+            .unwrap_or_default();
+        let account =
+            get_verified_account(provider, address, &slots, block_id, state_root, fetch_err);
+        let code = async {
+            if !list.accounts.contains(&address) {
+                return Ok(None);
+            }
+            provider
+                .get_code(address, block_id)
+                .await
+                .map(Some)
+                .map_err(|err| fetch_err(Arc::new(eyre::Error::new(err))))
+        };
+
+        let (account, code) = futures::try_join!(account, code)?;
+        let info = code.map(|code| account.with_code(code)).transpose()?;
+        Ok::<_, DatabaseError>((address, info, account.storage))
+    }))
+    .await?;
+
+    let mut state = PrefetchedState::default();
+    for (address, info, storage) in accounts {
+        if let Some(info) = info {
+            state.accounts.push((address, info));
+        }
+        state.storage.extend(
+            storage
+                .into_iter()
+                .map(|(slot, value)| (address, slot, value)),
+        );
+    }
+    Ok(state)
+}
+
+/// Reference from a trie node to one of its children
+enum Child<'a> {
+    Hash(H256),
+    /// Nodes shorter than 32 bytes are embedded in their parent
+    Inline(&'a [u8]),
+}
+
+/// Walk a Merkle Patricia proof from `root` down the path of `key`
+///
+/// Returns the value stored at `key`, `None` if the proof shows there's none.
+fn verify_proof(root: H256, key: &[u8], proof: &[Bytes]) -> Result<Option<Vec<u8>>, ProofError> {
+    let nibbles: Vec<u8> = key.iter().flat_map(|b| [b >> 4, b & 0x0f]).collect();
+    let mut key = nibbles.as_slice();
+    let mut nodes = proof.iter().enumerate();
+    let mut index = 0;
+    let mut next = Child::Hash(root);
+
+    loop {
+        let node: &[u8] = match next {
+            Child::Hash(hash) => match nodes.next() {
+                Some((i, node)) => {
+                    index = i;
+                    if H256(keccak256(node)) != hash {
+                        return Err(ProofError::HashMismatch {
+                            index,
+                            expected: hash,
+                        });
+                    }
+                    node
+                }
+                // the proof of an empty trie has no nodes
+                None if hash == EMPTY_ROOT => return Ok(None),
+                None => return Err(ProofError::Incomplete),
+            },
+            Child::Inline(node) => node,
+        };
+
+        let invalid = |reason: String| ProofError::InvalidNode { index, reason };
+        let decoding = |err: DecoderError| invalid(err.to_string());
+        let node = Rlp::new(node);
+
+        match node.item_count().map_err(decoding)? {
+            // branch
+            17 => match key.split_first() {
+                Some((nibble, rest)) => {
+                    key = rest;
+                    next = match child(node.at(*nibble as usize).map_err(decoding)?)
+                        .map_err(decoding)?
+                    {
+                        Some(child) => child,
+                        None => return Ok(None),
+                    };
+                }
+                None => {
+                    let value = node.at(16).and_then(|v| v.data()).map_err(decoding)?;
+                    return Ok((!value.is_empty()).then(|| value.to_vec()));
+                }
+            },
+            // leaf or extension
+            2 => {
+                let path = node.at(0).and_then(|p| p.data()).map_err(decoding)?;
+                let (is_leaf, path) =
+                    decode_path(path).ok_or_else(|| invalid("invalid path".to_string()))?;
+                if is_leaf {
+                    if key != path.as_slice() {
+                        return Ok(None);
+                    }
+                    let value = node.at(1).and_then(|v| v.data()).map_err(decoding)?;
+                    return Ok(Some(value.to_vec()));
+                }
+                if !key.starts_with(&path) {
+                    return Ok(None);
+                }
+                key = &key[path.len()..];
+                next = child(node.at(1).map_err(decoding)?)
+                    .map_err(decoding)?
+                    .ok_or_else(|| invalid("extension without child".to_string()))?;
+            }
+            items => return Err(invalid(format!("{} items", items))),
+        }
+    }
+}
+
+fn child(item: Rlp<'_>) -> Result<Option<Child<'_>>, DecoderError> {
+    if item.is_list() {
+        return Ok(Some(Child::Inline(item.as_raw())));
+    }
+    match item.data()? {
+        [] => Ok(None),
+        hash if hash.len() == 32 => Ok(Some(Child::Hash(H256::from_slice(hash)))),
+        _ => Err(DecoderError::RlpInvalidLength),
+    }
+}
+
+/// Decode the hex-prefix encoded path of a leaf or extension, returns whether it's a leaf
+fn decode_path(path: &[u8]) -> Option<(bool, Vec<u8>)> {
+    let (first, rest) = path.split_first()?;
+    let is_leaf = match first >> 4 {
+        0 | 1 => false,
+        2 | 3 => true,
+        _ => return None,
+    };
+    let mut nibbles = Vec::with_capacity(rest.len() * 2 + 1);
+    // odd length paths keep their first nibble in the flag byte
+    if first >> 4 & 1 == 1 {
+        nibbles.push(first & 0x0f);
+    }
+    nibbles.extend(rest.iter().flat_map(|b| [b >> 4, b & 0x0f]));
+    Some((is_leaf, nibbles))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::providers::{Provider, Ws};
+
+    fn fixture() -> EIP1186ProofResponse {
+        serde_json::from_str(include_str!("test_data/proof.json")).unwrap()
+    }
+
+    #[test]
+    fn test_verify_proof_fixture() {
+        let proof = fixture();
+        // the fixture's block isn't recorded, its state root is the hash of the root node.
+        // `test_verify_against_block_header` checks proofs against a real header
+        let state_root = H256(keccak256(&proof.account_proof[0]));
+
+        verify_account(state_root, proof.address, &proof).unwrap();
+        // the fixture proves slot 0 is unset
+        let slot = &proof.storage_proof[0];
+        verify_storage(proof.storage_hash, U256::zero(), slot).unwrap();
+
+        let mut lying = proof.clone();
+        lying.balance += U256::one();
+        assert!(matches!(
+            verify_account(state_root, proof.address, &lying),
+            Err(ProofError::ValueMismatch {
+                field: "balance",
+                ..
+            })
+        ));
+
+        let mut lying = slot.clone();
+        lying.value = U256::from(7);
+        assert!(matches!(
+            verify_storage(proof.storage_hash, U256::zero(), &lying),
+            Err(ProofError::ValueMismatch { .. })
+        ));
+
+        // another state root or a tampered node break the chain of hashes
+        assert!(matches!(
+            verify_account(H256::random(), proof.address, &proof),
+            Err(ProofError::HashMismatch { index: 0, .. })
+        ));
+        let mut tampered = proof.clone();
+        let mut node = tampered.account_proof[1].to_vec();
+        node[5] ^= 1;
+        tampered.account_proof[1] = node.into();
+        assert!(matches!(
+            verify_account(state_root, proof.address, &tampered),
+            Err(ProofError::HashMismatch { index: 1, .. })
+        ));
+
+        assert!(verify_code(H256::zero(), &Bytes::default()).is_ok());
+        assert!(verify_code(proof.code_hash, &Bytes::from(vec![0x60])).is_err());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_verify_against_block_header() {
+        let provider = Provider::<Ws>::connect("wss://eth.llamarpc.com")
+            .await
+            .unwrap();
+        let number = provider.get_block_number().await.unwrap() - 5;
+        let header = provider.get_block(number).await.unwrap().unwrap();
+
+        // WETH and its `name` slot
+        let weth: Address = "0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
+            .parse()
+            .unwrap();
+        let proof = provider
+            .get_proof(weth, vec![H256::zero()], Some(number.into()))
+            .await
+            .unwrap();
+        verify_account(header.state_root, weth, &proof).unwrap();
+        verify_storage(storage_root(&proof), U256::zero(), &proof.storage_proof[0]).unwrap();
+
+        let cache = StateRootCache::default();
+        let account = get_verified_account(
+            &provider,
+            weth,
+            &[U256::zero()],
+            Some(number.into()),
+            &cache,
+            DatabaseError::Prefetch,
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            account.storage,
+            vec![(U256::zero(), proof.storage_proof[0].value)]
+        );
+        assert_eq!(cache.lock().unwrap().1, header.state_root);
+
+        // the state behind a tag can move between the state root and the proof
+        for block_id in [None, Some(BlockNumber::Latest.into())] {
+            let unpinned = get_verified_account(
+                &provider,
+                weth,
+                &[],
+                block_id,
+                &cache,
+                DatabaseError::Prefetch,
+            )
+            .await;
+            assert!(matches!(unpinned, Err(DatabaseError::UnpinnedProof(_))));
+        }
+    }
+}
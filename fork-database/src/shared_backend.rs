@@ -2,9 +2,10 @@
 // https://github.com/foundry-rs/foundry/blob/master/evm/src/executor/fork/backend.rs
 use super::{
     backend_handler::{BackendHandler, BackendRequest},
-    blockchain_db::{BlockchainDb, FlushJsonBlockCacheDB},
+    blockchain_db::{BlockchainDb, FlushBlockCacheDB},
     errors::{DatabaseError, DatabaseResult},
-    utils::{b160_to_h160, b256_to_h256, h256_to_b256},
+    prefetch::{BatchClient, PrefetchList},
+    utils::{b160_to_h160, b256_to_h256, h160_to_b160, h256_to_b256, ru256_to_u256, u256_to_ru256},
 };
 use ethers::{
     providers::Middleware,
@@ -25,9 +26,9 @@ pub struct SharedBackend {
     /// Ensures that the underlying cache gets flushed once the last `SharedBackend` is dropped.
     ///
     /// There is only one instance of the type, so as soon as the last `SharedBackend` is deleted,
-    /// `FlushJsonBlockCacheDB` is also deleted and the cache is flushed.
+    /// `FlushBlockCacheDB` is also deleted and the cache is flushed.
     #[allow(unused_variables)]
-    cache: Arc<FlushJsonBlockCacheDB>,
+    cache: Arc<FlushBlockCacheDB>,
 }
 
 impl SharedBackend {
@@ -89,20 +90,75 @@ impl SharedBackend {
         M: Middleware + Unpin + 'static + Clone,
     {
         let (backend, backend_rx) = channel(1);
-        let cache = Arc::new(FlushJsonBlockCacheDB(Arc::clone(db.cache())));
+        let cache = Arc::new(FlushBlockCacheDB(Arc::clone(db.cache())));
         let handler = BackendHandler::new(provider, db, backend_rx, pin_block);
         (Self { backend, cache }, handler)
     }
 
     /// Updates the pinned block to fetch data from
+    ///
+    /// Returns once the fetches in flight at the previous block are written to the cache, so the
+    /// cache can be updated to the new block without racing them.
     pub fn set_pinned_block(&self, block: impl Into<BlockId>) -> eyre::Result<()> {
-        let req = BackendRequest::SetPinnedBlock(block.into());
+        tokio::task::block_in_place(|| {
+            let (sender, rx) = oneshot_channel();
+            let req = BackendRequest::SetPinnedBlock(block.into(), sender);
+            self.backend
+                .clone()
+                .try_send(req)
+                .map_err(|e| eyre::eyre!("{:?}", e))?;
+            rx.recv().map_err(|e| eyre::eyre!("{:?}", e))
+        })
+    }
+
+    /// Sends the prefetches as a single JSON-RPC batch with this client
+    pub fn set_batch_client(&self, client: BatchClient) -> eyre::Result<()> {
+        let req = BackendRequest::SetBatchClient(client);
+        self.backend
+            .clone()
+            .try_send(req)
+            .map_err(|e| eyre::eyre!("{:?}", e))
+    }
+
+    /// Fetches accounts and storage with `eth_getProof` and checks them against the state root of
+    /// the pinned block before caching them, a mismatch is a [DatabaseError::InvalidProof]
+    ///
+    /// The backend should be pinned at a block number or hash, see [Self::set_pinned_block],
+    /// reads fail with [DatabaseError::UnpinnedProof] otherwise. Entries already in
+    /// the cache, loaded from a cache file or fetched before, weren't proven and are discarded.
+    pub fn set_verify_proofs(&self, verify: bool) -> eyre::Result<()> {
+        if verify {
+            self.cache.0.db().clear();
+        }
+        let req = BackendRequest::SetVerifyProofs(verify);
         self.backend
             .clone()
             .try_send(req)
             .map_err(|e| eyre::eyre!("{:?}", e))
     }
 
+    /// Loads the accounts and storage slots of `list` missing from the cache in one go
+    ///
+    /// Reads of prefetched entries are answered from the cache without going through the
+    /// `BackendHandler`, so prefetching what a simulation touches before running it saves a
+    /// blocking round trip per cache miss.
+    ///
+    /// The list is sent as a single JSON-RPC batch if a [BatchClient] is set, see
+    /// [Self::set_batch_client], otherwise as one concurrent provider call per account field and
+    /// slot.
+    ///
+    /// Returns:
+    /// Ok(usize): how many accounts and slots were fetched
+    /// Err(DatabaseError): if the prefetch failed, nothing is cached then
+    pub fn prefetch(&self, list: PrefetchList) -> DatabaseResult<usize> {
+        tokio::task::block_in_place(|| {
+            let (sender, rx) = oneshot_channel();
+            let req = BackendRequest::Prefetch(list, sender);
+            self.backend.clone().try_send(req)?;
+            rx.recv()?
+        })
+    }
+
     /// Returns the full block for the given block identifier
     pub fn get_full_block(&self, block: impl Into<BlockId>) -> DatabaseResult<Block<Transaction>> {
         tokio::task::block_in_place(|| {
@@ -124,6 +180,17 @@ impl SharedBackend {
     }
 
     fn do_get_basic(&self, address: Address) -> DatabaseResult<Option<AccountInfo>> {
+        let cached = self
+            .cache
+            .0
+            .db()
+            .accounts
+            .read()
+            .get(&h160_to_b160(address))
+            .cloned();
+        if let Some(account) = cached {
+            return Ok(Some(account));
+        }
         tokio::task::block_in_place(|| {
             let (sender, rx) = oneshot_channel();
             let req = BackendRequest::Basic(address, sender);
@@ -133,6 +200,17 @@ impl SharedBackend {
     }
 
     fn do_get_storage(&self, address: Address, index: U256) -> DatabaseResult<U256> {
+        let cached = self
+            .cache
+            .0
+            .db()
+            .storage
+            .read()
+            .get(&h160_to_b160(address))
+            .and_then(|slots| slots.get(&u256_to_ru256(index)).copied());
+        if let Some(value) = cached {
+            return Ok(ru256_to_u256(value));
+        }
         tokio::task::block_in_place(|| {
             let (sender, rx) = oneshot_channel();
             let req = BackendRequest::Storage(address, index, sender);
@@ -1,14 +1,20 @@
 // ported from foundry's executor
 // https://github.com/foundry-rs/foundry/blob/master/evm/src/executor/backend/snapshot.rs
+use crate::{
+    blockchain_db::BlockchainDbMeta,
+    errors::{DatabaseError, DatabaseResult},
+};
 use hashbrown::HashMap as Map;
 use revm::{
-    primitives::{AccountInfo, Env, B160, B256, U256},
+    db::{AccountState, CacheDB, DatabaseRef, DbAccount},
+    primitives::{AccountInfo, Bytecode, Env, B160, B256, U256},
     JournaledState,
 };
 use serde::{Deserialize, Serialize};
+use std::{fs, io::BufReader, io::BufWriter, path::Path};
 
 /// A minimal abstraction of a state at a certain point in time
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Default, Debug, Clone, Serialize, Deserialize)]
 pub struct StateSnapshot {
     pub accounts: Map<B160, AccountInfo>,
     pub storage: Map<B160, Map<U256, U256>>,
@@ -48,3 +54,128 @@ impl<T> BackendSnapshot<T> {
         self.journaled_state.logs = current.logs.clone();
     }
 }
+
+/// A fork snapshot written to disk, replayable without the remote client
+///
+/// Holds the remote state fetched when the snapshot was taken, the local modifications made on
+/// top of it, and the env of the block the fork was at.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct SnapshotFile {
+    pub meta: BlockchainDbMeta,
+    pub remote: StateSnapshot,
+    pub local: LocalState,
+}
+
+impl SnapshotFile {
+    pub fn load(path: impl AsRef<Path>) -> DatabaseResult<Self> {
+        let path = path.as_ref();
+        let file = fs::File::open(path).map_err(|err| snapshot_error(path, err))?;
+        serde_json::from_reader(BufReader::new(file)).map_err(|err| snapshot_error(path, err))
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> DatabaseResult<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|err| snapshot_error(path, err))?;
+        }
+        let file = fs::File::create(path).map_err(|err| snapshot_error(path, err))?;
+        serde_json::to_writer(BufWriter::new(file), self).map_err(|err| snapshot_error(path, err))
+    }
+}
+
+fn snapshot_error(path: &Path, err: impl std::fmt::Display) -> DatabaseError {
+    DatabaseError::Snapshot(path.to_path_buf(), err.to_string())
+}
+
+/// Serializable content of the local [CacheDB] layer of a fork
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct LocalState {
+    pub accounts: Map<B160, LocalAccount>,
+    pub contracts: Map<B256, Bytecode>,
+    pub block_hashes: Map<U256, B256>,
+}
+
+impl LocalState {
+    pub fn from_cache_db<ExtDB: DatabaseRef>(db: &CacheDB<ExtDB>) -> Self {
+        Self {
+            accounts: db
+                .accounts
+                .iter()
+                .map(|(address, account)| (*address, LocalAccount::from(account)))
+                .collect(),
+            contracts: db
+                .contracts
+                .iter()
+                .map(|(hash, code)| (*hash, code.clone()))
+                .collect(),
+            block_hashes: db
+                .block_hashes
+                .iter()
+                .map(|(number, hash)| (*number, *hash))
+                .collect(),
+        }
+    }
+
+    /// Write the local modifications over the ones of `db`
+    pub fn restore<ExtDB: DatabaseRef>(self, db: &mut CacheDB<ExtDB>) {
+        db.accounts.extend(
+            self.accounts
+                .into_iter()
+                .map(|(address, account)| (address, account.into())),
+        );
+        db.contracts.extend(self.contracts);
+        db.block_hashes.extend(self.block_hashes);
+    }
+}
+
+/// Serializable [DbAccount]
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LocalAccount {
+    pub info: AccountInfo,
+    pub state: LocalAccountState,
+    pub storage: Map<U256, U256>,
+}
+
+/// Serializable [AccountState]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LocalAccountState {
+    NotExisting,
+    Touched,
+    StorageCleared,
+    #[default]
+    None,
+}
+
+impl From<&DbAccount> for LocalAccount {
+    fn from(account: &DbAccount) -> Self {
+        Self {
+            info: account.info.clone(),
+            state: match account.account_state {
+                AccountState::NotExisting => LocalAccountState::NotExisting,
+                AccountState::Touched => LocalAccountState::Touched,
+                AccountState::StorageCleared => LocalAccountState::StorageCleared,
+                AccountState::None => LocalAccountState::None,
+            },
+            storage: account
+                .storage
+                .iter()
+                .map(|(index, value)| (*index, *value))
+                .collect(),
+        }
+    }
+}
+
+impl From<LocalAccount> for DbAccount {
+    fn from(account: LocalAccount) -> Self {
+        Self {
+            info: account.info,
+            account_state: match account.state {
+                LocalAccountState::NotExisting => AccountState::NotExisting,
+                LocalAccountState::Touched => AccountState::Touched,
+                LocalAccountState::StorageCleared => AccountState::StorageCleared,
+                LocalAccountState::None => AccountState::None,
+            },
+            storage: account.storage.into_iter().collect(),
+        }
+    }
+}
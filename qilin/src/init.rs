@@ -5,6 +5,11 @@ use crate::utils::{
 };
 use anyhow::Result;
 use clap::{arg, Command};
+use collectors::{
+    mempool_collector::QilinMempoolCollector,
+    state_diff::pair_key,
+    tx_tracker::{PendingTxTracker, QilinTxTrackerCollector},
+};
 use dashmap::DashMap;
 use dotenv;
 use ethers::{
@@ -21,9 +26,7 @@ use qilin_cfmms::{
     dex::PairSyncError,
     pool::{Pool, PoolVariant},
 };
-use std::collections::hash_map::DefaultHasher;
 use std::env;
-use std::hash::{Hash, Hasher};
 use std::sync::Arc;
 use thiserror::Error;
 use url::Url;
@@ -182,6 +185,28 @@ pub async fn setup() -> Result<
     Ok((flashbot_client, all_pools, hash_pools))
 }
 
+/// Create the mempool collector and the collector of the lifecycle events of the txs it sees
+///
+/// Both share a [PendingTxTracker], so duplicate and underpriced replacement txs are dropped
+/// before being traced, and the swaps of each tx are decoded against `all_pools`
+pub fn setup_mempool_collectors(
+    provider: Arc<Provider<Ws>>,
+    block: Block<H256>,
+    all_pools: Arc<RwLock<DashMap<Address, Pool>>>,
+) -> (
+    QilinMempoolCollector<Provider<Ws>>,
+    QilinTxTrackerCollector<Provider<Ws>>,
+) {
+    let tracker = Arc::new(PendingTxTracker::default());
+
+    let mempool_collector = QilinMempoolCollector::new(provider.clone(), block)
+        .with_tracker(tracker.clone())
+        .with_pools(all_pools);
+    let tx_tracker_collector = QilinTxTrackerCollector::new(provider, tracker);
+
+    (mempool_collector, tx_tracker_collector)
+}
+
 async fn load_pools(
     provider: Arc<Provider<Ws>>,
 ) -> Result<
@@ -250,22 +275,12 @@ async fn load_pools(
             .await
             .expect("Failed to sync dexes");
 
-            let mut hasher = DefaultHasher::new();
-            let mut token0;
-            let mut token1;
-
             let write_lock = all_pools.write();
             for pool in synced_pools {
                 write_lock.insert(pool.address, pool);
 
-                token0 = pool.token_0;
-                token1 = pool.token_1;
-                token0.hash(&mut hasher);
-                token1.hash(&mut hasher);
-                let hash = hasher.finish();
-
                 hash_addr_pools
-                    .entry(H160::from_low_u64_be(hash))
+                    .entry(pair_key(pool.token_0, pool.token_1))
                     .and_modify(|pools| pools.push(pool))
                     .or_insert_with(|| vec![pool]);
             }
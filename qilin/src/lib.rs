@@ -7,12 +7,10 @@ use anyhow::Result;
 use env_logger::Env;
 use ethers::{core::types::Block, prelude::*, providers::Middleware};
 
-use collectors::mempool_collector::QilinMempoolCollector;
-
 pub async fn runner() -> Result<()> {
     env_logger::Builder::from_env(Env::default()).init();
 
-    let (flashbot_client, _all_pools, _hash_addr_pools) = init::setup().await?;
+    let (flashbot_client, all_pools, _hash_addr_pools) = init::setup().await?;
     let ws_provider = flashbot_client.inner().inner().clone();
     let initial_block_num = ws_provider
         .get_block_number()
@@ -26,7 +24,8 @@ pub async fn runner() -> Result<()> {
 
     // let engine = Engine::<Event, Action>::default();
 
-    let _mempool_collector = QilinMempoolCollector::new(ws_provider.clone(), initial_block.clone());
+    let (_mempool_collector, _tx_tracker_collector) =
+        init::setup_mempool_collectors(ws_provider.clone(), initial_block.clone(), all_pools);
     // let block_collector: Box<dyn Collector<BlockPayload>> = QilinBlockCollector::new(
     //     ws_provider.clone(),
     //     all_pools.clone(),
@@ -0,0 +1,299 @@
+use ethers::core::types::{
+    transaction::eip2930::AccessList, Address, Bytes, Eip1559TransactionRequest, NameOrAddress,
+    U256, U64,
+};
+use thiserror::Error;
+
+/// Basis points
+const BPS: u64 = 10_000;
+/// Gas limit headroom over the simulated gas used, in basis points
+const DEFAULT_GAS_HEADROOM_BPS: u64 = 2_000;
+/// Max fee headroom over the target block's base fee, in basis points, the base fee can rise by
+/// 12.5% per block
+const BASE_FEE_HEADROOM_BPS: u64 = 1_250;
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum PricingError {
+    #[error("Profit {profit} doesn't cover the {gas_cost} gas cost")]
+    Unprofitable { profit: U256, gas_cost: U256 },
+    #[error("Bundle has no legs to price")]
+    NoLegs,
+}
+
+/// How much of a bundle's profit is bid to the builder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BribePolicy {
+    /// Bid a fixed share of the profit left after gas, in basis points
+    Fixed { share_bps: u64 },
+    /// Bid a share growing with the profit, from `min_share_bps` for small opportunities to
+    /// `max_share_bps` for large ones, which are the most contested
+    ///
+    /// The share is halfway between both at `half_profit`.
+    Competitive {
+        min_share_bps: u64,
+        max_share_bps: u64,
+        half_profit: U256,
+    },
+}
+
+impl BribePolicy {
+    /// Share of `profit` to bid, in basis points
+    pub fn share_bps(&self, profit: U256) -> u64 {
+        match *self {
+            BribePolicy::Fixed { share_bps } => share_bps.min(BPS),
+            BribePolicy::Competitive {
+                min_share_bps,
+                max_share_bps,
+                half_profit,
+            } => {
+                let min = min_share_bps.min(BPS);
+                let max = max_share_bps.min(BPS).max(min);
+                if profit.is_zero() {
+                    return min;
+                }
+                let spread = U256::from(max - min) * profit / (profit + half_profit);
+                min + spread.as_u64()
+            }
+        }
+    }
+}
+
+/// How the bid reaches the builder
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BribePayment {
+    /// Spread over the gas of the legs as their priority fee
+    PriorityFee,
+    /// Transferred to `block.coinbase` by the last leg, the legs pay no priority fee
+    ///
+    /// The bid is paid exactly instead of being rounded down to the gas, and isn't paid if the
+    /// last leg reverts.
+    CoinbaseTransfer,
+}
+
+/// Fees of one tx of a bundle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LegPrice {
+    pub gas_limit: U256,
+    pub max_fee_per_gas: U256,
+    pub max_priority_fee_per_gas: U256,
+}
+
+impl LegPrice {
+    /// Build the EIP-1559 tx of the leg
+    pub fn build_tx(
+        &self,
+        to: NameOrAddress,
+        from: Address,
+        data: Bytes,
+        value: Option<U256>,
+        nonce: U256,
+        chain_id: U64,
+    ) -> Eip1559TransactionRequest {
+        Eip1559TransactionRequest {
+            to: Some(to),
+            from: Some(from),
+            data: Some(data),
+            chain_id: Some(chain_id),
+            max_priority_fee_per_gas: Some(self.max_priority_fee_per_gas),
+            max_fee_per_gas: Some(self.max_fee_per_gas),
+            gas: Some(self.gas_limit),
+            nonce: Some(nonce),
+            value,
+            access_list: AccessList::default(),
+        }
+    }
+}
+
+/// Fees of a bundle targeting the next block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BundlePrice {
+    /// Base fee of the target block
+    pub base_fee: U256,
+    pub legs: Vec<LegPrice>,
+    /// Total bid to the builder
+    pub bribe: U256,
+    /// Part of the bid the last leg transfers to `block.coinbase`, zero if it's paid as
+    /// priority fee
+    pub coinbase_transfer: U256,
+    /// Profit left after gas and bribe
+    pub net_profit: U256,
+}
+
+/// Prices the txs of a bundle from its simulation
+///
+/// The bid is spread over the gas of the legs as their priority fee, or transferred to the
+/// coinbase, depending on the [BribePayment].
+#[derive(Debug, Clone, Copy)]
+pub struct BundlePricer {
+    pub policy: BribePolicy,
+    pub payment: BribePayment,
+    /// Gas limit headroom over the simulated gas used, in basis points
+    pub gas_headroom_bps: u64,
+}
+
+impl BundlePricer {
+    pub fn new(policy: BribePolicy) -> Self {
+        Self {
+            policy,
+            payment: BribePayment::PriorityFee,
+            gas_headroom_bps: DEFAULT_GAS_HEADROOM_BPS,
+        }
+    }
+
+    pub fn with_payment(mut self, payment: BribePayment) -> Self {
+        self.payment = payment;
+        self
+    }
+
+    pub fn with_gas_headroom_bps(mut self, gas_headroom_bps: u64) -> Self {
+        self.gas_headroom_bps = gas_headroom_bps;
+        self
+    }
+
+    /// Price a bundle
+    ///
+    /// Arguments:
+    /// * `gas_used`: gas used by each of our txs in the bundle simulation, in bundle order
+    /// * `base_fee`: base fee of the target block, see
+    ///   [calculate_next_block_base_fee](crate::utils::base_fee_helper::calculate_next_block_base_fee)
+    /// * `profit`: simulated profit of the bundle, before gas
+    ///
+    /// Returns:
+    /// Ok(BundlePrice): the fees of every leg and the bid
+    /// Err(PricingError): if the profit doesn't cover the gas at the base fee
+    pub fn price(
+        &self,
+        gas_used: &[U256],
+        base_fee: U256,
+        profit: U256,
+    ) -> Result<BundlePrice, PricingError> {
+        if gas_used.is_empty() {
+            return Err(PricingError::NoLegs);
+        }
+
+        let total_gas = gas_used
+            .iter()
+            .fold(U256::zero(), |total, gas| total + *gas);
+        let gas_cost = total_gas * base_fee;
+        let net = match profit.checked_sub(gas_cost) {
+            Some(net) if !net.is_zero() => net,
+            _ => return Err(PricingError::Unprofitable { profit, gas_cost }),
+        };
+
+        let share = net * self.policy.share_bps(net) / BPS;
+        let (priority_fee, bribe, coinbase_transfer) = match self.payment {
+            BribePayment::PriorityFee => {
+                // rounded down so that the bid never exceeds the share of the profit
+                let priority_fee = share.checked_div(total_gas).unwrap_or_default();
+                (priority_fee, priority_fee * total_gas, U256::zero())
+            }
+            BribePayment::CoinbaseTransfer => (U256::zero(), share, share),
+        };
+
+        // only the base fee and the tip are paid, the headroom keeps the legs valid if the
+        // bundle lands a block later than targeted
+        let max_fee_per_gas = base_fee + base_fee * BASE_FEE_HEADROOM_BPS / BPS + priority_fee;
+        let legs = gas_used
+            .iter()
+            .map(|gas| LegPrice {
+                gas_limit: *gas + *gas * self.gas_headroom_bps / BPS,
+                max_fee_per_gas,
+                max_priority_fee_per_gas: priority_fee,
+            })
+            .collect();
+
+        Ok(BundlePrice {
+            base_fee,
+            legs,
+            bribe,
+            coinbase_transfer,
+            net_profit: net - bribe,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_price_bundle() {
+        let gwei = U256::exp10(9);
+        let gas_used = [U256::from(100_000), U256::from(50_000)];
+        let base_fee = gwei * 20;
+        // 0.01 ETH of profit, 0.003 ETH of gas
+        let profit = U256::exp10(16);
+
+        let fixed = BundlePricer::new(BribePolicy::Fixed { share_bps: 9_000 });
+        let price = fixed.price(&gas_used, base_fee, profit).unwrap();
+        let net = profit - U256::from(150_000) * base_fee;
+        assert_eq!(
+            price.legs[0].max_priority_fee_per_gas,
+            net * 9 / 10 / 150_000
+        );
+        // 12.5% over the base fee, 22.5 gwei
+        assert_eq!(
+            price.legs[0].max_fee_per_gas,
+            gwei * 225 / 10 + price.legs[0].max_priority_fee_per_gas
+        );
+        assert_eq!(price.legs[1].gas_limit, U256::from(60_000));
+        assert_eq!(
+            price.bribe,
+            price.legs[0].max_priority_fee_per_gas * 150_000
+        );
+        assert_eq!(price.bribe + price.net_profit, net);
+        assert!(price.coinbase_transfer.is_zero());
+
+        assert_eq!(
+            fixed.price(&gas_used, gwei * 100, profit),
+            Err(PricingError::Unprofitable {
+                profit,
+                gas_cost: gwei * 100 * 150_000
+            })
+        );
+    }
+
+    #[test]
+    fn test_competitive_share() {
+        let policy = BribePolicy::Competitive {
+            min_share_bps: 5_000,
+            max_share_bps: 9_900,
+            half_profit: U256::exp10(17),
+        };
+        assert_eq!(policy.share_bps(U256::zero()), 5_000);
+        assert_eq!(policy.share_bps(U256::exp10(17)), 7_450);
+        assert!(policy.share_bps(U256::exp10(20)) > 9_800);
+        assert!(policy.share_bps(U256::exp10(16)) < policy.share_bps(U256::exp10(17)));
+
+        // out of range shares are clamped before bounding each other
+        let policy = BribePolicy::Competitive {
+            min_share_bps: 20_000,
+            max_share_bps: 5_000,
+            half_profit: U256::exp10(17),
+        };
+        assert_eq!(policy.share_bps(U256::zero()), 10_000);
+        assert_eq!(policy.share_bps(U256::exp10(20)), 10_000);
+    }
+
+    #[test]
+    fn test_price_coinbase_transfer() {
+        let gwei = U256::exp10(9);
+        let gas_used = [U256::from(100_000), U256::from(50_000)];
+        let base_fee = gwei * 20;
+        let profit = U256::exp10(16);
+
+        let pricer = BundlePricer::new(BribePolicy::Fixed { share_bps: 9_000 })
+            .with_payment(BribePayment::CoinbaseTransfer);
+        let price = pricer.price(&gas_used, base_fee, profit).unwrap();
+        let net = profit - U256::from(150_000) * base_fee;
+        // the share is paid exactly, the legs only pay the base fee
+        assert_eq!(price.bribe, net * 9 / 10);
+        assert_eq!(price.coinbase_transfer, price.bribe);
+        assert!(price
+            .legs
+            .iter()
+            .all(|leg| leg.max_priority_fee_per_gas.is_zero()));
+        assert_eq!(price.legs[0].max_fee_per_gas, gwei * 225 / 10);
+        assert_eq!(price.bribe + price.net_profit, net);
+    }
+}
@@ -76,14 +76,3 @@ pub fn get_selectors(selector: &[&str]) -> Vec<Bytes> {
         .map(|s| hex_to_bytes(s).expect("Invalid selector"))
         .collect()
 }
-
-pub fn decode_revert_bytes(data: &[u8]) -> Result<String, Box<dyn Error>> {
-    let bytes = hex::decode(&data[130..])?;
-    match std::str::from_utf8(&bytes) {
-        Ok(s) => {
-            println!("Decoded string: {}", s);
-            Ok(s.to_string())
-        }
-        Err(e) => Err(Box::new(e)),
-    }
-}
@@ -1,5 +1,7 @@
 pub mod base_fee_helper;
+pub mod bundle_pricing;
 pub mod constants;
 pub mod helpers;
 pub mod relayer;
+pub mod revert_decoder;
 pub mod serialization;
@@ -1,70 +1,110 @@
-use crate::utils::relayer;
-use ethers::core::types::{Bytes, Eip1559TransactionRequest, NameOrAddress, U256, U64};
+use crate::utils::{
+    base_fee_helper::calculate_next_block_base_fee,
+    bundle_pricing::{BundlePrice, BundlePricer, LegPrice},
+    relayer,
+    revert_decoder::{FailureReason, RevertDecoder, SimulationFailure},
+};
+use ethers::core::types::{
+    BlockNumber, Bytes, Eip1559TransactionRequest, NameOrAddress, U256, U64,
+};
 use ethers::prelude::SignerMiddleware;
 use ethers::providers::{Middleware, Provider, Ws};
 use ethers::signers::{LocalWallet, Signer};
-use ethers::types::transaction::{eip2718::TypedTransaction, eip2930::AccessList};
+use ethers::types::transaction::eip2718::TypedTransaction;
 use ethers_flashbots::{BundleRequest, BundleTransaction, FlashbotsMiddleware, SimulatedBundle};
+use executors::nonce_manager::NonceManager;
 use std::error::Error;
 use std::sync::Arc;
 
+/// Gas limit of the unpriced simulation measuring the gas a tx uses
+const GAS_PROBE_LIMIT: u64 = 1_000_000;
+
+/// Simulate a tx as a bundle for the next block, priced from its own simulation
+///
+/// The tx is first simulated at the next block's base fee with no tip to measure its gas,
+/// then priced by `pricer` against `expected_profit` and simulated again with its final fees.
+/// The simulated tx is never sent, so it takes the next nonce without reserving it.
+#[allow(clippy::too_many_arguments)]
 pub async fn simulate_bundle(
     _to: NameOrAddress,
     _data: Bytes,
+    expected_profit: U256,
+    pricer: &BundlePricer,
     flashbot_client: &Arc<
         SignerMiddleware<FlashbotsMiddleware<Arc<Provider<Ws>>, LocalWallet>, LocalWallet>,
     >,
     ws_provider: &Provider<Ws>,
     wallet: &LocalWallet,
+    nonce_manager: &NonceManager,
     chain_id: &i32,
-) -> Result<SimulatedBundle, Box<dyn Error>> {
-    let current_block = ws_provider.get_block_number().await?;
+) -> Result<(SimulatedBundle, BundlePrice), Box<dyn Error>> {
+    let current_block = ws_provider
+        .get_block(BlockNumber::Latest)
+        .await?
+        .ok_or("Latest block not found")?;
+    let current_block_number = current_block.number.ok_or("Latest block is pending")?;
+    let base_fee = calculate_next_block_base_fee(current_block);
 
     let wallet_address = wallet.address();
-    let _nonce = flashbot_client
-        .get_transaction_count(wallet_address, None)
-        .await?;
-    println!("Nonce: {}", _nonce);
-
-    let test_transaction_request = Eip1559TransactionRequest {
-        to: Some(_to),
-        from: Some(wallet_address),
-        data: Some(_data),
-        chain_id: Some(U64::from(*chain_id)),
-        max_priority_fee_per_gas: Some(U256::from(0)),
-        max_fee_per_gas: Some(U256::MAX),
-        gas: Some(U256::from(550000)),
-        nonce: Some(_nonce),
-        value: None,
-        access_list: AccessList::default(),
-    };
+    let _nonce = nonce_manager.next_nonce();
+    let chain_id = U64::from(*chain_id);
 
-    let frontrun_tx_typed = TypedTransaction::Eip1559(test_transaction_request);
-
-    let tx = {
-        let mut inner: TypedTransaction = frontrun_tx_typed;
-        flashbot_client
-            .clone()
-            .fill_transaction(&mut inner, None)
-            .await?;
-        inner
+    let probe = LegPrice {
+        gas_limit: U256::from(GAS_PROBE_LIMIT),
+        max_fee_per_gas: base_fee,
+        max_priority_fee_per_gas: U256::zero(),
     };
-    println!("Tx: {:?}", tx);
+    let probe_tx = probe.build_tx(
+        _to.clone(),
+        wallet_address,
+        _data.clone(),
+        None,
+        _nonce,
+        chain_id,
+    );
+    let probe_bundle = sign_bundle(flashbot_client, vec![probe_tx], current_block_number).await?;
+    let probe_simulation = flashbot_client
+        .inner()
+        .simulate_bundle(&probe_bundle)
+        .await?;
+    validate_simulation_response(&probe_simulation)?;
+
+    let gas_used: Vec<U256> = probe_simulation
+        .transactions
+        .iter()
+        .map(|tx| tx.gas_used)
+        .collect();
+    let price = pricer.price(&gas_used, base_fee, expected_profit)?;
+    log::info!("Bundle priced at {:?}", price);
+
+    let priced_tx = price.legs[0].build_tx(_to, wallet_address, _data, None, _nonce, chain_id);
+    let bundle = sign_bundle(flashbot_client, vec![priced_tx], current_block_number).await?;
+    let simulated_bundle = flashbot_client.inner().simulate_bundle(&bundle).await?;
+    log::debug!("simulated_bundle: {:?}", simulated_bundle);
 
-    let signature = flashbot_client.signer().sign_transaction(&tx).await?;
-    let signed_frontrun_tx = tx.rlp_signed(&signature);
-    let signed_transactions = vec![signed_frontrun_tx];
-    println!("signed_transactions: {:?}", signed_transactions);
+    Ok((simulated_bundle, price))
+}
 
-    let bundle = relayer::construct_bundle(signed_transactions, current_block).map_err(|e| {
-        println!("Bundle Construction Error{:?}", e);
+/// Sign txs and bundle them for the block following `block_number`
+async fn sign_bundle(
+    flashbot_client: &Arc<
+        SignerMiddleware<FlashbotsMiddleware<Arc<Provider<Ws>>, LocalWallet>, LocalWallet>,
+    >,
+    txs: Vec<Eip1559TransactionRequest>,
+    block_number: U64,
+) -> Result<BundleRequest, Box<dyn Error>> {
+    let mut signed_transactions = vec![];
+    for tx in txs {
+        let tx = TypedTransaction::Eip1559(tx);
+        let signature = flashbot_client.signer().sign_transaction(&tx).await?;
+        signed_transactions.push(tx.rlp_signed(&signature));
+    }
+
+    let bundle = relayer::construct_bundle(signed_transactions, block_number).map_err(|e| {
+        log::error!("Bundle Construction Error{:?}", e);
         e
     })?;
-
-    let simulated_bundle = flashbot_client.inner().simulate_bundle(&bundle).await?;
-    println!("simulated_bundle: {:?}", simulated_bundle);
-
-    Ok(simulated_bundle)
+    Ok(bundle)
 }
 
 // for testing
@@ -76,9 +116,12 @@ pub async fn simulate_bundle(
 // let bundle_payload = relayer::simulate_bundle(
 //     _to,
 //     _data,
+//     expected_profit,
+//     &BundlePricer::new(BribePolicy::Fixed { share_bps: 9_000 }),
 //     &flashbot_client.clone(),
 //     &ws_provider.clone().unwrap(),
 //     &wallet.clone(),
+//     &nonce_manager,
 //     &chain_id.unwrap(),
 // )
 // .await?;
@@ -87,17 +130,22 @@ pub async fn simulate_bundle(
 /// Helper function to help catch the various ways errors can be thrown from simulation
 /// This helper function is needed as simulation response has many ways where the
 /// error can be thrown.... which is not documented
-pub fn validate_simulation_response(sim: &SimulatedBundle) -> eyre::Result<()> {
+///
+/// Returns the first failed tx of the bundle, with its decoded revert reason
+pub fn validate_simulation_response(sim: &SimulatedBundle) -> Result<(), SimulationFailure> {
     // Make sure no simulated bundle transactions have errors or reverts
-    for tx in &sim.transactions {
-        if let Some(e) = &tx.error {
-            eyre::bail!("Error in bundled transaction: {:?}", e);
-        }
-        if let Some(r) = &tx.revert {
-            eyre::bail!("Transaction reverts: {:?}", r);
-        }
+    let failures = RevertDecoder::global().simulation_failures(sim);
+    for failure in &failures {
+        let kind = match &failure.reason {
+            FailureReason::Reverted(reason) => reason.kind(),
+            FailureReason::Errored(_) => "error",
+        };
+        log::warn!("Bundle simulation failed [{}]: {}", kind, failure);
+    }
+    match failures.into_iter().next() {
+        Some(failure) => Err(failure),
+        None => Ok(()),
     }
-    Ok(())
 }
 
 /// Construct a Bundle Request for FlashBots
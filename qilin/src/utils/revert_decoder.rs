@@ -0,0 +1,355 @@
+use ethers::abi::{Abi, AbiError, ParamType, Token};
+use ethers::contract::Lazy;
+use ethers::core::types::{Address, Bytes, H256, U256};
+use ethers_flashbots::SimulatedBundle;
+use log::warn;
+use qilin_cfmms::bindings;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::Path;
+use thiserror::Error;
+
+/// Selector of `Error(string)`
+const ERROR_STRING_SELECTOR: [u8; 4] = [0x08, 0xc3, 0x79, 0xa0];
+/// Selector of `Panic(uint256)`
+const PANIC_SELECTOR: [u8; 4] = [0x4e, 0x48, 0x7b, 0x71];
+/// Length of the data returned by the sandwich contract's `revert(3, 3)`
+const SANDWICH_REVERT_LEN: usize = 3;
+
+/// Decoder shared by the simulation helpers, knowing the ABIs of `/abi` and of the cfmms bindings,
+/// and the sandwich contract set in `SANDWICH_CONTRACT`
+static DEFAULT_DECODER: Lazy<RevertDecoder> = Lazy::new(|| {
+    let decoder =
+        RevertDecoder::with_bindings().with_abi_dir(concat!(env!("CARGO_MANIFEST_DIR"), "/../abi"));
+    match std::env::var("SANDWICH_CONTRACT").map(|address| address.parse::<Address>()) {
+        Ok(Ok(address)) => decoder.with_sandwich_contract(address),
+        _ => decoder,
+    }
+});
+
+/// Why a call reverted
+#[derive(Debug, Clone, PartialEq)]
+pub enum RevertReason {
+    /// `Error(string)`, thrown by `require` and `revert("...")`
+    Message(String),
+    /// `Panic(uint256)`, thrown by failed asserts, overflows, out of bounds accesses, ...
+    Panic(U256),
+    /// A custom error declared in one of the known ABIs
+    Custom { name: String, args: Vec<Token> },
+    /// The sandwich contract's `revert(3, 3)`: unauthorized caller, or its transfer or swap failed
+    Sandwich,
+    /// Reverted without data
+    Empty,
+    /// Data no known error matches
+    Unknown(Bytes),
+}
+
+impl RevertReason {
+    /// Short label of the kind of revert, for metrics
+    pub fn kind(&self) -> &'static str {
+        match self {
+            RevertReason::Message(_) => "error_string",
+            RevertReason::Panic(_) => "panic",
+            RevertReason::Custom { .. } => "custom_error",
+            RevertReason::Sandwich => "sandwich",
+            RevertReason::Empty => "empty",
+            RevertReason::Unknown(_) => "unknown",
+        }
+    }
+}
+
+impl fmt::Display for RevertReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RevertReason::Message(message) => write!(f, "{}", message),
+            RevertReason::Panic(code) => write!(f, "panic {:#04x}: {}", code, panic_reason(*code)),
+            RevertReason::Custom { name, args } => {
+                let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+                write!(f, "{}({})", name, args.join(", "))
+            }
+            RevertReason::Sandwich => write!(f, "sandwich contract reverted"),
+            RevertReason::Empty => write!(f, "reverted without data"),
+            RevertReason::Unknown(data) => write!(f, "unknown revert {}", data),
+        }
+    }
+}
+
+/// Why a tx of a simulated bundle failed
+#[derive(Error, Debug, Clone, PartialEq)]
+pub enum FailureReason {
+    #[error("reverted: {0}")]
+    Reverted(RevertReason),
+    /// The tx couldn't run, e.g. it ran out of gas or its nonce is wrong
+    #[error("errored: {0}")]
+    Errored(String),
+}
+
+/// A failed tx of a simulated bundle
+#[derive(Error, Debug, Clone, PartialEq)]
+#[error("Tx {index} ({hash:?}) of the bundle {reason}")]
+pub struct SimulationFailure {
+    /// Position of the tx in the bundle
+    pub index: usize,
+    pub hash: H256,
+    pub reason: FailureReason,
+}
+
+/// Decodes revert data into a [RevertReason]
+///
+/// Custom errors are matched by selector against the ABIs the decoder was built with.
+#[derive(Debug, Clone, Default)]
+pub struct RevertDecoder {
+    errors: HashMap<[u8; 4], Vec<AbiError>>,
+    /// Calls to this contract reverting with 3 bytes are [RevertReason::Sandwich]
+    sandwich_contract: Option<Address>,
+}
+
+impl RevertDecoder {
+    /// Decoder knowing no custom error
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Decoder knowing the custom errors of the cfmms bindings
+    pub fn with_bindings() -> Self {
+        [
+            &*bindings::dai::DAI_ABI,
+            &*bindings::uniswap_universal_router::UNISWAP_UNIVERSAL_ROUTER_ABI,
+            &*bindings::uniswap_v2_factory::UNISWAP_V2_FACTORY_ABI,
+            &*bindings::uniswap_v2_router_1::UNISWAP_V2_ROUTER_1_ABI,
+            &*bindings::uniswap_v2_router_2::SWAPROUTER_ABI,
+            &*bindings::uniswap_v3_factory::UNISWAP_V3_FACTORY_ABI,
+            &*bindings::uniswap_v3_quoter::UNISWAP_V3_QUOTER_ABI,
+            &*bindings::uniswap_v3_quoter_v2::UNISWAP_V3_QUOTER_V2_ABI,
+            &*bindings::uniswap_v3_router_1::SWAPROUTER_ABI,
+            &*bindings::uniswap_v3_router_2::SWAPROUTER_ABI,
+            &*bindings::uniswap_v3_weth_dai_lp::UNISWAP_V3_WETH_DAI_LP_ABI,
+            &*bindings::usdc::USDC_ABI,
+            &*bindings::usdt::USDT_ABI,
+            &*bindings::weth::WETH_ABI,
+        ]
+        .into_iter()
+        .fold(Self::new(), |decoder, abi| decoder.with_abi(abi))
+    }
+
+    /// Decoder shared by the simulation helpers
+    pub fn global() -> &'static RevertDecoder {
+        &DEFAULT_DECODER
+    }
+
+    /// Recognize the `revert(3, 3)` of the sandwich contract deployed at `address`
+    pub fn with_sandwich_contract(mut self, address: Address) -> Self {
+        self.sandwich_contract = Some(address);
+        self
+    }
+
+    /// Learn the custom errors of an ABI
+    pub fn with_abi(mut self, abi: &Abi) -> Self {
+        for error in abi.errors() {
+            let mut selector = [0u8; 4];
+            selector.copy_from_slice(&error.signature()[..4]);
+            let known = self.errors.entry(selector).or_default();
+            if !known.iter().any(|e| e.signature() == error.signature()) {
+                known.push(error.clone());
+            }
+        }
+        self
+    }
+
+    /// Learn the custom errors of every JSON ABI in `dir`, skipping the files that aren't one
+    pub fn with_abi_dir(mut self, dir: impl AsRef<Path>) -> Self {
+        let entries = match std::fs::read_dir(dir.as_ref()) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to read ABI dir {:?}: {}", dir.as_ref(), e);
+                return self;
+            }
+        };
+
+        for path in entries
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.path())
+        {
+            if path.extension().and_then(|ext| ext.to_str()) != Some("json") {
+                continue;
+            }
+            let abi = std::fs::read_to_string(&path)
+                .map_err(|e| e.to_string())
+                .and_then(|json| serde_json::from_str::<Abi>(&json).map_err(|e| e.to_string()));
+            match abi {
+                Ok(abi) => self = self.with_abi(&abi),
+                Err(e) => warn!("Skipping ABI {:?}: {}", path, e),
+            }
+        }
+        self
+    }
+
+    /// Decode the data a call to `to` reverted with
+    ///
+    /// The 3 bytes of the sandwich contract's `revert(3, 3)` are only recognized in calls to the
+    /// contract set with [with_sandwich_contract](Self::with_sandwich_contract)
+    pub fn decode_call(&self, to: Option<Address>, data: &[u8]) -> RevertReason {
+        if data.len() == SANDWICH_REVERT_LEN && to.is_some() && to == self.sandwich_contract {
+            return RevertReason::Sandwich;
+        }
+        self.decode(data)
+    }
+
+    /// Decode the data a call reverted with
+    pub fn decode(&self, data: &[u8]) -> RevertReason {
+        if data.is_empty() {
+            return RevertReason::Empty;
+        }
+        if data.len() < 4 {
+            return RevertReason::Unknown(Bytes::from(data.to_vec()));
+        }
+
+        let (selector, args) = data.split_at(4);
+        let decoded = match selector {
+            s if s == ERROR_STRING_SELECTOR => ethers::abi::decode(&[ParamType::String], args)
+                .ok()
+                .and_then(|tokens| tokens.into_iter().next())
+                .and_then(Token::into_string)
+                .map(RevertReason::Message),
+            s if s == PANIC_SELECTOR => ethers::abi::decode(&[ParamType::Uint(256)], args)
+                .ok()
+                .and_then(|tokens| tokens.into_iter().next())
+                .and_then(Token::into_uint)
+                .map(RevertReason::Panic),
+            s => self.errors.get(s).and_then(|errors| {
+                errors.iter().find_map(|error| {
+                    error.decode(args).ok().map(|args| RevertReason::Custom {
+                        name: error.name.clone(),
+                        args,
+                    })
+                })
+            }),
+        };
+
+        decoded.unwrap_or_else(|| RevertReason::Unknown(Bytes::from(data.to_vec())))
+    }
+
+    /// Decode the `revert` field of a simulated tx sent to `to`
+    ///
+    /// The local simulation returns the revert data as hex, the relay the decoded reason as
+    /// plain text, which is kept as a [RevertReason::Message].
+    pub fn decode_str(&self, to: Option<Address>, revert: &str) -> RevertReason {
+        match revert.strip_prefix("0x").map(hex::decode) {
+            Some(Ok(data)) => self.decode_call(to, &data),
+            _ if revert.is_empty() => RevertReason::Empty,
+            _ => RevertReason::Message(revert.to_string()),
+        }
+    }
+
+    /// Failed txs of a simulated bundle, in bundle order
+    pub fn simulation_failures(&self, sim: &SimulatedBundle) -> Vec<SimulationFailure> {
+        sim.transactions
+            .iter()
+            .enumerate()
+            .filter_map(|(index, tx)| {
+                let reason = match (&tx.error, &tx.revert) {
+                    (_, Some(revert)) => FailureReason::Reverted(self.decode_str(tx.to, revert)),
+                    (Some(error), None) => FailureReason::Errored(error.clone()),
+                    (None, None) => return None,
+                };
+                Some(SimulationFailure {
+                    index,
+                    hash: tx.hash,
+                    reason,
+                })
+            })
+            .collect()
+    }
+}
+
+/// What a Solidity panic code means
+pub fn panic_reason(code: U256) -> &'static str {
+    if code > U256::from(u8::MAX) {
+        return "unknown panic";
+    }
+    match code.as_u32() {
+        0x00 => "generic compiler panic",
+        0x01 => "assertion failed",
+        0x11 => "arithmetic overflow or underflow",
+        0x12 => "division or modulo by zero",
+        0x21 => "invalid enum value",
+        0x22 => "invalid storage byte array encoding",
+        0x31 => "pop on empty array",
+        0x32 => "array index out of bounds",
+        0x41 => "out of memory",
+        0x51 => "call to uninitialized function",
+        _ => "unknown panic",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethers::abi::{encode, short_signature};
+
+    #[test]
+    fn test_decode_reverts() {
+        let decoder = RevertDecoder::with_bindings();
+
+        let mut data = ERROR_STRING_SELECTOR.to_vec();
+        data.extend(encode(&[Token::String("UniswapV2: K".to_string())]));
+        assert_eq!(
+            decoder.decode(&data),
+            RevertReason::Message("UniswapV2: K".to_string())
+        );
+        // the local simulation returns the data as hex, the relay the reason as plain text
+        assert_eq!(
+            decoder.decode_str(None, &Bytes::from(data).to_string()),
+            RevertReason::Message("UniswapV2: K".to_string())
+        );
+        assert_eq!(
+            decoder.decode_str(None, "UniswapV2: K"),
+            RevertReason::Message("UniswapV2: K".to_string())
+        );
+        assert_eq!(
+            decoder.decode_str(None, "TF!"),
+            RevertReason::Message("TF!".to_string())
+        );
+
+        let mut data = PANIC_SELECTOR.to_vec();
+        data.extend(encode(&[Token::Uint(U256::from(0x11))]));
+        let panic = decoder.decode(&data);
+        assert_eq!(panic, RevertReason::Panic(U256::from(0x11)));
+        assert_eq!(
+            panic.to_string(),
+            "panic 0x11: arithmetic overflow or underflow"
+        );
+
+        // universal router's `InvalidCommandType(uint256)`
+        let mut data = short_signature("InvalidCommandType", &[ParamType::Uint(256)]).to_vec();
+        data.extend(encode(&[Token::Uint(U256::from(7))]));
+        assert_eq!(
+            decoder.decode(&data),
+            RevertReason::Custom {
+                name: "InvalidCommandType".to_string(),
+                args: vec![Token::Uint(U256::from(7))]
+            }
+        );
+
+        // 3 bytes only mean the sandwich contract reverted in calls to it
+        let sandwich = Address::from_low_u64_be(0x5a4d);
+        let decoder = decoder.with_sandwich_contract(sandwich);
+        assert_eq!(
+            decoder.decode_call(Some(sandwich), &[0, 0, 0]),
+            RevertReason::Sandwich
+        );
+        assert_eq!(
+            decoder.decode_str(Some(sandwich), "0x000000"),
+            RevertReason::Sandwich
+        );
+        assert_eq!(
+            decoder
+                .decode_call(Some(Address::zero()), &[0, 0, 0])
+                .kind(),
+            "unknown"
+        );
+        assert_eq!(decoder.decode(&[0, 0, 0]).kind(), "unknown");
+        assert_eq!(decoder.decode(&[]), RevertReason::Empty);
+        assert_eq!(decoder.decode(&[0xde, 0xad, 0xbe, 0xef]).kind(), "unknown");
+    }
+}
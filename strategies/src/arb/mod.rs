@@ -4,12 +4,23 @@ pub mod v3;
 use argmin::core::observers::{ObserverMode, SlogLogger};
 use argmin::core::{CostFunction, Error, Executor};
 use argmin::solver::brent::BrentOpt;
+use collectors::{
+    state_diff::{extract_arb_pools, extract_arb_pools_from_swaps, ArbOpportunity},
+    types::NewTx,
+};
+use dashmap::DashMap;
 use ethers::providers::{Provider, Ws};
-use ethers::types::{I256, U256};
+use ethers::types::{Address, H160, I256, U256};
+use fork_database::forked_db::ForkedDatabase;
 use qilin_cfmms::batch_requests::uniswap_v3::UniswapV3TickData;
 use qilin_cfmms::pool::{Pool, PoolType};
 use std::sync::Arc;
 
+use crate::{
+    sandwich::utils::constants::get_base_tokens,
+    token_safety::{TokenRegistry, TokenSafetyChecker},
+};
+
 #[derive(Debug)]
 struct ArbPool {
     borrowing_pool_reserve_0: f64,
@@ -212,6 +223,41 @@ impl ArbPool {
     }
 }
 
+/// Find the arb opportunities a tx leaves, skipping pools that trade an unsafe token
+///
+/// Base tokens are held as inventory and never checked, other tokens unknown to `registry` are
+/// classified on the fork against the pool the tx touched.
+///
+/// Arguments:
+/// * `registry`: cached token verdicts
+/// * `checker`: classifies the tokens missing from `registry`
+/// * `fork_db`: fork the tokens are classified on
+/// * `new_tx`: the mempool tx, its decoded swaps are used if the collector attached them
+/// * `all_pools`: HashMap of Address and Pool, used to decode the state diff otherwise
+/// * `hash_pools`: pools of each pair, keyed by [collectors::state_diff::pair_key]
+///
+/// Returns:
+/// Vec<ArbOpportunity>: every profitable pair of pools trading safe tokens
+pub fn extract_tradable_arbs(
+    registry: &TokenRegistry,
+    checker: &TokenSafetyChecker,
+    fork_db: &mut ForkedDatabase,
+    new_tx: &NewTx,
+    all_pools: &DashMap<Address, Pool>,
+    hash_pools: &DashMap<H160, Vec<Pool>>,
+) -> Vec<ArbOpportunity> {
+    let base_tokens = get_base_tokens();
+    let is_tradable = |token, pool: &Pool| {
+        base_tokens.contains(&token) || registry.is_tradable(checker, fork_db, token, pool)
+    };
+    match &new_tx.swaps {
+        Some(swaps) => {
+            extract_arb_pools_from_swaps(swaps, &new_tx.state_diff, hash_pools, is_tradable)
+        }
+        None => extract_arb_pools(&new_tx.state_diff, all_pools, hash_pools, is_tradable),
+    }
+}
+
 impl CostFunction for ArbPool {
     type Param = f64;
     type Output = f64;
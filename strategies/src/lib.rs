@@ -1,3 +1,7 @@
 pub mod arb;
 pub mod sandwich;
+pub mod token_safety;
 pub mod types;
+
+#[cfg(test)]
+mod test_utils;
@@ -0,0 +1,248 @@
+use crate::sandwich::{
+    sizing::{next_block_env, transact, SizingError},
+    utils::{
+        constants::{
+            get_braindance_address, get_braindance_code, get_braindance_controller_address,
+        },
+        tx_builder::braindance::{
+            build_swap_v2_data, build_swap_v3_data, decode_swap_v2_result, decode_swap_v3_result,
+        },
+    },
+};
+use collectors::slot_finder::{SlotFinderError, StorageLayoutFinder};
+use ethers::{
+    abi::AbiError,
+    types::{Address, Bytes, I256, U256},
+};
+use fork_database::{
+    errors::DatabaseError,
+    forked_db::ForkedDatabase,
+    utils::{h160_to_b160, u256_to_ru256},
+};
+use qilin_cfmms::pool::{Pool, PoolVariant};
+use revm::primitives::{
+    AccountInfo, Bytecode, ExecutionResult, Output, TransactTo, TxEnv, U256 as rU256,
+};
+use thiserror::Error;
+
+/// Gas limit of a braindance quote
+const BRAINDANCE_GAS_LIMIT: u64 = 1_000_000;
+
+#[derive(Error, Debug)]
+pub enum BraindanceError {
+    #[error("Could not find the balanceOf slot to fund the braindance contract")]
+    SlotFinderError(#[from] SlotFinderError),
+    #[error("Failed to write to the fork database")]
+    DatabaseError(#[from] DatabaseError),
+    #[error(transparent)]
+    SimulationError(#[from] SizingError),
+    #[error("Braindance swap reverted: {0}")]
+    Reverted(Bytes),
+    #[error("Braindance swap halted")]
+    Halted,
+    #[error("Failed to decode braindance output")]
+    DecodeError(#[from] AbiError),
+}
+
+/// Quotes swaps by running the braindance contract in a [ForkedDatabase]
+///
+/// The contract is injected at [get_braindance_address] and funded by writing its balance of the
+/// input token straight into the token's storage. Every quote runs on a snapshot of the fork
+/// that is reverted afterwards.
+#[derive(Debug, Clone)]
+pub struct BraindanceHarness<'a> {
+    layouts: &'a StorageLayoutFinder,
+    address: Address,
+    controller: Address,
+}
+
+impl<'a> BraindanceHarness<'a> {
+    pub fn new(layouts: &'a StorageLayoutFinder) -> Self {
+        Self {
+            layouts,
+            address: get_braindance_address(),
+            controller: get_braindance_controller_address(),
+        }
+    }
+
+    /// Quote a swap of `amount_in` of `input` for `output` on `pool`
+    ///
+    /// Returns:
+    /// Ok((amount_out, real_after_balance)): the amount the pool's math gives out and the
+    /// `output` balance the contract actually ended up with, a smaller balance means `output`
+    /// charges a fee on transfer
+    /// Err(BraindanceError): if the contract couldn't be funded or the swap failed
+    pub fn quote(
+        &self,
+        fork_db: &mut ForkedDatabase,
+        pool: &Pool,
+        amount_in: U256,
+        input: Address,
+        output: Address,
+    ) -> Result<(U256, U256), BraindanceError> {
+        let snapshot = fork_db.insert_snapshot();
+        let result = self.run_quote(fork_db, pool, amount_in, input, output);
+        fork_db.revert_snapshot(snapshot);
+        result
+    }
+
+    fn run_quote(
+        &self,
+        fork_db: &mut ForkedDatabase,
+        pool: &Pool,
+        amount_in: U256,
+        input: Address,
+        output: Address,
+    ) -> Result<(U256, U256), BraindanceError> {
+        self.deploy(fork_db);
+        self.fund(fork_db, input, amount_in)?;
+        self.swap(fork_db, pool, amount_in, input, output)
+    }
+
+    /// Swap `amount_in` of `input` held by the deployed contract for `output` on `pool`
+    ///
+    /// Unlike [BraindanceHarness::quote], the swap is committed to the fork so that several
+    /// steps can be chained, callers are responsible for snapshotting the fork
+    pub fn swap(
+        &self,
+        fork_db: &mut ForkedDatabase,
+        pool: &Pool,
+        amount_in: U256,
+        input: Address,
+        output: Address,
+    ) -> Result<(U256, U256), BraindanceError> {
+        let data = match pool.pool_variant {
+            PoolVariant::UniswapV2 => build_swap_v2_data(amount_in, pool.address, input, output),
+            PoolVariant::UniswapV3 => {
+                build_swap_v3_data(I256::from_raw(amount_in), pool.address, input, output)
+            }
+        };
+
+        let mut tx = TxEnv::default();
+        tx.caller = h160_to_b160(self.controller);
+        tx.transact_to = TransactTo::Call(h160_to_b160(self.address));
+        tx.data = data.0;
+        tx.gas_limit = BRAINDANCE_GAS_LIMIT;
+
+        let block = next_block_env(fork_db);
+        let output = match transact(fork_db, &block, tx)? {
+            ExecutionResult::Success {
+                output: Output::Call(output),
+                ..
+            } => Bytes::from(output),
+            ExecutionResult::Success { .. } | ExecutionResult::Halt { .. } => {
+                return Err(BraindanceError::Halted)
+            }
+            ExecutionResult::Revert { output, .. } => {
+                return Err(BraindanceError::Reverted(Bytes::from(output)))
+            }
+        };
+
+        let quote = match pool.pool_variant {
+            PoolVariant::UniswapV2 => decode_swap_v2_result(output)?,
+            PoolVariant::UniswapV3 => decode_swap_v3_result(output)?,
+        };
+        Ok(quote)
+    }
+
+    /// Address the braindance contract is deployed at
+    pub fn address(&self) -> Address {
+        self.address
+    }
+
+    /// Inject the braindance bytecode into the fork
+    pub fn deploy(&self, fork_db: &mut ForkedDatabase) {
+        let code = Bytecode::new_raw(get_braindance_code().0);
+        fork_db.database_mut().insert_account_info(
+            h160_to_b160(self.address),
+            AccountInfo::new(rU256::ZERO, 0, code),
+        );
+    }
+
+    /// Set the braindance contract's balance of `token`
+    pub fn fund(
+        &self,
+        fork_db: &mut ForkedDatabase,
+        token: Address,
+        amount: U256,
+    ) -> Result<(), BraindanceError> {
+        set_token_balance(self.layouts, fork_db, token, self.address, amount)
+    }
+}
+
+/// Overwrite the `token` balance of `holder` in the fork through the token's `balanceOf` slot
+pub fn set_token_balance(
+    layouts: &StorageLayoutFinder,
+    fork_db: &mut ForkedDatabase,
+    token: Address,
+    holder: Address,
+    amount: U256,
+) -> Result<(), BraindanceError> {
+    let block = fork_db.inner().meta().read().block_env.number.as_limbs()[0];
+    let slot = layouts.balance_of_slot(&*fork_db, token, block)?;
+    let key = U256::from_big_endian(slot.address_key(holder).as_bytes());
+
+    fork_db.database_mut().insert_account_storage(
+        h160_to_b160(token),
+        u256_to_ru256(key),
+        u256_to_ru256(amount),
+    )?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sandwich::{
+            sizing::{balance_of, get_amount_out, v2_reserves},
+            utils::constants::get_weth_address,
+        },
+        test_utils::{address, fork_db, v2_pair, DAI},
+    };
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_quote_v2_pool() {
+        let mut fork_db = fork_db().await;
+        let layouts = StorageLayoutFinder::default();
+        let harness = BraindanceHarness::new(&layouts);
+        let (weth, dai) = (get_weth_address(), address(DAI));
+        let pool = v2_pair(weth, dai);
+        let amount_in = U256::exp10(18);
+
+        let (reserve_weth, reserve_dai) = v2_reserves(&fork_db, &pool, weth).unwrap();
+        let expected = get_amount_out(amount_in, reserve_weth, reserve_dai, pool.swap_fee);
+
+        // DAI takes no fee on transfer, the contract receives what the pool gives out
+        let quote = harness
+            .quote(&mut fork_db, &pool, amount_in, weth, dai)
+            .unwrap();
+        assert_eq!(quote, (expected, expected));
+        // and the quote left the fork untouched
+        assert_eq!(
+            v2_reserves(&fork_db, &pool, weth).unwrap(),
+            (reserve_weth, reserve_dai)
+        );
+
+        // the same swap, step by step, is committed to the fork
+        harness.deploy(&mut fork_db);
+        harness.fund(&mut fork_db, weth, amount_in).unwrap();
+        let block = next_block_env(&fork_db);
+        assert_eq!(
+            balance_of(&mut fork_db, &block, weth, harness.address()).unwrap(),
+            amount_in
+        );
+        let (amount_out, _) = harness
+            .swap(&mut fork_db, &pool, amount_in, weth, dai)
+            .unwrap();
+        assert_eq!(amount_out, expected);
+        assert_eq!(
+            v2_reserves(&fork_db, &pool, weth).unwrap(),
+            (reserve_weth + amount_in, reserve_dai - expected)
+        );
+        assert_eq!(
+            balance_of(&mut fork_db, &block, dai, harness.address()).unwrap(),
+            expected
+        );
+    }
+}
@@ -0,0 +1,344 @@
+use crate::sandwich::{
+    sizing::{next_block_env, transact, victim_tx, SandwichSizer, SimulatedSandwich, SizingError},
+    utils::state_diff::SandwichablePool,
+};
+use ethers::types::{Transaction, U256};
+use fork_database::forked_db::ForkedDatabase;
+
+/// A pending tx swapping against a pool we can sandwich
+#[derive(Debug, Clone)]
+pub struct Victim {
+    pub tx: Transaction,
+    pub pool: SandwichablePool,
+}
+
+/// Pending txs swapping in the same direction on the same pool, in the order they were seen
+#[derive(Debug, Clone)]
+pub struct VictimGroup {
+    pub pool: SandwichablePool,
+    pub victims: Vec<Transaction>,
+}
+
+/// A sandwich ready to be bundled as frontrun -> victims -> backrun
+#[derive(Debug, Clone)]
+pub struct SandwichBundle {
+    pub pool: SandwichablePool,
+    pub victims: Vec<Transaction>,
+    pub sandwich: SimulatedSandwich,
+}
+
+/// Group victims by pool and swap direction
+///
+/// Victims keep the order they were received in, so txs from the same sender stay in nonce
+/// order. A tx seen twice is only kept once.
+pub fn group_victims(victims: &[Victim]) -> Vec<VictimGroup> {
+    let mut groups: Vec<VictimGroup> = vec![];
+
+    for victim in victims {
+        let key = (victim.pool.pool.address, victim.pool.is_weth_input);
+        match groups
+            .iter_mut()
+            .find(|group| (group.pool.pool.address, group.pool.is_weth_input) == key)
+        {
+            Some(group) => {
+                if !group.victims.iter().any(|tx| tx.hash == victim.tx.hash) {
+                    group.victims.push(victim.tx.clone());
+                }
+            }
+            None => groups.push(VictimGroup {
+                pool: victim.pool,
+                victims: vec![victim.tx.clone()],
+            }),
+        }
+    }
+
+    groups
+}
+
+impl<'a> SandwichSizer<'a> {
+    /// Find the most profitable bundle for a group of victims
+    ///
+    /// Every victim that doesn't revert on the fork behind the victims before it is within its
+    /// slippage limit and joins the group, even if sandwiching it alone isn't profitable it still
+    /// moves the price the backrun sells at. The group is sized together, the frontrun must
+    /// leave every one of them within its slippage limit, and victims with a tight limit capping
+    /// the group's frontrun are dropped, see [select_victims]. The frontrun and backrun cost the
+    /// same gas whatever the number of victims, so the bundles are compared on their WETH
+    /// profit.
+    ///
+    /// Returns:
+    /// Ok(Some(SandwichBundle)): the best of the multi-victim and single-victim bundles
+    /// Ok(None): if no bundle is profitable
+    /// Err(SizingError): if the fork database failed
+    pub fn best_bundle(
+        &self,
+        fork_db: &mut ForkedDatabase,
+        group: &VictimGroup,
+    ) -> Result<Option<SandwichBundle>, SizingError> {
+        let within_slippage = succeeding_in_order(fork_db, &group.victims)?;
+
+        let best = select_victims(&within_slippage, |victims| {
+            Ok(self
+                .optimize(fork_db, &group.pool, victims)?
+                .map(|sandwich| (sandwich.profit, sandwich)))
+        })?;
+
+        Ok(best.map(|(victims, sandwich)| {
+            if victims.len() > 1 {
+                log::info!(
+                    "Sandwiching {} victims on {:?} for {} WETH",
+                    victims.len(),
+                    group.pool.pool.address,
+                    sandwich.profit
+                );
+            }
+            SandwichBundle {
+                pool: group.pool,
+                victims,
+                sandwich,
+            }
+        }))
+    }
+}
+
+/// The victims executing without reverting when run one after another in bundle order
+///
+/// A victim reverting behind the earlier ones is left out, its revert doesn't move the price
+/// the next ones trade at. The fork is left untouched.
+fn succeeding_in_order(
+    fork_db: &mut ForkedDatabase,
+    victims: &[Transaction],
+) -> Result<Vec<Transaction>, SizingError> {
+    let snapshot = fork_db.insert_snapshot();
+    let block = next_block_env(fork_db);
+    let result = victims.iter().try_fold(vec![], |mut succeeding, victim| {
+        if transact(fork_db, &block, victim_tx(victim))?.is_success() {
+            succeeding.push(victim.clone());
+        }
+        Ok(succeeding)
+    });
+    fork_db.revert_snapshot(snapshot);
+    result
+}
+
+/// Pick the victims to sandwich among `victims`, all within their slippage limit
+///
+/// All of them are sized together, then the victim whose removal leaves the most profitable
+/// sandwich is dropped until a single one is left, so that a victim with a tight slippage
+/// limit doesn't rule out every larger group. `optimize` sizes a sandwich of the given victims,
+/// kept in their order, and returns its profit, `None` if it isn't profitable.
+///
+/// Returns the victims and the evaluation of the most profitable sandwich, the first one
+/// evaluated on ties
+pub fn select_victims<V: Clone, T, E, F>(
+    victims: &[V],
+    mut optimize: F,
+) -> Result<Option<(Vec<V>, T)>, E>
+where
+    F: FnMut(&[V]) -> Result<Option<(U256, T)>, E>,
+{
+    if victims.is_empty() {
+        return Ok(None);
+    }
+
+    let mut best: Option<(U256, Vec<V>, T)> = None;
+    let mut group = victims.to_vec();
+    let mut evaluated = optimize(&group)?;
+
+    loop {
+        if let Some((profit, evaluation)) = evaluated {
+            if best
+                .as_ref()
+                .map_or(true, |(best_profit, ..)| profit > *best_profit)
+            {
+                best = Some((profit, group.clone(), evaluation));
+            }
+        }
+        if group.len() == 1 {
+            break;
+        }
+
+        // unprofitable groups rank below every profitable one, the latest victim is dropped
+        // first on ties
+        let mut next: Option<(Option<U256>, Vec<V>, Option<(U256, T)>)> = None;
+        for index in (0..group.len()).rev() {
+            let mut candidate = group.clone();
+            candidate.remove(index);
+            let result = optimize(&candidate)?;
+            let profit = result.as_ref().map(|(profit, _)| *profit);
+            if next
+                .as_ref()
+                .map_or(true, |(next_profit, ..)| profit > *next_profit)
+            {
+                next = Some((profit, candidate, result));
+            }
+        }
+
+        match next {
+            Some((_, candidate, result)) => {
+                group = candidate;
+                evaluated = result;
+            }
+            None => break,
+        }
+    }
+
+    Ok(best.map(|(_, victims, evaluation)| (victims, evaluation)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sandwich::{
+            sizing::{get_amount_out, v2_reserves},
+            utils::constants::get_weth_address,
+        },
+        test_utils::{address, fork_db, sandwich_maker, v2_buy_tx, v2_pair, DAI},
+    };
+    use collectors::slot_finder::StorageLayoutFinder;
+    use ethers::types::{Address, H256, U256};
+    use qilin_cfmms::pool::{Pool, PoolVariant};
+
+    fn victim(hash: u64, pool: Pool, is_weth_input: bool) -> Victim {
+        Victim {
+            tx: Transaction {
+                hash: H256::from_low_u64_be(hash),
+                ..Default::default()
+            },
+            pool: SandwichablePool::new(pool, is_weth_input),
+        }
+    }
+
+    #[test]
+    fn test_group_victims() {
+        let pool = |address: u64| {
+            Pool::new_empty_pool(
+                Address::from_low_u64_be(address),
+                Address::from_low_u64_be(1),
+                Address::from_low_u64_be(2),
+                U256::from(3000),
+                PoolVariant::UniswapV2,
+            )
+        };
+        let (pool_a, pool_b) = (pool(0xa), pool(0xb));
+
+        let groups = group_victims(&[
+            victim(1, pool_a, true),
+            victim(2, pool_b, true),
+            victim(3, pool_a, true),
+            victim(4, pool_a, false),
+            victim(3, pool_a, true),
+        ]);
+
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups[0].pool.pool.address, pool_a.address);
+        assert_eq!(
+            groups[0]
+                .victims
+                .iter()
+                .map(|tx| tx.hash)
+                .collect::<Vec<_>>(),
+            vec![H256::from_low_u64_be(1), H256::from_low_u64_be(3)]
+        );
+        assert_eq!(groups[1].victims.len(), 1);
+        assert!(!groups[2].pool.is_weth_input);
+    }
+
+    /// Profit of sandwiching a set of victims, keyed by their sorted ids
+    fn select(victims: &[u64], profits: &[(&[u64], u64)]) -> Option<Vec<u64>> {
+        select_victims::<_, _, (), _>(victims, |candidate| {
+            let profit = profits
+                .iter()
+                .find(|(set, _)| *set == candidate)
+                .map(|(_, profit)| U256::from(*profit));
+            Ok(profit.map(|profit| (profit, candidate.to_vec())))
+        })
+        .unwrap()
+        .map(|(victims, _)| victims)
+    }
+
+    #[test]
+    fn test_select_victims() {
+        // 2 isn't worth sandwiching alone, but moves the price the backrun of 1 sells at
+        assert_eq!(
+            select(&[1, 2], &[(&[1], 10), (&[1, 2], 15)]),
+            Some(vec![1, 2])
+        );
+        // 3's tight slippage limit caps the group's frontrun
+        assert_eq!(
+            select(&[1, 3], &[(&[1], 10), (&[3], 2), (&[1, 3], 6)]),
+            Some(vec![1])
+        );
+        // 3 caps every group it's in, 1 and 2 are still sandwiched together
+        assert_eq!(
+            select(
+                &[1, 2, 3],
+                &[
+                    (&[1, 2, 3], 5),
+                    (&[1, 2], 15),
+                    (&[1, 3], 6),
+                    (&[2, 3], 3),
+                    (&[1], 10)
+                ]
+            ),
+            Some(vec![1, 2])
+        );
+        // the earliest bundle wins ties
+        assert_eq!(select(&[1, 4], &[(&[1], 10), (&[4], 10)]), Some(vec![1]));
+        assert_eq!(select(&[1, 2], &[]), None);
+        assert_eq!(select(&[], &[]), None);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_best_bundle_on_fork() {
+        let mut fork_db = fork_db().await;
+        let layouts = StorageLayoutFinder::default();
+        let ether = U256::exp10(18);
+        let maker = sandwich_maker(&mut fork_db, &layouts, ether * 100);
+        let sizer = SandwichSizer::new(&maker, ether * 100);
+        let (weth, dai) = (get_weth_address(), address(DAI));
+        let pool = SandwichablePool::new(v2_pair(weth, dai), true);
+
+        // victims buying DAI with 10 ETH, their limits are relative to the untouched pool
+        let victim_in = ether * 10;
+        let (reserve_weth, reserve_dai) = v2_reserves(&fork_db, &pool.pool, weth).unwrap();
+        let quote = get_amount_out(victim_in, reserve_weth, reserve_dai, pool.pool.swap_fee);
+        let first = v2_buy_tx(&mut fork_db, 1, dai, victim_in, quote * 97 / 100);
+        // no slippage allowed, it only executes alone
+        let exact = v2_buy_tx(&mut fork_db, 2, dai, victim_in, quote);
+        let second = v2_buy_tx(&mut fork_db, 3, dai, victim_in, quote * 95 / 100);
+
+        let group = VictimGroup {
+            pool,
+            victims: vec![first.clone(), exact.clone(), second.clone()],
+        };
+        let bundle = sizer.best_bundle(&mut fork_db, &group).unwrap().unwrap();
+        let hashes = |txs: &[Transaction]| txs.iter().map(|tx| tx.hash).collect::<Vec<_>>();
+        // `exact` reverts behind `first`
+        assert!(!hashes(&bundle.victims).contains(&exact.hash));
+
+        let mut profit = |victims: &[Transaction]| {
+            sizer
+                .optimize(&mut fork_db, &pool, victims)
+                .unwrap()
+                .map_or(U256::zero(), |sandwich| sandwich.profit)
+        };
+        let both = profit(&[first.clone(), second.clone()]);
+        let first_alone = profit(&[first.clone()]);
+        let second_alone = profit(&[second.clone()]);
+
+        // the second victim adds to the price the backrun of the first sells at
+        assert!(both > first_alone);
+        // and the bundle is the best of the two-victim and single-victim bundles
+        assert_eq!(
+            bundle.sandwich.profit,
+            both.max(first_alone).max(second_alone)
+        );
+        if bundle.victims.len() == 2 {
+            assert_eq!(hashes(&bundle.victims), hashes(&[first, second]));
+            assert!(bundle.sandwich.profit > second_alone);
+        }
+        assert!(bundle.sandwich.frontrun.gas_used > 0 && bundle.sandwich.backrun.gas_used > 0);
+    }
+}
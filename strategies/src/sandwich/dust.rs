@@ -0,0 +1,413 @@
+use crate::sandwich::{
+    sizing::{
+        balance_of, get_amount_out, next_block_env, transact, v2_reserves, SimulatedTx, SizingError,
+    },
+    state::BotState,
+    utils::{
+        constants::{get_erc20_transfer_event_signature, get_weth_address},
+        tx_builder::{v2::decode_intermediary, v3::encode_intermediary_token, SandwichMaker},
+    },
+};
+use dashmap::DashMap;
+use ethers::{
+    signers::Signer,
+    types::{
+        Address, Bytes, Eip1559TransactionRequest, NameOrAddress, TransactionReceipt, H256, I256,
+        U256, U64,
+    },
+};
+use fork_database::{
+    forked_db::ForkedDatabase,
+    utils::{h160_to_b160, u256_to_ru256},
+};
+use qilin_cfmms::pool::{Pool, PoolVariant};
+use revm::primitives::{ExecutionResult, TransactTo, TxEnv};
+
+/// Gas limit of a simulated dust sale
+const DUST_SALE_GAS_LIMIT: u64 = 500_000;
+/// Largest amount the sandwich contract takes unrounded in a v3 swap (0xFFFFFFFFFFFF)
+const V3_SMALL_AMOUNT_MAX: u128 = 281474976710655;
+/// Default number of blocks between two dust liquidations
+pub const DEFAULT_DUST_INTERVAL: u64 = 100;
+/// Priority fee paid by dust sales, nothing is competing for them
+pub const DUST_PRIORITY_FEE: u64 = 1_000_000_000;
+
+/// A sale of a dust token for WETH through the sandwich contract
+#[derive(Debug, Clone)]
+pub struct DustSale {
+    pub token: Address,
+    /// Pool the dust is sold in, the one giving out the most WETH
+    pub pool: Pool,
+    /// Amount of dust sold, after the contract's encoding
+    pub amount_in: U256,
+    /// WETH received by the contract in the simulation
+    pub weth_out: U256,
+    pub tx: SimulatedTx,
+}
+
+impl DustSale {
+    /// WETH left after paying for the sale's gas at `gas_price`
+    pub fn net_value(&self, gas_price: U256) -> Option<U256> {
+        self.weth_out
+            .checked_sub(U256::from(self.tx.gas_used) * gas_price)
+            .filter(|value| !value.is_zero())
+    }
+}
+
+/// A dust sale sent to the mempool, waiting for its receipt
+#[derive(Debug, Clone)]
+pub struct PendingDustSale {
+    pub sale: DustSale,
+    pub tx_hash: H256,
+    /// Block the sale was sent at, it's given up on after a dust interval
+    pub sent_block: U64,
+}
+
+/// Turns the tokens left on the sandwich contract by backruns back into WETH
+///
+/// Every `interval` blocks, each token in [BotState::token_dust] is sold on the fork through
+/// every WETH pool it trades in, and the best sale is kept if it's worth more than its gas.
+/// The dust is only removed from the bot state once a sale is included.
+#[derive(Debug, Clone)]
+pub struct DustManager<'a> {
+    maker: &'a SandwichMaker,
+    state: &'a BotState,
+    interval: u64,
+}
+
+impl<'a> DustManager<'a> {
+    pub fn new(maker: &'a SandwichMaker, state: &'a BotState) -> Self {
+        Self {
+            maker,
+            state,
+            interval: DEFAULT_DUST_INTERVAL,
+        }
+    }
+
+    /// Set the number of blocks between two liquidations
+    pub fn with_interval(mut self, interval: u64) -> Self {
+        self.interval = interval.max(1);
+        self
+    }
+
+    /// Should the dust be liquidated at `block_number`?
+    pub fn is_due(&self, block_number: U64) -> bool {
+        block_number.as_u64() % self.interval == 0
+    }
+
+    /// Find the dust worth selling at `gas_price`
+    ///
+    /// Arguments:
+    /// * `fork_db`: fork of the latest block, left untouched
+    /// * `all_pools`: pools to sell the dust in
+    /// * `gas_price`: price per gas the sales will pay, base fee included
+    ///
+    /// Returns:
+    /// Ok(Vec<DustSale>): the best sale of every token whose WETH value exceeds its gas cost
+    /// Err(SizingError): if the fork database failed
+    pub fn quote_dust(
+        &self,
+        fork_db: &mut ForkedDatabase,
+        all_pools: &DashMap<Address, Pool>,
+        gas_price: U256,
+    ) -> Result<Vec<DustSale>, SizingError> {
+        let weth = get_weth_address();
+        let dust = self.state.token_dust.read().clone();
+        let mut sales = vec![];
+
+        for token in dust {
+            let pools: Vec<Pool> = all_pools
+                .iter()
+                .filter(|pool| {
+                    (pool.token_0 == token && pool.token_1 == weth)
+                        || (pool.token_0 == weth && pool.token_1 == token)
+                })
+                .map(|pool| *pool.value())
+                .collect();
+
+            let mut best: Option<DustSale> = None;
+            for pool in pools {
+                let sale = match self.simulate_sale(fork_db, &pool, token)? {
+                    Some(sale) => sale,
+                    None => continue,
+                };
+                best = match best {
+                    Some(best) if best.weth_out >= sale.weth_out => Some(best),
+                    _ => Some(sale),
+                };
+            }
+
+            match best {
+                Some(sale) if sale.net_value(gas_price).is_some() => sales.push(sale),
+                Some(sale) => log::debug!(
+                    "Dust {:?} worth {} WETH doesn't cover {} gas",
+                    token,
+                    sale.weth_out,
+                    sale.tx.gas_used
+                ),
+                None => log::debug!("No WETH pool to sell dust {:?} in", token),
+            }
+        }
+
+        Ok(sales)
+    }
+
+    /// Simulate selling all of the contract's `token` in `pool`, leaving the fork untouched
+    ///
+    /// Returns `None` if the contract holds no sellable amount or the sale reverted
+    pub fn simulate_sale(
+        &self,
+        fork_db: &mut ForkedDatabase,
+        pool: &Pool,
+        token: Address,
+    ) -> Result<Option<DustSale>, SizingError> {
+        let snapshot = fork_db.insert_snapshot();
+        let result = self.run_sale(fork_db, pool, token);
+        fork_db.revert_snapshot(snapshot);
+        result
+    }
+
+    fn run_sale(
+        &self,
+        fork_db: &mut ForkedDatabase,
+        pool: &Pool,
+        token: Address,
+    ) -> Result<Option<DustSale>, SizingError> {
+        let weth = get_weth_address();
+        let sandwich = self.maker.sandwich_address;
+        let block = next_block_env(fork_db);
+
+        let balance = balance_of(fork_db, &block, token, sandwich)?;
+        let (amount_in, data, value) = match pool.pool_variant {
+            PoolVariant::UniswapV2 => {
+                let amount_in = decode_intermediary(balance, false, token);
+                let (reserve_token, reserve_weth) = v2_reserves(fork_db, pool, token)?;
+                let amount_out =
+                    get_amount_out(amount_in, reserve_token, reserve_weth, pool.swap_fee);
+                let (data, value) = self
+                    .maker
+                    .v2
+                    .create_payload_weth_is_output(amount_in, amount_out, token, *pool);
+                (amount_in, data, value)
+            }
+            PoolVariant::UniswapV3 => {
+                let amount_in = if balance <= U256::from(V3_SMALL_AMOUNT_MAX) {
+                    balance
+                } else {
+                    encode_intermediary_token(balance)
+                };
+                let data = self.maker.v3.create_payload_weth_is_output(
+                    I256::from_raw(amount_in),
+                    token,
+                    weth,
+                    *pool,
+                );
+                (amount_in, data, U256::zero())
+            }
+        };
+        if amount_in.is_zero() {
+            return Ok(None);
+        }
+
+        let weth_before = balance_of(fork_db, &block, weth, sandwich)?;
+
+        let data = Bytes::from(data);
+        let mut tx = TxEnv::default();
+        tx.caller = h160_to_b160(self.maker.searcher_wallet.address());
+        tx.transact_to = TransactTo::Call(h160_to_b160(sandwich));
+        tx.data = data.0.clone();
+        tx.value = u256_to_ru256(value);
+        tx.gas_limit = DUST_SALE_GAS_LIMIT;
+
+        let gas_used = match transact(fork_db, &block, tx)? {
+            ExecutionResult::Success { gas_used, .. } => gas_used,
+            _ => return Ok(None),
+        };
+
+        let weth_after = balance_of(fork_db, &block, weth, sandwich)?;
+
+        Ok(Some(DustSale {
+            token,
+            pool: *pool,
+            amount_in,
+            weth_out: weth_after.saturating_sub(weth_before),
+            tx: SimulatedTx {
+                data,
+                value,
+                gas_used,
+            },
+        }))
+    }
+
+    /// Build the tx of a dust sale, with 20% of gas headroom over the simulation
+    pub fn build_tx(
+        &self,
+        sale: &DustSale,
+        nonce: U256,
+        max_fee_per_gas: U256,
+        max_priority_fee_per_gas: U256,
+    ) -> Eip1559TransactionRequest {
+        Eip1559TransactionRequest {
+            to: Some(NameOrAddress::Address(self.maker.sandwich_address)),
+            from: Some(self.maker.searcher_wallet.address()),
+            data: Some(sale.tx.data.clone()),
+            chain_id: Some(U64::from(self.maker.searcher_wallet.chain_id())),
+            max_fee_per_gas: Some(max_fee_per_gas),
+            max_priority_fee_per_gas: Some(max_priority_fee_per_gas),
+            gas: Some(U256::from(sale.tx.gas_used) * 12 / 10),
+            nonce: Some(nonce),
+            value: Some(sale.tx.value),
+            access_list: Default::default(),
+        }
+    }
+
+    /// Update the bot state once a sale's tx is included
+    ///
+    /// The WETH received is credited by [BotState::sync_block] from the sale's Transfer logs,
+    /// only the dust is removed here
+    ///
+    /// Returns the WETH received by the contract, `None` if the tx reverted and the dust is
+    /// still on the contract
+    pub async fn on_included(&self, sale: &DustSale, receipt: &TransactionReceipt) -> Option<U256> {
+        if receipt.status != Some(U64::from(1)) {
+            log::warn!("Dust sale of {:?} reverted", sale.token);
+            return None;
+        }
+
+        let received = weth_received(receipt, self.maker.sandwich_address);
+        self.state.remove_dust(&sale.token).await;
+        log::info!("Sold dust {:?} for {} WETH", sale.token, received);
+
+        Some(received)
+    }
+}
+
+/// Sum of the WETH transferred to `recipient` in a receipt
+pub fn weth_received(receipt: &TransactionReceipt, recipient: Address) -> U256 {
+    let weth = get_weth_address();
+    let transfer = get_erc20_transfer_event_signature();
+    let recipient = H256::from(recipient);
+
+    receipt
+        .logs
+        .iter()
+        .filter(|log| {
+            log.address == weth
+                && log.topics.len() == 3
+                && log.topics[0] == transfer
+                && log.topics[2] == recipient
+        })
+        .fold(U256::zero(), |total, log| {
+            total + U256::from_big_endian(&log.data)
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sandwich::{braindance::set_token_balance, state::BotStateCheckpoint},
+        test_utils::{address, fork_db, sandwich_maker, transfer_log, v2_pair, DAI, DAI_WETH_V3},
+    };
+    use collectors::slot_finder::StorageLayoutFinder;
+
+    #[test]
+    fn test_weth_received() {
+        let (sandwich, pool) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2));
+        let token = Address::from_low_u64_be(3);
+        let receipt = TransactionReceipt {
+            logs: vec![
+                transfer_log(token, sandwich, pool, 500),
+                transfer_log(get_weth_address(), pool, sandwich, 1000),
+                transfer_log(get_weth_address(), pool, Address::from_low_u64_be(4), 7),
+            ],
+            ..Default::default()
+        };
+
+        assert_eq!(weth_received(&receipt, sandwich), U256::from(1000));
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_sell_dust_on_fork() {
+        let mut fork_db = fork_db().await;
+        let layouts = StorageLayoutFinder::default();
+        let ether = U256::exp10(18);
+        let maker = sandwich_maker(&mut fork_db, &layouts, ether);
+        let (weth, dai) = (get_weth_address(), address(DAI));
+        let dust = ether * 1000;
+        set_token_balance(&layouts, &mut fork_db, dai, maker.sandwich_address, dust).unwrap();
+
+        let state = BotState::from_checkpoint(
+            BotStateCheckpoint {
+                sandwich_address: maker.sandwich_address,
+                token_dust: vec![dai],
+                ..Default::default()
+            },
+            None,
+        );
+        let manager = DustManager::new(&maker, &state);
+        let v2 = v2_pair(weth, dai);
+        let v3 = Pool::new_empty_pool(
+            address(DAI_WETH_V3),
+            weth,
+            dai,
+            U256::from(3000),
+            PoolVariant::UniswapV3,
+        );
+
+        let mut sales = vec![];
+        for pool in [v2, v3] {
+            let sale = manager
+                .simulate_sale(&mut fork_db, &pool, dai)
+                .unwrap()
+                .unwrap();
+            // 1000 DAI are worth about 0.57 ETH at the fork block
+            assert!(
+                sale.weth_out > ether / 4 && sale.weth_out < ether,
+                "{}",
+                sale.weth_out
+            );
+            assert!(sale.amount_in > dust * 99 / 100 && sale.amount_in <= dust);
+            assert!(
+                (21_000..DUST_SALE_GAS_LIMIT).contains(&sale.tx.gas_used),
+                "{}",
+                sale.tx.gas_used
+            );
+            sales.push(sale);
+        }
+
+        // the v2 sale gets exactly the pair's quote, and the fork is left untouched
+        let (reserve_dai, reserve_weth) = v2_reserves(&fork_db, &v2, dai).unwrap();
+        assert_eq!(
+            sales[0].weth_out,
+            get_amount_out(sales[0].amount_in, reserve_dai, reserve_weth, v2.swap_fee)
+        );
+        let block = next_block_env(&fork_db);
+        assert_eq!(
+            balance_of(&mut fork_db, &block, dai, maker.sandwich_address).unwrap(),
+            dust
+        );
+
+        // the quote keeps the pool giving out the most WETH, as long as it covers the gas
+        let all_pools = DashMap::new();
+        all_pools.insert(v2.address, v2);
+        all_pools.insert(v3.address, v3);
+        let quoted = manager
+            .quote_dust(&mut fork_db, &all_pools, U256::from(30_000_000_000u64))
+            .unwrap();
+        assert_eq!(quoted.len(), 1);
+        assert_eq!(quoted[0].weth_out, sales[0].weth_out.max(sales[1].weth_out));
+        assert!(manager
+            .quote_dust(&mut fork_db, &all_pools, ether)
+            .unwrap()
+            .is_empty());
+
+        let sale = &quoted[0];
+        let tx = manager.build_tx(sale, U256::from(7), U256::from(100), U256::from(1));
+        assert_eq!(tx.to, Some(NameOrAddress::Address(maker.sandwich_address)));
+        assert_eq!(tx.data, Some(sale.tx.data.clone()));
+        assert_eq!(tx.value, Some(sale.tx.value));
+        assert_eq!(tx.nonce, Some(U256::from(7)));
+        assert_eq!(tx.gas, Some(U256::from(sale.tx.gas_used) * 12 / 10));
+    }
+}
@@ -1,11 +1,27 @@
 pub mod abi;
+pub mod braindance;
+pub mod bundling;
+pub mod dust;
+pub mod sizing;
 pub mod state;
 pub mod utils;
 
 use std::sync::Arc;
 
-use crate::sandwich::state::BotState;
+use crate::sandwich::{
+    dust::{DustManager, PendingDustSale, DEFAULT_DUST_INTERVAL, DUST_PRIORITY_FEE},
+    state::BotState,
+    utils::{
+        state_diff::{extract_pools_from_swaps, SandwichablePool},
+        tx_builder::SandwichMaker,
+    },
+};
 
+use collectors::{
+    inclusion_tracker::InclusionTracker,
+    state_diff::extract_pool_swaps,
+    types::{BlockPayload, BundleResult, NewTx},
+};
 use dashmap::DashMap;
 use parking_lot::RwLock;
 use qilin_cfmms::pool::Pool;
@@ -14,7 +30,7 @@ use ethers::{
     middleware::SignerMiddleware,
     providers::{JsonRpcClient, Middleware, PubsubClient},
     signers::Signer,
-    types::{Address, U64},
+    types::{Address, Block, H256, U256, U64},
 };
 use eyre::Result;
 use fork_database::forked_db::ForkedDatabase;
@@ -31,6 +47,13 @@ pub struct RustySandoStrategy<M, S> {
     pub sandwich_state: Arc<BotState>,
     pub all_pools: AllPools,
     pub fork_db: Arc<RwLock<ForkedDatabase>>,
+    /// Resolves the bundles sent by the strategy as new blocks come in
+    pub inclusion_tracker: Option<Arc<InclusionTracker>>,
+    /// Sells the dust left on the sandwich contract every `dust_interval` blocks
+    pub sandwich_maker: Option<Arc<SandwichMaker>>,
+    pub dust_interval: u64,
+    /// Dust sales sent and not resolved yet
+    pub pending_dust: Arc<RwLock<Vec<PendingDustSale>>>,
     // TODO: add bundle sender
 }
 
@@ -73,14 +96,176 @@ where
             sandwich_state,
             all_pools,
             fork_db,
+            inclusion_tracker: None,
+            sandwich_maker: None,
+            dust_interval: DEFAULT_DUST_INTERVAL,
+            pending_dust: Arc::new(RwLock::new(vec![])),
         })
     }
+
+    /// Track the landing of the sent bundles with `tracker`
+    pub fn with_inclusion_tracker(mut self, tracker: Arc<InclusionTracker>) -> Self {
+        self.inclusion_tracker = Some(tracker);
+        self
+    }
+
+    /// Sell the dust left on the sandwich contract with `maker` every `interval` blocks
+    pub fn with_dust_liquidation(mut self, maker: Arc<SandwichMaker>, interval: u64) -> Self {
+        self.sandwich_maker = Some(maker);
+        self.dust_interval = interval.max(1);
+        self
+    }
+
+    fn dust_manager(&self) -> Option<DustManager<'_>> {
+        self.sandwich_maker.as_ref().map(|maker| {
+            DustManager::new(maker, &self.sandwich_state).with_interval(self.dust_interval)
+        })
+    }
+
+    /// Handle a [NewBlock](crate::types::Event::NewBlock) event from the block collector
+    ///
+    /// Syncs the bot state with the transfers of the sandwich contract up to the block, resolves
+    /// the pending dust sales and sells the dust if it's due, then returns the bundles the block
+    /// resolved, if bundles are tracked
+    pub async fn on_new_block(&self, payload: &BlockPayload) -> Vec<BundleResult> {
+        if let Some(block_number) = payload.block_hash.number {
+            if let Err(e) = self
+                .sandwich_state
+                .sync_block(&self.provider, block_number)
+                .await
+            {
+                log::warn!(
+                    "Failed to sync the bot state to block {}: {}",
+                    block_number,
+                    e
+                );
+            }
+            self.resolve_dust_sales(block_number).await;
+            self.liquidate_dust(&payload.block_hash, block_number).await;
+        }
+
+        match &self.inclusion_tracker {
+            Some(tracker) => {
+                tracker
+                    .process_block(self.provider.as_ref(), &payload.block_hash)
+                    .await
+            }
+            None => vec![],
+        }
+    }
+
+    /// Remove the dust of the sales included by `block_number`, and give up on the sales sent a
+    /// dust interval ago
+    async fn resolve_dust_sales(&self, block_number: U64) {
+        let manager = match self.dust_manager() {
+            Some(manager) => manager,
+            None => return,
+        };
+
+        let pending = self.pending_dust.read().clone();
+        let mut unresolved = vec![];
+        for pending_sale in pending {
+            match self
+                .provider
+                .get_transaction_receipt(pending_sale.tx_hash)
+                .await
+            {
+                Ok(Some(receipt)) => {
+                    manager.on_included(&pending_sale.sale, &receipt).await;
+                }
+                Ok(None) if block_number < pending_sale.sent_block + self.dust_interval => {
+                    unresolved.push(pending_sale)
+                }
+                Ok(None) => log::warn!(
+                    "Dust sale {:?} of {:?} never landed",
+                    pending_sale.tx_hash,
+                    pending_sale.sale.token
+                ),
+                Err(e) => {
+                    log::warn!(
+                        "Failed to get the receipt of dust sale {:?}: {}",
+                        pending_sale.tx_hash,
+                        e
+                    );
+                    unresolved.push(pending_sale)
+                }
+            }
+        }
+        *self.pending_dust.write() = unresolved;
+    }
+
+    /// Send the sales of the dust worth more than their gas if the dust is due at `block`
+    ///
+    /// Nothing is sold while sales are pending, the dust they sell is still in the bot state
+    async fn liquidate_dust(&self, block: &Block<H256>, block_number: U64) {
+        let (manager, maker) = match (self.dust_manager(), &self.sandwich_maker) {
+            (Some(manager), Some(maker)) if manager.is_due(block_number) => (manager, maker),
+            _ => return,
+        };
+        if !self.pending_dust.read().is_empty() {
+            return;
+        }
+
+        let base_fee = block.next_block_base_fee().unwrap_or_default();
+        let priority_fee = U256::from(DUST_PRIORITY_FEE);
+        let sales = {
+            let mut fork_db = self.fork_db.write();
+            let all_pools = self.all_pools.read();
+            manager.quote_dust(&mut fork_db, &all_pools, base_fee + priority_fee)
+        };
+        let sales = match sales {
+            Ok(sales) => sales,
+            Err(e) => {
+                log::warn!("Failed to quote the dust at block {}: {}", block_number, e);
+                return;
+            }
+        };
+
+        for sale in sales {
+            // the sale may wait in the mempool until it's given up on
+            let reservation = maker
+                .nonce_manager
+                .reserve(1, block_number + self.dust_interval);
+            let tx = manager.build_tx(
+                &sale,
+                reservation.first_nonce,
+                base_fee * 2 + priority_fee,
+                priority_fee,
+            );
+            match self.wallet.send_transaction(tx, None).await {
+                Ok(pending_tx) => self.pending_dust.write().push(PendingDustSale {
+                    tx_hash: pending_tx.tx_hash(),
+                    sale,
+                    sent_block: block_number,
+                }),
+                Err(e) => {
+                    maker.nonce_manager.release(&reservation);
+                    log::warn!("Failed to send the dust sale of {:?}: {}", sale.token, e);
+                }
+            }
+        }
+    }
+
+    /// Pools a [NewMempoolTx](crate::types::Event::NewMempoolTx) can be sandwiched on
+    ///
+    /// The swaps decoded by the mempool collector are reused, the state diff is only decoded
+    /// if the collector wasn't given the pools.
+    pub fn sandwichable_pools(&self, new_tx: &NewTx) -> Vec<SandwichablePool> {
+        match &new_tx.swaps {
+            Some(swaps) => extract_pools_from_swaps(swaps),
+            None => {
+                let swaps = extract_pool_swaps(&new_tx.state_diff, &self.all_pools.read());
+                extract_pools_from_swaps(&swaps)
+            }
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::utils::state_diff::{extract_pools, get_from_txs, to_cache_db};
+    use super::utils::state_diff::{extract_pools, get_from_txs, to_cache_db, SandwichablePool};
     use super::*;
+    use collectors::slot_finder::StorageLayoutFinder;
 
     use dotenv::dotenv;
     use env_logger;
@@ -127,7 +312,7 @@ mod tests {
 
         let url = anvil.ws_endpoint().to_string();
         let provider = Arc::new(
-            Provider::<Ws>::connect(url)
+            Provider::<Ws>::connect(url.clone())
                 .await
                 .ok()
                 .ok_or(eyre::eyre!("Error connecting to anvil instance"))?,
@@ -152,8 +337,12 @@ mod tests {
         }
 
         // setup fork database
-        let fork_db =
-            fork_database::setup_fork_db(provider.clone(), mainnet_http_url.to_string()).await;
+        let fork_db = fork_database::ForkDbBuilder::new(provider.clone())
+            .with_block(block_num)
+            // the fork reads through anvil, so key its cache on the anvil endpoint
+            .with_host(url)
+            .build()
+            .await?;
 
         // setup wallet and client for sandwich contract deployment
         let wallet: LocalWallet = anvil.keys()[0].clone().into();
@@ -178,7 +367,7 @@ mod tests {
         Ok(rusty)
     }
 
-    #[tokio::test]
+    #[tokio::test(flavor = "multi_thread")]
     async fn test_rusty_sando_strategy() -> Result<()> {
         let rusty = setup().await?;
 
@@ -207,7 +396,16 @@ mod tests {
         .await
         .unwrap();
 
-        let sandwitch_pools = extract_pools(&res, &rusty.all_pools.clone().read()).unwrap();
+        let layouts = StorageLayoutFinder::default();
+        let routes = extract_pools(
+            &layouts,
+            &*rusty.fork_db.read(),
+            INIT_BLOCK,
+            &res,
+            &rusty.all_pools.clone().read(),
+        )
+        .unwrap();
+        let sandwitch_pools = SandwichablePool::from_routes(&routes);
 
         assert_eq!(sandwitch_pools.len(), 1);
         // Uniswap V3 USDC 3 Pool Address: 0x88e6A0c2dDD26FEEb64F039a2c41296FcB3f5640
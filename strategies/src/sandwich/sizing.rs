@@ -0,0 +1,677 @@
+use crate::{
+    sandwich::utils::{
+        constants::get_weth_address,
+        state_diff::SandwichablePool,
+        tx_builder::{
+            v2::{encode_intermediary_with_dust, encode_weth},
+            v3::encode_intermediary_token,
+            SandwichMaker,
+        },
+    },
+    token_safety::{TokenRegistry, TokenSafetyChecker},
+};
+use ethers::{
+    abi::{self, Token},
+    signers::Signer,
+    types::{Address, Bytes, Transaction, I256, U256},
+};
+use fork_database::{
+    errors::DatabaseError,
+    forked_db::ForkedDatabase,
+    utils::{h160_to_b160, ru256_to_u256, u256_to_ru256},
+};
+use qilin_cfmms::pool::{Pool, PoolVariant};
+use revm::{
+    db::DatabaseRef,
+    primitives::{BlockEnv, CreateScheme, ExecutionResult, Output, TransactTo, U256 as rU256},
+    EVM,
+};
+use thiserror::Error;
+
+/// Gas limit of the simulated frontrun and backrun
+const SANDWICH_GAS_LIMIT: u64 = 1_000_000;
+/// Storage slot of `reserve0`, `reserve1` and `blockTimestampLast` in a UniswapV2 pair
+const V2_RESERVES_SLOT: u64 = 8;
+/// Denominator of [Pool::swap_fee], fees are in hundredths of a bip
+const SWAP_FEE_DENOMINATOR: u64 = 1_000_000;
+/// Default number of sandwich simulations per optimization
+pub const DEFAULT_MAX_ITERATIONS: usize = 24;
+
+#[derive(Error, Debug)]
+pub enum SizingError {
+    #[error("EVM error: {0}")]
+    EvmError(String),
+    #[error("Failed to read from the fork database")]
+    DatabaseError(#[from] DatabaseError),
+}
+
+/// A frontrun or backrun as executed on the fork
+#[derive(Debug, Clone)]
+pub struct SimulatedTx {
+    /// Calldata sent to the sandwich contract
+    pub data: Bytes,
+    /// Encoded call value sent along the calldata
+    pub value: U256,
+    pub gas_used: u64,
+}
+
+/// The outcome of running frontrun, victims and backrun on the fork
+#[derive(Debug, Clone)]
+pub struct SimulatedSandwich {
+    /// WETH sold in the frontrun
+    pub frontrun_in: U256,
+    pub frontrun: SimulatedTx,
+    pub backrun: SimulatedTx,
+    /// WETH gained by the sandwich contract, before gas
+    pub profit: U256,
+}
+
+/// Chooses the frontrun amount of a sandwich by simulating it on a [ForkedDatabase]
+///
+/// Every candidate amount runs the frontrun, the victims and the backrun on top of a snapshot of
+/// the fork, which is reverted before the next candidate.
+#[derive(Debug, Clone)]
+pub struct SandwichSizer<'a> {
+    maker: &'a SandwichMaker,
+    /// WETH held by the sandwich contract, the frontrun can't spend more
+    weth_balance: U256,
+    /// Stop searching once the search interval is narrower than this
+    tolerance: U256,
+    max_iterations: usize,
+    /// Verdicts consulted before sizing, tokens that aren't safe to trade are skipped
+    token_safety: Option<(&'a TokenRegistry, &'a TokenSafetyChecker<'a>)>,
+}
+
+impl<'a> SandwichSizer<'a> {
+    pub fn new(maker: &'a SandwichMaker, weth_balance: U256) -> Self {
+        Self {
+            maker,
+            weth_balance,
+            // 0.001 WETH
+            tolerance: U256::from(10).pow(U256::from(15)),
+            max_iterations: DEFAULT_MAX_ITERATIONS,
+            token_safety: None,
+        }
+    }
+
+    pub fn with_tolerance(mut self, tolerance: U256) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    pub fn with_max_iterations(mut self, max_iterations: usize) -> Self {
+        self.max_iterations = max_iterations;
+        self
+    }
+
+    /// Check the token traded against WETH before sizing, unchecked tokens are classified on
+    /// the fork and cached in `registry`
+    pub fn with_token_safety(
+        mut self,
+        registry: &'a TokenRegistry,
+        checker: &'a TokenSafetyChecker<'a>,
+    ) -> Self {
+        self.token_safety = Some((registry, checker));
+        self
+    }
+
+    /// Find the frontrun amount maximizing the WETH profit of sandwiching `victims` on `pool`
+    ///
+    /// Only victims buying with WETH can be sandwiched, since the frontrun has to sell WETH.
+    /// Amounts at which a victim reverts (its slippage limit is exceeded) or the sandwich fails
+    /// are never returned.
+    ///
+    /// Returns:
+    /// Ok(Some(SimulatedSandwich)): the most profitable sandwich found
+    /// Ok(None): if no amount is profitable
+    /// Err(SizingError): if the fork database failed
+    pub fn optimize(
+        &self,
+        fork_db: &mut ForkedDatabase,
+        pool: &SandwichablePool,
+        victims: &[Transaction],
+    ) -> Result<Option<SimulatedSandwich>, SizingError> {
+        if !pool.is_weth_input || victims.is_empty() {
+            return Ok(None);
+        }
+
+        if let Some((registry, checker)) = self.token_safety {
+            let token = if pool.pool.token_0 == get_weth_address() {
+                pool.pool.token_1
+            } else {
+                pool.pool.token_0
+            };
+            if !registry.is_tradable(checker, fork_db, token, &pool.pool) {
+                return Ok(None);
+            }
+        }
+
+        search_optimal_amount(
+            self.weth_balance,
+            self.tolerance,
+            self.max_iterations,
+            |amount| {
+                Ok(self
+                    .simulate(fork_db, pool, victims, amount)?
+                    .map(|sandwich| (sandwich.profit, sandwich)))
+            },
+        )
+    }
+
+    /// Simulate a sandwich with a given frontrun amount, leaving the fork untouched
+    ///
+    /// Returns `None` if any of the txs reverted
+    pub fn simulate(
+        &self,
+        fork_db: &mut ForkedDatabase,
+        pool: &SandwichablePool,
+        victims: &[Transaction],
+        frontrun_in: U256,
+    ) -> Result<Option<SimulatedSandwich>, SizingError> {
+        let snapshot = fork_db.insert_snapshot();
+        let result = self.run_sandwich(fork_db, pool, victims, frontrun_in);
+        fork_db.revert_snapshot(snapshot);
+        result
+    }
+
+    fn run_sandwich(
+        &self,
+        fork_db: &mut ForkedDatabase,
+        pool: &SandwichablePool,
+        victims: &[Transaction],
+        frontrun_in: U256,
+    ) -> Result<Option<SimulatedSandwich>, SizingError> {
+        let weth = get_weth_address();
+        let sandwich = self.maker.sandwich_address;
+        let searcher = self.maker.searcher_wallet.address();
+        let other_token = if pool.pool.token_0 == weth {
+            pool.pool.token_1
+        } else {
+            pool.pool.token_0
+        };
+        let block = next_block_env(fork_db);
+
+        let weth_before = balance_of(fork_db, &block, weth, sandwich)?;
+
+        // frontrun: buy the token the victims buy
+        let frontrun_in = encode_weth(frontrun_in);
+        let (frontrun_data, frontrun_value) = match pool.pool.pool_variant {
+            PoolVariant::UniswapV2 => {
+                let (reserve_weth, reserve_other) = v2_reserves(fork_db, &pool.pool, weth)?;
+                let amount_out =
+                    get_amount_out(frontrun_in, reserve_weth, reserve_other, pool.pool.swap_fee);
+                self.maker.v2.create_payload_weth_is_input(
+                    frontrun_in,
+                    amount_out,
+                    other_token,
+                    pool.pool,
+                )
+            }
+            PoolVariant::UniswapV3 => self.maker.v3.create_payload_weth_is_input(
+                I256::from_raw(frontrun_in),
+                weth,
+                other_token,
+                pool.pool,
+            ),
+        };
+        let frontrun = match self.execute_sandwich_tx(
+            fork_db,
+            &block,
+            searcher,
+            frontrun_data,
+            frontrun_value,
+        )? {
+            Some(frontrun) => frontrun,
+            None => return Ok(None),
+        };
+
+        // victims revert if the frontrun pushed the price past their slippage limit
+        for victim in victims {
+            if !transact(fork_db, &block, victim_tx(victim))?.is_success() {
+                return Ok(None);
+            }
+        }
+
+        // backrun: sell everything the frontrun bought, keeping some dust on the contract
+        let intermediary = balance_of(fork_db, &block, other_token, sandwich)?;
+        if intermediary.is_zero() {
+            return Ok(None);
+        }
+        let (backrun_data, backrun_value) = match pool.pool.pool_variant {
+            PoolVariant::UniswapV2 => {
+                let backrun_in = encode_intermediary_with_dust(intermediary, false, other_token);
+                let (reserve_weth, reserve_other) = v2_reserves(fork_db, &pool.pool, weth)?;
+                let amount_out =
+                    get_amount_out(backrun_in, reserve_other, reserve_weth, pool.pool.swap_fee);
+                self.maker.v2.create_payload_weth_is_output(
+                    backrun_in,
+                    amount_out,
+                    other_token,
+                    pool.pool,
+                )
+            }
+            PoolVariant::UniswapV3 => {
+                let backrun_in = encode_intermediary_token(intermediary);
+                let payload = self.maker.v3.create_payload_weth_is_output(
+                    I256::from_raw(backrun_in),
+                    other_token,
+                    weth,
+                    pool.pool,
+                );
+                (payload, U256::zero())
+            }
+        };
+        let backrun = match self.execute_sandwich_tx(
+            fork_db,
+            &block,
+            searcher,
+            backrun_data,
+            backrun_value,
+        )? {
+            Some(backrun) => backrun,
+            None => return Ok(None),
+        };
+
+        let weth_after = balance_of(fork_db, &block, weth, sandwich)?;
+
+        Ok(Some(SimulatedSandwich {
+            frontrun_in,
+            frontrun,
+            backrun,
+            profit: weth_after.saturating_sub(weth_before),
+        }))
+    }
+
+    fn execute_sandwich_tx(
+        &self,
+        fork_db: &mut ForkedDatabase,
+        block: &BlockEnv,
+        searcher: Address,
+        data: Vec<u8>,
+        value: U256,
+    ) -> Result<Option<SimulatedTx>, SizingError> {
+        let data = Bytes::from(data);
+        let mut tx = revm::primitives::TxEnv::default();
+        tx.caller = h160_to_b160(searcher);
+        tx.transact_to = TransactTo::Call(h160_to_b160(self.maker.sandwich_address));
+        tx.data = data.0.clone();
+        tx.value = u256_to_ru256(value);
+        tx.gas_limit = SANDWICH_GAS_LIMIT;
+
+        match transact(fork_db, block, tx)? {
+            ExecutionResult::Success { gas_used, .. } => Ok(Some(SimulatedTx {
+                data,
+                value,
+                gas_used,
+            })),
+            _ => Ok(None),
+        }
+    }
+}
+
+/// Search the amount in `[0, upper]` maximizing the profit returned by `evaluate`
+///
+/// `evaluate` returns `None` for infeasible amounts. Feasibility is assumed to be monotone (an
+/// amount is feasible if every smaller amount is), and profit to be unimodal over the feasible
+/// range, so the largest feasible amount is binary searched first and the profit is then
+/// maximized over `[0, largest feasible amount]`.
+///
+/// Returns the evaluation with the highest non zero profit seen
+pub fn search_optimal_amount<T, E, F>(
+    upper: U256,
+    tolerance: U256,
+    max_iterations: usize,
+    mut evaluate: F,
+) -> Result<Option<T>, E>
+where
+    F: FnMut(U256) -> Result<Option<(U256, T)>, E>,
+{
+    let mut best: Option<(U256, T)> = None;
+    let mut iterations = 0;
+    let tolerance = tolerance.max(U256::one());
+
+    let mut probe = |amount: U256, best: &mut Option<(U256, T)>| -> Result<Option<U256>, E> {
+        let evaluation = evaluate(amount)?;
+        let profit = evaluation.as_ref().map(|(profit, _)| *profit);
+        match (evaluation, best.as_ref()) {
+            (Some((profit, _)), _) if profit.is_zero() => {}
+            (Some((profit, _)), Some((best_profit, _))) if profit <= *best_profit => {}
+            (Some(evaluation), _) => *best = Some(evaluation),
+            (None, _) => {}
+        }
+        Ok(profit)
+    };
+
+    // largest feasible amount
+    let (mut low, mut high) = (U256::zero(), upper);
+    iterations += 1;
+    if probe(upper, &mut best)?.is_some() {
+        low = upper;
+    } else {
+        while high - low > tolerance && iterations < max_iterations / 2 {
+            let mid = low + (high - low) / 2;
+            iterations += 1;
+            if probe(mid, &mut best)?.is_some() {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+    }
+
+    // maximize the profit over the feasible range
+    let (mut low, mut high) = (U256::zero(), low);
+    while high - low > tolerance && iterations + 2 <= max_iterations {
+        let third = (high - low) / 3;
+        let (left, right) = (low + third, high - third);
+        iterations += 2;
+        let left_profit = probe(left, &mut best)?.unwrap_or_default();
+        let right_profit = probe(right, &mut best)?.unwrap_or_default();
+        if left_profit < right_profit {
+            low = left;
+        } else {
+            high = right;
+        }
+    }
+
+    Ok(best.map(|(_, result)| result))
+}
+
+/// The env of the block the sandwich lands in
+///
+/// Base fee is zeroed so that simulations don't depend on the senders' ETH balance, gas is
+/// priced separately from the profit
+pub(crate) fn next_block_env(fork_db: &ForkedDatabase) -> BlockEnv {
+    let mut block = fork_db.inner().meta().read().block_env.clone();
+    block.number += rU256::from(1);
+    block.timestamp += rU256::from(12);
+    block.basefee = rU256::ZERO;
+    block
+}
+
+/// Execute and commit a tx on the fork
+pub(crate) fn transact(
+    fork_db: &mut ForkedDatabase,
+    block: &BlockEnv,
+    tx: revm::primitives::TxEnv,
+) -> Result<ExecutionResult, SizingError> {
+    let mut evm = EVM::new();
+    evm.env.block = block.clone();
+    evm.env.tx = tx;
+    evm.database(fork_db);
+
+    evm.transact_commit()
+        .map_err(|e| SizingError::EvmError(format!("{:?}", e)))
+}
+
+/// Read an ERC20 balance on the fork
+pub(crate) fn balance_of(
+    fork_db: &mut ForkedDatabase,
+    block: &BlockEnv,
+    token: Address,
+    holder: Address,
+) -> Result<U256, SizingError> {
+    let mut data = vec![0x70, 0xa0, 0x82, 0x31];
+    data.extend(abi::encode(&[Token::Address(holder)]));
+
+    let mut evm = EVM::new();
+    evm.env.block = block.clone();
+    evm.env.tx.transact_to = TransactTo::Call(h160_to_b160(token));
+    evm.env.tx.data = data.into();
+    evm.env.tx.gas_limit = SANDWICH_GAS_LIMIT;
+    evm.database(fork_db);
+
+    let result = evm
+        .transact()
+        .map_err(|e| SizingError::EvmError(format!("{:?}", e)))?
+        .result;
+
+    match result {
+        ExecutionResult::Success {
+            output: Output::Call(output),
+            ..
+        } if output.len() >= 32 => Ok(U256::from_big_endian(&output[..32])),
+        _ => Err(SizingError::EvmError(format!(
+            "balanceOf({:?}) on {:?} failed",
+            holder, token
+        ))),
+    }
+}
+
+/// Build the env of a pending tx, priced at zero gas
+pub(crate) fn victim_tx(tx: &Transaction) -> revm::primitives::TxEnv {
+    let mut env = revm::primitives::TxEnv::default();
+    env.caller = h160_to_b160(tx.from);
+    env.transact_to = match tx.to {
+        Some(to) => TransactTo::Call(h160_to_b160(to)),
+        None => TransactTo::Create(CreateScheme::Create),
+    };
+    env.data = tx.input.0.clone();
+    env.value = u256_to_ru256(tx.value);
+    env.gas_limit = tx.gas.as_u64();
+    env
+}
+
+/// Returns the `(token, other token)` reserves of a UniswapV2 pair on the fork
+pub(crate) fn v2_reserves(
+    fork_db: &ForkedDatabase,
+    pool: &Pool,
+    token: Address,
+) -> Result<(U256, U256), SizingError> {
+    let reserves = ru256_to_u256(DatabaseRef::storage(
+        fork_db,
+        h160_to_b160(pool.address),
+        rU256::from(V2_RESERVES_SLOT),
+    )?);
+    let mask = (U256::one() << 112) - 1;
+    let (reserve_0, reserve_1) = (reserves & mask, (reserves >> 112) & mask);
+
+    if pool.token_0 == token {
+        Ok((reserve_0, reserve_1))
+    } else {
+        Ok((reserve_1, reserve_0))
+    }
+}
+
+/// UniswapV2Library.getAmountOut, for a pair charging `swap_fee`
+///
+/// Arguments:
+/// * `swap_fee`: the pool's [Pool::swap_fee], in hundredths of a bip (3000 for UniswapV2)
+pub fn get_amount_out(
+    amount_in: U256,
+    reserve_in: U256,
+    reserve_out: U256,
+    swap_fee: U256,
+) -> U256 {
+    let denominator = U256::from(SWAP_FEE_DENOMINATOR);
+    if amount_in.is_zero()
+        || reserve_in.is_zero()
+        || reserve_out.is_zero()
+        || swap_fee >= denominator
+    {
+        return U256::zero();
+    }
+    let amount_in_with_fee = amount_in * (denominator - swap_fee);
+    let numerator = amount_in_with_fee * reserve_out;
+    numerator / (reserve_in * denominator + amount_in_with_fee)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sandwich::braindance::BraindanceHarness,
+        test_utils::{address, fork_db, sandwich_maker, v2_buy_tx, v2_pair, DAI},
+    };
+    use collectors::slot_finder::StorageLayoutFinder;
+
+    fn search(upper: u64, profit: impl Fn(u64) -> Option<u64>) -> Option<u64> {
+        search_optimal_amount::<_, (), _>(U256::from(upper), U256::one(), 64, |amount| {
+            let amount = amount.as_u64();
+            Ok(profit(amount).map(|p| (U256::from(p), amount)))
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_search_stops_at_slippage_limit() {
+        // profit grows with the frontrun until the victim reverts above 600
+        let best = search(1_000, |x| (x <= 600).then_some(x / 2)).unwrap();
+        assert_eq!(best, 600);
+    }
+
+    #[test]
+    fn test_search_finds_interior_optimum() {
+        // profit peaks at 300, victim reverts above 800
+        let best = search(1_000, |x| {
+            (x <= 800).then_some(90_000u64.saturating_sub(x.abs_diff(300).pow(2)))
+        })
+        .unwrap();
+        assert!((295..=305).contains(&best), "{}", best);
+    }
+
+    #[test]
+    fn test_search_unprofitable() {
+        assert_eq!(search(1_000, |_| Some(0)), None);
+        assert_eq!(search(1_000, |_| None), None);
+    }
+
+    #[test]
+    fn test_get_amount_out() {
+        let reserve = U256::from(10).pow(U256::from(21));
+        let amount_in = U256::from(10).pow(U256::from(18));
+        // 1 in for 1000 reserves at 0.3% fee
+        assert_eq!(
+            get_amount_out(amount_in, reserve, reserve, U256::from(3000)),
+            U256::from_dec_str("996006981039903216").unwrap()
+        );
+        // a pair forked with a 0.25% fee
+        assert_eq!(
+            get_amount_out(amount_in, reserve, reserve, U256::from(2500)),
+            U256::from_dec_str("996505985279683515").unwrap()
+        );
+        assert_eq!(
+            get_amount_out(amount_in, reserve, reserve, U256::from(1_000_000)),
+            U256::zero()
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_search_sandwich_on_fork() {
+        let mut fork_db = fork_db().await;
+        let layouts = StorageLayoutFinder::default();
+        let harness = BraindanceHarness::new(&layouts);
+        let (weth, dai) = (get_weth_address(), address(DAI));
+        let pool = v2_pair(weth, dai);
+        let ether = U256::exp10(18);
+
+        // a victim buying DAI with 10 WETH at 1% slippage
+        let victim_in = ether * 10;
+        let (reserve_weth, reserve_dai) = v2_reserves(&fork_db, &pool, weth).unwrap();
+        let min_out =
+            get_amount_out(victim_in, reserve_weth, reserve_dai, pool.swap_fee) * 99 / 100;
+
+        // frontrun, victim and backrun swapped through the braindance contract
+        let mut profit = |frontrun_in: U256| -> Result<Option<(U256, U256)>, ()> {
+            let snapshot = fork_db.insert_snapshot();
+            let mut sandwich = || -> Option<U256> {
+                harness.deploy(&mut fork_db);
+                harness.fund(&mut fork_db, weth, frontrun_in).ok()?;
+                let (bought, _) = harness
+                    .swap(&mut fork_db, &pool, frontrun_in, weth, dai)
+                    .ok()?;
+                harness.fund(&mut fork_db, weth, victim_in).ok()?;
+                let (victim_out, _) = harness
+                    .swap(&mut fork_db, &pool, victim_in, weth, dai)
+                    .ok()?;
+                if victim_out < min_out {
+                    return None;
+                }
+                let (sold, _) = harness.swap(&mut fork_db, &pool, bought, dai, weth).ok()?;
+                Some(sold.saturating_sub(frontrun_in))
+            };
+            let result = sandwich();
+            fork_db.revert_snapshot(snapshot);
+            Ok(result.map(|profit| (profit, frontrun_in)))
+        };
+
+        let best = search_optimal_amount(
+            ether * 100,
+            ether / 100,
+            DEFAULT_MAX_ITERATIONS,
+            &mut profit,
+        )
+        .unwrap()
+        .unwrap();
+        let (best_profit, _) = profit(best).unwrap().unwrap();
+
+        // no amount on a grid of the range does noticeably better, the search stops within a
+        // few binary search steps of the victim's limit
+        for step in 1..=20u64 {
+            let amount = ether * 5 * step;
+            match profit(amount).unwrap() {
+                Some((grid_profit, _)) => assert!(
+                    grid_profit <= best_profit + best_profit / 100,
+                    "{} at {} beats {} at {}",
+                    grid_profit,
+                    amount,
+                    best_profit,
+                    best
+                ),
+                None => assert!(amount > best),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_optimize_on_fork() {
+        let mut fork_db = fork_db().await;
+        let layouts = StorageLayoutFinder::default();
+        let ether = U256::exp10(18);
+        let maker = sandwich_maker(&mut fork_db, &layouts, ether * 100);
+        let sizer = SandwichSizer::new(&maker, ether * 100);
+        let (weth, dai) = (get_weth_address(), address(DAI));
+        let pool = SandwichablePool::new(v2_pair(weth, dai), true);
+
+        // a victim buying DAI with 10 ETH at 1% slippage
+        let victim_in = ether * 10;
+        let (reserve_weth, reserve_dai) = v2_reserves(&fork_db, &pool.pool, weth).unwrap();
+        let quote = get_amount_out(victim_in, reserve_weth, reserve_dai, pool.pool.swap_fee);
+        let victim = v2_buy_tx(&mut fork_db, 1, dai, victim_in, quote * 99 / 100);
+        let victims = [victim];
+
+        let best = sizer
+            .optimize(&mut fork_db, &pool, &victims)
+            .unwrap()
+            .unwrap();
+        assert!(best.profit > U256::zero());
+        assert!(best.frontrun_in > U256::zero() && best.frontrun_in <= ether * 100);
+        // both legs are single pair swaps through the sandwich contract
+        for leg in [&best.frontrun, &best.backrun] {
+            assert!(
+                (21_000..200_000).contains(&leg.gas_used),
+                "{}",
+                leg.gas_used
+            );
+        }
+
+        // the fork was left untouched, the same amount simulates to the same sandwich
+        assert_eq!(
+            v2_reserves(&fork_db, &pool.pool, weth).unwrap(),
+            (reserve_weth, reserve_dai)
+        );
+        let again = sizer
+            .simulate(&mut fork_db, &pool, &victims, best.frontrun_in)
+            .unwrap()
+            .unwrap();
+        assert_eq!(again.profit, best.profit);
+        assert_eq!(again.frontrun.gas_used, best.frontrun.gas_used);
+        assert_eq!(again.backrun.gas_used, best.backrun.gas_used);
+
+        // twice the frontrun pushes the victim past its slippage limit
+        assert!(sizer
+            .simulate(&mut fork_db, &pool, &victims, best.frontrun_in * 2)
+            .unwrap()
+            .is_none());
+    }
+}
@@ -7,21 +7,44 @@ use ethers::prelude::*;
 use eyre::Result;
 use log;
 use parking_lot::RwLock;
+use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
+use std::fs;
+use std::path::PathBuf;
 use std::str::FromStr;
 
 use crate::sandwich::{abi::Erc20, utils};
 
+/// Number of blocks scanned per `eth_getLogs` request
+const LOG_SCAN_STEP: u64 = 10000;
+
+/// What [BotState] persists between runs
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BotStateCheckpoint {
+    /// Contract the checkpoint was taken for, a checkpoint of another contract is discarded
+    pub sandwich_address: Address,
+    pub token_dust: Vec<Address>,
+    pub weth_balance: U256,
+    /// Last block whose Transfer logs are accounted for
+    pub last_block: U64,
+}
+
 #[derive(Clone, Debug)]
 /// Holds the state of the bot
 pub struct BotState {
     pub token_dust: Arc<RwLock<Vec<Address>>>,
     pub weth_balance: Arc<RwLock<U256>>,
+    pub last_block: Arc<RwLock<U64>>,
+    pub sandwich_address: Address,
+    cache_path: Option<PathBuf>,
 }
 
 impl BotState {
     // Create a new instance of the bot state
     //
+    // The state is resumed from the checkpoint at `BOT_STATE_PATH` if set, only the blocks
+    // after the checkpoint are scanned for Transfer logs
+    //
     // Arguments:
     // * `sandwich_inception_block`: block number sandwich was deployed
     // * `client`: websocket provider to use for fetching data
@@ -42,21 +65,115 @@ impl BotState {
         M::Provider: PubsubClient,
         M::Provider: JsonRpcClient,
     {
-        let token_dust =
-            Self::find_all_dust(sandwich_inception_block, client, test, sandwich_address).await?;
-        let token_dust = Arc::new(RwLock::new(token_dust));
-
         let sandy_addr = get_sandy_addr(test, sandwich_address);
+        let cache_path = if test { None } else { get_bot_state_path() };
+
+        let checkpoint = cache_path
+            .as_ref()
+            .and_then(|path| load_checkpoint(path))
+            .filter(|checkpoint| checkpoint.sandwich_address == sandy_addr);
+
+        let resumed = checkpoint.is_some();
+        let (start_block, mut checkpoint) = match checkpoint {
+            Some(checkpoint) => {
+                log::info!("Resuming bot state from block {}", checkpoint.last_block);
+                (checkpoint.last_block + 1, checkpoint)
+            }
+            None => (
+                sandwich_inception_block,
+                BotStateCheckpoint {
+                    sandwich_address: sandy_addr,
+                    ..Default::default()
+                },
+            ),
+        };
+
+        let current_block = client
+            .get_block_number()
+            .await
+            .map_err(|e| eyre::eyre!("Failed to get current block: {:?}", e))?;
+
+        let touched =
+            Self::find_touched_tokens(start_block, current_block, client, sandy_addr).await?;
+
+        // doing calls to remove false positives, WETH is tracked by `weth_balance`
+        let weth = utils::constants::get_weth_address();
+        let mut token_dust: HashSet<Address> = checkpoint.token_dust.drain(..).collect();
+        for touched_addr in touched.iter().filter(|token| **token != weth) {
+            let erc20 = Erc20::new(*touched_addr, client.clone());
+            let balance: U256 = erc20.balance_of(sandy_addr).await?;
+
+            if balance.is_zero() {
+                token_dust.remove(touched_addr);
+            } else {
+                token_dust.insert(*touched_addr);
+            }
+        }
+        log::info!("Found {:?} tokens worth of dust", token_dust.len());
+
+        let weth_balance = if !resumed || touched.contains(&weth) {
+            let weth_contract = utils::contracts::get_erc20_contract(&weth, client);
+            weth_contract.balance_of(sandy_addr).call().await?
+        } else {
+            checkpoint.weth_balance
+        };
+
+        let state = Self::from_checkpoint(
+            BotStateCheckpoint {
+                sandwich_address: sandy_addr,
+                token_dust: token_dust.into_iter().collect(),
+                weth_balance,
+                last_block: current_block,
+            },
+            cache_path,
+        );
+        state.save();
+
+        Ok(state)
+    }
+
+    // Create the bot state from a checkpoint
+    //
+    // Arguments:
+    // * `checkpoint`: state to start from
+    // * `cache_path`: file the state is saved to after every update, nothing is saved if `None`
+    pub fn from_checkpoint(checkpoint: BotStateCheckpoint, cache_path: Option<PathBuf>) -> Self {
+        BotState {
+            token_dust: Arc::new(RwLock::new(checkpoint.token_dust)),
+            weth_balance: Arc::new(RwLock::new(checkpoint.weth_balance)),
+            last_block: Arc::new(RwLock::new(checkpoint.last_block)),
+            sandwich_address: checkpoint.sandwich_address,
+            cache_path,
+        }
+    }
+
+    // Snapshot of the state
+    pub fn checkpoint(&self) -> BotStateCheckpoint {
+        BotStateCheckpoint {
+            sandwich_address: self.sandwich_address,
+            token_dust: self.token_dust.read().clone(),
+            weth_balance: *self.weth_balance.read(),
+            last_block: *self.last_block.read(),
+        }
+    }
 
-        let weth_contract =
-            utils::contracts::get_erc20_contract(&utils::constants::get_weth_address(), client);
-        let weth_balance = weth_contract.balance_of(sandy_addr).call().await?;
-        let weth_balance = Arc::new(RwLock::new(weth_balance));
+    // Write the state to its cache file, failures are logged and otherwise ignored
+    pub fn save(&self) {
+        let path = match &self.cache_path {
+            Some(path) => path,
+            None => return,
+        };
 
-        Ok(BotState {
-            token_dust,
-            weth_balance,
-        })
+        // write to a temp file first so a crash never leaves a truncated checkpoint
+        let mut tmp_path = path.clone().into_os_string();
+        tmp_path.push(".tmp");
+        let result = serde_json::to_vec(&self.checkpoint())
+            .map_err(|e| e.to_string())
+            .and_then(|bytes| fs::write(&tmp_path, bytes).map_err(|e| e.to_string()))
+            .and_then(|_| fs::rename(&tmp_path, path).map_err(|e| e.to_string()));
+        if let Err(e) = result {
+            log::warn!("Failed to save bot state to {:?}: {}", path, e);
+        }
     }
 
     // Check if contract has dust for specific token
@@ -78,7 +195,23 @@ impl BotState {
     // * `token`: token to add dust for
     pub async fn add_dust(&self, token: Address) {
         let mut dust = self.token_dust.write();
-        dust.push(token);
+        if !dust.contains(&token) {
+            dust.push(token);
+        }
+        drop(dust);
+        self.save();
+    }
+
+    // Remove dust from contract once it has been sold
+    //
+    // Arguments:
+    // * `&self`: reference to `BotState` instance
+    // * `token`: token to remove dust for
+    pub async fn remove_dust(&self, token: &Address) {
+        let mut dust = self.token_dust.write();
+        dust.retain(|dust_token| dust_token != token);
+        drop(dust);
+        self.save();
     }
 
     // Update the WETH balance of the contract
@@ -90,96 +223,211 @@ impl BotState {
     pub async fn update_weth_balance(&self, value_to_add: U256) {
         let mut lock = self.weth_balance.write();
         *lock += value_to_add;
+        drop(lock);
+        self.save();
+    }
+
+    // Apply the Transfer logs of a new block involving the sandwich contract
+    //
+    // Blocks at or before the last synced block are ignored so that logs are never counted
+    // twice. Tokens received are added to the dust, WETH transfers move the WETH balance.
+    // Tokens sent out stay in the dust since backruns always leave some on the contract, the
+    // dust manager removes the tokens it sells.
+    //
+    // Arguments:
+    // * `&self`: reference to `BotState` instance
+    // * `block_number`: block the logs were emitted in
+    // * `logs`: the block's logs, logs not involving the sandwich contract are skipped
+    //
+    // Returns:
+    // bool: true if the block was applied, false if it was already synced
+    pub fn apply_block_logs(&self, block_number: U64, logs: &[Log]) -> bool {
+        let applied = self.apply_logs(block_number, logs);
+        if applied {
+            self.save();
+        }
+        applied
+    }
+
+    // Apply the Transfer logs of every block after the last synced one up to `block_number`
+    //
+    // Logs are applied block by block in order, blocks without logs only move `last_block`
+    // forward. The state is saved once at the end.
+    //
+    // Arguments:
+    // * `&self`: reference to `BotState` instance
+    // * `block_number`: last block covered by `logs`
+    // * `logs`: Transfer logs of the range, in any order
+    pub fn apply_range_logs(&self, block_number: U64, mut logs: Vec<Log>) {
+        logs.retain(|log| log.block_number.is_some());
+        logs.sort_by_key(|log| (log.block_number, log.log_index));
+
+        let mut start = 0;
+        while start < logs.len() {
+            let number = logs[start].block_number.unwrap_or_default();
+            let end = start
+                + logs[start..]
+                    .iter()
+                    .take_while(|log| log.block_number == Some(number))
+                    .count();
+            if number <= block_number {
+                self.apply_logs(number, &logs[start..end]);
+            }
+            start = end;
+        }
+        // the last blocks may have had no transfers
+        self.apply_logs(block_number, &[]);
+        self.save();
+    }
+
+    fn apply_logs(&self, block_number: U64, logs: &[Log]) -> bool {
+        {
+            let mut last_block = self.last_block.write();
+            if block_number <= *last_block {
+                return false;
+            }
+            *last_block = block_number;
+        }
+
+        let weth = utils::constants::get_weth_address();
+        let transfer_signature = utils::constants::get_erc20_transfer_event_signature();
+        let sandy_topic = H256::from(self.sandwich_address);
+
+        for log in logs {
+            if log.topics.len() != 3 || log.topics[0] != transfer_signature {
+                continue;
+            }
+            let (is_incoming, is_outgoing) =
+                (log.topics[2] == sandy_topic, log.topics[1] == sandy_topic);
+            if is_incoming == is_outgoing {
+                continue;
+            }
+            let amount = U256::from_big_endian(&log.data);
+
+            if log.address == weth {
+                let mut weth_balance = self.weth_balance.write();
+                *weth_balance = if is_incoming {
+                    *weth_balance + amount
+                } else {
+                    weth_balance.saturating_sub(amount)
+                };
+            } else if is_incoming && !amount.is_zero() {
+                let mut dust = self.token_dust.write();
+                if !dust.contains(&log.address) {
+                    dust.push(log.address);
+                }
+            }
+        }
+        true
     }
 
-    // Find dust that bot has collected from a specific block onwards
+    // Fetch and apply the Transfer logs involving the sandwich contract of every block since
+    // the last synced one
     //
     // Arguments:
-    // * `start_block`: block to start searching for dust
+    // * `&self`: reference to `BotState` instance
     // * `client`: websocket provider to use for fetching data
+    // * `block_number`: new block to sync up to
     //
     // Returns:
-    // `Ok(Vec<Address>)`: address of token dust collected by bot
-    // `Err(eyre::Error)`: failed to find dust
-    async fn find_all_dust<M>(
-        start_block: U64,
-        client: &Arc<M>,
-        test: bool,
-        sandwich_address: Option<Address>,
-    ) -> Result<Vec<Address>>
+    // `Ok(())`: if the block was synced or was already synced
+    // `Err(eyre::Error)`: failed to fetch the logs
+    pub async fn sync_block<M>(&self, client: &Arc<M>, block_number: U64) -> Result<()>
     where
         M: Middleware + 'static,
-        M::Provider: PubsubClient,
-        M::Provider: JsonRpcClient,
     {
-        let sandy_addr = get_sandy_addr(test, sandwich_address);
-
-        // Define the step for searching a range of block logs for transfer events
-        let step = 10000;
+        let from_block = *self.last_block.read() + 1;
+        if block_number < from_block {
+            return Ok(());
+        }
 
-        // Find dust upto this block
-        let current_block = match client.get_block_number().await {
-            Ok(block) => block.as_u64(),
-            Err(e) => {
-                log::error!("Failed to get current_block {:?}", e);
-                eyre::bail!("todo error msg here");
-            }
-        };
+        // blocks skipped since the last sync are fetched too, their transfers would be lost
+        let mut logs = Vec::new();
+        for start in (from_block.as_u64()..=block_number.as_u64()).step_by(LOG_SCAN_STEP as usize) {
+            let end = (start + LOG_SCAN_STEP - 1).min(block_number.as_u64());
+            logs.extend(
+                get_transfer_logs(client, self.sandwich_address, start.into(), end.into()).await?,
+            );
+        }
+        self.apply_range_logs(block_number, logs);
+        Ok(())
+    }
 
-        let start_block = start_block.as_u64();
+    // Find tokens the bot has sent or received within a range of blocks
+    //
+    // Arguments:
+    // * `start_block`: first block to search
+    // * `end_block`: last block to search
+    // * `client`: websocket provider to use for fetching data
+    // * `sandy_addr`: address of the sandwich contract
+    //
+    // Returns:
+    // `Ok(HashSet<Address>)`: address of the tokens transferred to or from the contract
+    // `Err(eyre::Error)`: failed to fetch the logs
+    async fn find_touched_tokens<M>(
+        start_block: U64,
+        end_block: U64,
+        client: &Arc<M>,
+        sandy_addr: Address,
+    ) -> Result<HashSet<Address>>
+    where
+        M: Middleware + 'static,
+    {
+        let (start_block, end_block) = (start_block.as_u64(), end_block.as_u64());
 
-        // holds erc20 and associated balance
+        // holds erc20 interacted with
         let mut address_interacted_with = HashSet::new();
 
-        // for each block within the range, get all transfer events asynchronously
-        for from_block in (start_block..=current_block).step_by(step) {
-            let to_block = from_block + step as u64;
-
-            // check for all incoming and outgoing txs within step range
-            let transfer_logs = client
-                .get_logs(
-                    &Filter::new()
-                        .topic0(utils::constants::get_erc20_transfer_event_signature())
-                        .topic1(sandy_addr.clone())
-                        .from_block(BlockNumber::Number(U64([from_block])))
-                        .to_block(BlockNumber::Number(U64([to_block]))),
-                )
-                .await?;
-
-            let receive_logs = client
-                .get_logs(
-                    &Filter::new()
-                        .topic0(utils::constants::get_erc20_transfer_event_signature())
-                        .topic2(sandy_addr.clone())
-                        .from_block(BlockNumber::Number(U64([from_block])))
-                        .to_block(BlockNumber::Number(U64([to_block]))),
-                )
-                .await?;
-
-            // combine all logs
-            for log in transfer_logs {
-                address_interacted_with.insert(log.address);
-            }
-            for log in receive_logs {
+        for from_block in (start_block..=end_block).step_by(LOG_SCAN_STEP as usize) {
+            let to_block = (from_block + LOG_SCAN_STEP - 1).min(end_block);
+
+            let logs =
+                get_transfer_logs(client, sandy_addr, from_block.into(), to_block.into()).await?;
+            for log in logs {
                 address_interacted_with.insert(log.address);
             }
         }
 
-        let mut token_dust = vec![];
+        Ok(address_interacted_with)
+    }
+}
 
-        // doing calls to remove false positives
-        for touched_addr in address_interacted_with {
-            let erc20 = Erc20::new(touched_addr, client.clone());
-            let balance: U256 = erc20.balance_of(sandy_addr).await?;
+// Get all Transfer logs to or from the sandwich contract within a range of blocks
+async fn get_transfer_logs<M>(
+    client: &Arc<M>,
+    sandy_addr: Address,
+    from_block: U64,
+    to_block: U64,
+) -> Result<Vec<Log>>
+where
+    M: Middleware + 'static,
+{
+    let filter = Filter::new()
+        .topic0(utils::constants::get_erc20_transfer_event_signature())
+        .from_block(BlockNumber::Number(from_block))
+        .to_block(BlockNumber::Number(to_block));
 
-            if !balance.is_zero() {
-                token_dust.push(touched_addr);
-            }
-        }
+    // check for all incoming and outgoing txs within range
+    let transfer_logs = client
+        .get_logs(&filter.clone().topic1(sandy_addr))
+        .await
+        .map_err(|e| eyre::eyre!("Failed to get transfer logs: {:?}", e))?;
+    let receive_logs = client
+        .get_logs(&filter.topic2(sandy_addr))
+        .await
+        .map_err(|e| eyre::eyre!("Failed to get receive logs: {:?}", e))?;
 
-        log::info!("Found {:?} tokens worth of dust", token_dust.len());
+    Ok(transfer_logs.into_iter().chain(receive_logs).collect())
+}
 
-        Ok(token_dust)
+fn load_checkpoint(path: &PathBuf) -> Option<BotStateCheckpoint> {
+    let bytes = fs::read(path).ok()?;
+    match serde_json::from_slice(&bytes) {
+        Ok(checkpoint) => Some(checkpoint),
+        Err(e) => {
+            log::warn!("Ignoring corrupt bot state at {:?}: {}", path, e);
+            None
+        }
     }
 }
 
@@ -190,6 +438,11 @@ pub fn get_sandwich_contract_address() -> Address {
         .expect("Required environment variable \"SANDWICH_CONTRACT\" not set");
     Address::from_str(&addr).expect("Failed to parse \"SANDWICH_CONTRACT\"")
 }
+/// Returns the file the bot state is persisted to, if configured
+pub fn get_bot_state_path() -> Option<PathBuf> {
+    dotenv::dotenv().ok();
+    std::env::var("BOT_STATE_PATH").ok().map(PathBuf::from)
+}
 /// Construct the searcher wallet
 pub fn get_searcher_wallet() -> LocalWallet {
     dotenv::dotenv().ok();
@@ -214,3 +467,85 @@ fn get_sandy_addr(test: bool, sandwich_address: Option<Address>) -> Address {
     }
     sandy_addr
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::transfer_log;
+
+    #[test]
+    fn test_apply_block_logs_and_resume() {
+        let path =
+            std::env::temp_dir().join(format!("qilin_bot_state_test_{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let sandy = Address::from_low_u64_be(0x5a);
+        let (pool, token) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2));
+        let weth = utils::constants::get_weth_address();
+
+        let state = BotState::from_checkpoint(
+            BotStateCheckpoint {
+                sandwich_address: sandy,
+                weth_balance: U256::from(1000),
+                last_block: 10.into(),
+                ..Default::default()
+            },
+            Some(path.clone()),
+        );
+
+        let logs = vec![
+            transfer_log(weth, sandy, pool, 400),
+            transfer_log(token, pool, sandy, 50),
+            transfer_log(token, sandy, pool, 49),
+            transfer_log(weth, pool, sandy, 500),
+            transfer_log(weth, pool, Address::from_low_u64_be(3), 7),
+        ];
+        assert!(state.apply_block_logs(11.into(), &logs));
+        // a block is never applied twice
+        assert!(!state.apply_block_logs(11.into(), &logs));
+
+        let resumed = load_checkpoint(&path).unwrap();
+        assert_eq!(resumed, state.checkpoint());
+        assert_eq!(resumed.weth_balance, U256::from(1100));
+        assert_eq!(resumed.token_dust, vec![token]);
+        assert_eq!(resumed.last_block, 11.into());
+
+        let _ = fs::remove_file(&path);
+    }
+
+    #[test]
+    fn test_apply_range_logs_catches_up_skipped_blocks() {
+        let sandy = Address::from_low_u64_be(0x5a);
+        let (pool, token) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2));
+        let weth = utils::constants::get_weth_address();
+
+        let state = BotState::from_checkpoint(
+            BotStateCheckpoint {
+                sandwich_address: sandy,
+                weth_balance: U256::from(1000),
+                last_block: 10.into(),
+                ..Default::default()
+            },
+            None,
+        );
+
+        let in_block = |mut log: Log, number: u64, index: u64| {
+            log.block_number = Some(number.into());
+            log.log_index = Some(index.into());
+            log
+        };
+        // blocks 11 and 13 were never synced on their own, block 10 is already accounted for
+        let logs = vec![
+            in_block(transfer_log(weth, pool, sandy, 500), 13, 0),
+            in_block(transfer_log(weth, sandy, pool, 300), 11, 1),
+            in_block(transfer_log(token, pool, sandy, 50), 11, 2),
+            in_block(transfer_log(weth, pool, sandy, 9999), 10, 0),
+        ];
+        state.apply_range_logs(14.into(), logs);
+
+        let checkpoint = state.checkpoint();
+        assert_eq!(checkpoint.weth_balance, U256::from(1200));
+        assert_eq!(checkpoint.token_dust, vec![token]);
+        assert_eq!(checkpoint.last_block, 14.into());
+    }
+}
@@ -1,4 +1,10 @@
 // use crate::{prelude::Pool, utils};
+use crate::sandwich::utils::constants::{get_base_tokens, get_weth_address};
+use collectors::{
+    slot_finder::StorageLayoutFinder,
+    state_diff::extract_sandwich_pools,
+    types::{PoolSwap, SwapRoute},
+};
 use dashmap::DashMap;
 use ethers::prelude::*;
 use fork_database::forked_db::ForkedDatabase;
@@ -6,7 +12,10 @@ use futures::stream::FuturesUnordered;
 use log;
 use parking_lot::RwLock;
 use qilin_cfmms::pool::Pool;
-use revm::primitives::{AccountInfo, Bytecode};
+use revm::{
+    db::DatabaseRef,
+    primitives::{AccountInfo, Bytecode},
+};
 use std::{
     collections::{btree_map::Entry, BTreeMap},
     sync::Arc,
@@ -16,7 +25,7 @@ use std::{
 #[derive(Clone, Copy, Debug)]
 pub struct SandwichablePool {
     pub pool: Pool,
-    // Is swap direction zero to one?
+    /// Does the victim sell WETH into the pool? The frontrun then buys the other token with WETH
     pub is_weth_input: bool,
 }
 impl SandwichablePool {
@@ -26,6 +35,16 @@ impl SandwichablePool {
             is_weth_input,
         }
     }
+
+    /// Hops of the routes that the sandwich contract can trade against, i.e. those buying or
+    /// selling WETH
+    ///
+    /// Routes are decoded for every base token, but hops trading only the other base tokens are
+    /// dropped, see [extract_pools_from_swaps]
+    pub fn from_routes(routes: &[SwapRoute]) -> Vec<Self> {
+        let hops: Vec<PoolSwap> = routes.iter().flat_map(|route| route.hops.clone()).collect();
+        extract_pools_from_swaps(&hops)
+    }
 }
 
 // ported directly from rusty sando
@@ -93,55 +112,55 @@ where
     Some(merged_state_diffs)
 }
 
-/// Decode statediff to produce Vec of pools interacted with
+/// Decode statediff to produce the swap routes that trade one of the base tokens
 ///
 /// Arguments:
+/// * `layouts`: finder used to locate the `balanceOf` mapping of each token
+/// * `db`: database the token contracts are probed against
+/// * `block`: the block `db` is at
 /// * `state_diffs`: BTreeMap of Address and AccountDiff
 /// * `all_pools`: HashMap of Address and Pool
 ///
 /// Returns:
-/// Some(Vec<SandwichablePool>): Vec of pools that have been interacted with
-/// None: If state_diffs is empty
-pub fn extract_pools(
+/// Some(Vec<SwapRoute>): full routes buying or selling WETH, USDC, USDT or DAI in any hop
+/// None: If no known pool was touched
+pub fn extract_pools<DB>(
+    layouts: &StorageLayoutFinder,
+    db: &DB,
+    block: u64,
     state_diffs: &BTreeMap<Address, AccountDiff>,
     all_pools: &DashMap<Address, Pool>,
-) -> Option<Vec<SandwichablePool>> {
-    // capture all addresses that have a state change and are also a pool
-    let touched_pools: Vec<Pool> = state_diffs
-        .keys()
-        .filter_map(|e| all_pools.get(e).map(|p| (*p.value()).clone()))
-        .collect();
-
-    // find direction of swap based on state diff (does weth have state changes?)
-    let weth_state_diff = &state_diffs
-        .get(
-            &"0xC02aaA39b223FE8D0A0e5C4F27eAD9083C756Cc2"
-                .parse::<Address>()
-                .unwrap(),
-        )?
-        .storage;
-
-    let mut sandwichable_pools: Vec<SandwichablePool> = vec![];
-
-    // find storage mapping index for each pool
-    for pool in touched_pools {
-        // find mapping storage location
-        let storage_key = TxHash::from(ethers::utils::keccak256(abi::encode(&[
-            abi::Token::Address(pool.address),
-            abi::Token::Uint(U256::from(3)),
-        ])));
-        let is_weth_input = match weth_state_diff.get(&storage_key)? {
-            Diff::Changed(c) => {
-                let from = U256::from(c.from.to_fixed_bytes());
-                let to = U256::from(c.to.to_fixed_bytes());
-                to > from
-            }
-            _ => continue,
-        };
-        sandwichable_pools.push(SandwichablePool::new(pool, is_weth_input));
-    }
+) -> Option<Vec<SwapRoute>>
+where
+    DB: DatabaseRef,
+    DB::Error: std::fmt::Debug,
+{
+    extract_sandwich_pools(
+        layouts,
+        db,
+        block,
+        state_diffs,
+        all_pools,
+        &get_base_tokens(),
+    )
+}
 
-    Some(sandwichable_pools)
+/// Produce Vec of pools that can be sandwiched from the swaps decoded by the mempool collector
+///
+/// Arguments:
+/// * `swaps`: swaps attached to the incoming `NewTx`
+///
+/// Returns:
+/// Vec<SandwichablePool>: pools where the victim either buys or sells WETH
+pub fn extract_pools_from_swaps(swaps: &[PoolSwap]) -> Vec<SandwichablePool> {
+    let weth = get_weth_address();
+    swaps
+        .iter()
+        // the sandwich contract only holds WETH and its payloads only encode WETH legs, a
+        // USDC, USDT or DAI pool would need the contract to fund the frontrun in that token
+        .filter(|swap| swap.touches_any(&[weth]))
+        .map(|swap| SandwichablePool::new(swap.pool, swap.token_in() == weth))
+        .collect()
 }
 
 // Turn state_diffs into a new cache_db
@@ -4,43 +4,53 @@ use std::sync::Arc;
 
 use crate::sandwich::state;
 use ethers::prelude::{k256::ecdsa::SigningKey, *};
-use parking_lot::RwLock;
+use executors::nonce_manager::NonceManager;
+use thiserror::Error;
 pub mod v2;
 pub mod v3;
 
+#[derive(Error, Debug)]
+pub enum SandwichMakerError {
+    #[error("Nonce manager manages {managed:?}, not the searcher wallet {searcher:?}")]
+    WalletMismatch { managed: Address, searcher: Address },
+}
+
 #[derive(Debug, Clone)]
 pub struct SandwichMaker {
     pub v2: v2::SandwichLogicV2,
     pub v3: v3::SandwichLogicV3,
     pub sandwich_address: Address,
     pub searcher_wallet: Wallet<SigningKey>,
-    pub nonce: Arc<RwLock<U256>>,
+    /// Shared with every strategy signing with `searcher_wallet`
+    pub nonce_manager: Arc<NonceManager>,
 }
 
 impl SandwichMaker {
     // Create a new `SandwichMaker` instance
-    pub async fn new(provider: Arc<Provider<Ws>>) -> Self {
+    //
+    // Arguments:
+    // * `nonce_manager`: nonce manager of the searcher wallet
+    //
+    // Returns:
+    // Err(SandwichMakerError::WalletMismatch) if `nonce_manager` is for another account
+    pub fn new(nonce_manager: Arc<NonceManager>) -> Result<Self, SandwichMakerError> {
         let sandwich_address = state::get_sandwich_contract_address();
         let searcher_wallet = state::get_searcher_wallet();
 
-        let nonce = if let Ok(n) = provider
-            .get_transaction_count(searcher_wallet.address(), None)
-            .await
-        {
-            n
-        } else {
-            panic!("Failed to get searcher wallet nonce...");
-        };
-
-        let nonce = Arc::new(RwLock::new(nonce));
+        if nonce_manager.address() != searcher_wallet.address() {
+            return Err(SandwichMakerError::WalletMismatch {
+                managed: nonce_manager.address(),
+                searcher: searcher_wallet.address(),
+            });
+        }
 
-        Self {
+        Ok(Self {
             v2: v2::SandwichLogicV2::new(),
             v3: v3::SandwichLogicV3::new(),
             sandwich_address,
             searcher_wallet,
-            nonce,
-        }
+            nonce_manager,
+        })
     }
 }
 
@@ -0,0 +1,176 @@
+// Helpers shared by the tests simulating on a mainnet fork
+use crate::sandwich::{
+    braindance::set_token_balance,
+    utils::{
+        constants::{get_erc20_transfer_event_signature, get_test_sandwich_code, get_weth_address},
+        tx_builder::{v2::SandwichLogicV2, v3::SandwichLogicV3, SandwichMaker},
+    },
+};
+use collectors::slot_finder::StorageLayoutFinder;
+use ethers::{
+    abi::{self, Token},
+    providers::{Provider, Ws},
+    signers::{LocalWallet, Signer},
+    types::{Address, Log, Transaction, H256, U256},
+    utils::{get_create2_address_from_hash, keccak256},
+};
+use executors::nonce_manager::NonceManager;
+use fork_database::{
+    forked_db::ForkedDatabase,
+    utils::{h160_to_b160, u256_to_ru256},
+    ForkDbBuilder,
+};
+use qilin_cfmms::pool::{Pool, PoolVariant};
+use revm::primitives::{AccountInfo, Bytecode, U256 as rU256};
+use std::{str::FromStr, sync::Arc};
+
+/// Block the fork tests run at
+pub const FORK_BLOCK: u64 = 17_444_939;
+
+pub const UNISWAP_V2_FACTORY: &str = "0x5C69bEe701ef814a2B6a3EDD4B1652CB9cc5aA6f";
+pub const UNISWAP_V2_INIT_CODE_HASH: &str =
+    "0x96e8ac4277198ff8b6f785478aa9a39f403cb768dd02cbee326c3e7da348845f";
+pub const UNISWAP_V2_ROUTER: &str = "0x7a250d5630B4cF539739dF2C5dAcb4c659F2488D";
+pub const DAI: &str = "0x6B175474E89094C44Da98b954EedeAC495271d0F";
+/// UniswapV3 DAI/WETH pool with a 0.3% fee
+pub const DAI_WETH_V3: &str = "0xC2e9F25Be6257c210d7Adf0D4Cd6E3E881ba25f8";
+/// Takes a 0.02% fee on every transfer
+pub const PAXG: &str = "0x45804880De22913dAFE09f4980848ECE6EcbAf78";
+/// Where the sandwich contract of [get_test_sandwich_code] is injected
+pub const TEST_SANDWICH: &str = "0x00000000000000000000000000000000005A4D00";
+/// Private key `1`, the owner hardcoded in [get_test_sandwich_code]
+pub const TEST_SEARCHER_KEY: &str =
+    "0000000000000000000000000000000000000000000000000000000000000001";
+
+pub fn address(address: &str) -> Address {
+    Address::from_str(address).unwrap()
+}
+
+/// An ERC20 `Transfer` log of `amount` of `token`
+pub fn transfer_log(token: Address, from: Address, to: Address, amount: u64) -> Log {
+    let mut data = [0u8; 32];
+    U256::from(amount).to_big_endian(&mut data);
+    Log {
+        address: token,
+        topics: vec![
+            get_erc20_transfer_event_signature(),
+            H256::from(from),
+            H256::from(to),
+        ],
+        data: data.to_vec().into(),
+        ..Default::default()
+    }
+}
+
+/// Fork mainnet at [FORK_BLOCK] through the `WSS_RPC` endpoint
+pub async fn fork_db() -> ForkedDatabase {
+    dotenv::dotenv().ok();
+    let url = std::env::var("WSS_RPC").expect("WSS_RPC must be set to run fork tests");
+    let provider = Arc::new(Provider::<Ws>::connect(url.as_str()).await.unwrap());
+
+    ForkDbBuilder::new(provider)
+        .with_block(FORK_BLOCK)
+        .with_host(url)
+        .build()
+        .await
+        .unwrap()
+}
+
+/// The UniswapV2 pair of two tokens, with only the fields the fork simulations read
+pub fn v2_pair(token_a: Address, token_b: Address) -> Pool {
+    let (token_0, token_1) = if token_a < token_b {
+        (token_a, token_b)
+    } else {
+        (token_b, token_a)
+    };
+    let salt = keccak256([token_0.as_bytes(), token_1.as_bytes()].concat());
+    let pair = get_create2_address_from_hash(
+        address(UNISWAP_V2_FACTORY),
+        salt,
+        H256::from_str(UNISWAP_V2_INIT_CODE_HASH).unwrap(),
+    );
+
+    Pool::new_empty_pool(
+        pair,
+        token_0,
+        token_1,
+        U256::from(3000),
+        PoolVariant::UniswapV2,
+    )
+}
+
+/// Inject the sandwich contract into the fork holding `weth_balance`, and return a maker signing
+/// as its owner
+pub fn sandwich_maker(
+    fork_db: &mut ForkedDatabase,
+    layouts: &StorageLayoutFinder,
+    weth_balance: U256,
+) -> SandwichMaker {
+    let sandwich_address = address(TEST_SANDWICH);
+    fork_db.database_mut().insert_account_info(
+        h160_to_b160(sandwich_address),
+        AccountInfo::new(
+            rU256::ZERO,
+            0,
+            Bytecode::new_raw(get_test_sandwich_code().0),
+        ),
+    );
+    set_token_balance(
+        layouts,
+        fork_db,
+        get_weth_address(),
+        sandwich_address,
+        weth_balance,
+    )
+    .unwrap();
+
+    let searcher_wallet: LocalWallet = TEST_SEARCHER_KEY.parse().unwrap();
+    SandwichMaker {
+        v2: SandwichLogicV2::new(),
+        v3: SandwichLogicV3::new(),
+        sandwich_address,
+        nonce_manager: Arc::new(NonceManager::new(searcher_wallet.address(), U256::zero())),
+        searcher_wallet,
+    }
+}
+
+/// A pending tx buying `token` with `amount_in` ETH through the UniswapV2 router, which reverts
+/// if it gets less than `amount_out_min`. Its sender, derived from `id`, is funded on the fork.
+pub fn v2_buy_tx(
+    fork_db: &mut ForkedDatabase,
+    id: u64,
+    token: Address,
+    amount_in: U256,
+    amount_out_min: U256,
+) -> Transaction {
+    let buyer = Address::from_low_u64_be(0xb0b0_0000 + id);
+    fork_db.database_mut().insert_account_info(
+        h160_to_b160(buyer),
+        AccountInfo {
+            balance: u256_to_ru256(amount_in),
+            ..Default::default()
+        },
+    );
+
+    // swapExactETHForTokens(uint256,address[],address,uint256)
+    let mut input = vec![0x7f, 0xf3, 0x6a, 0xb5];
+    input.extend(abi::encode(&[
+        Token::Uint(amount_out_min),
+        Token::Array(vec![
+            Token::Address(get_weth_address()),
+            Token::Address(token),
+        ]),
+        Token::Address(buyer),
+        Token::Uint(U256::MAX),
+    ]));
+
+    Transaction {
+        hash: H256::from_low_u64_be(id),
+        from: buyer,
+        to: Some(address(UNISWAP_V2_ROUTER)),
+        value: amount_in,
+        gas: U256::from(300_000),
+        input: input.into(),
+        ..Default::default()
+    }
+}
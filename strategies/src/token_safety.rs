@@ -0,0 +1,405 @@
+use crate::sandwich::{
+    braindance::{set_token_balance, BraindanceError, BraindanceHarness},
+    sizing::{balance_of, get_amount_out, next_block_env, transact, v2_reserves},
+};
+use collectors::slot_finder::StorageLayoutFinder;
+use dashmap::DashMap;
+use ethers::{
+    abi::{self, Token},
+    types::{Address, U256},
+};
+use fork_database::{forked_db::ForkedDatabase, utils::h160_to_b160};
+use qilin_cfmms::pool::{Pool, PoolVariant};
+use revm::primitives::{TransactTo, TxEnv};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+/// Basis points
+const BPS: u64 = 10_000;
+/// Share of the pool's base token reserve used to probe a token, in basis points
+const DEFAULT_PROBE_SIZE_BPS: u64 = 10;
+/// Taxes up to this many basis points are treated as rounding
+const DEFAULT_TOLERANCE_BPS: u32 = 1;
+/// `transfer(address,uint256)`
+const TRANSFER_SELECTOR: [u8; 4] = [0xa9, 0x05, 0x9c, 0xbb];
+/// Gas limit of the probe transfer, enough for tokens taking a fee or updating rewards
+const TRANSFER_GAS_LIMIT: u64 = 200_000;
+
+/// How a token behaves when bought, transferred and sold
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenVerdict {
+    Safe,
+    /// Takes a fee on transfers, in basis points of the amount
+    Taxed {
+        buy_bps: u32,
+        transfer_bps: u32,
+        sell_bps: u32,
+    },
+    /// Can be bought and transferred but not sold back through the probed pool, or trades
+    /// for nothing
+    Unsellable,
+    /// The probe failed for a reason that may not hold on another block (an empty pool, a buy
+    /// or transfer that failed, a tx running out of gas), the token is checked again next time
+    Inconclusive,
+}
+
+impl TokenVerdict {
+    /// Can opportunities route through this token?
+    pub fn is_tradable(&self) -> bool {
+        matches!(self, TokenVerdict::Safe)
+    }
+
+    /// Does the verdict only depend on the token's code? Only those are cached
+    pub fn is_conclusive(&self) -> bool {
+        !matches!(self, TokenVerdict::Inconclusive)
+    }
+}
+
+/// Classifies tokens by simulating a buy, a transfer and a sell on the fork
+///
+/// The buy and the sell go through the braindance contract on the given pool, funded with the
+/// pool's other token. The transfer is sent between two fresh accounts.
+#[derive(Debug, Clone)]
+pub struct TokenSafetyChecker<'a> {
+    layouts: &'a StorageLayoutFinder,
+    harness: BraindanceHarness<'a>,
+    probe_size_bps: u64,
+    tolerance_bps: u32,
+}
+
+impl<'a> TokenSafetyChecker<'a> {
+    pub fn new(layouts: &'a StorageLayoutFinder) -> Self {
+        Self {
+            layouts,
+            harness: BraindanceHarness::new(layouts),
+            probe_size_bps: DEFAULT_PROBE_SIZE_BPS,
+            tolerance_bps: DEFAULT_TOLERANCE_BPS,
+        }
+    }
+
+    /// Set the share of the pool's base token reserve spent on the probe buy
+    pub fn with_probe_size_bps(mut self, probe_size_bps: u64) -> Self {
+        self.probe_size_bps = probe_size_bps;
+        self
+    }
+
+    pub fn with_tolerance_bps(mut self, tolerance_bps: u32) -> Self {
+        self.tolerance_bps = tolerance_bps;
+        self
+    }
+
+    /// Classify `token` by trading it against the other token of `pool`, leaving the fork
+    /// untouched
+    ///
+    /// A sell reverting right after the same tokens were bought and transferred is what a
+    /// honeypot looks like. It can't be a gas issue (that halts) nor a race since the fork
+    /// doesn't move in between, so it's the only failure classified as
+    /// [TokenVerdict::Unsellable], every other failure is [TokenVerdict::Inconclusive].
+    pub fn check(
+        &self,
+        fork_db: &mut ForkedDatabase,
+        token: Address,
+        pool: &Pool,
+    ) -> Result<TokenVerdict, BraindanceError> {
+        let snapshot = fork_db.insert_snapshot();
+        let verdict = self.run_checks(fork_db, token, pool);
+        fork_db.revert_snapshot(snapshot);
+        verdict
+    }
+
+    fn run_checks(
+        &self,
+        fork_db: &mut ForkedDatabase,
+        token: Address,
+        pool: &Pool,
+    ) -> Result<TokenVerdict, BraindanceError> {
+        let base = if pool.token_0 == token {
+            pool.token_1
+        } else {
+            pool.token_0
+        };
+        let block = next_block_env(fork_db);
+
+        let amount_in =
+            balance_of(fork_db, &block, base, pool.address)? * self.probe_size_bps / BPS;
+        if amount_in.is_zero() {
+            return Ok(TokenVerdict::Inconclusive);
+        }
+
+        // buy
+        self.harness.deploy(fork_db);
+        self.harness.fund(fork_db, base, amount_in)?;
+        let (bought, received) = match self.harness.swap(fork_db, pool, amount_in, base, token) {
+            Ok(quote) => quote,
+            Err(BraindanceError::Reverted(_)) | Err(BraindanceError::Halted) => {
+                return Ok(TokenVerdict::Inconclusive)
+            }
+            Err(e) => return Err(e),
+        };
+        if received.is_zero() {
+            return Ok(TokenVerdict::Unsellable);
+        }
+        let buy_bps = tax_bps(bought, received);
+
+        // transfer between two accounts that never held the token
+        let (sender, receiver) = (
+            Address::from_low_u64_be(0x5afe_0001),
+            Address::from_low_u64_be(0x5afe_0002),
+        );
+        set_token_balance(self.layouts, fork_db, token, sender, received)?;
+
+        let mut tx = TxEnv::default();
+        tx.caller = h160_to_b160(sender);
+        tx.transact_to = TransactTo::Call(h160_to_b160(token));
+        tx.data = [
+            TRANSFER_SELECTOR.to_vec(),
+            abi::encode(&[Token::Address(receiver), Token::Uint(received)]),
+        ]
+        .concat()
+        .into();
+        tx.gas_limit = TRANSFER_GAS_LIMIT;
+        if !transact(fork_db, &block, tx)?.is_success() {
+            return Ok(TokenVerdict::Inconclusive);
+        }
+        let transfer_bps = tax_bps(received, balance_of(fork_db, &block, token, receiver)?);
+
+        // sell what was bought back into the pool
+        let expected = match pool.pool_variant {
+            PoolVariant::UniswapV2 => {
+                let (reserve_token, reserve_base) = v2_reserves(fork_db, pool, token)?;
+                Some(get_amount_out(
+                    received,
+                    reserve_token,
+                    reserve_base,
+                    pool.swap_fee,
+                ))
+            }
+            // v3 pools revert when they receive less than the amount owed, a taxed token can't
+            // be sold and ends up unsellable
+            PoolVariant::UniswapV3 => None,
+        };
+        let sold = match self.harness.swap(fork_db, pool, received, token, base) {
+            Ok((sold, _)) => sold,
+            Err(BraindanceError::Reverted(_)) => return Ok(TokenVerdict::Unsellable),
+            Err(BraindanceError::Halted) => return Ok(TokenVerdict::Inconclusive),
+            Err(e) => return Err(e),
+        };
+        if sold.is_zero() {
+            return Ok(TokenVerdict::Unsellable);
+        }
+        let sell_bps = expected.map_or(0, |expected| tax_bps(expected, sold));
+
+        if [buy_bps, transfer_bps, sell_bps]
+            .iter()
+            .all(|bps| *bps <= self.tolerance_bps)
+        {
+            Ok(TokenVerdict::Safe)
+        } else {
+            Ok(TokenVerdict::Taxed {
+                buy_bps,
+                transfer_bps,
+                sell_bps,
+            })
+        }
+    }
+}
+
+/// Share of `expected` that didn't arrive, in basis points
+fn tax_bps(expected: U256, received: U256) -> u32 {
+    if expected.is_zero() || received >= expected {
+        return 0;
+    }
+    ((expected - received) * BPS / expected).as_u32()
+}
+
+/// Verdicts of every token checked so far, persisted to disk
+#[derive(Debug, Default)]
+pub struct TokenRegistry {
+    verdicts: DashMap<Address, TokenVerdict>,
+    cache_path: Option<PathBuf>,
+}
+
+impl TokenRegistry {
+    /// Create a new registry, loading the verdicts saved at `cache_path` if it exists
+    pub fn new(cache_path: Option<PathBuf>) -> Self {
+        let verdicts = cache_path
+            .as_ref()
+            .and_then(|path| fs::read(path).ok())
+            .and_then(|bytes| {
+                serde_json::from_slice::<BTreeMap<Address, TokenVerdict>>(&bytes).ok()
+            })
+            .map(|verdicts| {
+                verdicts
+                    .into_iter()
+                    .filter(|(_, verdict)| verdict.is_conclusive())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Self {
+            verdicts,
+            cache_path,
+        }
+    }
+
+    pub fn get(&self, token: &Address) -> Option<TokenVerdict> {
+        self.verdicts.get(token).map(|verdict| *verdict)
+    }
+
+    /// Record a verdict and write the registry to disk, inconclusive verdicts aren't recorded
+    pub fn insert(&self, token: Address, verdict: TokenVerdict) {
+        if !verdict.is_conclusive() {
+            return;
+        }
+        self.verdicts.insert(token, verdict);
+        if let Err(e) = self.flush() {
+            log::warn!("Failed to flush token registry: {}", e);
+        }
+    }
+
+    /// Returns the cached verdict of `token`, checking it against `pool` if it's unknown
+    ///
+    /// Inconclusive verdicts are returned but not cached, the token is checked again next time
+    pub fn verdict(
+        &self,
+        checker: &TokenSafetyChecker,
+        fork_db: &mut ForkedDatabase,
+        token: Address,
+        pool: &Pool,
+    ) -> Result<TokenVerdict, BraindanceError> {
+        if let Some(verdict) = self.get(&token) {
+            return Ok(verdict);
+        }
+
+        let verdict = checker.check(fork_db, token, pool)?;
+        log::info!("Token {:?} classified as {:?}", token, verdict);
+        self.insert(token, verdict);
+        Ok(verdict)
+    }
+
+    /// Can opportunities route through `token`? Tokens that couldn't be checked are skipped
+    pub fn is_tradable(
+        &self,
+        checker: &TokenSafetyChecker,
+        fork_db: &mut ForkedDatabase,
+        token: Address,
+        pool: &Pool,
+    ) -> bool {
+        match self.verdict(checker, fork_db, token, pool) {
+            Ok(verdict) if verdict.is_tradable() => true,
+            Ok(verdict) => {
+                log::info!("Skipping {:?} on {:?}: {:?}", token, pool.address, verdict);
+                false
+            }
+            Err(e) => {
+                log::warn!("Failed to check token {:?}: {}", token, e);
+                false
+            }
+        }
+    }
+
+    /// Write all verdicts to the cache file, if one is configured
+    pub fn flush(&self) -> std::io::Result<()> {
+        if let Some(path) = &self.cache_path {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let verdicts: BTreeMap<Address, TokenVerdict> = self
+                .verdicts
+                .iter()
+                .map(|entry| (*entry.key(), *entry.value()))
+                .collect();
+            fs::write(path, serde_json::to_vec(&verdicts)?)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        sandwich::utils::constants::get_weth_address,
+        test_utils::{address, fork_db, v2_pair, DAI, PAXG},
+    };
+
+    #[test]
+    fn test_tax_bps() {
+        assert_eq!(tax_bps(U256::from(1000), U256::from(1000)), 0);
+        assert_eq!(tax_bps(U256::from(1000), U256::from(950)), 500);
+        assert_eq!(tax_bps(U256::from(1000), U256::from(1001)), 0);
+        assert_eq!(tax_bps(U256::zero(), U256::zero()), 0);
+    }
+
+    #[test]
+    fn test_token_registry_persistence() {
+        // the registry creates the missing directory
+        let dir = std::env::temp_dir().join(format!("qilin_token_registry_{}", std::process::id()));
+        let path = dir.join("verdicts.json");
+        let _ = fs::remove_dir_all(&dir);
+        let (safe, taxed) = (Address::from_low_u64_be(1), Address::from_low_u64_be(2));
+
+        let registry = TokenRegistry::new(Some(path.clone()));
+        registry.insert(safe, TokenVerdict::Safe);
+        registry.insert(
+            taxed,
+            TokenVerdict::Taxed {
+                buy_bps: 500,
+                transfer_bps: 0,
+                sell_bps: 500,
+            },
+        );
+
+        let reloaded = TokenRegistry::new(Some(path.clone()));
+        assert!(reloaded.get(&safe).unwrap().is_tradable());
+        assert!(!reloaded.get(&taxed).unwrap().is_tradable());
+        assert_eq!(reloaded.get(&Address::from_low_u64_be(3)), None);
+
+        // inconclusive probes are never cached
+        registry.insert(Address::from_low_u64_be(3), TokenVerdict::Inconclusive);
+        assert_eq!(registry.get(&Address::from_low_u64_be(3)), None);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_classify_tokens_on_fork() {
+        let mut fork_db = fork_db().await;
+        let layouts = StorageLayoutFinder::default();
+        // PAXG's 0.02% fee rounds down to 1bps on some amounts
+        let checker = TokenSafetyChecker::new(&layouts).with_tolerance_bps(0);
+        let registry = TokenRegistry::new(None);
+        let weth = get_weth_address();
+
+        let dai = address(DAI);
+        let verdict = registry
+            .verdict(&checker, &mut fork_db, dai, &v2_pair(dai, weth))
+            .unwrap();
+        assert_eq!(verdict, TokenVerdict::Safe);
+        assert_eq!(registry.get(&dai), Some(TokenVerdict::Safe));
+
+        let paxg = address(PAXG);
+        let verdict = registry
+            .verdict(&checker, &mut fork_db, paxg, &v2_pair(paxg, weth))
+            .unwrap();
+        match verdict {
+            TokenVerdict::Taxed {
+                buy_bps,
+                transfer_bps,
+                sell_bps,
+            } => {
+                assert!((1..=2).contains(&buy_bps), "{}", buy_bps);
+                assert!((1..=2).contains(&transfer_bps), "{}", transfer_bps);
+                assert!((1..=2).contains(&sell_bps), "{}", sell_bps);
+            }
+            verdict => panic!("PAXG classified as {:?}", verdict),
+        }
+
+        // the pair of a token nobody listed has no liquidity to probe with
+        let unlisted = Address::from_low_u64_be(0x7e57);
+        let verdict = registry
+            .verdict(&checker, &mut fork_db, unlisted, &v2_pair(unlisted, weth))
+            .unwrap();
+        assert_eq!(verdict, TokenVerdict::Inconclusive);
+        assert_eq!(registry.get(&unlisted), None);
+    }
+}
@@ -1,10 +1,12 @@
-use collectors::types::{BlockPayload, NewTx};
+use collectors::types::{BlockPayload, MevShareEvent, NewTx, TxEvent};
 
 /// Core Event implementation for the strategies
 #[derive(Debug, Clone)]
 pub enum Event {
     NewBlock(BlockPayload),
     NewMempoolTx(NewTx),
+    TxLifecycle(TxEvent),
+    MevShare(MevShareEvent),
 }
 
 impl From<BlockPayload> for Event {
@@ -19,6 +21,18 @@ impl From<NewTx> for Event {
     }
 }
 
+impl From<TxEvent> for Event {
+    fn from(event: TxEvent) -> Self {
+        Self::TxLifecycle(event)
+    }
+}
+
+impl From<MevShareEvent> for Event {
+    fn from(event: MevShareEvent) -> Self {
+        Self::MevShare(event)
+    }
+}
+
 /// Core Action implementation for the strategies
 #[derive(Debug, Clone)]
 pub enum Action {
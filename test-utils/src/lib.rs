@@ -0,0 +1,127 @@
+//! Helpers shared by the tests of the workspace crates
+use std::sync::{Arc, Mutex};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+};
+
+/// A request received by a [MockServer]
+#[derive(Debug, Clone, Default)]
+pub struct ReceivedRequest {
+    /// Request line and headers
+    pub head: String,
+    pub body: String,
+}
+
+impl ReceivedRequest {
+    /// Value of the header `name`, matched case insensitively
+    pub fn header(&self, name: &str) -> Option<String> {
+        header(&self.head, name)
+    }
+}
+
+/// A local HTTP server answering a single request with a canned response
+#[derive(Debug, Clone)]
+pub struct MockServer {
+    status: &'static str,
+    content_type: &'static str,
+    body: String,
+    /// Number of connections answered before the server stops
+    connections: usize,
+}
+
+impl MockServer {
+    /// Answers `200 OK` with the JSON `body`
+    pub fn json(body: impl ToString) -> Self {
+        Self {
+            status: "200 OK",
+            content_type: "application/json",
+            body: body.to_string(),
+            connections: 1,
+        }
+    }
+
+    /// Answers `200 OK` with a `text/event-stream` body sending each event as `data`
+    pub fn sse<'a>(events: impl IntoIterator<Item = &'a str>) -> Self {
+        let mut body = String::from(":heartbeat\n\n");
+        for event in events {
+            body.push_str(&format!("data: {}\n\n", event));
+        }
+        Self {
+            status: "200 OK",
+            content_type: "text/event-stream",
+            body,
+            connections: 1,
+        }
+    }
+
+    /// Sets the status line, e.g. `"400 Bad Request"`
+    pub fn with_status(mut self, status: &'static str) -> Self {
+        self.status = status;
+        self
+    }
+
+    /// Answers `connections` requests, one per connection, with the same response
+    pub fn with_connections(mut self, connections: usize) -> Self {
+        self.connections = connections;
+        self
+    }
+
+    /// Starts serving on a local socket
+    ///
+    /// Returns:
+    /// The url of the server and the last request it received, once it's answered
+    pub async fn spawn(self) -> (String, Arc<Mutex<Option<ReceivedRequest>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let received = Arc::new(Mutex::new(None));
+        let received_clone = received.clone();
+
+        tokio::spawn(async move {
+            for _ in 0..self.connections {
+                let (mut socket, _) = listener.accept().await.unwrap();
+
+                let mut request = vec![];
+                let mut buffer = [0u8; 1024];
+                let (head, body) = loop {
+                    let read = socket.read(&mut buffer).await.unwrap();
+                    request.extend_from_slice(&buffer[..read]);
+                    let text = String::from_utf8_lossy(&request).to_string();
+                    if let Some(end) = text.find("\r\n\r\n") {
+                        let head = text[..end].to_string();
+                        let length: usize = header(&head, "content-length")
+                            .map(|length| length.parse().unwrap())
+                            .unwrap_or(0);
+                        if request.len() >= end + 4 + length {
+                            break (head, text[end + 4..end + 4 + length].to_string());
+                        }
+                    }
+                    if read == 0 {
+                        return;
+                    }
+                };
+                *received_clone.lock().unwrap() = Some(ReceivedRequest { head, body });
+
+                let reply = format!(
+                    "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                    self.status,
+                    self.content_type,
+                    self.body.len(),
+                    self.body
+                );
+                socket.write_all(reply.as_bytes()).await.unwrap();
+                socket.shutdown().await.unwrap();
+            }
+        });
+
+        (format!("http://{}", addr), received)
+    }
+}
+
+fn header(head: &str, name: &str) -> Option<String> {
+    head.lines().find_map(|line| {
+        let (key, value) = line.split_once(':')?;
+        key.eq_ignore_ascii_case(name)
+            .then(|| value.trim().to_string())
+    })
+}